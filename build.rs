@@ -0,0 +1,94 @@
+// Packs `assets/` (or `$ASSETS_DIR`) into the bundle `assets::BUILTIN` links in. See
+// src/assets.rs for the format; the two have to agree.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+fn main() {
+    let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let dir = env::var("ASSETS_DIR")
+        .map(|dir| manifest.join(dir))
+        .unwrap_or_else(|_| manifest.join("assets"));
+    println!("cargo:rerun-if-env-changed=ASSETS_DIR");
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let mut assets = Vec::new();
+    if dir.is_dir() {
+        collect(&dir, &dir, &mut assets);
+    }
+    let mut entries: Vec<(u32, String, Vec<u8>)> = assets
+        .into_iter()
+        .map(|(name, data)| (fnv1a(name.as_bytes()), name, data))
+        .collect();
+    entries.sort_by_key(|(hash, ..)| *hash);
+    for pair in entries.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            panic!(
+                "assets {:?} and {:?} have the same hash; rename one",
+                pair[0].1, pair[1].1
+            );
+        }
+    }
+
+    let count = u16::try_from(entries.len()).expect("too many assets for one bundle");
+    let mut bundle = Vec::new();
+    bundle.extend_from_slice(b"RAST");
+    bundle.extend_from_slice(&1u16.to_le_bytes());
+    bundle.extend_from_slice(&count.to_le_bytes());
+    let mut offset = 8 + 16 * entries.len() as u32;
+    for (hash, _, data) in &entries {
+        for word in [*hash, offset, data.len() as u32, crc32(data)] {
+            bundle.extend_from_slice(&word.to_le_bytes());
+        }
+        offset += data.len() as u32;
+    }
+    for (_, _, data) in &entries {
+        bundle.extend_from_slice(data);
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("assets.bundle");
+    fs::write(out, bundle).unwrap();
+}
+
+// Every file under `dir`, named by its path from `root` with forward slashes.
+fn collect(root: &Path, dir: &Path, assets: &mut Vec<(String, Vec<u8>)>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        if path.is_dir() {
+            collect(root, &path, assets);
+        } else {
+            let name = path
+                .strip_prefix(root)
+                .unwrap()
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            assets.push((name, fs::read(&path).unwrap()));
+        }
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
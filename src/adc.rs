@@ -0,0 +1,51 @@
+// One-shot conversions on the ADC, shared by everything that samples an analog input.
+//
+// Channels 0-3 are GPIO26-29; channel 4 is the internal temperature sensor.
+
+pub const TEMPERATURE_SENSOR: u8 = 4;
+
+// Millivolts at full scale, with the usual 3.3V reference.
+pub const REFERENCE_MV: u32 = 3300;
+
+// Average `samples` conversions of `channel` and return the raw 12-bit result.
+//
+// Brings the ADC out of reset the first time it's used. GPIO channels have their pad switched
+// over to analog, so don't point this at a pin that's in use for something else.
+pub fn read(channel: u8, samples: u32) -> u16 {
+    assert!(channel <= TEMPERATURE_SENSOR, "no such ADC channel");
+    let adc = unsafe { &*rp2040_pac::ADC::ptr() };
+    cortex_m::interrupt::free(|_| {
+        // ADC
+        crate::resets::bring_up(1 << 0);
+        if channel < TEMPERATURE_SENSOR {
+            let pin = 26 + channel as usize;
+            let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+            let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+            // OD set, IE clear: no digital input or output fighting the analog signal.
+            pads.gpio[pin].modify(|r, w| unsafe { w.bits((r.bits() | 1 << 7) & !(1 << 6)) });
+            io.gpio[pin].gpio_ctrl.write(|w| unsafe { w.bits(0x1f) }); // FUNCSEL = NULL
+        }
+        // EN | TS_EN | AINSEL. The sensor is left on; it only draws a few tens of uA.
+        let cs = 1 | 1 << 1 | (channel as u32) << 12;
+        adc.cs.write(|w| unsafe { w.bits(cs) });
+        let samples = samples.max(1);
+        let mut total = 0;
+        for _ in 0..samples {
+            // READY goes high once the ADC (and the sensor) has settled.
+            while adc.cs.read().bits() & 1 << 8 == 0 {
+                cortex_m::asm::nop();
+            }
+            adc.cs.write(|w| unsafe { w.bits(cs | 1 << 2) }); // START_ONCE
+            while adc.cs.read().bits() & 1 << 8 == 0 {
+                cortex_m::asm::nop();
+            }
+            total += adc.result.read().bits() & 0xfff;
+        }
+        (total / samples) as u16
+    })
+}
+
+// Like `read`, converted to millivolts at the pin.
+pub fn read_mv(channel: u8, samples: u32) -> u32 {
+    read(channel, samples) as u32 * REFERENCE_MV / 4096
+}
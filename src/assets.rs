@@ -0,0 +1,259 @@
+// Read-only bundles of named assets (bitmaps, audio clips, PIO programs) so firmware can load
+// them by name instead of keeping track of offsets.
+//
+// build.rs packs everything under `assets/` (or `$ASSETS_DIR`) into a bundle, named by path
+// relative to it, and it's linked in as `BUILTIN`. The same file can be written to an external
+// flash chip or PSRAM instead and opened there; `Source` is anything a bundle can be read
+// from.
+//
+//     let mut bundle = Bundle::open(assets::BUILTIN, 0).await?;
+//     let splash = bundle.find("ui/splash.raw").await?;
+//     let mut reader = bundle.reader(splash);
+//     while let n @ 1.. = reader.read(&mut line).await? { ... }
+//
+// The format, all little-endian: "RAST", a u16 version (1) and a u16 count, then `count`
+// index entries sorted by hash, then the assets. Each entry is the FNV-1a hash of the name,
+// the asset's offset from the start of the bundle, its length and its CRC-32, all u32. build.rs
+// refuses to pack two names with the same hash.
+
+use embedded_hal_async::spi::SpiDevice;
+use embedded_io_async::{ErrorKind, ErrorType, Read};
+use embedded_storage_async::nor_flash::ReadNorFlash;
+
+use crate::{psram::Psram, spi_flash::SpiFlash};
+
+const MAGIC: [u8; 4] = *b"RAST";
+const VERSION: u16 = 1;
+const HEADER: u32 = 8;
+const ENTRY: u32 = 16;
+
+// The bundle build.rs made from `assets/`, linked into the boot flash.
+pub static BUILTIN: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/assets.bundle"));
+
+// Somewhere a bundle can be read from.
+#[allow(async_fn_in_trait)]
+pub trait Source {
+    type Error: core::fmt::Debug;
+
+    async fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+// Memory-mapped: `BUILTIN`, or anything else in the boot flash or RAM.
+impl Source for &'static [u8] {
+    type Error = ();
+
+    async fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+        let start = offset as usize;
+        let bytes = self.get(start..start + buf.len()).ok_or(())?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl<S: SpiDevice> Source for SpiFlash<S> {
+    type Error = crate::spi_flash::Error<S::Error>;
+
+    async fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.read(offset, buf).await
+    }
+}
+
+impl<S: SpiDevice> Source for Psram<S> {
+    type Error = crate::psram::Error<S::Error>;
+
+    async fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.read(offset, buf).await
+    }
+}
+
+// Any other `NorFlash` chip.
+pub struct Flash<F>(pub F);
+
+impl<F: ReadNorFlash> Source for Flash<F> {
+    type Error = F::Error;
+
+    async fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), F::Error> {
+        self.0.read(offset, buf).await
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Source(E),
+    // There's no bundle there, or it's a version this doesn't understand.
+    NotABundle,
+    NotFound,
+    // The asset's contents don't match its CRC.
+    Corrupt,
+}
+
+impl<E: core::fmt::Debug> embedded_io_async::Error for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Corrupt => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+// Where one asset is in its bundle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Asset {
+    offset: u32,
+    len: u32,
+    crc: u32,
+}
+
+impl Asset {
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+pub struct Bundle<S> {
+    source: S,
+    base: u32,
+    count: u16,
+}
+
+impl<S: Source> Bundle<S> {
+    // Open the bundle that starts `base` bytes into `source`.
+    pub async fn open(mut source: S, base: u32) -> Result<Self, Error<S::Error>> {
+        let mut header = [0; HEADER as usize];
+        source
+            .read_at(base, &mut header)
+            .await
+            .map_err(Error::Source)?;
+        if header[..4] != MAGIC || u16::from_le_bytes([header[4], header[5]]) != VERSION {
+            return Err(Error::NotABundle);
+        }
+        Ok(Bundle {
+            source,
+            base,
+            count: u16::from_le_bytes([header[6], header[7]]),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn release(self) -> S {
+        self.source
+    }
+
+    pub async fn find(&mut self, name: &str) -> Result<Asset, Error<S::Error>> {
+        let hash = fnv1a(name.as_bytes());
+        let (mut lo, mut hi) = (0, self.count as u32);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let mut entry = [0; ENTRY as usize];
+            self.source
+                .read_at(self.base + HEADER + mid * ENTRY, &mut entry)
+                .await
+                .map_err(Error::Source)?;
+            let word = |i: usize| u32::from_le_bytes(entry[i * 4..i * 4 + 4].try_into().unwrap());
+            match word(0).cmp(&hash) {
+                core::cmp::Ordering::Less => lo = mid + 1,
+                core::cmp::Ordering::Greater => hi = mid,
+                core::cmp::Ordering::Equal => {
+                    return Ok(Asset {
+                        offset: word(1),
+                        len: word(2),
+                        crc: word(3),
+                    })
+                }
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    // Read from `at` bytes into `asset`, as much as fits in `buf` or is left. Returns how much
+    // that was.
+    pub async fn read(
+        &mut self,
+        asset: &Asset,
+        at: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<S::Error>> {
+        let len = (asset.len.saturating_sub(at) as usize).min(buf.len());
+        self.source
+            .read_at(self.base + asset.offset + at, &mut buf[..len])
+            .await
+            .map_err(Error::Source)?;
+        Ok(len)
+    }
+
+    // Read `asset` through from the start, a chunk at a time.
+    pub fn reader(&mut self, asset: Asset) -> Reader<'_, S> {
+        Reader {
+            bundle: self,
+            asset,
+            at: 0,
+        }
+    }
+
+    // Check `asset` against its CRC, reading it through `buf`.
+    pub async fn verify(&mut self, asset: &Asset, buf: &mut [u8]) -> Result<(), Error<S::Error>> {
+        let mut crc = !0;
+        let mut at = 0;
+        while at < asset.len {
+            let n = self.read(asset, at, buf).await?;
+            crc = crc32_update(crc, &buf[..n]);
+            at += n as u32;
+        }
+        if !crc == asset.crc {
+            Ok(())
+        } else {
+            Err(Error::Corrupt)
+        }
+    }
+}
+
+// Streams one asset, as `embedded_io_async::Read`.
+pub struct Reader<'a, S> {
+    bundle: &'a mut Bundle<S>,
+    asset: Asset,
+    at: u32,
+}
+
+impl<S: Source> ErrorType for Reader<'_, S> {
+    type Error = Error<S::Error>;
+}
+
+impl<S: Source> Read for Reader<'_, S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.bundle.read(&self.asset, self.at, buf).await?;
+        self.at += n as u32;
+        Ok(n)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+// CRC-32 (the zlib one), without the final inversion so it can be carried across chunks.
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
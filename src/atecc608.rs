@@ -0,0 +1,266 @@
+// Microchip ATECC608 secure element: keys that never leave the chip, for device identity and
+// TLS. Signs digests and does ECDH with P-256 keys held in its slots, and has a hardware
+// random number generator.
+//
+// The chip sleeps between uses, and loses its TempKey (the digest `sign` works on) when it
+// does, so each call here wakes it, runs its commands and puts it back to sleep.
+//
+// Waking it takes SDA held low for 60 µs, which is done by addressing the general call
+// address: eight low bits at 100 kHz is 80 µs. On a faster bus, drop to 100 kHz around these
+// calls or wire SDA up for a manual wake.
+//
+// Which slots hold which keys, and what they may be used for, is the chip's configuration,
+// which is locked at provisioning time and is none of this driver's business.
+
+use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+pub const ADDRESS: u8 = 0x60;
+
+// Word addresses: what the first byte of a write is.
+const WORD_SLEEP: u8 = 0x01;
+const WORD_COMMAND: u8 = 0x03;
+
+const OP_READ: u8 = 0x02;
+const OP_RANDOM: u8 = 0x1b;
+const OP_NONCE: u8 = 0x16;
+const OP_GENKEY: u8 = 0x40;
+const OP_SIGN: u8 = 0x41;
+const OP_ECDH: u8 = 0x43;
+const OP_VERIFY: u8 = 0x45;
+
+// What the chip says when it's just woken up.
+const WAKE_RESPONSE: [u8; 4] = [0x04, 0x11, 0x33, 0x43];
+// tWHI, from the wake pulse to the first command.
+const WAKE_DELAY_US: u32 = 1500;
+// Longest a response can be: the count, 64 bytes and the CRC.
+const MAX_RESPONSE: usize = 1 + 64 + 2;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    // A response failed its CRC.
+    Crc,
+    // The chip didn't answer the wake as it should.
+    Wake,
+    // The chip's status code for a failed command: 0x01 a verify miscompare, 0x03 a bad
+    // command, 0x05 an ECC fault, 0x0f an execution error (a slot that doesn't allow it, say).
+    Status(u8),
+}
+
+pub struct Atecc608<I, D> {
+    i2c: I,
+    address: u8,
+    delay: D,
+}
+
+impl<I: I2c, D: DelayNs> Atecc608<I, D> {
+    // Doesn't talk to the chip until it's used.
+    pub fn new(i2c: I, address: u8, delay: D) -> Self {
+        Atecc608 {
+            i2c,
+            address,
+            delay,
+        }
+    }
+
+    // 32 bytes from the hardware RNG.
+    pub async fn random(&mut self) -> Result<[u8; 32], Error<I::Error>> {
+        self.session(async |chip| {
+            let mut random = [0; 32];
+            chip.command(OP_RANDOM, 0, 0, &[], 23, &mut random).await?;
+            Ok(random)
+        })
+        .await
+    }
+
+    // The chip's 9-byte serial number, from the start of the config zone.
+    pub async fn serial_number(&mut self) -> Result<[u8; 9], Error<I::Error>> {
+        self.session(async |chip| {
+            let mut config = [0; 32];
+            // Zone = config, 32 bytes, block 0.
+            chip.command(OP_READ, 0x80, 0, &[], 2, &mut config).await?;
+            let mut serial = [0; 9];
+            serial[..4].copy_from_slice(&config[..4]);
+            serial[4..].copy_from_slice(&config[8..13]);
+            Ok(serial)
+        })
+        .await
+    }
+
+    // The P-256 public key (X then Y) for the private key in `slot`.
+    pub async fn public_key(&mut self, slot: u16) -> Result<[u8; 64], Error<I::Error>> {
+        self.session(async |chip| {
+            let mut key = [0; 64];
+            // Mode 0: compute the public key from the stored private key.
+            chip.command(OP_GENKEY, 0x00, slot, &[], 115, &mut key)
+                .await?;
+            Ok(key)
+        })
+        .await
+    }
+
+    // Sign `digest` (a SHA-256 hash) with the private key in `slot`. Returns R then S.
+    pub async fn sign(
+        &mut self,
+        slot: u16,
+        digest: &[u8; 32],
+    ) -> Result<[u8; 64], Error<I::Error>> {
+        self.session(async |chip| {
+            // Nonce in pass-through mode puts the digest in TempKey as it is.
+            chip.command(OP_NONCE, 0x03, 0, digest, 7, &mut []).await?;
+            let mut signature = [0; 64];
+            // Mode 0x80: sign an external message, the one in TempKey.
+            chip.command(OP_SIGN, 0x80, slot, &[], 115, &mut signature)
+                .await?;
+            Ok(signature)
+        })
+        .await
+    }
+
+    // ECDH between the private key in `slot` and `public_key` (X then Y). Returns the shared
+    // secret's X coordinate, which the slot has to be configured to allow out in the clear.
+    pub async fn ecdh(
+        &mut self,
+        slot: u16,
+        public_key: &[u8; 64],
+    ) -> Result<[u8; 32], Error<I::Error>> {
+        self.session(async |chip| {
+            let mut secret = [0; 32];
+            // Mode 0x0c: the result goes to the output buffer, unencrypted.
+            chip.command(OP_ECDH, 0x0c, slot, public_key, 58, &mut secret)
+                .await?;
+            Ok(secret)
+        })
+        .await
+    }
+
+    // Check `signature` (R then S) over `digest` against an outside P-256 `public_key` (X then
+    // Y), as certificate chains need. A signature that doesn't match is `Ok(false)`.
+    pub async fn verify(
+        &mut self,
+        digest: &[u8; 32],
+        signature: &[u8; 64],
+        public_key: &[u8; 64],
+    ) -> Result<bool, Error<I::Error>> {
+        self.session(async |chip| {
+            chip.command(OP_NONCE, 0x03, 0, digest, 7, &mut []).await?;
+            let mut data = [0; 128];
+            data[..64].copy_from_slice(signature);
+            data[64..].copy_from_slice(public_key);
+            // Mode 0x02: external public key; param2 4: it's a P-256 key.
+            match chip.command(OP_VERIFY, 0x02, 4, &data, 58, &mut []).await {
+                Ok(()) => Ok(true),
+                Err(Error::Status(0x01)) => Ok(false),
+                Err(error) => Err(error),
+            }
+        })
+        .await
+    }
+
+    pub fn release(self) -> (I, D) {
+        (self.i2c, self.delay)
+    }
+
+    // Wake the chip, run `f`, and put it back to sleep whatever happened.
+    async fn session<T>(
+        &mut self,
+        f: impl AsyncFnOnce(&mut Self) -> Result<T, Error<I::Error>>,
+    ) -> Result<T, Error<I::Error>> {
+        self.wake().await?;
+        let result = f(self).await;
+        let slept = self
+            .i2c
+            .write(self.address, &[WORD_SLEEP])
+            .await
+            .map_err(Error::I2c);
+        let value = result?;
+        slept?;
+        Ok(value)
+    }
+
+    async fn wake(&mut self) -> Result<(), Error<I::Error>> {
+        // Nobody answers the general call; the low address bits are the point.
+        let _ = self.i2c.write(0x00, &[]).await;
+        self.delay.delay_us(WAKE_DELAY_US).await;
+        let mut response = [0; 4];
+        self.i2c
+            .read(self.address, &mut response)
+            .await
+            .map_err(Error::I2c)?;
+        if response != WAKE_RESPONSE {
+            return Err(Error::Wake);
+        }
+        Ok(())
+    }
+
+    // Send a command and read its response into `out`, which is however long the response
+    // is; empty for commands that only return a status. `max_ms` is the longest the datasheet
+    // says the command takes: the chip doesn't answer its address until it's done, so the
+    // response is polled for until then.
+    async fn command(
+        &mut self,
+        opcode: u8,
+        param1: u8,
+        param2: u16,
+        data: &[u8],
+        max_ms: u32,
+        out: &mut [u8],
+    ) -> Result<(), Error<I::Error>> {
+        let mut packet = [0; 1 + 1 + 1 + 1 + 2 + 128 + 2];
+        let count = 7 + data.len();
+        packet[0] = WORD_COMMAND;
+        packet[1] = count as u8;
+        packet[2] = opcode;
+        packet[3] = param1;
+        packet[4..6].copy_from_slice(&param2.to_le_bytes());
+        packet[6..6 + data.len()].copy_from_slice(data);
+        let crc = crc16(&packet[1..count - 1]);
+        packet[count - 1..count + 1].copy_from_slice(&crc.to_le_bytes());
+        self.i2c
+            .write(self.address, &packet[..1 + count])
+            .await
+            .map_err(Error::I2c)?;
+
+        // A status-only response is 4 bytes, the same as an empty one would be with its CRC.
+        let len = 1 + out.len().max(1) + 2;
+        let mut response = [0; MAX_RESPONSE];
+        let mut waited = 0;
+        loop {
+            self.delay.delay_ms(1).await;
+            waited += 1;
+            match self.i2c.read(self.address, &mut response[..len]).await {
+                Ok(()) => break,
+                Err(_) if waited < max_ms => {}
+                Err(error) => return Err(Error::I2c(error)),
+            }
+        }
+        let n = response[0] as usize;
+        if !(4..=len).contains(&n)
+            || crc16(&response[..n - 2]) != u16::from_le_bytes([response[n - 2], response[n - 1]])
+        {
+            return Err(Error::Crc);
+        }
+        // A status packet where data was expected, or a status that isn't success.
+        if n == 4 && (!out.is_empty() || response[1] != 0) {
+            return Err(Error::Status(response[1]));
+        }
+        out.copy_from_slice(&response[1..1 + out.len()]);
+        Ok(())
+    }
+}
+
+// CRC-16 with polynomial 0x8005, fed LSB first, as the chip checks packets with.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        for bit in 0..8 {
+            let data_bit = (byte >> bit) & 1 != 0;
+            let crc_bit = crc & 0x8000 != 0;
+            crc <<= 1;
+            if data_bit != crc_bit {
+                crc ^= 0x8005;
+            }
+        }
+    }
+    crc
+}
@@ -0,0 +1,75 @@
+// Atomics with the full set of operations, not just load and store.
+//
+// The M0+ has no exclusive load/store instructions, so `core::sync::atomic` can't do
+// `compare_exchange`, `fetch_add` and so on. These come from `portable-atomic`, which does
+// them inside a critical section. The critical section is implemented here: interrupts off on
+// this core, plus a hardware spinlock so the other core keeps out too.
+//
+// Plain loads and stores are still single instructions and don't take the lock.
+
+pub use portable_atomic::{
+    AtomicBool, AtomicI16, AtomicI32, AtomicI8, AtomicIsize, AtomicU16, AtomicU32, AtomicU8,
+    AtomicUsize, Ordering,
+};
+
+// Host unit tests use critical-section's `std` implementation instead.
+#[cfg(not(test))]
+mod dual_core {
+    use core::sync::atomic;
+
+    use crate::sync::{self, SpinLock};
+
+    // Spinlock 31 is kept for this; nothing else takes it directly. `sync::Mutex` shares it by
+    // going through the critical section.
+    static LOCK: SpinLock<31> = SpinLock::new();
+
+    // How many critical sections each core is inside. Only ever touched by its own core, with
+    // interrupts off.
+    static DEPTH: [atomic::AtomicU8; 2] = [atomic::AtomicU8::new(0), atomic::AtomicU8::new(0)];
+    // Whether interrupts were enabled before each core's outermost section.
+    static WERE_ENABLED: [atomic::AtomicBool; 2] = [
+        atomic::AtomicBool::new(false),
+        atomic::AtomicBool::new(false),
+    ];
+
+    struct DualCore;
+    critical_section::set_impl!(DualCore);
+
+    // Sections are counted rather than handing back a restore state, so they can end in any
+    // order: `Mutex` guards are dropped whenever their owner likes, not innermost first. The
+    // lock is let go, and interrupts put back, when the last one on the core ends.
+    unsafe impl critical_section::Impl for DualCore {
+        unsafe fn acquire() {
+            let enabled = cortex_m::register::primask::read().is_active();
+            cortex_m::interrupt::disable();
+            let core = sync::core();
+            let depth = DEPTH[core].load(atomic::Ordering::Relaxed);
+            if depth == 0 {
+                LOCK.lock();
+                WERE_ENABLED[core].store(enabled, atomic::Ordering::Relaxed);
+            }
+            DEPTH[core].store(depth + 1, atomic::Ordering::Relaxed);
+        }
+
+        unsafe fn release(_: ()) {
+            let core = sync::core();
+            let depth = DEPTH[core].load(atomic::Ordering::Relaxed) - 1;
+            DEPTH[core].store(depth, atomic::Ordering::Relaxed);
+            if depth != 0 {
+                return;
+            }
+            // Safety: We took it in the outermost `acquire`.
+            unsafe { LOCK.unlock() };
+            if WERE_ENABLED[core].load(atomic::Ordering::Relaxed) {
+                // Safety: They were enabled before the outermost section started.
+                unsafe { cortex_m::interrupt::enable() };
+            }
+        }
+    }
+}
+
+// Run `f` inside the same critical section the atomics use, for a check and an update that
+// have to happen as one.
+pub fn with<R>(f: impl FnOnce() -> R) -> R {
+    critical_section::with(|_| f())
+}
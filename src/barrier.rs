@@ -0,0 +1,37 @@
+// Memory barriers, in one place so the ordering story between the cores can be checked.
+//
+// The RP2040 has no caches and its buses complete writes in order, so in practice the
+// hardware rarely reorders anything. The compiler does, though, and code here also has to stay
+// right under the architecture's rules, which allow more. Every cross-core handoff goes
+// through one of these rather than a bare fence:
+//
+// - `publish` before letting the other side see that data is ready: releasing a lock, pushing
+//   a pointer through the FIFO, setting a flag.
+// - `acquire` after seeing that it is: taking a lock, popping from the FIFO, reading a flag.
+// - `settle` after changing how this core itself handles exceptions or fetches instructions
+//   (VTOR, vector table entries, NVIC masks, MPU), before relying on the change.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+// Everything written before this is visible to anyone who `acquire`s after seeing a write
+// made after it.
+#[inline(always)]
+pub fn publish() {
+    compiler_fence(Ordering::Release);
+    cortex_m::asm::dmb();
+}
+
+// Reads after this see everything the other side wrote before its `publish`.
+#[inline(always)]
+pub fn acquire() {
+    cortex_m::asm::dmb();
+    compiler_fence(Ordering::Acquire);
+}
+
+// Wait for all outstanding writes to complete and refetch the following instructions, so
+// system configuration changes take effect before the next instruction.
+#[inline(always)]
+pub fn settle() {
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
@@ -0,0 +1,182 @@
+// Sharing one SPI or I2C bus between several device drivers.
+//
+// Each driver gets its own device handle and uses it like a bus of its own; the handles take
+// turns one whole transaction at a time. Drivers on different tasks can use the bus
+// concurrently without any locking of their own.
+//
+// Handles can have a priority. When a transaction finishes, the highest priority handle that's
+// waiting goes next, so a touch controller doesn't sit behind a queue of display flushes.
+// Anything left waiting longer than the starvation limit goes first regardless.
+
+mod arbiter;
+
+use embedded_hal::{
+    digital::OutputPin,
+    spi::{self, ErrorKind, Operation},
+};
+use embedded_hal_async::{
+    delay::DelayNs,
+    i2c::{self, I2c, SevenBitAddress},
+    spi::{SpiBus, SpiDevice},
+};
+
+use core::time::Duration;
+
+use crate::time::Delay;
+
+use arbiter::Arbiter;
+
+// Priority of handles made without one. Higher goes first.
+pub const DEFAULT_PRIORITY: u8 = 0;
+
+const STARVATION_LIMIT: Duration = Duration::from_millis(50);
+
+pub struct SharedSpi<B> {
+    bus: Arbiter<B>,
+}
+
+impl<B: SpiBus> SharedSpi<B> {
+    pub const fn new(bus: B) -> Self {
+        SharedSpi {
+            bus: Arbiter::new(bus, STARVATION_LIMIT),
+        }
+    }
+
+    // How long a waiting transaction can be passed over before it goes first. 50ms by default.
+    pub fn set_starvation_limit(&self, limit: Duration) {
+        self.bus.set_starvation_limit(limit);
+    }
+
+    // A device on the bus, selected by `cs`. The pin is set high (deselected) straight away.
+    pub fn device<C: OutputPin>(&self, cs: C) -> Result<SharedSpiDevice<'_, B, C>, C::Error> {
+        self.device_with_priority(cs, DEFAULT_PRIORITY)
+    }
+
+    pub fn device_with_priority<C: OutputPin>(
+        &self,
+        mut cs: C,
+        priority: u8,
+    ) -> Result<SharedSpiDevice<'_, B, C>, C::Error> {
+        cs.set_high()?;
+        Ok(SharedSpiDevice {
+            bus: self,
+            cs,
+            priority,
+        })
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus.into_inner()
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceError<B, C> {
+    Spi(B),
+    Cs(C),
+}
+
+impl<B: spi::Error, C: core::fmt::Debug> spi::Error for DeviceError<B, C> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DeviceError::Spi(e) => e.kind(),
+            DeviceError::Cs(_) => ErrorKind::ChipSelectFault,
+        }
+    }
+}
+
+pub struct SharedSpiDevice<'a, B, C> {
+    bus: &'a SharedSpi<B>,
+    cs: C,
+    priority: u8,
+}
+
+impl<B: SpiBus, C: OutputPin> spi::ErrorType for SharedSpiDevice<'_, B, C> {
+    type Error = DeviceError<B::Error, C::Error>;
+}
+
+impl<B: SpiBus, C: OutputPin> SpiDevice for SharedSpiDevice<'_, B, C> {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        let mut bus = self.bus.bus.lock(self.priority).await;
+        self.cs.set_low().map_err(DeviceError::Cs)?;
+        let result = run(&mut *bus, operations).await;
+        // Always finish the transfer and deselect, even if something went wrong part way.
+        let flushed = bus.flush().await;
+        let deselected = self.cs.set_high();
+        result.map_err(DeviceError::Spi)?;
+        flushed.map_err(DeviceError::Spi)?;
+        deselected.map_err(DeviceError::Cs)
+    }
+}
+
+async fn run<B: SpiBus>(bus: &mut B, operations: &mut [Operation<'_, u8>]) -> Result<(), B::Error> {
+    for operation in operations {
+        match operation {
+            Operation::Read(buf) => bus.read(buf).await?,
+            Operation::Write(buf) => bus.write(buf).await?,
+            Operation::Transfer(read, write) => bus.transfer(read, write).await?,
+            Operation::TransferInPlace(buf) => bus.transfer_in_place(buf).await?,
+            Operation::DelayNs(ns) => {
+                // The delay is from the end of the previous operation, not just its queueing.
+                bus.flush().await?;
+                Delay.delay_ns(*ns).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct SharedI2c<B> {
+    bus: Arbiter<B>,
+}
+
+impl<B: I2c> SharedI2c<B> {
+    pub const fn new(bus: B) -> Self {
+        SharedI2c {
+            bus: Arbiter::new(bus, STARVATION_LIMIT),
+        }
+    }
+
+    pub fn set_starvation_limit(&self, limit: Duration) {
+        self.bus.set_starvation_limit(limit);
+    }
+
+    // A handle for one driver. I2C devices are picked by address, so any number can be made.
+    pub fn device(&self) -> SharedI2cDevice<'_, B> {
+        self.device_with_priority(DEFAULT_PRIORITY)
+    }
+
+    pub fn device_with_priority(&self, priority: u8) -> SharedI2cDevice<'_, B> {
+        SharedI2cDevice {
+            bus: self,
+            priority,
+        }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.bus.into_inner()
+    }
+}
+
+pub struct SharedI2cDevice<'a, B> {
+    bus: &'a SharedI2c<B>,
+    priority: u8,
+}
+
+impl<B: I2c> i2c::ErrorType for SharedI2cDevice<'_, B> {
+    type Error = B::Error;
+}
+
+impl<B: I2c> I2c for SharedI2cDevice<'_, B> {
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [i2c::Operation<'_>],
+    ) -> Result<(), B::Error> {
+        let mut bus = self.bus.bus.lock(self.priority).await;
+        bus.transaction(address, operations).await
+    }
+}
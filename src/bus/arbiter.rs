@@ -0,0 +1,203 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use crate::{sync::Mutex, time::Instant};
+
+struct Waiting {
+    ticket: u32,
+    priority: u8,
+    since: Instant,
+    waker: Waker,
+    // Handed the bus, but hasn't been polled to pick it up yet.
+    granted: bool,
+}
+
+struct State {
+    locked: bool,
+    waiting: Vec<Waiting>,
+    next_ticket: u32,
+    starvation_limit: Duration,
+}
+
+// An async mutex that goes to the highest priority waiter when it's released, instead of the
+// first to arrive. A waiter that's been passed over for longer than the starvation limit goes
+// ahead of everyone, so a steady stream of urgent transactions can't lock out bulk ones.
+pub(super) struct Arbiter<T> {
+    state: Mutex<State, 28>,
+    data: UnsafeCell<T>,
+}
+
+impl<T> Arbiter<T> {
+    pub const fn new(data: T, starvation_limit: Duration) -> Self {
+        Arbiter {
+            state: Mutex::new(State {
+                locked: false,
+                waiting: Vec::new(),
+                next_ticket: 0,
+                starvation_limit,
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn set_starvation_limit(&self, limit: Duration) {
+        self.state.with(|state| state.starvation_limit = limit);
+    }
+
+    pub fn lock(&self, priority: u8) -> Acquire<'_, T> {
+        Acquire {
+            arbiter: self,
+            priority,
+            ticket: None,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    // Hand the bus to the next waiter, or free it if there's nobody.
+    fn release(&self) {
+        let waker = self.state.with(|state| {
+            let now = Instant::now();
+            let limit = state.starvation_limit;
+            // Tickets are handed out in order, so the first starving one is the oldest.
+            let next = state
+                .waiting
+                .iter()
+                .position(|w| now - w.since >= limit)
+                .or_else(|| {
+                    // Highest priority, first come first served among equals. `max_by_key`
+                    // picks the last of equals, hence the `rev`.
+                    let (index, _) = state
+                        .waiting
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .max_by_key(|(_, w)| w.priority)?;
+                    Some(index)
+                });
+            match next {
+                Some(index) => {
+                    let waiting = &mut state.waiting[index];
+                    waiting.granted = true;
+                    Some(waiting.waker.clone())
+                }
+                None => {
+                    state.locked = false;
+                    None
+                }
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+unsafe impl<T> Sync for Arbiter<T> where T: Send {}
+
+pub(super) struct Acquire<'a, T> {
+    arbiter: &'a Arbiter<T>,
+    priority: u8,
+    // Set once we're in the queue.
+    ticket: Option<u32>,
+}
+
+impl<'a, T> Future for Acquire<'a, T> {
+    type Output = ArbiterGuard<'a, T>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<ArbiterGuard<'a, T>> {
+        let priority = self.priority;
+        let ticket = self.ticket;
+        let (ready, ticket) = self.arbiter.state.with(|state| match ticket {
+            None if !state.locked && state.waiting.is_empty() => {
+                state.locked = true;
+                (true, None)
+            }
+            None => {
+                let ticket = state.next_ticket;
+                state.next_ticket = state.next_ticket.wrapping_add(1);
+                state.waiting.push(Waiting {
+                    ticket,
+                    priority,
+                    since: Instant::now(),
+                    waker: cx.waker().clone(),
+                    granted: false,
+                });
+                (false, Some(ticket))
+            }
+            Some(ticket) => {
+                let index = state
+                    .waiting
+                    .iter()
+                    .position(|w| w.ticket == ticket)
+                    .expect("Acquire polled after completing");
+                if state.waiting[index].granted {
+                    state.waiting.remove(index);
+                    (true, None)
+                } else {
+                    state.waiting[index].waker = cx.waker().clone();
+                    (false, Some(ticket))
+                }
+            }
+        });
+        self.ticket = ticket;
+        if ready {
+            Poll::Ready(ArbiterGuard {
+                arbiter: self.arbiter,
+            })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Acquire<'_, T> {
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        // Given up on. If the bus was already handed to us, pass it on.
+        let granted = self.arbiter.state.with(|state| {
+            let index = state.waiting.iter().position(|w| w.ticket == ticket)?;
+            Some(state.waiting.remove(index).granted)
+        });
+        if granted == Some(true) {
+            self.arbiter.release();
+        }
+    }
+}
+
+pub(super) struct ArbiterGuard<'a, T> {
+    arbiter: &'a Arbiter<T>,
+}
+
+impl<T> Drop for ArbiterGuard<'_, T> {
+    fn drop(&mut self) {
+        self.arbiter.release();
+    }
+}
+
+impl<T> Deref for ArbiterGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: We hold the lock, so nobody else has a reference to the data.
+        unsafe { &*self.arbiter.data.get() }
+    }
+}
+
+impl<T> DerefMut for ArbiterGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: We hold the lock, so nobody else has a reference to the data.
+        unsafe { &mut *self.arbiter.data.get() }
+    }
+}
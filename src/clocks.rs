@@ -0,0 +1,195 @@
+// What the clocks are running at, for drivers that turn durations into cycle counts.
+//
+// Nothing in the tree sets the clocks up, so rather than take them on trust they're measured
+// with the frequency counter in the CLOCKS block, against clk_ref. clk_sys is cached, since
+// drivers want it all the time; call `invalidate` after changing it, and everything that
+// converts through here picks up the new rate. `generation` changes too, for drivers that
+// keep their own cycle counts around.
+//
+// `Instant` doesn't need any of this: the TIMER (and SysTick, with `systick-time`) count the
+// 1 µs reference tick, which comes from clk_ref and is the same whatever clk_sys is. The tick
+// is clk_ref divided by a whole number, though, so switching clk_ref over (from the ROSC to
+// the XOSC, say) needs `ref_changed` to keep it at a microsecond.
+
+use core::{ptr, time::Duration};
+
+use crate::atomic::{AtomicBool, AtomicU32, Ordering};
+
+static SYS_HZ: AtomicU32 = AtomicU32::new(0);
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+// The frequency counter is shared by both cores.
+static COUNTER_BUSY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Frequencies {
+    pub ref_hz: u32,
+    pub sys_hz: u32,
+    pub peri_hz: u32,
+    pub usb_hz: u32,
+    pub adc_hz: u32,
+    pub rtc_hz: u32,
+    // The sources, whether or not anything's using them. 0 for one that isn't running.
+    pub xosc_hz: u32,
+    pub rosc_hz: u32,
+    pub pll_sys_hz: u32,
+    pub pll_usb_hz: u32,
+}
+
+// FC0_SRC values.
+#[derive(Clone, Copy)]
+#[repr(u32)]
+enum Source {
+    PllSys = 0x01,
+    PllUsb = 0x02,
+    Rosc = 0x03,
+    Xosc = 0x05,
+    Sys = 0x09,
+    Peri = 0x0a,
+    Usb = 0x0b,
+    Adc = 0x0c,
+    Rtc = 0x0d,
+}
+
+pub fn sys_hz() -> u32 {
+    match SYS_HZ.load(Ordering::Relaxed) {
+        0 => {
+            let hz = measure(Source::Sys);
+            SYS_HZ.store(hz, Ordering::Relaxed);
+            hz
+        }
+        hz => hz,
+    }
+}
+
+// Forget the cached frequency, after clk_sys has been changed.
+pub fn invalidate() {
+    SYS_HZ.store(0, Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+// Changes whenever clk_sys might have, so a driver that worked out cycle counts can tell it
+// needs to do it again.
+pub fn generation() -> u32 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+// Measure every clock. Takes a millisecond or so per clock.
+pub fn frequencies() -> Frequencies {
+    Frequencies {
+        ref_hz: ref_hz(),
+        sys_hz: measure(Source::Sys),
+        peri_hz: measure(Source::Peri),
+        usb_hz: measure(Source::Usb),
+        adc_hz: measure(Source::Adc),
+        rtc_hz: measure(Source::Rtc),
+        xosc_hz: measure(Source::Xosc),
+        rosc_hz: measure(Source::Rosc),
+        pll_sys_hz: measure(Source::PllSys),
+        pll_usb_hz: measure(Source::PllUsb),
+    }
+}
+
+// clk_ref, going by how the reference tick divides it down to a microsecond.
+pub fn ref_hz() -> u32 {
+    let watchdog = unsafe { &*rp2040_pac::WATCHDOG::ptr() };
+    // CYCLES
+    (watchdog.tick.read().bits() & 0x1ff) * 1_000_000
+}
+
+// Call after switching clk_ref to `ref_hz`, to keep the reference tick (and so `Instant`) at
+// a microsecond. It has to be a whole number of MHz; the ROSC isn't, so time only runs
+// roughly right while clk_ref comes from it. Also invalidates clk_sys, in case it runs from
+// clk_ref.
+pub fn ref_changed(ref_hz: u32) {
+    let watchdog = unsafe { &*rp2040_pac::WATCHDOG::ptr() };
+    let cycles = ((ref_hz + 500_000) / 1_000_000).clamp(1, 0x1ff);
+    // ENABLE
+    watchdog.tick.write(|w| unsafe { w.bits(1 << 9 | cycles) });
+    invalidate();
+}
+
+// Put clk_sys divided down to about `hz` out on GPOUT `gpout` (0-3, on GPIO 21, 23, 24 and 25),
+// for parts that want a clock from us: smart cards, audio codecs, cameras. Returns the rate it
+// got, which is as close as a 24.8 divider allows.
+pub fn start_gpout(gpout: u8, hz: u32) -> u32 {
+    let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+    let pin = match gpout {
+        0 => 21,
+        1 => 23,
+        2 => 24,
+        3 => 25,
+        _ => panic!("no such GPOUT"),
+    };
+    // Each GPOUT has a CTRL, DIV and SELECTED register, in that order from the start of
+    // CLOCKS. They're different types in the PAC, so go by address.
+    let ctrl = unsafe { (rp2040_pac::CLOCKS::ptr() as *mut u32).add(3 * gpout as usize) };
+    let div = unsafe { ctrl.add(1) };
+    let sys_hz = sys_hz();
+    let divisor = ((sys_hz as u64 * 256 / hz.max(1) as u64) as u32).max(256);
+    unsafe {
+        // Off while it's changed, then AUXSRC = clk_sys, ENABLE.
+        ptr::write_volatile(ctrl, 0);
+        ptr::write_volatile(div, divisor);
+        ptr::write_volatile(ctrl, 1 << 11 | 0x6 << 5);
+    }
+    // FUNCSEL = GPCK
+    io.gpio[pin].gpio_ctrl.write(|w| unsafe { w.bits(8) });
+    (sys_hz as u64 * 256 / divisor as u64) as u32
+}
+
+// clk_sys cycles in `duration`, at the current rate.
+pub fn cycles(duration: Duration) -> u64 {
+    (duration.as_nanos() * sys_hz() as u128 / 1_000_000_000) as u64
+}
+
+pub fn duration_of(cycles: u64) -> Duration {
+    Duration::from_nanos(cycles * 1_000_000_000 / sys_hz().max(1) as u64)
+}
+
+// Busy-wait for at least `duration` by counting cycles. For before anything's set up, or with
+// interrupts off; otherwise the blocking `time::Delay` is more accurate.
+pub fn spin(duration: Duration) {
+    let mut cycles = cycles(duration);
+    while cycles > 0 {
+        let chunk = cycles.min(u32::MAX as u64);
+        cortex_m::asm::delay(chunk as u32);
+        cycles -= chunk;
+    }
+}
+
+fn measure(source: Source) -> u32 {
+    // With interrupts off, so a handler on this core can't find the counter busy and spin
+    // forever.
+    cortex_m::interrupt::free(|_| measure_locked(source))
+}
+
+fn measure_locked(source: Source) -> u32 {
+    let clocks = unsafe { &*rp2040_pac::CLOCKS::ptr() };
+    while COUNTER_BUSY
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {}
+    // RUNNING
+    while clocks.fc0_status.read().bits() & 1 << 8 != 0 {}
+    clocks
+        .fc0_ref_khz
+        .write(|w| unsafe { w.bits(ref_hz() / 1000) });
+    // 2^10 µs, about a millisecond.
+    clocks.fc0_interval.write(|w| unsafe { w.bits(10) });
+    clocks.fc0_min_khz.write(|w| unsafe { w.bits(0) });
+    clocks.fc0_max_khz.write(|w| unsafe { w.bits(0x1ff_ffff) });
+    // Writing the source starts the count.
+    clocks.fc0_src.write(|w| unsafe { w.bits(source as u32) });
+    // DONE
+    while clocks.fc0_status.read().bits() & 1 << 4 == 0 {}
+    // DIED: the clock isn't running.
+    let died = clocks.fc0_status.read().bits() & 1 << 28 != 0;
+    // kHz in bits 29:5, and 1/32 kHz below that.
+    let result = clocks.fc0_result.read().bits();
+    COUNTER_BUSY.store(false, Ordering::Release);
+    if died {
+        0
+    } else {
+        (result >> 5) * 1000 + (result & 0x1f) * 1000 / 32
+    }
+}
@@ -0,0 +1,378 @@
+// Byte-stuffing framing for packets over serial links: COBS and SLIP.
+//
+// Both wrap an embedded-io-async stream and send and receive whole frames. Received frames go
+// into a buffer the caller passes in, so the largest frame is up to them. A frame that doesn't
+// fit is skipped up to its end and reported, and the next one is read normally, so one bad frame
+// doesn't lose sync.
+
+use embedded_io_async::{Read, Write};
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    // The stream ended.
+    Eof,
+    // The frame was bigger than the buffer. It's been skipped.
+    Overflow,
+    // The frame wasn't validly encoded. It's been skipped.
+    Invalid,
+}
+
+// Reads a byte at a time, through a small buffer.
+struct Rx<T> {
+    io: T,
+    buf: [u8; 32],
+    pos: usize,
+    len: usize,
+}
+
+impl<T: Read> Rx<T> {
+    fn new(io: T) -> Self {
+        Rx {
+            io,
+            buf: [0; 32],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    async fn next_byte(&mut self) -> Result<u8, Error<T::Error>> {
+        if self.pos == self.len {
+            self.len = self.io.read(&mut self.buf).await.map_err(Error::Io)?;
+            self.pos = 0;
+            if self.len == 0 {
+                return Err(Error::Eof);
+            }
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+}
+
+// Consistent Overhead Byte Stuffing: frames end with a zero byte and never contain one,
+// at a cost of one byte per 254.
+pub struct Cobs<T> {
+    rx: Rx<T>,
+}
+
+impl<T: Read + Write> Cobs<T> {
+    pub fn new(io: T) -> Self {
+        Cobs { rx: Rx::new(io) }
+    }
+
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<T::Error>> {
+        let io = &mut self.rx.io;
+        let mut rest = frame;
+        loop {
+            // Each block is a code byte, then up to 254 data bytes. Codes below 0xff stand for
+            // a zero after the block, except for the last block.
+            match rest.iter().take(254).position(|&b| b == 0) {
+                Some(n) => {
+                    io.write_all(&[n as u8 + 1]).await.map_err(Error::Io)?;
+                    io.write_all(&rest[..n]).await.map_err(Error::Io)?;
+                    rest = &rest[n + 1..];
+                }
+                None if rest.len() >= 254 => {
+                    io.write_all(&[0xff]).await.map_err(Error::Io)?;
+                    io.write_all(&rest[..254]).await.map_err(Error::Io)?;
+                    rest = &rest[254..];
+                }
+                None => {
+                    io.write_all(&[rest.len() as u8 + 1])
+                        .await
+                        .map_err(Error::Io)?;
+                    io.write_all(rest).await.map_err(Error::Io)?;
+                    break;
+                }
+            }
+        }
+        io.write_all(&[0]).await.map_err(Error::Io)?;
+        io.flush().await.map_err(Error::Io)
+    }
+
+    // Wait for the next frame and decode it into `buf`, returning its length. Empty frames are
+    // skipped.
+    pub async fn recv_frame(&mut self, buf: &mut [u8]) -> Result<usize, Error<T::Error>> {
+        loop {
+            let mut len = 0;
+            let mut overflow = false;
+            // Data bytes left in the current block, and whether a zero follows it.
+            let mut left = 0;
+            let mut zero = false;
+            loop {
+                let byte = self.rx.next_byte().await?;
+                if byte == 0 {
+                    break;
+                }
+                let data = if left > 0 {
+                    left -= 1;
+                    byte
+                } else {
+                    // A code byte. The zero from the block before goes in now that we know
+                    // the frame didn't end there.
+                    let zero_before = zero;
+                    left = byte - 1;
+                    zero = byte < 0xff;
+                    if !zero_before {
+                        continue;
+                    }
+                    0
+                };
+                match buf.get_mut(len) {
+                    Some(slot) => *slot = data,
+                    None => overflow = true,
+                }
+                len += 1;
+            }
+            // Cut short in the middle of a block.
+            if left > 0 {
+                return Err(Error::Invalid);
+            }
+            if overflow {
+                return Err(Error::Overflow);
+            }
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.rx.io
+    }
+}
+
+const END: u8 = 0xc0;
+const ESC: u8 = 0xdb;
+const ESC_END: u8 = 0xdc;
+const ESC_ESC: u8 = 0xdd;
+
+// SLIP (RFC 1055): frames end with 0xc0, and 0xc0 and 0xdb in the data are escaped.
+pub struct Slip<T> {
+    rx: Rx<T>,
+}
+
+impl<T: Read + Write> Slip<T> {
+    pub fn new(io: T) -> Self {
+        Slip { rx: Rx::new(io) }
+    }
+
+    // The frame is sent with an END in front too, so any line noise before it makes up an
+    // empty or invalid frame of its own instead of corrupting this one.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error<T::Error>> {
+        let io = &mut self.rx.io;
+        io.write_all(&[END]).await.map_err(Error::Io)?;
+        let mut rest = frame;
+        while let Some(n) = rest.iter().position(|&b| b == END || b == ESC) {
+            io.write_all(&rest[..n]).await.map_err(Error::Io)?;
+            let escaped = if rest[n] == END { ESC_END } else { ESC_ESC };
+            io.write_all(&[ESC, escaped]).await.map_err(Error::Io)?;
+            rest = &rest[n + 1..];
+        }
+        io.write_all(rest).await.map_err(Error::Io)?;
+        io.write_all(&[END]).await.map_err(Error::Io)?;
+        io.flush().await.map_err(Error::Io)
+    }
+
+    // Wait for the next frame and decode it into `buf`, returning its length. Empty frames are
+    // skipped.
+    pub async fn recv_frame(&mut self, buf: &mut [u8]) -> Result<usize, Error<T::Error>> {
+        loop {
+            let mut len = 0;
+            let mut overflow = false;
+            let mut invalid = false;
+            loop {
+                let byte = match self.rx.next_byte().await? {
+                    END => break,
+                    ESC => match self.rx.next_byte().await? {
+                        ESC_END => END,
+                        ESC_ESC => ESC,
+                        // Probably the end of a frame that was cut short.
+                        END => {
+                            invalid = true;
+                            break;
+                        }
+                        _ => {
+                            invalid = true;
+                            continue;
+                        }
+                    },
+                    byte => byte,
+                };
+                match buf.get_mut(len) {
+                    Some(slot) => *slot = byte,
+                    None => overflow = true,
+                }
+                len += 1;
+            }
+            if invalid {
+                return Err(Error::Invalid);
+            }
+            if overflow {
+                return Err(Error::Overflow);
+            }
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.rx.io
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{collections::VecDeque, vec::Vec};
+
+    use embedded_io_async::{ErrorKind, ErrorType};
+
+    use super::*;
+
+    // Bytes written come back out of reads, a few at a time.
+    #[derive(Default)]
+    struct Loopback(VecDeque<u8>);
+
+    impl ErrorType for Loopback {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Loopback {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            let n = buf.len().min(self.0.len()).min(5);
+            for (slot, byte) in buf.iter_mut().zip(self.0.drain(..n)) {
+                *slot = byte;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Loopback {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.0.extend(buf);
+            Ok(buf.len())
+        }
+    }
+
+    // Nothing here ever waits, so one poll finishes it.
+    fn now<T>(future: impl Future<Output = T>) -> T {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("loopback never pends"),
+        }
+    }
+
+    fn frames() -> Vec<Vec<u8>> {
+        vec![
+            vec![1],
+            vec![0],
+            vec![0, 0],
+            vec![0x11, 0x22, 0x00, 0x33],
+            vec![0xc0, 0xdb, 0xdc, 0xdd, 0xc0],
+            (1..=253).collect(),
+            (1..=254).collect(),
+            (1..=255).collect(),
+            (0..600).map(|i| (i % 256) as u8).collect(),
+        ]
+    }
+
+    #[test]
+    fn cobs_round_trip() {
+        let mut cobs = Cobs::new(Loopback::default());
+        let mut buf = [0; 1024];
+        for frame in frames() {
+            now(cobs.send_frame(&frame)).unwrap();
+            // The only zero is the one that ends the frame.
+            let sent = &cobs.rx.io.0;
+            assert_eq!(sent.iter().position(|&b| b == 0), Some(sent.len() - 1));
+            let len = now(cobs.recv_frame(&mut buf)).unwrap();
+            assert_eq!(&buf[..len], &frame[..]);
+        }
+    }
+
+    #[test]
+    fn cobs_known_encoding() {
+        let mut cobs = Cobs::new(Loopback::default());
+        now(cobs.send_frame(&[0x11, 0x22, 0x00, 0x33])).unwrap();
+        let sent: Vec<u8> = cobs.into_inner().0.into();
+        assert_eq!(sent, [0x03, 0x11, 0x22, 0x02, 0x33, 0x00]);
+    }
+
+    #[test]
+    fn slip_round_trip() {
+        let mut slip = Slip::new(Loopback::default());
+        let mut buf = [0; 1024];
+        for frame in frames() {
+            now(slip.send_frame(&frame)).unwrap();
+            let len = now(slip.recv_frame(&mut buf)).unwrap();
+            assert_eq!(&buf[..len], &frame[..]);
+        }
+    }
+
+    #[test]
+    fn slip_known_encoding() {
+        let mut slip = Slip::new(Loopback::default());
+        now(slip.send_frame(&[0x01, END, ESC, 0x02])).unwrap();
+        let sent: Vec<u8> = slip.into_inner().0.into();
+        assert_eq!(sent, [END, 0x01, ESC, ESC_END, ESC, ESC_ESC, 0x02, END]);
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped() {
+        let mut cobs = Cobs::new(Loopback::default());
+        let mut slip = Slip::new(Loopback::default());
+        let mut buf = [0; 4];
+        now(cobs.send_frame(&[1, 2, 3, 4, 5])).unwrap();
+        now(cobs.send_frame(&[6, 0])).unwrap();
+        now(slip.send_frame(&[1, 2, 3, 4, 5])).unwrap();
+        now(slip.send_frame(&[6, 0])).unwrap();
+        assert!(matches!(
+            now(cobs.recv_frame(&mut buf)),
+            Err(Error::Overflow)
+        ));
+        assert_eq!(now(cobs.recv_frame(&mut buf)).unwrap(), 2);
+        assert_eq!(&buf[..2], &[6, 0]);
+        assert!(matches!(
+            now(slip.recv_frame(&mut buf)),
+            Err(Error::Overflow)
+        ));
+        assert_eq!(now(slip.recv_frame(&mut buf)).unwrap(), 2);
+        assert_eq!(&buf[..2], &[6, 0]);
+    }
+
+    #[test]
+    fn bad_frames_are_reported_and_skipped() {
+        let mut cobs = Cobs::new(Loopback::default());
+        let mut buf = [0; 16];
+        // A block that claims four data bytes but ends after one.
+        cobs.rx.io.0.extend([0x05, 0x11, 0x00]);
+        now(cobs.send_frame(&[7])).unwrap();
+        assert!(matches!(
+            now(cobs.recv_frame(&mut buf)),
+            Err(Error::Invalid)
+        ));
+        assert_eq!(now(cobs.recv_frame(&mut buf)).unwrap(), 1);
+
+        let mut slip = Slip::new(Loopback::default());
+        slip.rx.io.0.extend([0x01, ESC, 0x02, END]);
+        now(slip.send_frame(&[7])).unwrap();
+        assert!(matches!(
+            now(slip.recv_frame(&mut buf)),
+            Err(Error::Invalid)
+        ));
+        assert_eq!(now(slip.recv_frame(&mut buf)).unwrap(), 1);
+        assert_eq!(buf[0], 7);
+    }
+
+    #[test]
+    fn end_of_stream() {
+        let mut cobs = Cobs::new(Loopback::default());
+        assert!(matches!(now(cobs.recv_frame(&mut [0; 4])), Err(Error::Eof)));
+    }
+}
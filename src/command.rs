@@ -0,0 +1,258 @@
+// Request/response plumbing for line-based serial protocols (AT modems, GPS configuration...).
+//
+// `Commander` writes a command, then hands each response line to a matcher until it says
+// the command is done. Each attempt gets a timeout, and failed or timed-out attempts are
+// retried, so drivers only have to describe what a good answer looks like.
+
+use embedded_hal_async::delay::DelayNs;
+use embedded_io_async::{Read, Write};
+
+use crate::future;
+
+// Longest response line kept. Longer lines are dropped.
+const MAX_LINE: usize = 256;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    // The stream ended.
+    Eof,
+    // No answer within the timeout, on every attempt.
+    Timeout,
+    // The matcher rejected the answer on every attempt.
+    Rejected,
+}
+
+// What a matcher makes of a response line.
+pub enum Match<R> {
+    // The command is done and this is the result.
+    Done(R),
+    // The command failed, e.g. ERROR from a modem. It's retried if there are retries left.
+    Fail,
+    // Not the answer (an echo, an unsolicited message...). Keep reading.
+    Continue,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub timeout_ms: u32,
+    // Attempts after the first one.
+    pub retries: u8,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            timeout_ms: 1000,
+            retries: 2,
+        }
+    }
+}
+
+pub struct Commander<T, D> {
+    lines: Lines<T>,
+    delay: D,
+}
+
+struct Lines<T> {
+    io: T,
+    line: [u8; MAX_LINE],
+    len: usize,
+    // Set while dropping the rest of an overlong line.
+    overflow: bool,
+    rx: [u8; 32],
+    rx_pos: usize,
+    rx_len: usize,
+}
+
+impl<T: Read + Write, D: DelayNs> Commander<T, D> {
+    pub fn new(io: T, delay: D) -> Self {
+        Commander {
+            lines: Lines {
+                io,
+                line: [0; MAX_LINE],
+                len: 0,
+                overflow: false,
+                rx: [0; 32],
+                rx_pos: 0,
+                rx_len: 0,
+            },
+            delay,
+        }
+    }
+
+    // Send `command` and feed response lines to `matcher` until it's done or fails.
+    // Line endings are stripped and empty lines skipped.
+    pub async fn request<R>(
+        &mut self,
+        command: &[u8],
+        policy: Policy,
+        mut matcher: impl FnMut(&[u8]) -> Match<R>,
+    ) -> Result<R, Error<T::Error>> {
+        let mut timed_out = false;
+        for _ in 0..=policy.retries {
+            self.write(command).await?;
+            match self.response(policy.timeout_ms, &mut matcher).await {
+                Ok(result) => return Ok(result),
+                Err(Error::Rejected) => timed_out = false,
+                Err(Error::Timeout) => timed_out = true,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(if timed_out {
+            Error::Timeout
+        } else {
+            Error::Rejected
+        })
+    }
+
+    // Feed response lines to `matcher` without sending anything first, e.g. for the rest of
+    // an answer that started with binary data. Not retried.
+    pub async fn response<R>(
+        &mut self,
+        timeout_ms: u32,
+        mut matcher: impl FnMut(&[u8]) -> Match<R>,
+    ) -> Result<R, Error<T::Error>> {
+        let lines = &mut self.lines;
+        let attempt = async {
+            loop {
+                let line = lines.read_line().await?;
+                match matcher(line) {
+                    Match::Done(result) => return Ok(result),
+                    Match::Fail => return Err(Error::Rejected),
+                    Match::Continue => {}
+                }
+            }
+        };
+        let timeout = self.delay.delay_ms(timeout_ms);
+        match future::with_timeout(timeout, attempt).await {
+            Some(result) => result,
+            None => {
+                // Don't glue half a late answer onto the next one.
+                self.lines.len = 0;
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    // Wait for the next non-empty line, e.g. an unsolicited message between commands.
+    pub async fn read_line(&mut self) -> Result<&[u8], Error<T::Error>> {
+        self.lines.read_line().await
+    }
+
+    // Like `read_line`, but give up after `timeout_ms` and return None.
+    pub async fn read_line_within(
+        &mut self,
+        timeout_ms: u32,
+    ) -> Result<Option<&[u8]>, Error<T::Error>> {
+        let timeout = self.delay.delay_ms(timeout_ms);
+        future::with_timeout(timeout, self.lines.read_line())
+            .await
+            .transpose()
+    }
+
+    // Read up to `delim` or the end of a line, whichever comes first, skipping line endings
+    // at the start. For prompts and headers that aren't followed by a line ending, like a
+    // modem's `>` or the `+DATA:<len>,` in front of binary data.
+    pub async fn read_until(
+        &mut self,
+        delim: u8,
+        timeout_ms: u32,
+    ) -> Result<&[u8], Error<T::Error>> {
+        let timeout = self.delay.delay_ms(timeout_ms);
+        future::with_timeout(timeout, self.lines.read_until(delim))
+            .await
+            .unwrap_or(Err(Error::Timeout))
+    }
+
+    // Read exactly `buf.len()` bytes of binary data.
+    pub async fn read_exact(
+        &mut self,
+        buf: &mut [u8],
+        timeout_ms: u32,
+    ) -> Result<(), Error<T::Error>> {
+        let lines = &mut self.lines;
+        let read = async {
+            for byte in buf.iter_mut() {
+                *byte = lines.next_byte().await?;
+            }
+            Ok(())
+        };
+        let timeout = self.delay.delay_ms(timeout_ms);
+        future::with_timeout(timeout, read)
+            .await
+            .unwrap_or(Err(Error::Timeout))
+    }
+
+    // Write raw bytes, e.g. a payload after a modem's `>` prompt.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error<T::Error>> {
+        let io = &mut self.lines.io;
+        io.write_all(data).await.map_err(Error::Io)?;
+        io.flush().await.map_err(Error::Io)
+    }
+
+    pub fn into_inner(self) -> (T, D) {
+        (self.lines.io, self.delay)
+    }
+}
+
+impl<T: Read> Lines<T> {
+    async fn read_line(&mut self) -> Result<&[u8], Error<T::Error>> {
+        loop {
+            let byte = self.next_byte().await?;
+            match byte {
+                b'\r' | b'\n' => {
+                    let len = core::mem::take(&mut self.len);
+                    if core::mem::take(&mut self.overflow) || len == 0 {
+                        continue;
+                    }
+                    return Ok(&self.line[..len]);
+                }
+                _ if self.len < MAX_LINE => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                }
+                _ => {
+                    self.len = 0;
+                    self.overflow = true;
+                }
+            }
+        }
+    }
+
+    async fn read_until(&mut self, delim: u8) -> Result<&[u8], Error<T::Error>> {
+        loop {
+            let byte = self.next_byte().await?;
+            match byte {
+                b'\r' | b'\n' if self.len == 0 && !self.overflow => {}
+                _ if byte == delim || byte == b'\r' || byte == b'\n' => {
+                    let len = core::mem::take(&mut self.len);
+                    if core::mem::take(&mut self.overflow) {
+                        continue;
+                    }
+                    return Ok(&self.line[..len]);
+                }
+                _ if self.len < MAX_LINE => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                }
+                _ => {
+                    self.len = 0;
+                    self.overflow = true;
+                }
+            }
+        }
+    }
+
+    async fn next_byte(&mut self) -> Result<u8, Error<T::Error>> {
+        if self.rx_pos == self.rx_len {
+            self.rx_len = self.io.read(&mut self.rx).await.map_err(Error::Io)?;
+            self.rx_pos = 0;
+            if self.rx_len == 0 {
+                return Err(Error::Eof);
+            }
+        }
+        self.rx_pos += 1;
+        Ok(self.rx[self.rx_pos - 1])
+    }
+}
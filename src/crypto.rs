@@ -0,0 +1,81 @@
+// Software crypto for a core with no crypto instructions: SHA-256 and HMAC, AES in CTR and
+// GCM modes, X25519, and CRC-32 on the DMA sniffer for integrity checks that don't need to be
+// cryptographic.
+//
+// None of it branches on secrets. AES looks its S-box up by secret index, which is only safe
+// because the table is in SRAM: the M0+ has no data cache, so every SRAM read takes the same
+// time, where a table in flash would go through the XIP cache and leak.
+//
+// Hashing a firmware image or a 16 KB TLS record takes tens of milliseconds, so each algorithm
+// also has a `_chunked` async version that works through its input `CHUNK` bytes at a time and
+// lets the executor run other tasks in between. The results are the same either way.
+//
+//     let mut hash = Sha256::new();
+//     hash.update_chunked(&image).await;
+//     if !crypto::ct_eq(&hash.finalize(), &expected) { ... }
+
+pub mod aes;
+pub mod crc;
+pub mod sha256;
+pub mod x25519;
+
+pub use aes::{Aes, Gcm};
+pub use sha256::{HmacSha256, Sha256};
+
+// How much the chunked versions process between yields: around 200 µs of SHA-256 at 125 MHz.
+pub const CHUNK: usize = 256;
+
+// Compare two MACs or hashes in time that depends only on their lengths.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | a ^ b);
+    // Through a volatile read, so the compiler can't turn this into an early exit.
+    unsafe { core::ptr::read_volatile(&diff) == 0 }
+}
+
+// Fill `out` with random bytes for keys and nonces: the ring oscillator's random bit, which is
+// biased and correlated sample to sample, sampled 1024 times for every 32 bytes and hashed.
+// The ROSC has to be running, which it is unless it's been stopped for dormant mode. A
+// hardware RNG like the ATECC608's is better where there is one.
+pub fn random(out: &mut [u8]) {
+    let rosc = unsafe { &*rp2040_pac::ROSC::ptr() };
+    for (block, chunk) in out.chunks_mut(sha256::DIGEST_LEN).enumerate() {
+        let mut hash = Sha256::new();
+        hash.update(&(block as u32).to_le_bytes());
+        hash.update(&crate::time::Instant::now().as_micros().to_le_bytes());
+        for _ in 0..32 {
+            let mut word = 0u32;
+            for _ in 0..32 {
+                word = word << 1 | rosc.randombit.read().bits() & 1;
+            }
+            hash.update(&word.to_le_bytes());
+        }
+        chunk.copy_from_slice(&hash.finalize()[..chunk.len()]);
+    }
+}
+
+// Test vectors are written in hex, as the standards give them.
+#[cfg(test)]
+fn hex<const N: usize>(digits: &str) -> [u8; N] {
+    let mut out = [0; N];
+    assert_eq!(digits.len(), N * 2);
+    for (byte, pair) in out.iter_mut().zip(digits.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares() {
+        assert!(ct_eq(b"abc", b"abc"));
+        assert!(!ct_eq(b"abc", b"abd"));
+        assert!(!ct_eq(b"abc", b"ab"));
+        assert!(ct_eq(&hex::<2>("00ff"), &[0x00, 0xff]));
+    }
+}
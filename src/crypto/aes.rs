@@ -0,0 +1,358 @@
+// AES (FIPS 197) encryption with 128, 192 or 256 bit keys, and the CTR and GCM (SP 800-38D)
+// modes built on it. Both modes only ever encrypt blocks, so there's no decryption cipher.
+
+use super::CHUNK;
+use crate::future;
+
+pub const BLOCK: usize = 16;
+pub const TAG_LEN: usize = 16;
+pub const NONCE_LEN: usize = 12;
+
+// In SRAM rather than flash: see the module docs in `crypto`.
+#[link_section = ".data"]
+static SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+#[derive(Clone)]
+pub struct Aes {
+    round_keys: [[u8; BLOCK]; 15],
+    rounds: usize,
+}
+
+impl Aes {
+    // `None` unless `key` is 16, 24 or 32 bytes.
+    pub fn new(key: &[u8]) -> Option<Self> {
+        let nk = key.len() / 4;
+        if !matches!(key.len(), 16 | 24 | 32) {
+            return None;
+        }
+        let rounds = nk + 6;
+        let mut w = [[0u8; 4]; 4 * 15];
+        for (w, bytes) in w.iter_mut().zip(key.chunks_exact(4)) {
+            w.copy_from_slice(bytes);
+        }
+        for i in nk..4 * (rounds + 1) {
+            let mut temp = w[i - 1];
+            if i % nk == 0 {
+                temp.rotate_left(1);
+                temp = temp.map(sub_byte);
+                temp[0] ^= RCON[i / nk - 1];
+            } else if nk > 6 && i % nk == 4 {
+                temp = temp.map(sub_byte);
+            }
+            for j in 0..4 {
+                w[i][j] = w[i - nk][j] ^ temp[j];
+            }
+        }
+        let mut round_keys = [[0; BLOCK]; 15];
+        for (key, words) in round_keys.iter_mut().zip(w.chunks_exact(4)) {
+            for (bytes, word) in key.chunks_exact_mut(4).zip(words) {
+                bytes.copy_from_slice(word);
+            }
+        }
+        Some(Aes { round_keys, rounds })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK]) {
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..=self.rounds {
+            for byte in block.iter_mut() {
+                *byte = sub_byte(*byte);
+            }
+            shift_rows(block);
+            if round != self.rounds {
+                mix_columns(block);
+            }
+            add_round_key(block, &self.round_keys[round]);
+        }
+    }
+
+    // Encrypt or decrypt `data` in place in CTR mode, starting from `counter`, whose last four
+    // bytes are a big-endian block counter. `counter` is left at the next unused block, so a
+    // stream can be done in pieces as long as they're all a multiple of 16 bytes but the last.
+    pub fn ctr(&self, counter: &mut [u8; BLOCK], data: &mut [u8]) {
+        for chunk in data.chunks_mut(BLOCK) {
+            let mut keystream = *counter;
+            self.encrypt_block(&mut keystream);
+            for (byte, key) in chunk.iter_mut().zip(keystream) {
+                *byte ^= key;
+            }
+            increment(counter);
+        }
+    }
+
+    // `ctr`, yielding to the executor every `CHUNK` bytes.
+    pub async fn ctr_chunked(&self, counter: &mut [u8; BLOCK], data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(CHUNK).enumerate() {
+            if i > 0 {
+                future::pending_once().await;
+            }
+            self.ctr(counter, chunk);
+        }
+    }
+}
+
+// The authentication tag didn't match: the data, the associated data or the nonce have been
+// tampered with, or the key is wrong. The data has been left as it was.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagMismatch;
+
+// AES-GCM with 96 bit nonces, as TLS 1.2 and 1.3 use it. A nonce must never be used twice with
+// the same key.
+#[derive(Clone)]
+pub struct Gcm {
+    aes: Aes,
+    // The hash key: the encryption of the zero block.
+    h: u128,
+}
+
+impl Gcm {
+    pub fn new(aes: Aes) -> Self {
+        let mut h = [0; BLOCK];
+        aes.encrypt_block(&mut h);
+        Gcm {
+            aes,
+            h: u128::from_be_bytes(h),
+        }
+    }
+
+    // Encrypt `data` in place, and return the tag covering it and `aad`.
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], data: &mut [u8]) -> [u8; TAG_LEN] {
+        let mut counter = self.first_counter(nonce);
+        self.aes.ctr(&mut counter, data);
+        let mut y = self.ghash(0, aad);
+        y = self.ghash(y, data);
+        self.tag(nonce, y, aad.len(), data.len())
+    }
+
+    // Check `tag`, and decrypt `data` in place if it's right.
+    pub fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> Result<(), TagMismatch> {
+        let mut y = self.ghash(0, aad);
+        y = self.ghash(y, data);
+        if !super::ct_eq(&self.tag(nonce, y, aad.len(), data.len()), tag) {
+            return Err(TagMismatch);
+        }
+        let mut counter = self.first_counter(nonce);
+        self.aes.ctr(&mut counter, data);
+        Ok(())
+    }
+
+    // `seal`, yielding to the executor every `CHUNK` bytes.
+    pub async fn seal_chunked(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> [u8; TAG_LEN] {
+        let mut counter = self.first_counter(nonce);
+        let mut y = self.ghash(0, aad);
+        for (i, chunk) in data.chunks_mut(CHUNK).enumerate() {
+            if i > 0 {
+                future::pending_once().await;
+            }
+            self.aes.ctr(&mut counter, chunk);
+            y = self.ghash(y, chunk);
+        }
+        self.tag(nonce, y, aad.len(), data.len())
+    }
+
+    // `open`, yielding to the executor every `CHUNK` bytes.
+    pub async fn open_chunked(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> Result<(), TagMismatch> {
+        let mut y = self.ghash(0, aad);
+        for (i, chunk) in data.chunks(CHUNK).enumerate() {
+            if i > 0 {
+                future::pending_once().await;
+            }
+            y = self.ghash(y, chunk);
+        }
+        if !super::ct_eq(&self.tag(nonce, y, aad.len(), data.len()), tag) {
+            return Err(TagMismatch);
+        }
+        let mut counter = self.first_counter(nonce);
+        self.aes.ctr_chunked(&mut counter, data).await;
+        Ok(())
+    }
+
+    // The counter for the first block of data. The one before it encrypts the tag.
+    fn first_counter(&self, nonce: &[u8; NONCE_LEN]) -> [u8; BLOCK] {
+        let mut counter = [0; BLOCK];
+        counter[..NONCE_LEN].copy_from_slice(nonce);
+        counter[BLOCK - 1] = 2;
+        counter
+    }
+
+    // Hash `data` into `y`, zero padded to a whole number of blocks. Chunks of data hashed
+    // separately come out the same as hashing them together, as long as they're multiples of
+    // the block size.
+    fn ghash(&self, mut y: u128, data: &[u8]) -> u128 {
+        for chunk in data.chunks(BLOCK) {
+            let mut block = [0; BLOCK];
+            block[..chunk.len()].copy_from_slice(chunk);
+            y = gf_mul(y ^ u128::from_be_bytes(block), self.h);
+        }
+        y
+    }
+
+    fn tag(&self, nonce: &[u8; NONCE_LEN], y: u128, aad_len: usize, len: usize) -> [u8; TAG_LEN] {
+        let lengths = (aad_len as u128 * 8) << 64 | (len as u128 * 8);
+        let s = gf_mul(y ^ lengths, self.h);
+        let mut tag = [0; BLOCK];
+        tag[..NONCE_LEN].copy_from_slice(nonce);
+        tag[BLOCK - 1] = 1;
+        self.aes.encrypt_block(&mut tag);
+        (u128::from_be_bytes(tag) ^ s).to_be_bytes()
+    }
+}
+
+fn sub_byte(byte: u8) -> u8 {
+    SBOX[byte as usize]
+}
+
+fn add_round_key(block: &mut [u8; BLOCK], key: &[u8; BLOCK]) {
+    for (byte, key) in block.iter_mut().zip(key) {
+        *byte ^= key;
+    }
+}
+
+// The block is in columns: byte `4 * column + row`. Row r moves r columns left.
+fn shift_rows(block: &mut [u8; BLOCK]) {
+    let old = *block;
+    for column in 0..4 {
+        for row in 1..4 {
+            block[4 * column + row] = old[4 * ((column + row) % 4) + row];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8; BLOCK]) {
+    for column in block.chunks_exact_mut(4) {
+        let [a, b, c, d] = [column[0], column[1], column[2], column[3]];
+        let all = a ^ b ^ c ^ d;
+        column[0] ^= all ^ xtime(a ^ b);
+        column[1] ^= all ^ xtime(b ^ c);
+        column[2] ^= all ^ xtime(c ^ d);
+        column[3] ^= all ^ xtime(d ^ a);
+    }
+}
+
+// Multiply by x in GF(2^8), without branching on the top bit.
+fn xtime(byte: u8) -> u8 {
+    byte << 1 ^ 0x1b & 0u8.wrapping_sub(byte >> 7)
+}
+
+fn increment(counter: &mut [u8; BLOCK]) {
+    let count = u32::from_be_bytes(counter[12..].try_into().unwrap()).wrapping_add(1);
+    counter[12..].copy_from_slice(&count.to_be_bytes());
+}
+
+// Multiply in GCM's GF(2^128), where the first bit of the block is the lowest power of x. A bit
+// at a time with masks, so it takes the same time whatever the values.
+fn gf_mul(x: u128, h: u128) -> u128 {
+    let mut z = 0;
+    let mut v = h;
+    for i in 0..128 {
+        z ^= v & 0u128.wrapping_sub(x >> (127 - i) & 1);
+        v = v >> 1 ^ 0xe1 << 120 & 0u128.wrapping_sub(v & 1);
+    }
+    z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hex;
+
+    // FIPS 197 appendix C, for each key size.
+    #[test]
+    fn block() {
+        let plaintext = hex("00112233445566778899aabbccddeeff");
+        for (key, expected) in [
+            (
+                &hex::<16>("000102030405060708090a0b0c0d0e0f")[..],
+                "69c4e0d86a7b0430d8cdb78070b4c55a",
+            ),
+            (
+                &hex::<24>("000102030405060708090a0b0c0d0e0f1011121314151617"),
+                "dda97ca4864cdfe06eaf70a0ec0d7191",
+            ),
+            (
+                &hex::<32>("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"),
+                "8ea2b7ca516745bfeafc49904b496089",
+            ),
+        ] {
+            let mut block = plaintext;
+            Aes::new(key).unwrap().encrypt_block(&mut block);
+            assert_eq!(block, hex(expected));
+        }
+        assert!(Aes::new(&[0; 15]).is_none());
+    }
+
+    // Test cases 1, 2 and 4 from the GCM spec.
+    #[test]
+    fn gcm() {
+        let gcm = Gcm::new(Aes::new(&[0; 16]).unwrap());
+        assert_eq!(
+            gcm.seal(&[0; NONCE_LEN], &[], &mut []),
+            hex("58e2fccefa7e3061367f1d57a4e7455a")
+        );
+        let mut data = [0; 16];
+        let tag = gcm.seal(&[0; NONCE_LEN], &[], &mut data);
+        assert_eq!(data, hex("0388dace60b6a392f328c2b971b2fe78"));
+        assert_eq!(tag, hex("ab6e47d42cec13bdf53a67b21257bddf"));
+
+        let gcm = Gcm::new(Aes::new(&hex::<16>("feffe9928665731c6d6a8f9467308308")).unwrap());
+        let nonce = hex("cafebabefacedbaddecaf888");
+        let aad = hex::<20>("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let plaintext = hex::<60>(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        );
+        let mut data = plaintext;
+        let tag = gcm.seal(&nonce, &aad, &mut data);
+        assert_eq!(
+            data,
+            hex::<60>(
+                "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+                 21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091"
+            )
+        );
+        assert_eq!(tag, hex("5bc94fbc3221a5db94fae95ae7121a47"));
+
+        assert_eq!(
+            gcm.open(&nonce, &aad[1..], &mut data, &tag),
+            Err(TagMismatch)
+        );
+        assert_eq!(gcm.open(&nonce, &aad, &mut data, &tag), Ok(()));
+        assert_eq!(data, plaintext);
+    }
+}
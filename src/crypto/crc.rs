@@ -0,0 +1,97 @@
+// CRC-32 (the zlib one) computed by the DMA sniffer, which watches a channel's reads and
+// checksums them at a byte per clock while the CPU does something else. For checking a
+// firmware image or an asset before use; it's no defence against tampering, which needs a
+// `Sha256` or a signature.
+//
+// The data is read by the given DMA channel into a dummy word, so any address the DMA can
+// read works, flash included. The sniffer is shared by all channels, so only one checksum can
+// run at a time.
+
+use crate::{
+    atomic::{AtomicBool, Ordering},
+    dma, future,
+};
+
+// CTRL bits.
+const EN: u32 = 1 << 0;
+const INCR_READ: u32 = 1 << 4;
+const CHAIN_TO_SHIFT: u32 = 11;
+const TREQ_PERMANENT: u32 = 0x3f << 15;
+const SNIFF_EN: u32 = 1 << 23;
+const BUSY: u32 = 1 << 24;
+
+// SNIFF_CTRL: CRC-32 over bit-reversed data, with the result reversed and inverted, is the
+// reflected CRC-32 that zlib and Ethernet use.
+const SNIFF_CTRL_EN: u32 = 1 << 0;
+const SNIFF_DMACH_SHIFT: u32 = 1;
+const SNIFF_CALC_CRC32R: u32 = 1 << 5;
+const SNIFF_OUT_INV: u32 = 1 << 10;
+const SNIFF_OUT_REV: u32 = 1 << 11;
+
+static SNIFFER_BUSY: AtomicBool = AtomicBool::new(false);
+
+// Someone else is using the sniffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Busy;
+
+// The CRC-32 of `data`, read through `channel`. Returns when the transfer is done, yielding to
+// the executor while it runs.
+pub async fn crc32(channel: &mut dma::Channel, data: &[u8]) -> Result<u32, Busy> {
+    let channel = channel.number();
+    if SNIFFER_BUSY.swap(true, Ordering::Acquire) {
+        return Err(Busy);
+    }
+    let dma = unsafe { &*rp2040_pac::DMA::ptr() };
+    let ch = &dma.ch[channel as usize];
+    // Where the bytes go: nowhere useful.
+    let mut sink = 0u32;
+    let mut transfer = Transfer {
+        channel,
+        done: false,
+    };
+    dma.sniff_data.write(|w| unsafe { w.bits(!0) });
+    dma.sniff_ctrl.write(|w| unsafe {
+        w.bits(
+            SNIFF_CTRL_EN
+                | (channel as u32) << SNIFF_DMACH_SHIFT
+                | SNIFF_CALC_CRC32R
+                | SNIFF_OUT_INV
+                | SNIFF_OUT_REV,
+        )
+    });
+    ch.ch_read_addr
+        .write(|w| unsafe { w.bits(data.as_ptr() as u32) });
+    ch.ch_write_addr
+        .write(|w| unsafe { w.bits(&mut sink as *mut u32 as u32) });
+    ch.ch_trans_count
+        .write(|w| unsafe { w.bits(data.len() as u32) });
+    // Byte transfers, chained to itself (no chain), as fast as the bus allows.
+    ch.ch_ctrl_trig.write(|w| unsafe {
+        w.bits(EN | INCR_READ | (channel as u32) << CHAIN_TO_SHIFT | TREQ_PERMANENT | SNIFF_EN)
+    });
+    while ch.ch_ctrl_trig.read().bits() & BUSY != 0 {
+        future::pending_once().await;
+    }
+    transfer.done = true;
+    Ok(dma.sniff_data.read().bits())
+}
+
+// Stops the transfer if the future is dropped before it's done, since it's writing to the
+// future's stack, and frees the sniffer either way.
+struct Transfer {
+    channel: u8,
+    done: bool,
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let dma = unsafe { &*rp2040_pac::DMA::ptr() };
+        if !self.done {
+            dma.chan_abort
+                .write(|w| unsafe { w.bits(1 << self.channel) });
+            while dma.chan_abort.read().bits() & 1 << self.channel != 0 {}
+        }
+        dma.sniff_ctrl.write(|w| unsafe { w.bits(0) });
+        SNIFFER_BUSY.store(false, Ordering::Release);
+    }
+}
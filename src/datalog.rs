@@ -0,0 +1,274 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    future::poll_fn,
+    mem,
+    task::{Poll, Waker},
+};
+
+use crate::{
+    sync::{Mutex, WaitQueue},
+    time::Instant,
+};
+
+// On-storage record layout (all integers little-endian):
+//
+// | magic: u8 | len: u8 | tag: u8 | timestamp: u64 | payload: [u8; len] | crc: u16 |
+//
+// The CRC covers everything before it. A record that was only partially written when
+// the power went out fails its CRC and is skipped by `records`, which then resyncs
+// on the next magic byte.
+const MAGIC: u8 = 0xA5;
+const HEADER_LEN: usize = 1 + 1 + 1 + 8;
+const CRC_LEN: usize = 2;
+
+pub const MAX_PAYLOAD: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // The record can never fit: the payload is longer than MAX_PAYLOAD,
+    // or the framed record is larger than the whole batch buffer.
+    TooLarge,
+    // The batch buffer is full right now; try again after the flusher has caught up.
+    Full,
+}
+
+// Where flushed batches end up: a flash region, a file on an SD card, a UART...
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    type Error;
+    async fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+pub struct Record<'a> {
+    pub tag: u8,
+    pub timestamp: Instant,
+    pub payload: &'a [u8],
+}
+
+struct State {
+    batch: Vec<u8>,
+    flush_requested: bool,
+    flusher: Option<Waker>,
+    producers: WaitQueue,
+}
+
+// A timestamped record log that batches up to CAP bytes in RAM.
+// Records are appended by any number of tasks with `record`, and written out
+// to a `Storage` by a single task running `run`.
+pub struct DataLog<const CAP: usize> {
+    state: Mutex<State, 8>,
+}
+
+impl<const CAP: usize> DataLog<CAP> {
+    pub const fn new() -> Self {
+        DataLog {
+            state: Mutex::new(State {
+                batch: Vec::new(),
+                flush_requested: false,
+                flusher: None,
+                producers: WaitQueue::new(),
+            }),
+        }
+    }
+
+    // Append a record, waiting for the flusher to make room if the batch is full.
+    // The timestamp is taken when this is called, not when the record fits.
+    pub async fn record(&self, tag: u8, payload: &[u8]) -> Result<(), Error> {
+        let timestamp = Instant::now();
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            match Self::push(&mut state, tag, timestamp, payload) {
+                Err(Error::Full) => {
+                    // The batch may be under half full and still not have room for this one,
+                    // which wouldn't wake the flusher by itself.
+                    state.flush_requested = true;
+                    if let Some(flusher) = state.flusher.take() {
+                        flusher.wake();
+                    }
+                    state.producers.register(cx.waker());
+                    Poll::Pending
+                }
+                ret => Poll::Ready(ret),
+            }
+        })
+        .await
+    }
+
+    // Append a record without waiting. Returns Error::Full instead of applying backpressure.
+    pub fn try_record(&self, tag: u8, payload: &[u8]) -> Result<(), Error> {
+        let timestamp = Instant::now();
+        Self::push(&mut self.state.lock(), tag, timestamp, payload)
+    }
+
+    // Ask the flusher to write out the current batch even if it isn't half full yet.
+    pub fn flush(&self) {
+        let mut state = self.state.lock();
+        state.flush_requested = true;
+        if let Some(flusher) = state.flusher.take() {
+            flusher.wake();
+        }
+    }
+
+    // Write batches out to `storage` as they fill up. Only one task should run this.
+    pub async fn run<S: Storage>(&self, storage: &mut S) -> Result<!, S::Error> {
+        loop {
+            let batch = poll_fn(|cx| {
+                let mut state = self.state.lock();
+                if state.batch.len() >= CAP / 2
+                    || (state.flush_requested && !state.batch.is_empty())
+                {
+                    state.flush_requested = false;
+                    let batch = mem::take(&mut state.batch);
+                    state.producers.wake_all();
+                    Poll::Ready(batch)
+                } else {
+                    state.flush_requested = false;
+                    state.flusher = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+            .await;
+            storage.write(&batch).await?;
+        }
+    }
+
+    fn push(state: &mut State, tag: u8, timestamp: Instant, payload: &[u8]) -> Result<(), Error> {
+        let len = HEADER_LEN + payload.len() + CRC_LEN;
+        if payload.len() > MAX_PAYLOAD || len > CAP {
+            return Err(Error::TooLarge);
+        }
+        if state.batch.len() + len > CAP {
+            return Err(Error::Full);
+        }
+        if state.batch.capacity() == 0 {
+            state.batch.reserve_exact(CAP);
+        }
+
+        let start = state.batch.len();
+        state.batch.push(MAGIC);
+        state.batch.push(payload.len() as u8);
+        state.batch.push(tag);
+        state
+            .batch
+            .extend_from_slice(&timestamp.as_micros().to_le_bytes());
+        state.batch.extend_from_slice(payload);
+        let crc = crc16(&state.batch[start..]);
+        state.batch.extend_from_slice(&crc.to_le_bytes());
+
+        if state.batch.len() >= CAP / 2 {
+            if let Some(flusher) = state.flusher.take() {
+                flusher.wake();
+            }
+        }
+        Ok(())
+    }
+}
+
+// Iterate over the intact records in data previously written by `DataLog::run`.
+pub fn records(data: &[u8]) -> Records<'_> {
+    Records { data }
+}
+
+pub struct Records<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+    fn next(&mut self) -> Option<Record<'a>> {
+        while self.data.len() >= HEADER_LEN + CRC_LEN {
+            let len = HEADER_LEN + self.data[1] as usize + CRC_LEN;
+            if self.data[0] != MAGIC || self.data.len() < len {
+                self.data = &self.data[1..];
+                continue;
+            }
+            let (frame, rest) = self.data.split_at(len);
+            let crc = u16::from_le_bytes([frame[len - 2], frame[len - 1]]);
+            if crc16(&frame[..len - CRC_LEN]) != crc {
+                // Torn or corrupted record; resync on the next magic byte.
+                self.data = &self.data[1..];
+                continue;
+            }
+            self.data = rest;
+            let mut timestamp = [0; 8];
+            timestamp.copy_from_slice(&frame[3..HEADER_LEN]);
+            return Some(Record {
+                tag: frame[2],
+                timestamp: Instant::from_micros(u64::from_le_bytes(timestamp)),
+                payload: &frame[HEADER_LEN..len - CRC_LEN],
+            });
+        }
+        None
+    }
+}
+
+// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A record laid out the way `try_record` writes it.
+    fn frame(tag: u8, micros: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![MAGIC, payload.len() as u8, tag];
+        frame.extend_from_slice(&micros.to_le_bytes());
+        frame.extend_from_slice(payload);
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut data = frame(1, 1000, b"hello");
+        data.extend(frame(2, u64::MAX, &[]));
+        data.extend(frame(3, 7, &[MAGIC; MAX_PAYLOAD]));
+        let records: Vec<_> = records(&data).collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].tag, 1);
+        assert_eq!(records[0].timestamp, Instant::from_micros(1000));
+        assert_eq!(records[0].payload, b"hello");
+        assert_eq!(records[1].timestamp, Instant::from_micros(u64::MAX));
+        assert!(records[1].payload.is_empty());
+        assert_eq!(records[2].payload, &[MAGIC; MAX_PAYLOAD]);
+    }
+
+    #[test]
+    fn skips_torn_and_corrupted_records() {
+        let good = frame(1, 1, b"ok");
+        let mut corrupted = frame(2, 2, b"bad");
+        corrupted[HEADER_LEN] ^= 1;
+        let torn = &frame(3, 3, b"cut short")[..HEADER_LEN + 4];
+        let mut data = good.clone();
+        data.extend_from_slice(&corrupted);
+        data.extend_from_slice(&[0, 0xff, MAGIC]);
+        data.extend_from_slice(torn);
+        data.extend_from_slice(&good);
+        let tags: Vec<_> = records(&data).map(|record| record.tag).collect();
+        assert_eq!(tags, [1, 1]);
+        // A torn record at the very end is dropped too.
+        let tags: Vec<_> = records(&data[..data.len() - 1]).map(|r| r.tag).collect();
+        assert_eq!(tags, [1]);
+    }
+}
@@ -0,0 +1,206 @@
+// Deadlock detection for async code, for tracking down hangs in designs ported from threads.
+//
+// `AsyncMutex` records who holds it and who's waiting for it, and a task blocked sending to
+// a full `Channel` records that too. `watchdog` goes over the resulting wait-for graph every
+// so often and logs cycles (tasks waiting on each other's mutexes) and tasks that have been
+// blocked for longer than a threshold. Receiving isn't tracked, since a task waiting for work
+// is normal.
+//
+// Only tasks in the `taskinfo` table are tracked.
+
+use core::time::Duration;
+
+use crate::{
+    sync::Mutex,
+    taskinfo,
+    time::{self, Instant},
+};
+
+const MAX_WAITS: usize = 32;
+const MAX_HOLDS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Waiting {
+    task: u32,
+    resource: usize,
+    kind: &'static str,
+    since: Instant,
+    // Already logged as blocked too long.
+    reported: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    resource: usize,
+    owner: u32,
+}
+
+struct Graph {
+    waits: [Option<Waiting>; MAX_WAITS],
+    holds: [Option<Held>; MAX_HOLDS],
+}
+
+impl Graph {
+    fn owner(&self, resource: usize) -> Option<u32> {
+        self.holds
+            .iter()
+            .flatten()
+            .find(|held| held.resource == resource)
+            .map(|held| held.owner)
+    }
+
+    fn waiting_on(&self, task: u32) -> Option<usize> {
+        self.waits
+            .iter()
+            .flatten()
+            .find(|waiting| waiting.task == task)
+            .map(|waiting| waiting.resource)
+    }
+}
+
+static GRAPH: Mutex<Graph, 1> = Mutex::new(Graph {
+    waits: [None; MAX_WAITS],
+    holds: [None; MAX_HOLDS],
+});
+
+// A task's wait on one resource. Create it before the wait, `start` it whenever the wait
+// has to go to sleep, and it's taken off the graph when dropped: when the wait's over, or
+// when the future doing it was dropped.
+pub struct Wait {
+    entry: Option<(u32, usize)>,
+}
+
+impl Wait {
+    pub const fn new() -> Self {
+        Wait { entry: None }
+    }
+
+    pub fn start(&mut self, resource: *const (), kind: &'static str) {
+        if self.entry.is_some() {
+            return;
+        }
+        let Some(task) = taskinfo::current_id() else {
+            return;
+        };
+        let resource = resource as usize;
+        let added = GRAPH.with(|graph| {
+            let free = graph.waits.iter_mut().find(|slot| slot.is_none())?;
+            *free = Some(Waiting {
+                task,
+                resource,
+                kind,
+                since: Instant::now(),
+                reported: false,
+            });
+            Some(())
+        });
+        if added.is_some() {
+            self.entry = Some((task, resource));
+        }
+    }
+}
+
+impl Drop for Wait {
+    fn drop(&mut self) {
+        if let Some((task, resource)) = self.entry {
+            GRAPH.with(|graph| {
+                let slot = graph.waits.iter_mut().find(|slot| {
+                    slot.is_some_and(|waiting| waiting.task == task && waiting.resource == resource)
+                });
+                if let Some(slot) = slot {
+                    *slot = None;
+                }
+            })
+        }
+    }
+}
+
+// The current task took `resource`.
+pub fn acquired(resource: *const ()) {
+    let Some(owner) = taskinfo::current_id() else {
+        return;
+    };
+    GRAPH.with(|graph| {
+        if let Some(free) = graph.holds.iter_mut().find(|slot| slot.is_none()) {
+            *free = Some(Held {
+                resource: resource as usize,
+                owner,
+            });
+        }
+    })
+}
+
+// `resource` was let go, by whoever had it.
+pub fn released(resource: *const ()) {
+    GRAPH.with(|graph| {
+        let slot = graph
+            .holds
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|held| held.resource == resource as usize));
+        if let Some(slot) = slot {
+            *slot = None;
+        }
+    })
+}
+
+// Check the graph every `interval`, forever, logging cycles and waits longer than
+// `threshold`. Each long wait is logged once; a cycle is logged every time it's seen, since
+// it'll never go away by itself.
+pub async fn watchdog(interval: Duration, threshold: Duration) -> ! {
+    loop {
+        time::sleep(interval).await;
+        check(threshold);
+    }
+}
+
+fn check(threshold: Duration) {
+    let (waits, cycles) = GRAPH.with(|graph| {
+        let mut long = [None; MAX_WAITS];
+        for (slot, waiting) in long.iter_mut().zip(graph.waits.iter_mut().flatten()) {
+            if !waiting.reported && waiting.since.elapsed() >= threshold {
+                waiting.reported = true;
+                *slot = Some(*waiting);
+            }
+        }
+        // Follow each waiter to the owner of what it's waiting for, then to what that owner's
+        // waiting for, and so on. Coming back round to the start is a deadlock.
+        let mut cycles = [None; MAX_WAITS];
+        for (slot, waiting) in cycles.iter_mut().zip(graph.waits.iter().flatten()) {
+            let mut task = waiting.task;
+            for _ in 0..MAX_WAITS {
+                let Some(next) = graph
+                    .waiting_on(task)
+                    .and_then(|resource| graph.owner(resource))
+                else {
+                    break;
+                };
+                if next == waiting.task {
+                    *slot = Some(*waiting);
+                    break;
+                }
+                task = next;
+            }
+        }
+        (long, cycles)
+    });
+
+    for waiting in waits.iter().flatten() {
+        log::warn!(
+            "task {} ({}) blocked on {} {:#x} for {} ms",
+            waiting.task,
+            taskinfo::name_of(waiting.task).unwrap_or("?"),
+            waiting.kind,
+            waiting.resource,
+            waiting.since.elapsed().as_millis()
+        );
+    }
+    for waiting in cycles.iter().flatten() {
+        log::error!(
+            "deadlock: task {} ({}) is waiting on {} {:#x} in a cycle",
+            waiting.task,
+            taskinfo::name_of(waiting.task).unwrap_or("?"),
+            waiting.kind,
+            waiting.resource
+        );
+    }
+}
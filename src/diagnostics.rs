@@ -0,0 +1,171 @@
+// A background task that snapshots DMA, PIO and interrupt state every so often, for working out
+// why a transfer is stuck without a logic analyzer.
+//
+// Spawn `run` and read the history back with `samples`, or write it to the log with `report`.
+// The last HISTORY samples are kept, oldest first.
+
+use core::time::Duration;
+
+use crate::{
+    executor,
+    sync::Mutex,
+    time::{self, Instant},
+};
+
+pub const HISTORY: usize = 16;
+const DMA_CHANNELS: usize = 12;
+const IRQS: usize = 26;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub at: Instant,
+    // One bit per DMA channel with a transfer in progress.
+    pub dma_busy: u16,
+    // Transfers each DMA channel has left to do.
+    pub dma_remaining: [u32; DMA_CHANNELS],
+    // TX and RX FIFO levels of each state machine, per PIO block.
+    pub pio_tx_level: [[u8; 4]; 2],
+    pub pio_rx_level: [[u8; 4]; 2],
+    // FDEBUG of each PIO block: sticky stall, overflow and underflow flags.
+    pub pio_fdebug: [u32; 2],
+    // Interrupts pending in the NVIC on the core that took the sample.
+    pub irq_pending: u32,
+    // Tasks woken by each interrupt during the last full second.
+    pub irq_wakes_per_sec: [u32; IRQS],
+}
+
+const EMPTY: Sample = Sample {
+    at: Instant::from_micros(0),
+    dma_busy: 0,
+    dma_remaining: [0; DMA_CHANNELS],
+    pio_tx_level: [[0; 4]; 2],
+    pio_rx_level: [[0; 4]; 2],
+    pio_fdebug: [0; 2],
+    irq_pending: 0,
+    irq_wakes_per_sec: [0; IRQS],
+};
+
+struct Ring {
+    samples: [Sample; HISTORY],
+    // Where the next sample goes.
+    next: usize,
+    len: usize,
+}
+
+// Shares a spinlock with the profiler; neither holds it while taking the other.
+static RING: Mutex<Ring, 16> = Mutex::new(Ring {
+    samples: [EMPTY; HISTORY],
+    next: 0,
+    len: 0,
+});
+
+// Take a sample every `interval`, forever. Sampling takes a few microseconds; spawn this with
+// everything else and it only runs when its timer comes round.
+pub async fn run(interval: Duration) -> ! {
+    loop {
+        record(sample());
+        time::sleep(interval).await;
+    }
+}
+
+pub fn sample() -> Sample {
+    let resets = unsafe { &*rp2040_pac::RESETS::ptr() };
+    let in_reset = resets.reset.read().bits();
+    let mut sample = EMPTY;
+    sample.at = Instant::now();
+
+    // Peripherals still held in reset have nothing to report.
+    const DMA: u32 = 1 << 2;
+    if in_reset & DMA == 0 {
+        let dma = unsafe { &*rp2040_pac::DMA::ptr() };
+        for (ch, remaining) in sample.dma_remaining.iter_mut().enumerate() {
+            // BUSY
+            if dma.ch[ch].ch_ctrl_trig.read().bits() & 1 << 24 != 0 {
+                sample.dma_busy |= 1 << ch;
+            }
+            *remaining = dma.ch[ch].ch_trans_count.read().bits();
+        }
+    }
+
+    for block in 0..2 {
+        if in_reset & 1 << (10 + block) != 0 {
+            continue;
+        }
+        let pio = unsafe {
+            &*match block {
+                0 => rp2040_pac::PIO0::ptr(),
+                _ => rp2040_pac::PIO1::ptr(),
+            }
+        };
+        let levels = pio.flevel.read().bits();
+        for sm in 0..4 {
+            sample.pio_tx_level[block][sm] = (levels >> (sm * 8) & 0xf) as u8;
+            sample.pio_rx_level[block][sm] = (levels >> (sm * 8 + 4) & 0xf) as u8;
+        }
+        sample.pio_fdebug[block] = pio.fdebug.read().bits();
+    }
+
+    let nvic = unsafe { &*cortex_m::peripheral::NVIC::PTR };
+    sample.irq_pending = nvic.ispr[0].read();
+    sample.irq_wakes_per_sec = executor::stats().irq_wakes_per_sec;
+    sample
+}
+
+fn record(sample: Sample) {
+    RING.with(|ring| {
+        let next = ring.next;
+        ring.samples[next] = sample;
+        ring.next = (next + 1) % HISTORY;
+        ring.len = (ring.len + 1).min(HISTORY);
+    })
+}
+
+// The samples taken so far, oldest first, and how many of them there are. The rest of the
+// array is unused.
+pub fn samples() -> ([Sample; HISTORY], usize) {
+    RING.with(|ring| {
+        let mut samples = [EMPTY; HISTORY];
+        let oldest = (ring.next + HISTORY - ring.len) % HISTORY;
+        for (i, sample) in samples[..ring.len].iter_mut().enumerate() {
+            *sample = ring.samples[(oldest + i) % HISTORY];
+        }
+        (samples, ring.len)
+    })
+}
+
+pub fn clear() {
+    RING.with(|ring| {
+        ring.next = 0;
+        ring.len = 0;
+    })
+}
+
+// Write the history to the log, a few lines per sample. Idle DMA channels and interrupts that
+// woke nothing are left out.
+pub fn report() {
+    let (samples, len) = samples();
+    for sample in &samples[..len] {
+        log::info!(
+            "diag @{} us: pending irqs {:#010x}",
+            sample.at.as_micros(),
+            sample.irq_pending
+        );
+        for ch in (0..DMA_CHANNELS).filter(|ch| sample.dma_busy & 1 << ch != 0) {
+            log::info!("  dma {}: busy, {} left", ch, sample.dma_remaining[ch]);
+        }
+        for block in 0..2 {
+            log::info!(
+                "  pio{}: tx {:?} rx {:?} fdebug {:#010x}",
+                block,
+                sample.pio_tx_level[block],
+                sample.pio_rx_level[block],
+                sample.pio_fdebug[block]
+            );
+        }
+        for (irq, wakes) in sample.irq_wakes_per_sec.iter().enumerate() {
+            if *wakes > 0 {
+                log::info!("  irq {}: {} wakes/s", irq, wakes);
+            }
+        }
+    }
+}
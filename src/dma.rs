@@ -0,0 +1,429 @@
+// The DMA controller's 12 channels, handed out one at a time, with transfers that are futures
+// woken by the channel's interrupt when they finish.
+//
+//     let mut channel = dma::claim().unwrap();
+//     unsafe { channel.copy(&frame, &mut back_buffer) }.await?;
+//     unsafe { channel.write_to(&samples, pwm_cc, Dreq::pwm_wrap(2)) }.await?;
+//
+// A transfer copies memory to memory as fast as the bus allows, or moves words between memory
+// and a peripheral's FIFO register at the pace of the peripheral's DREQ. Tasks on core 0 are
+// woken through DMA_IRQ_0 and tasks on core 1 through DMA_IRQ_1, so both can have transfers
+// going at once; a channel's interrupt is only enabled while a task is waiting on it. All the
+// channels share those two lines, so their handler clears each channel's interrupt as soon as
+// it's raised and notes that the channel is done, rather than leaving the line asserted until
+// that channel's task gets round to it.
+//
+// A transfer starts when it's made, not when it's first polled, and dropping it stops the
+// channel. It borrows its buffers for as long as it exists, but that only keeps the DMA away
+// from memory that's been given back if it really is dropped: a transfer that's leaked with
+// `mem::forget` lets go of the borrows and leaves the channel running. That's why starting one
+// is unsafe, even between two buffers.
+
+use core::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use cortex_m::peripheral::NVIC;
+use rp2040_pac::{dma::RegisterBlock, Interrupt};
+
+use crate::{
+    irq, reactor, resets,
+    sync::{self, Mutex},
+};
+
+pub const CHANNELS: usize = 12;
+
+// CH_CTRL_TRIG
+const EN: u32 = 1 << 0;
+const DATA_SIZE_SHIFT: u32 = 2;
+const INCR_READ: u32 = 1 << 4;
+const INCR_WRITE: u32 = 1 << 5;
+const CHAIN_TO_SHIFT: u32 = 11;
+const TREQ_SEL_SHIFT: u32 = 15;
+const WRITE_ERROR: u32 = 1 << 29;
+const READ_ERROR: u32 = 1 << 30;
+
+struct State {
+    // Claimed channels, one bit each.
+    claimed: u16,
+    // Channels that have raised their interrupt since their transfer started, by finishing or
+    // stopping on an error. Set by the handler.
+    finished: u16,
+    // The task waiting on each channel.
+    wakers: [Option<Waker>; CHANNELS],
+    // Whether each core's line has the handler and is unmasked.
+    installed: [bool; 2],
+}
+
+const NO_WAKER: Option<Waker> = None;
+
+// Also held while changing interrupt enables, which all the channels share, and by the handler.
+static STATE: Mutex<State> = Mutex::new(State {
+    claimed: 0,
+    finished: 0,
+    wakers: [NO_WAKER; CHANNELS],
+    installed: [false; 2],
+});
+
+fn regs() -> &'static RegisterBlock {
+    unsafe { &*rp2040_pac::DMA::ptr() }
+}
+
+// A free channel, if there is one.
+pub fn claim() -> Option<Channel> {
+    // DMA
+    resets::bring_up(1 << 2);
+    let number = STATE.with(|state| {
+        let number = (0..CHANNELS as u8).find(|n| state.claimed & 1 << n == 0)?;
+        state.claimed |= 1 << number;
+        Some(number)
+    })?;
+    Some(Channel { number })
+}
+
+// What paces a transfer to or from a peripheral: the data request signal it raises when its
+// FIFO has room or data. Numbered as in the datasheet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dreq(u8);
+
+impl Dreq {
+    pub const SPI0_TX: Dreq = Dreq(16);
+    pub const SPI0_RX: Dreq = Dreq(17);
+    pub const SPI1_TX: Dreq = Dreq(18);
+    pub const SPI1_RX: Dreq = Dreq(19);
+    pub const UART0_TX: Dreq = Dreq(20);
+    pub const UART0_RX: Dreq = Dreq(21);
+    pub const UART1_TX: Dreq = Dreq(22);
+    pub const UART1_RX: Dreq = Dreq(23);
+    pub const I2C0_TX: Dreq = Dreq(32);
+    pub const I2C0_RX: Dreq = Dreq(33);
+    pub const I2C1_TX: Dreq = Dreq(34);
+    pub const I2C1_RX: Dreq = Dreq(35);
+    pub const ADC: Dreq = Dreq(36);
+    // No pacing at all: as fast as the bus allows.
+    pub const PERMANENT: Dreq = Dreq(0x3f);
+
+    pub const fn pio_tx(block: u8, sm: u8) -> Dreq {
+        Dreq(block * 8 + sm)
+    }
+
+    pub const fn pio_rx(block: u8, sm: u8) -> Dreq {
+        Dreq(block * 8 + 4 + sm)
+    }
+
+    pub const fn pwm_wrap(slice: u8) -> Dreq {
+        Dreq(24 + slice)
+    }
+}
+
+// What a transfer moves at a time; the buffer's element type decides.
+pub trait Word: Copy {
+    // CTRL's DATA_SIZE.
+    const SIZE: u32;
+}
+
+impl Word for u8 {
+    const SIZE: u32 = 0;
+}
+
+impl Word for u16 {
+    const SIZE: u32 = 1;
+}
+
+impl Word for u32 {
+    const SIZE: u32 = 2;
+}
+
+// The channel stopped on a bus error, reading or writing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    Read,
+    Write,
+}
+
+// One DMA channel. Dropping it gives it back.
+pub struct Channel {
+    number: u8,
+}
+
+impl Channel {
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    // How many words the last transfer left unmoved, if it was dropped before it finished.
+    pub fn remaining(&self) -> usize {
+        regs().ch[self.number as usize].ch_trans_count.read().bits() as usize
+    }
+
+    // Copy `from` into `to`, which has to be the same length.
+    //
+    // Safety: the transfer has to be dropped or run to the end, not leaked, or the DMA goes on
+    // writing to `to` after the borrow is over.
+    pub unsafe fn copy<'a, W: Word>(&'a mut self, from: &'a [W], to: &'a mut [W]) -> Transfer<'a> {
+        assert_eq!(from.len(), to.len(), "DMA copy between different lengths");
+        let (read, write) = (from.as_ptr() as u32, to.as_mut_ptr() as u32);
+        self.start::<W>(
+            read,
+            write,
+            from.len(),
+            INCR_READ | INCR_WRITE,
+            Dreq::PERMANENT,
+        )
+    }
+
+    // Fill `to` from a peripheral's register, a word each time `dreq` asks.
+    //
+    // Safety: `register` has to be a peripheral register that's fine to read `to.len()` times
+    // in the background, e.g. a receive FIFO, and stay so until the transfer is done. And as
+    // with `copy`, the transfer mustn't be leaked.
+    pub unsafe fn read_from<'a, W: Word>(
+        &'a mut self,
+        register: *const W,
+        dreq: Dreq,
+        to: &'a mut [W],
+    ) -> Transfer<'a> {
+        let (read, write) = (register as u32, to.as_mut_ptr() as u32);
+        self.start::<W>(read, write, to.len(), INCR_WRITE, dreq)
+    }
+
+    // Write `from` to a peripheral's register, a word each time `dreq` asks.
+    //
+    // Safety: `register` has to be a peripheral register that's fine to write `from.len()`
+    // times in the background, e.g. a transmit FIFO, and stay so until the transfer is done.
+    // And as with `copy`, the transfer mustn't be leaked.
+    pub unsafe fn write_to<'a, W: Word>(
+        &'a mut self,
+        from: &'a [W],
+        register: *mut W,
+        dreq: Dreq,
+    ) -> Transfer<'a> {
+        let (read, write) = (from.as_ptr() as u32, register as u32);
+        self.start::<W>(read, write, from.len(), INCR_READ, dreq)
+    }
+
+    // Write `*word` to a peripheral's register `count` times, each time `dreq` asks: the
+    // padding a transmit FIFO needs while only receiving, say.
+    //
+    // Safety: as with `write_to`.
+    pub unsafe fn write_repeated<'a, W: Word>(
+        &'a mut self,
+        word: &'a W,
+        count: usize,
+        register: *mut W,
+        dreq: Dreq,
+    ) -> Transfer<'a> {
+        let (read, write) = (word as *const W as u32, register as u32);
+        self.start::<W>(read, write, count, 0, dreq)
+    }
+
+    // Read a peripheral's register `count` times, each time `dreq` asks, keeping only the last
+    // word in `to`: draining a receive FIFO whose data isn't wanted, say.
+    //
+    // Safety: as with `read_from`.
+    pub unsafe fn read_repeated<'a, W: Word>(
+        &'a mut self,
+        register: *const W,
+        dreq: Dreq,
+        count: usize,
+        to: &'a mut W,
+    ) -> Transfer<'a> {
+        let (read, write) = (register as u32, to as *mut W as u32);
+        self.start::<W>(read, write, count, 0, dreq)
+    }
+
+    fn start<W: Word>(
+        &mut self,
+        read: u32,
+        write: u32,
+        len: usize,
+        increment: u32,
+        dreq: Dreq,
+    ) -> Transfer<'_> {
+        let number = self.number;
+        let ch = &regs().ch[number as usize];
+        // Anything left over from a transfer that was abandoned.
+        STATE.with(|state| {
+            regs().intr.write(|w| unsafe { w.bits(1 << number) });
+            state.finished &= !(1 << number);
+            state.wakers[number as usize] = None;
+        });
+        // With nothing to move there's nothing to wait for, so the channel isn't started.
+        if len > 0 {
+            ch.ch_read_addr.write(|w| unsafe { w.bits(read) });
+            ch.ch_write_addr.write(|w| unsafe { w.bits(write) });
+            ch.ch_trans_count.write(|w| unsafe { w.bits(len as u32) });
+            // Chained to itself (no chain), with any error flags from before cleared.
+            ch.ch_ctrl_trig.write(|w| unsafe {
+                w.bits(
+                    EN | W::SIZE << DATA_SIZE_SHIFT
+                        | increment
+                        | (number as u32) << CHAIN_TO_SHIFT
+                        | (dreq.0 as u32) << TREQ_SEL_SHIFT
+                        | READ_ERROR
+                        | WRITE_ERROR,
+                )
+            });
+        }
+        Transfer {
+            channel: self,
+            done: len == 0,
+            buffers: PhantomData,
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        STATE.with(|state| state.claimed &= !(1 << self.number));
+    }
+}
+
+// A transfer that's running. Resolves when the channel has moved every word, or stopped on an
+// error.
+pub struct Transfer<'a> {
+    channel: &'a mut Channel,
+    done: bool,
+    // The buffers the DMA is reading and writing.
+    buffers: PhantomData<&'a mut [u8]>,
+}
+
+impl Transfer<'_> {
+    // How many words are still to go.
+    pub fn remaining(&self) -> usize {
+        if self.done {
+            return 0;
+        }
+        regs().ch[self.channel.number as usize]
+            .ch_trans_count
+            .read()
+            .bits() as usize
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        let number = self.channel.number;
+        STATE.with(|state| {
+            route(number, None);
+            regs().intr.write(|w| unsafe { w.bits(1 << number) });
+            state.finished &= !(1 << number);
+            state.wakers[number as usize] = None;
+        });
+    }
+}
+
+impl Future for Transfer<'_> {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.done {
+            return Poll::Ready(Ok(()));
+        }
+        let number = self.channel.number;
+        let finished = STATE.with(|state| {
+            if state.finished & 1 << number != 0 {
+                return true;
+            }
+            let core = sync::core();
+            install_on(state, core);
+            let waker = &mut state.wakers[number as usize];
+            if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                *waker = Some(cx.waker().clone());
+            }
+            // If it finished in the meantime, the interrupt fires as soon as the lock is let go.
+            route(number, Some(core));
+            false
+        });
+        if !finished {
+            return Poll::Pending;
+        }
+        let ctrl = regs().ch[number as usize].ch_ctrl_trig.read().bits();
+        // The channel stops on a bus error and raises its interrupt as if it had finished.
+        if ctrl & (READ_ERROR | WRITE_ERROR) != 0 {
+            abort(number);
+            self.finish();
+            return Poll::Ready(Err(if ctrl & READ_ERROR != 0 {
+                Error::Read
+            } else {
+                Error::Write
+            }));
+        }
+        self.finish();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Transfer<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            abort(self.channel.number);
+            self.finish();
+        }
+    }
+}
+
+// Stop `number` and wait for the writes it has in flight to land.
+fn abort(number: u8) {
+    regs().chan_abort.write(|w| unsafe { w.bits(1 << number) });
+    while regs().chan_abort.read().bits() & 1 << number != 0 {
+        cortex_m::asm::nop();
+    }
+}
+
+// Route `number`'s interrupt to `core`'s line, or to neither. With `STATE` held.
+fn route(number: u8, core: Option<usize>) {
+    let bit = 1 << number;
+    let dma = regs();
+    dma.inte0.modify(|r, w| unsafe { w.bits(r.bits() & !bit) });
+    dma.inte1.modify(|r, w| unsafe { w.bits(r.bits() & !bit) });
+    match core {
+        Some(0) => dma.inte0.modify(|r, w| unsafe { w.bits(r.bits() | bit) }),
+        Some(_) => dma.inte1.modify(|r, w| unsafe { w.bits(r.bits() | bit) }),
+        None => {}
+    }
+}
+
+// Put the handler on `core`'s line, if it isn't there already.
+fn install_on(state: &mut State, core: usize) {
+    if !state.installed[core] {
+        state.installed[core] = true;
+        let irq = irq_for(core);
+        irq::reserve(irq);
+        reactor::set_raw_handler(irq, handler);
+        // Safety: The handler is installed, and only touches STATE and the DMA's interrupts.
+        unsafe { NVIC::unmask(irq) };
+    }
+}
+
+// Clear the interrupts of the channels routed to this core's line, mark them finished, and wake
+// their tasks.
+extern "C" fn handler() {
+    let dma = regs();
+    let mut woken = [NO_WAKER; CHANNELS];
+    STATE.with(|state| {
+        let pending = match sync::core() {
+            0 => dma.ints0.read().bits(),
+            _ => dma.ints1.read().bits(),
+        };
+        dma.intr.write(|w| unsafe { w.bits(pending) });
+        state.finished |= pending as u16;
+        for (number, woken) in woken.iter_mut().enumerate() {
+            if pending & 1 << number != 0 {
+                *woken = state.wakers[number].take();
+            }
+        }
+    });
+    for waker in woken.into_iter().flatten() {
+        waker.wake();
+    }
+}
+
+fn irq_for(core: usize) -> Interrupt {
+    match core {
+        0 => Interrupt::DMA_IRQ_0,
+        _ => Interrupt::DMA_IRQ_1,
+    }
+}
@@ -0,0 +1,92 @@
+// DMX512 output: one universe of 512 channel levels, sent on a hardware UART by DMA.
+//
+// A packet is a break, the start code (0 for dimmer data) and the 512 slots, and takes about
+// 23 ms at 250 kbaud. `send_frame` paces packets to `REFRESH` apart, which is the rate most
+// consoles send at and leaves the receivers some idle time; a task that calls it in a loop
+// refreshes the universe continuously, as DMX expects.
+//
+//     let mut uart = Uart::new(pac.UART1, irq, 4, 5, &uart::Config::DMX512, peri_hz);
+//     let (tx, _) = uart.split();
+//     let mut universe = Universe::new(tx, dma::claim().unwrap());
+//     loop {
+//         universe.set(1, level);
+//         universe.send_frame().await;
+//     }
+//
+// The task sends the break itself, then hands the start code and slots to the DMA channel,
+// which feeds them to the UART's TX FIFO as it drains. The task isn't woken again until the
+// packet is out.
+
+use core::time::Duration;
+
+use crate::{
+    dma,
+    pio_uart::Framing,
+    time::{self, Instant},
+    uart::port::{Instance, UartTx},
+};
+
+pub const SLOTS: usize = 512;
+// Time from the start of one packet to the start of the next.
+pub const REFRESH: Duration = Duration::from_micros(25_000);
+const DIMMER_START_CODE: u8 = 0;
+
+pub struct Universe<'a, U: Instance> {
+    tx: UartTx<'a, U>,
+    dma: dma::Channel,
+    // The start code, then the slots.
+    frame: [u8; 1 + SLOTS],
+    next: Option<Instant>,
+}
+
+impl<'a, U: Instance> Universe<'a, U> {
+    // `tx`'s UART should have been set up with `uart::Config::DMX512`.
+    pub fn new(tx: UartTx<'a, U>, dma: dma::Channel) -> Self {
+        Universe {
+            tx,
+            dma,
+            frame: [0; 1 + SLOTS],
+            next: None,
+        }
+    }
+
+    // Set channel `channel` (1-512, as lighting people count) to `level`.
+    pub fn set(&mut self, channel: u16, level: u8) {
+        assert!((1..=SLOTS as u16).contains(&channel), "no such DMX channel");
+        self.frame[channel as usize] = level;
+    }
+
+    pub fn get(&self, channel: u16) -> u8 {
+        assert!((1..=SLOTS as u16).contains(&channel), "no such DMX channel");
+        self.frame[channel as usize]
+    }
+
+    // All 512 levels, channel 1 first.
+    pub fn slots(&mut self) -> &mut [u8; SLOTS] {
+        (&mut self.frame[1..]).try_into().unwrap()
+    }
+
+    pub fn blackout(&mut self) {
+        self.slots().fill(0);
+    }
+
+    // Wait for the next refresh, then send the current levels. Returns when the packet has
+    // gone out.
+    pub async fn send_frame(&mut self) {
+        if let Some(next) = self.next {
+            time::sleep_until(next).await;
+        }
+        let start = Instant::now();
+        self.next = Some(start + REFRESH);
+        self.frame[0] = DIMMER_START_CODE;
+        let framing = Framing::DMX512;
+        self.tx
+            .send_break(framing.break_len, framing.mark_after_break)
+            .await;
+        self.tx.write_dma(&mut self.dma, &self.frame).await;
+    }
+
+    pub fn release(self) -> (UartTx<'a, U>, dma::Channel) {
+        (self.tx, self.dma)
+    }
+}
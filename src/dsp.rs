@@ -0,0 +1,246 @@
+// Signal processing blocks in fixed point, for sensor data on the FPU-less M0+.
+//
+// Samples are plain i32s in whatever unit the source uses (milli-g, mdps...). Biquad
+// coefficients are Q30, which leaves room for |a1| up to 2; FIR taps and gains are Q15
+// from `math::fixed`.
+//
+// The filters are cheap enough to run inline, but a chain of them behind a fast sensor fits
+// well on core 1: `spawn_on_core1` runs a pipeline there, fed and drained through Channels,
+// and leaves core 0's executor free for I/O.
+
+use core::{
+    future::Future,
+    pin::pin,
+    ptr,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+use crate::{
+    math::fixed::Q15,
+    power::{self, SleepMode},
+    sync::Channel,
+};
+
+pub trait Filter {
+    fn process(&mut self, x: i32) -> i32;
+}
+
+// Second order IIR section, direct form I. a0 is normalised to 1.
+pub struct Biquad {
+    b: [i32; 3],
+    a: [i32; 2],
+    x: [i32; 2],
+    y: [i32; 2],
+}
+
+impl Biquad {
+    pub const fn new(b: [i32; 3], a: [i32; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            x: [0; 2],
+            y: [0; 2],
+        }
+    }
+
+    // From coefficients designed offline, e.g. with scipy.signal.
+    pub fn from_float(b: [f32; 3], a: [f32; 2]) -> Self {
+        let q30 = |c: f32| (c * (1 << 30) as f32) as i32;
+        Biquad::new(b.map(q30), a.map(q30))
+    }
+
+    pub fn reset(&mut self) {
+        self.x = [0; 2];
+        self.y = [0; 2];
+    }
+}
+
+impl Filter for Biquad {
+    fn process(&mut self, x: i32) -> i32 {
+        let [b0, b1, b2] = self.b.map(|b| b as i64);
+        let [a1, a2] = self.a.map(|a| a as i64);
+        let acc = b0 * x as i64 + b1 * self.x[0] as i64 + b2 * self.x[1] as i64
+            - a1 * self.y[0] as i64
+            - a2 * self.y[1] as i64;
+        let y = ((acc + (1 << 29)) >> 30).clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// Finite impulse response filter with N taps.
+pub struct Fir<const N: usize> {
+    taps: [Q15; N],
+    history: [i32; N],
+    pos: usize,
+}
+
+impl<const N: usize> Fir<N> {
+    pub const fn new(taps: [Q15; N]) -> Self {
+        Fir {
+            taps,
+            history: [0; N],
+            pos: 0,
+        }
+    }
+
+    // A moving average over N samples.
+    pub const fn average() -> Self {
+        Fir::new([Q15((32768 / N) as i16); N])
+    }
+}
+
+impl<const N: usize> Filter for Fir<N> {
+    fn process(&mut self, x: i32) -> i32 {
+        self.history[self.pos] = x;
+        let mut acc = 0i64;
+        for (i, &tap) in self.taps.iter().enumerate() {
+            let sample = self.history[(self.pos + N - i) % N];
+            acc += tap.0 as i64 * sample as i64;
+        }
+        self.pos = (self.pos + 1) % N;
+        ((acc + (1 << 14)) >> 15) as i32
+    }
+}
+
+// Two filters in a row.
+pub struct Chain<A, B>(pub A, pub B);
+
+impl<A: Filter, B: Filter> Filter for Chain<A, B> {
+    fn process(&mut self, x: i32) -> i32 {
+        self.1.process(self.0.process(x))
+    }
+}
+
+// Fuses a gyro rate with an angle from the accelerometer: the gyro is trusted in the short
+// term and the accelerometer in the long term, which cancels out the gyro's drift.
+pub struct Complementary {
+    angle_mdeg: i32,
+    // Weight of the gyro path. 0.98 is typical at 100 Hz.
+    alpha: Q15,
+}
+
+impl Complementary {
+    pub const fn new(alpha: Q15) -> Self {
+        Complementary {
+            angle_mdeg: 0,
+            alpha,
+        }
+    }
+
+    pub fn update(&mut self, rate_mdps: i32, accel_angle_mdeg: i32, dt_us: u32) -> i32 {
+        let predicted = self.angle_mdeg + (rate_mdps as i64 * dt_us as i64 / 1_000_000) as i32;
+        self.angle_mdeg =
+            self.alpha.scale(predicted) + self.alpha.complement().scale(accel_angle_mdeg);
+        self.angle_mdeg
+    }
+
+    pub fn angle_mdeg(&self) -> i32 {
+        self.angle_mdeg
+    }
+}
+
+// One-dimensional Kalman filter: a value that moves by a known amount each step (e.g. an
+// angle integrated from a gyro) plus noise, observed through a noisy measurement.
+pub struct Kalman {
+    // Estimate, Q8.
+    x: i64,
+    // Its variance, and the process and measurement noise variances, in units squared.
+    p: i64,
+    q: i64,
+    r: i64,
+}
+
+impl Kalman {
+    pub const fn new(process_noise: u32, measurement_noise: u32) -> Self {
+        Kalman {
+            x: 0,
+            // Unknown start: trust the first measurement.
+            p: i32::MAX as i64,
+            q: process_noise as i64,
+            r: measurement_noise as i64,
+        }
+    }
+
+    pub fn update(&mut self, delta: i32, measurement: i32) -> i32 {
+        // Predict.
+        self.x += (delta as i64) << 8;
+        self.p = self.p.saturating_add(self.q);
+        // Correct, with the gain k = p / (p + r) in Q16.
+        let k = (self.p << 16) / (self.p + self.r).max(1);
+        self.x += (k * (((measurement as i64) << 8) - self.x)) >> 16;
+        self.p = ((65536 - k) * self.p) >> 16;
+        self.estimate()
+    }
+
+    pub fn estimate(&self) -> i32 {
+        (self.x >> 8) as i32
+    }
+}
+
+// atan2 in millidegrees, to about 0.3 degrees. For tilt from an accelerometer:
+// `atan2_mdeg(accel_y, accel_z)`.
+pub fn atan2_mdeg(y: i32, x: i32) -> i32 {
+    if x == 0 && y == 0 {
+        return 0;
+    }
+    let (ax, ay) = (x.unsigned_abs() as i64, y.unsigned_abs() as i64);
+    // atan(r) ~ 45r + 15.6r(1 - r) degrees for 0 <= r <= 1, with r in Q15.
+    let (r, swapped) = if ay <= ax {
+        ((ay << 15) / ax, false)
+    } else {
+        ((ax << 15) / ay, true)
+    };
+    let mut angle = (45_000 * r + ((15_642 * r * (32768 - r)) >> 15)) >> 15;
+    if swapped {
+        angle = 90_000 - angle;
+    }
+    if x < 0 {
+        angle = 180_000 - angle;
+    }
+    if y < 0 {
+        angle = -angle;
+    }
+    angle as i32
+}
+
+// Pass every value from `input` through `filter` into `output`, forever.
+pub async fn run<F: Filter, const IN: usize, const OUT: usize>(
+    mut filter: F,
+    input: &Channel<i32, IN>,
+    output: &Channel<i32, OUT>,
+) -> ! {
+    loop {
+        let x = input.recv().await;
+        output.send(filter.process(x)).await;
+    }
+}
+
+// Run `pipeline` on core 1, on its own: it's polled whenever anything wakes it and the core
+// sleeps otherwise. Core 0's executor isn't involved, so a busy pipeline can't delay its tasks.
+pub fn spawn_on_core1<F>(pipeline: F)
+where
+    F: Future<Output = !> + Send + 'static,
+{
+    crate::jumpstart::spawn(move || {
+        let mut pipeline = pin!(pipeline);
+        let waker = unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &SEV_VTABLE)) };
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(never) = pipeline.as_mut().poll(&mut cx) {
+                never
+            }
+            // A wake after the poll set the event register, so this returns straight away.
+            power::measure(SleepMode::Wfe, cortex_m::asm::wfe);
+        }
+    })
+}
+
+// Waking just means "poll again": SEV gets core 1 out of WFE.
+static SEV_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    |data| RawWaker::new(data, &SEV_VTABLE),
+    |_| cortex_m::asm::sev(),
+    |_| cortex_m::asm::sev(),
+    |_| {},
+);
@@ -0,0 +1,425 @@
+// WiFi through an ESP8266/ESP32 running Espressif's AT firmware (v2.x), over a serial port.
+//
+// TCP connections are available through embedded-nal-async's `TcpConnect`, so anything written
+// against that works over the modem. The modem holds received data until it's asked for
+// (passive receive mode), so nothing is lost while no task is reading; a read with nothing to
+// hand back checks again every POLL_MS without holding on to the modem.
+//
+// UDP and SIM7xxx cellular modems aren't supported yet.
+
+extern crate alloc;
+
+use alloc::{format, string::String};
+use core::{net::SocketAddr, time::Duration};
+
+use embedded_hal_async::delay::DelayNs;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use embedded_nal_async::TcpConnect;
+
+use crate::{
+    command::{self, Commander, Match, Policy},
+    retry,
+    sync::{AsyncMutex, AsyncMutexGuard, Mutex},
+};
+
+// Connections the firmware supports at once.
+const LINKS: u8 = 5;
+const POLL_MS: u32 = 50;
+// Most the modem accepts in one AT+CIPSEND or AT+CIPRECVDATA.
+const MAX_CHUNK: usize = 2048;
+
+const QUICK: Policy = Policy {
+    timeout_ms: 1000,
+    retries: 0,
+};
+const CONNECT: Policy = Policy {
+    timeout_ms: 10_000,
+    retries: 0,
+};
+const JOIN: Policy = Policy {
+    timeout_ms: 20_000,
+    retries: 0,
+};
+// Access points turn clients away for a while after a failed attempt, so back off properly.
+const JOIN_RETRY: retry::Policy = retry::Policy {
+    attempts: 4,
+    initial: Duration::from_secs(1),
+    max: Duration::from_secs(30),
+    multiplier: 4,
+    jitter: true,
+};
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Command(command::Error<E>),
+    // All LINKS connections are in use.
+    NoFreeLink,
+    // IPv6 addresses.
+    Unsupported,
+    // The other end closed the connection.
+    Closed,
+}
+
+impl<E> From<command::Error<E>> for Error<E> {
+    fn from(e: command::Error<E>) -> Self {
+        Error::Command(e)
+    }
+}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Command(command::Error::Io(e)) => e.kind(),
+            Error::Command(command::Error::Timeout) => ErrorKind::TimedOut,
+            Error::Command(_) => ErrorKind::Other,
+            Error::NoFreeLink => ErrorKind::OutOfMemory,
+            Error::Unsupported => ErrorKind::Unsupported,
+            Error::Closed => ErrorKind::NotConnected,
+        }
+    }
+}
+
+// One bit per link id.
+struct Links {
+    in_use: u8,
+    // Closed by the other end.
+    closed: u8,
+    // Dropped, but not closed on the modem yet.
+    to_close: u8,
+}
+
+pub struct EspAt<T, D> {
+    modem: AsyncMutex<Commander<T, D>>,
+    links: Mutex<Links, 21>,
+    // For waiting between polls without holding the modem.
+    delay: D,
+}
+
+impl<T, D> EspAt<T, D>
+where
+    T: Read + Write,
+    D: DelayNs + Clone,
+{
+    // Set the modem up for multiple connections with passive receive.
+    pub async fn new(io: T, delay: D) -> Result<Self, Error<T::Error>> {
+        let esp = EspAt {
+            modem: AsyncMutex::new(Commander::new(io, delay.clone())),
+            links: Mutex::new(Links {
+                in_use: 0,
+                closed: 0,
+                to_close: 0,
+            }),
+            delay,
+        };
+        {
+            let mut modem = esp.modem.lock().await;
+            // The modem may be halfway through something; retrying AT gets back in step.
+            let sync = Policy {
+                timeout_ms: 500,
+                retries: 5,
+            };
+            esp.command(&mut modem, b"AT\r\n", sync).await?;
+            esp.command(&mut modem, b"ATE0\r\n", QUICK).await?;
+            esp.command(&mut modem, b"AT+CIPMUX=1\r\n", QUICK).await?;
+            esp.command(&mut modem, b"AT+CIPRECVMODE=1\r\n", QUICK)
+                .await?;
+        }
+        Ok(esp)
+    }
+
+    // Join a WiFi network as a station. Retried with backoff if the modem reports a failure
+    // or doesn't answer; the modem is free for other tasks in between.
+    pub async fn join(&self, ssid: &str, password: &str) -> Result<(), Error<T::Error>> {
+        let cmd = format!("AT+CWJAP=\"{}\",\"{}\"\r\n", escape(ssid), escape(password));
+        retry::retry_if(
+            JOIN_RETRY,
+            || async {
+                let mut modem = self.modem().await?;
+                self.command(&mut modem, b"AT+CWMODE=1\r\n", QUICK).await?;
+                self.command(&mut modem, cmd.as_bytes(), JOIN).await
+            },
+            |e| {
+                matches!(
+                    e,
+                    Error::Command(command::Error::Rejected | command::Error::Timeout)
+                )
+            },
+        )
+        .await
+    }
+
+    // Lock the modem, closing any connections that were dropped in the meantime.
+    async fn modem(&self) -> Result<AsyncMutexGuard<'_, Commander<T, D>>, Error<T::Error>> {
+        let mut modem = self.modem.lock().await;
+        let to_close = self
+            .links
+            .with(|links| core::mem::take(&mut links.to_close));
+        for id in (0..LINKS).filter(|id| to_close & 1 << id != 0) {
+            let cmd = format!("AT+CIPCLOSE={}\r\n", id);
+            match self.command(&mut modem, cmd.as_bytes(), QUICK).await {
+                // It may have been closed from the other end already.
+                Ok(()) | Err(Error::Command(command::Error::Rejected)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(modem)
+    }
+
+    // Send a command that answers OK or ERROR.
+    async fn command(
+        &self,
+        modem: &mut Commander<T, D>,
+        cmd: &[u8],
+        policy: Policy,
+    ) -> Result<(), Error<T::Error>> {
+        modem
+            .request(cmd, policy, |line| match line {
+                b"OK" => Match::Done(()),
+                b"ERROR" | b"FAIL" => Match::Fail,
+                _ => {
+                    self.note(line);
+                    Match::Continue
+                }
+            })
+            .await?;
+        Ok(())
+    }
+
+    // Keep track of unsolicited messages that matter, like `<id>,CLOSED`.
+    fn note(&self, line: &[u8]) {
+        if let [id @ b'0'..=b'4', b',', b'C', b'L', b'O', b'S', b'E', b'D'] = *line {
+            self.links.with(|links| links.closed |= 1 << (id - b'0'));
+        }
+    }
+
+    fn is_closed(&self, id: u8) -> bool {
+        self.links.with(|links| links.closed & 1 << id != 0)
+    }
+
+    // Bytes waiting on the modem for link `id`, or None if the link is closed.
+    async fn available(
+        &self,
+        modem: &mut Commander<T, D>,
+        id: u8,
+    ) -> Result<Option<usize>, Error<T::Error>> {
+        let mut lens = None;
+        modem
+            .request(b"AT+CIPRECVLEN?\r\n", QUICK, |line| {
+                if let Some(rest) = line.strip_prefix(b"+CIPRECVLEN:") {
+                    lens = core::str::from_utf8(rest).ok().and_then(|rest| {
+                        let len = rest.split(',').nth(id as usize)?;
+                        Some(len.parse::<i32>().ok())
+                    });
+                }
+                match line {
+                    b"OK" => Match::Done(()),
+                    b"ERROR" => Match::Fail,
+                    _ => {
+                        self.note(line);
+                        Match::Continue
+                    }
+                }
+            })
+            .await?;
+        // -1 means the link isn't connected.
+        Ok(match lens.flatten() {
+            Some(len) if len >= 0 => Some(len as usize),
+            _ => None,
+        })
+    }
+
+    async fn receive(
+        &self,
+        modem: &mut Commander<T, D>,
+        id: u8,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<T::Error>> {
+        let cmd = format!("AT+CIPRECVDATA={},{}\r\n", id, buf.len());
+        modem.write(cmd.as_bytes()).await?;
+        // +CIPRECVDATA:<len>,<data>
+        let len = loop {
+            let header = modem.read_until(b',', QUICK.timeout_ms).await?;
+            if let Some(len) = header.strip_prefix(b"+CIPRECVDATA:") {
+                let len = core::str::from_utf8(len)
+                    .ok()
+                    .and_then(|len| len.parse().ok());
+                break len.ok_or(command::Error::Rejected)?;
+            }
+            if header == b"ERROR" {
+                return Err(command::Error::Rejected.into());
+            }
+        };
+        let len: usize = core::cmp::min(len, buf.len());
+        modem.read_exact(&mut buf[..len], QUICK.timeout_ms).await?;
+        modem
+            .response(QUICK.timeout_ms, |line| match line {
+                b"OK" => Match::Done(()),
+                b"ERROR" => Match::Fail,
+                _ => {
+                    self.note(line);
+                    Match::Continue
+                }
+            })
+            .await?;
+        Ok(len)
+    }
+
+    async fn send(
+        &self,
+        modem: &mut Commander<T, D>,
+        id: u8,
+        data: &[u8],
+    ) -> Result<(), Error<T::Error>> {
+        let cmd = format!("AT+CIPSEND={},{}\r\n", id, data.len());
+        modem.write(cmd.as_bytes()).await?;
+        // Wait for the `>` prompt. It isn't followed by a line ending, so it shows up as an
+        // empty token; anything else is an answer or an unsolicited message.
+        loop {
+            match modem.read_until(b'>', QUICK.timeout_ms).await? {
+                b"" => break,
+                b"ERROR" | b"link is not valid" => return Err(command::Error::Rejected.into()),
+                line => self.note(line),
+            }
+        }
+        modem.write(data).await?;
+        modem
+            .response(CONNECT.timeout_ms, |line| match line {
+                b"SEND OK" => Match::Done(()),
+                b"SEND FAIL" | b"ERROR" => Match::Fail,
+                _ => {
+                    self.note(line);
+                    Match::Continue
+                }
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+impl<T, D> TcpConnect for EspAt<T, D>
+where
+    T: Read + Write,
+    D: DelayNs + Clone,
+{
+    type Error = Error<T::Error>;
+    type Connection<'a>
+        = Connection<'a, T, D>
+    where
+        Self: 'a;
+
+    async fn connect<'a>(
+        &'a self,
+        remote: SocketAddr,
+    ) -> Result<Connection<'a, T, D>, Self::Error> {
+        let SocketAddr::V4(remote) = remote else {
+            return Err(Error::Unsupported);
+        };
+        let id = self
+            .links
+            .with(|links| {
+                let id = (0..LINKS).find(|id| links.in_use & 1 << id == 0)?;
+                links.in_use |= 1 << id;
+                links.closed &= !(1 << id);
+                Some(id)
+            })
+            .ok_or(Error::NoFreeLink)?;
+        // From here on, dropping the connection gives the link back.
+        let connection = Connection { esp: self, id };
+
+        let mut modem = self.modem().await?;
+        let cmd = format!(
+            "AT+CIPSTART={},\"TCP\",\"{}\",{}\r\n",
+            id,
+            remote.ip(),
+            remote.port()
+        );
+        self.command(&mut modem, cmd.as_bytes(), CONNECT).await?;
+        Ok(connection)
+    }
+}
+
+// A TCP connection through the modem. Closed when dropped.
+pub struct Connection<'a, T, D> {
+    esp: &'a EspAt<T, D>,
+    id: u8,
+}
+
+impl<T: ErrorType, D> ErrorType for Connection<'_, T, D> {
+    type Error = Error<T::Error>;
+}
+
+impl<T, D> Read for Connection<'_, T, D>
+where
+    T: Read + Write,
+    D: DelayNs + Clone,
+{
+    // Returns 0 once the other end has closed the connection and everything it sent was read.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            {
+                let mut modem = self.esp.modem().await?;
+                match self.esp.available(&mut modem, self.id).await? {
+                    Some(0) => {}
+                    Some(available) => {
+                        let len = buf.len().min(available).min(MAX_CHUNK);
+                        return self.esp.receive(&mut modem, self.id, &mut buf[..len]).await;
+                    }
+                    None => return Ok(0),
+                }
+                if self.esp.is_closed(self.id) {
+                    return Ok(0);
+                }
+            }
+            self.esp.delay.clone().delay_ms(POLL_MS).await;
+        }
+    }
+}
+
+impl<T, D> Write for Connection<'_, T, D>
+where
+    T: Read + Write,
+    D: DelayNs + Clone,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut modem = self.esp.modem().await?;
+        if self.esp.is_closed(self.id) {
+            return Err(Error::Closed);
+        }
+        let len = buf.len().min(MAX_CHUNK);
+        self.esp.send(&mut modem, self.id, &buf[..len]).await?;
+        Ok(len)
+    }
+
+    // Data is with the modem once `write` returns; there's nothing to wait for.
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<T, D> Drop for Connection<'_, T, D> {
+    fn drop(&mut self) {
+        // The modem can't be talked to from here, so the next command closes it.
+        self.esp.links.with(|links| {
+            links.in_use &= !(1 << self.id);
+            links.to_close |= 1 << self.id;
+        })
+    }
+}
+
+// Quotes, commas and backslashes in AT string parameters need a backslash in front.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '"' | ',' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -1,118 +1,753 @@
 extern crate alloc;
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, collections::VecDeque};
 use core::{
-    borrow::BorrowMut,
+    alloc::AllocError,
+    any::type_name_of_val,
+    cell::UnsafeCell,
     future::Future,
-    mem::forget,
-    pin::Pin,
+    mem::{self, ManuallyDrop},
+    pin::{pin, Pin},
     task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
 };
 
-use crate::sync::{Arc, Mutex};
+use crate::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    power::{self, SleepMode},
+    sync::{self, Arc, Mutex},
+    taskinfo::{self, State},
+    time::Instant,
+};
+
+// `Send` is checked by `spawn`; `spawn_local` tasks don't need it.
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + 'static>>;
+
+// Where a task is in its life. Wakes only queue an `Idle` task, so a task is never in the
+// queue more than once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum TaskState {
+    // Waiting for a wake.
+    Idle,
+    // Waiting to be polled. Also set by a wake that comes while the task is being polled,
+    // in which case it's put in the queue once the poll is done.
+    Queued,
+    Running,
+    // Finished; the future has been dropped and wakes do nothing.
+    Completed,
+}
+
+impl TaskState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => TaskState::Idle,
+            1 => TaskState::Queued,
+            2 => TaskState::Running,
+            _ => TaskState::Completed,
+        }
+    }
+}
+
+struct Task {
+    // Slot and id in the debugger-visible task table, if there was room.
+    info: Option<(usize, u32)>,
+    // The core a `spawn_local` task belongs to. `None` if either core can poll it.
+    core: Option<usize>,
+    state: AtomicU8,
+    // Set by `cancel`; the next `run` drops the future instead of polling it.
+    cancelled: AtomicBool,
+    // Dropped as soon as the task finishes. Only touched by the `run` that moved the task from
+    // `Queued` to `Running`, which no other can while it's there, so no lock is needed and
+    // tasks on the two cores are polled at the same time without holding each other up.
+    future: UnsafeCell<Option<BoxFuture<()>>>,
+}
+
+// Safety: Tasks that can move between cores were spawned with a `Send` future. Local tasks
+// are only polled on their own core, which is also where their future is dropped when they
+// finish; a wake from the other core only moves the `Arc` around. If the last reference goes
+// away on the other core before the task finished, the future is leaked rather than dropped
+// there.
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
+
+impl Task {
+    fn state(&self) -> TaskState {
+        TaskState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    // Move from `from` to `to`, if the task is still in `from`.
+    fn transition(&self, from: TaskState, to: TaskState) -> bool {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    // Keep the debugger's task table in step.
+    fn set_info(&self, state: State) {
+        if let Some((slot, id)) = self.info {
+            taskinfo::set_state(slot, id, state);
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace_id(&self) -> u32 {
+        self.info.map_or(0, |(_, id)| id)
+    }
+
+    fn waker(task: &ArcTask) -> Waker {
+        // Safety: The vtable functions below treat the pointer as the `ArcTask` it came from.
+        unsafe { Waker::from_raw(raw_waker(task.clone())) }
+    }
 
-type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + Sync + 'static>>;
-type ArcMutexFut = Arc<Mutex<BoxFuture<()>, 5>, 6>;
+    // Queue the task to be polled, unless it's already queued or finished. Wakers can run on
+    // either core, or in an interrupt handler.
+    fn wake(task: ArcTask) {
+        #[cfg(feature = "trace")]
+        crate::trace::record(crate::trace::Kind::Wake, task.trace_id());
+        loop {
+            match task.state() {
+                TaskState::Idle => {
+                    if task.transition(TaskState::Idle, TaskState::Queued) {
+                        enqueue(task);
+                        return;
+                    }
+                }
+                // `run` queues it when the poll is done.
+                TaskState::Running => {
+                    if task.transition(TaskState::Running, TaskState::Queued) {
+                        return;
+                    }
+                }
+                TaskState::Queued | TaskState::Completed => return,
+            }
+        }
+    }
+
+    // Stop the task for good. The future is dropped by `run`, so that a local task's is
+    // dropped on its own core.
+    fn cancel(task: &ArcTask) {
+        task.cancelled.store(true, Ordering::Release);
+        Task::wake(task.clone());
+    }
+
+    // Poll a task that was taken from the queue.
+    fn run(task: ArcTask) {
+        if !task.transition(TaskState::Queued, TaskState::Running) {
+            return;
+        }
+        task.set_info(State::Running);
+        #[cfg(feature = "trace")]
+        crate::trace::record(crate::trace::Kind::PollStart, task.trace_id());
+        // Safety: The transition above makes this the only `run` with the task until it's
+        // moved out of `Running` below.
+        let future = unsafe { &mut *task.future.get() };
+        let poll = match future.as_mut() {
+            Some(running) if !task.cancelled.load(Ordering::Acquire) => {
+                STATS.with(|counters| counters.stats.polls = counters.stats.polls.wrapping_add(1));
+                let waker = Task::waker(&task);
+                running.as_mut().poll(&mut Context::from_waker(&waker))
+            }
+            _ => Poll::Ready(()),
+        };
+        #[cfg(feature = "trace")]
+        crate::trace::polled(task.trace_id(), poll.is_ready());
+        if poll.is_ready() {
+            *future = None;
+            task.state
+                .store(TaskState::Completed as u8, Ordering::Release);
+            task.set_info(State::Free);
+            return;
+        }
+        if task.transition(TaskState::Running, TaskState::Idle) {
+            task.set_info(State::Waiting);
+        } else {
+            // Woken while it was running.
+            enqueue(task);
+        }
+    }
+}
 
-static TASK_QUEUE: Mutex<Vec<ArcMutexFut>, 0> = Mutex::new(Vec::new());
+impl Drop for Task {
+    fn drop(&mut self) {
+        if self.core.is_some_and(|core| core != sync::core()) {
+            if let Some(future) = self.future.get_mut().take() {
+                mem::forget(future);
+            }
+        }
+    }
+}
+
+type ArcTask = Arc<Task>;
 
-// Poll all tasks that can be polled.
+// First in, first out, so a task that wakes itself waits its turn behind the others.
+struct Queues {
+    // Tasks either core can poll.
+    shared: VecDeque<ArcTask>,
+    // `spawn_local` tasks, by core.
+    local: [VecDeque<ArcTask>; 2],
+}
+
+impl Queues {
+    fn for_task(&mut self, task: &Task) -> &mut VecDeque<ArcTask> {
+        match task.core.and_then(|core| self.local.get_mut(core)) {
+            Some(local) => local,
+            None => &mut self.shared,
+        }
+    }
+
+    // This core's local tasks go first, since the other core can't take them.
+    fn pop(&mut self) -> Option<ArcTask> {
+        self.local
+            .get_mut(sync::core())
+            .and_then(|local| local.pop_front())
+            .or_else(|| self.shared.pop_front())
+    }
+
+    // How many tasks this core could poll.
+    fn len(&self) -> usize {
+        self.shared.len() + self.local.get(sync::core()).map_or(0, |local| local.len())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.shared.is_empty()
+            && self
+                .local
+                .get(sync::core())
+                .is_none_or(|local| local.is_empty())
+    }
+}
+
+static TASK_QUEUE: Mutex<Queues, 0> = Mutex::new(Queues {
+    shared: VecDeque::new(),
+    local: [VecDeque::new(), VecDeque::new()],
+});
+
+// Poll the tasks that were queued for this core when it was called, once each. Ones queued
+// while it runs, including tasks that woke themselves, wait for the next call, so `block_on`
+// gets to poll its own future in between. A task that returns `Pending` is out of the queue
+// until its waker fires; one that finishes has its future dropped straight away, and its
+// `TaskHandle` is woken with the result.
 pub fn tick() {
-    let mut queue = TASK_QUEUE.lock();
-    while let Some(task) = queue.pop() {
-        let fut = task.borrow_mut().lock().as_mut();
-        let waker = unsafe { Waker::from_raw(construct_waker(task.clone())) };
-        fut.poll(&mut Context::from_waker(&waker));
-    }
-}
-
-fn construct_waker(future: ArcMutexFut) -> RawWaker {
-    let vtable = unsafe {
-        RawWakerVTable::new(
-            |data| unsafe {
-                let data: ArcMutexFut = Arc::from_raw(data);
-                let ret = construct_waker(data.clone());
-                forget(data); // Do NOT drop the ArcMutexFut here: this is still retained by the waker.
-                ret
-            },
-            |data| unsafe {
-                let data: ArcMutexFut = Arc::from_raw(data);
-                TASK_QUEUE.lock().push(data);
-                drop(data); // Drop the ArcMutexFut here: it is no longer retained by the waker.
-            },
-            |data| unsafe {
-                let data: ArcMutexFut = Arc::from_raw(data);
-                TASK_QUEUE.lock().push(data.clone());
-                forget(data); // Do NOT drop the ArcMutexFut here: this is still retained by the waker.
-            },
-            |data| unsafe {
-                let data: ArcMutexFut = Arc::from_raw(data);
-                drop(data); // We're dropping the ArcMutexFut to clean up.
-            },
-        )
+    let queued = TASK_QUEUE.with(|queue| queue.len());
+    for _ in 0..queued {
+        // Don't hold the queue lock while polling: the task may spawn or wake other tasks.
+        let Some(task) = TASK_QUEUE.with(|queue| queue.pop()) else {
+            break;
+        };
+        Task::run(task);
+    }
+}
+
+// The waker's data pointer is an `ArcTask` turned into a raw pointer, owning one reference.
+static VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_waker, wake_waker_by_ref, drop_waker);
+
+fn raw_waker(task: ArcTask) -> RawWaker {
+    RawWaker::new(task.to_raw(), &VTABLE)
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    // Borrow the waker's reference without giving it up.
+    let task = ManuallyDrop::new(ArcTask::from_raw(data));
+    raw_waker(ArcTask::clone(&task))
+}
+
+unsafe fn wake_waker(data: *const ()) {
+    Task::wake(ArcTask::from_raw(data));
+}
+
+unsafe fn wake_waker_by_ref(data: *const ()) {
+    let task = ManuallyDrop::new(ArcTask::from_raw(data));
+    Task::wake(ArcTask::clone(&task));
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(ArcTask::from_raw(data));
+}
+
+// Put a task in its queue. Only for tasks that have just been moved to `Queued`.
+fn enqueue(task: ArcTask) {
+    task.set_info(State::Queued);
+    let len = TASK_QUEUE.with(|queues| {
+        let queue = queues.for_task(&task);
+        sync::push_back_or_fatal(queue, task);
+        queue.len()
+    });
+    record_queue_len(len);
+    // The other core may be sleeping in wait_for_work(); make sure it notices.
+    cortex_m::asm::sev();
+}
+
+// Sleep until there may be tasks to poll.
+// Returns when a task is woken or spawned on either core, or on any interrupt.
+pub fn wait_for_work() {
+    if TASK_QUEUE.with(|queue| queue.is_empty()) {
+        // If a task was queued after the check, its SEV set the event register,
+        // so this returns immediately instead of missing it.
+        power::measure(SleepMode::Wfe, cortex_m::asm::wfe);
+    }
+}
+
+// Set when a core's `block_on` future is woken. Static rather than on `block_on`'s stack, so a
+// waker kept after it returned can't point at anything gone.
+static ROOT_WOKEN: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+static ROOT_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_root, wake_root, wake_root, drop_root);
+
+unsafe fn clone_root(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &ROOT_VTABLE)
+}
+
+unsafe fn wake_root(data: *const ()) {
+    (*(data as *const AtomicBool)).store(true, Ordering::Release);
+    cortex_m::asm::sev();
+}
+
+unsafe fn drop_root(_: *const ()) {}
+
+// Run `future` to completion on this core, polling this core's tasks (and shared ones) while
+// it waits, and sleeping when there's nothing to do. For `main`, or core 1's entry point; not
+// for inside a task.
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let mut future = pin!(future);
+    let woken = &ROOT_WOKEN[sync::core()];
+    woken.store(true, Ordering::Relaxed);
+    // Safety: The vtable functions treat the pointer as the `AtomicBool` it is, which is
+    // static.
+    let waker = unsafe {
+        Waker::from_raw(RawWaker::new(
+            woken as *const AtomicBool as *const (),
+            &ROOT_VTABLE,
+        ))
     };
-    let waker = RawWaker::new(future.clone().to_raw(), &vtable);
-    waker
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if woken.swap(false, Ordering::AcqRel) {
+            if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                return result;
+            }
+        }
+        tick();
+        // A wake after this check sets the event register, so the sleep doesn't miss it.
+        if !woken.load(Ordering::Acquire) {
+            wait_for_work();
+        }
+    }
+}
+
+// Hand this core over to the executor: run `future`, then keep polling tasks for good.
+//
+//     executor::run(async {
+//         executor::spawn(blink()).detach();
+//         serve().await
+//     })
+pub fn run(future: impl Future<Output = ()>) -> ! {
+    block_on(future);
+    loop {
+        tick();
+        wait_for_work();
+    }
+}
+
+// `future` must be `Send` unless `core` is set.
+fn spawn_inner(
+    name: &'static str,
+    poll_fn: usize,
+    core: Option<usize>,
+    future: impl Future<Output = ()> + 'static,
+) -> Result<ArcTask, AllocError> {
+    let future: BoxFuture<()> = Box::into_pin(Box::try_new(future)?);
+    let info = taskinfo::add(name, poll_fn);
+    let slot = SlotGuard(info);
+    let task = Arc::try_new(Task {
+        info,
+        core,
+        state: AtomicU8::new(TaskState::Queued as u8),
+        cancelled: AtomicBool::new(false),
+        future: UnsafeCell::new(Some(future)),
+    })?;
+    #[cfg(feature = "trace")]
+    crate::trace::spawned(task.trace_id(), name);
+    let len = TASK_QUEUE.with(|queues| {
+        let queue = queues.for_task(&task);
+        queue.try_reserve(1).map_err(|_| AllocError)?;
+        queue.push_back(task.clone());
+        Ok(queue.len())
+    })?;
+    slot.keep();
+    record_queue_len(len);
+    cortex_m::asm::sev();
+    Ok(task)
+}
+
+// Frees a task table slot if spawning fails after it was taken, whichever step fails.
+struct SlotGuard(Option<(usize, u32)>);
+
+impl SlotGuard {
+    // The task was spawned; the slot is its now.
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        if let Some((slot, id)) = self.0 {
+            taskinfo::set_state(slot, id, State::Free);
+        }
+    }
 }
 
-fn spawn_inner(task: impl Future<Output = ()> + Send + Sync + 'static) {
-    let mut queue = TASK_QUEUE.lock();
-    queue.push(Arc::new(Mutex::new(Box::pin(task))));
+// Address of F's poll function, for symbolizing in a debugger.
+fn poll_fn_of<F: Future>(_: &F) -> usize {
+    let poll: fn(Pin<&mut F>, &mut Context<'_>) -> Poll<F::Output> = F::poll;
+    poll as usize
 }
 
-// Spawn a task. The task will be ran to completion.
-// The returned future will complete when the task is completed.
-pub fn spawn<T>(task: impl Future<Output = T> + Send + Sync + 'static) -> impl Future<Output = T>
+// Spawn a task. The task will be ran to completion, on whichever core gets to it first.
+// The returned handle is a future that completes with the task's result. Dropping it leaves
+// the task running, unless `cancel_on_drop` was used.
+// The task shows up in the debugger task table under the name of its future's type.
+// Panics if the heap is exhausted; see `try_spawn`.
+pub fn spawn<T>(task: impl Future<Output = T> + Send + 'static) -> TaskHandle<T>
 where
-    T: Send + Sync,
+    T: Send + 'static,
 {
-    TaskHandle::new(task)
+    try_spawn(task).expect("out of memory spawning a task")
 }
 
-struct TaskHandle<T> {
-    waker: Arc<Mutex<Option<Waker>, 1>, 2>,
-    return_value: Arc<Mutex<Option<T>, 3>, 4>,
+// Like `spawn`, but with a name of your choosing in the debugger task table.
+pub fn spawn_named<T>(
+    name: &'static str,
+    task: impl Future<Output = T> + Send + 'static,
+) -> TaskHandle<T>
+where
+    T: Send + 'static,
+{
+    try_spawn_named(name, task).expect("out of memory spawning a task")
 }
 
-impl<T> TaskHandle<T>
+// Like `spawn`, but returns an error instead of panicking when the heap is exhausted.
+// The task is dropped without being polled in that case.
+pub fn try_spawn<T>(
+    task: impl Future<Output = T> + Send + 'static,
+) -> Result<TaskHandle<T>, AllocError>
 where
-    T: Send + Sync,
+    T: Send + 'static,
 {
-    fn new(task: impl Future<Output = T> + Send + Sync + 'static) -> Self {
-        let waker = Arc::new(Mutex::new(None));
-        let return_value = Arc::new(Mutex::new(None));
-        let ret = TaskHandle {
-            waker: waker.clone(),
-            return_value: return_value.clone(),
-        };
-        crate::executor::spawn_inner(async move {
-            let ret = task.await;
-            let mut return_value = return_value.lock();
-            *return_value = Some(ret);
-            let mut waker = waker.lock();
-            if let Some(waker) = waker.take() {
+    TaskHandle::try_new(type_name_of_val(&task), None, task)
+}
+
+// Like `spawn_named`, but returns an error instead of panicking when the heap is exhausted.
+pub fn try_spawn_named<T>(
+    name: &'static str,
+    task: impl Future<Output = T> + Send + 'static,
+) -> Result<TaskHandle<T>, AllocError>
+where
+    T: Send + 'static,
+{
+    TaskHandle::try_new(name, None, task)
+}
+
+// Spawn a task that stays on the calling core, so it doesn't have to be `Send`: it can hold
+// `Rc`s, `RefCell` borrows and the like across awaits. Only this core's `tick` polls it, so
+// this core has to be running an executor.
+// Panics if the heap is exhausted.
+pub fn spawn_local<T: 'static>(task: impl Future<Output = T> + 'static) -> TaskHandle<T> {
+    TaskHandle::try_new(type_name_of_val(&task), Some(sync::core()), task)
+        .expect("out of memory spawning a task")
+}
+
+// What the task and its handle share.
+struct Join<T> {
+    result: Option<T>,
+    // The handle's waker, if it's being awaited.
+    waker: Option<Waker>,
+}
+
+pub struct TaskHandle<T> {
+    task: ArcTask,
+    join: Arc<Mutex<Join<T>, 3>>,
+    cancel_on_drop: bool,
+}
+
+impl<T: 'static> TaskHandle<T> {
+    // `task` and `T` must be `Send` unless `core` is set.
+    fn try_new(
+        name: &'static str,
+        core: Option<usize>,
+        task: impl Future<Output = T> + 'static,
+    ) -> Result<Self, AllocError> {
+        let join = Arc::try_new(Mutex::new(Join {
+            result: None,
+            waker: None,
+        }))?;
+        let task_join = join.clone();
+        let poll_fn = poll_fn_of(&task);
+        let task = spawn_inner(name, poll_fn, core, async move {
+            let result = task.await;
+            let waker = task_join.with(|join| {
+                join.result = Some(result);
+                join.waker.take()
+            });
+            if let Some(waker) = waker {
                 waker.wake();
             }
-        });
-        TaskHandle {
-            waker,
-            return_value,
-        }
+        })?;
+        Ok(TaskHandle {
+            task,
+            join,
+            cancel_on_drop: false,
+        })
     }
 }
 
-impl<T> Future for TaskHandle<T>
-where
-    T: Send + Sync,
-{
+impl<T> TaskHandle<T> {
+    // Whether the task has finished, or been cancelled. The result may already have been
+    // taken by awaiting the handle.
+    pub fn is_finished(&self) -> bool {
+        self.task.state() == TaskState::Completed
+    }
+
+    // Cancel the task if the handle is dropped before it finishes: its future is dropped
+    // the next time its core gets to it, without being polled again.
+    pub fn cancel_on_drop(mut self) -> Self {
+        self.cancel_on_drop = true;
+        self
+    }
+
+    // Let the task carry on by itself, even if `cancel_on_drop` was used. Its result is
+    // dropped when it finishes.
+    pub fn detach(mut self) {
+        self.cancel_on_drop = false;
+    }
+}
+
+impl<T> Future for TaskHandle<T> {
     type Output = T;
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
-        let mut return_value = self.return_value.lock();
-        if let Some(return_value) = return_value.take() {
-            Poll::Ready(return_value)
-        } else {
-            let mut waker = self.waker.lock();
-            *waker = Some(cx.waker().clone());
-            Poll::Pending
+        self.join.with(|join| match join.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                join.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for TaskHandle<T> {
+    fn drop(&mut self) {
+        // Nobody's waiting any more, so don't keep whatever the waker holds alive.
+        let waker = self.join.with(|join| join.waker.take());
+        drop(waker);
+        if self.cancel_on_drop && !self.is_finished() {
+            Task::cancel(&self.task);
         }
     }
 }
+
+const IRQS: usize = 26;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub polls: u32,
+    pub wakes: u32,
+    // Longest the run queue has been.
+    pub queue_high_water: usize,
+    // Tasks woken by each interrupt during the last full second.
+    pub irq_wakes_per_sec: [u32; IRQS],
+    // Highest per-second count seen for each interrupt.
+    pub irq_wakes_per_sec_max: [u32; IRQS],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Warning {
+    QueueDepth(usize),
+    WakeStorm { irq: u8, wakes_per_sec: u32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Watermarks {
+    pub queue_len: usize,
+    pub irq_wakes_per_sec: u32,
+}
+
+struct Counters {
+    stats: Stats,
+    window_start: Option<Instant>,
+    window: [u32; IRQS],
+    watermarks: Option<(Watermarks, fn(Warning))>,
+}
+
+static STATS: Mutex<Counters, 17> = Mutex::new(Counters {
+    stats: Stats {
+        polls: 0,
+        wakes: 0,
+        queue_high_water: 0,
+        irq_wakes_per_sec: [0; IRQS],
+        irq_wakes_per_sec_max: [0; IRQS],
+    },
+    window_start: None,
+    window: [0; IRQS],
+    watermarks: None,
+});
+
+pub fn stats() -> Stats {
+    STATS.with(|counters| {
+        roll_window(counters);
+        counters.stats
+    })
+}
+
+// Call `warn` whenever the run queue gets longer than `watermarks.queue_len`, or an interrupt
+// wakes more than `watermarks.irq_wakes_per_sec` tasks in a second.
+// `warn` may be called from interrupt handlers, so keep it short.
+pub fn set_watermarks(watermarks: Watermarks, warn: fn(Warning)) {
+    STATS.with(|counters| counters.watermarks = Some((watermarks, warn)));
+}
+
+fn record_queue_len(len: usize) {
+    let warning = STATS.with(|counters| {
+        counters.stats.wakes = counters.stats.wakes.wrapping_add(1);
+        counters.stats.queue_high_water = counters.stats.queue_high_water.max(len);
+        match counters.watermarks {
+            Some((watermarks, warn)) if len > watermarks.queue_len => {
+                Some((warn, Warning::QueueDepth(len)))
+            }
+            _ => None,
+        }
+    });
+    // Don't hold the lock while calling out.
+    if let Some((warn, warning)) = warning {
+        warn(warning);
+    }
+}
+
+// Called by the reactor after an interrupt has woken `wakes` tasks.
+pub(crate) fn record_irq_wakes(irq: usize, wakes: usize) {
+    let warning = STATS.with(|counters| {
+        roll_window(counters);
+        let count = counters.window.get_mut(irq)?;
+        *count = count.saturating_add(wakes as u32);
+        let count = *count;
+        match counters.watermarks {
+            // Only warn once per window, when the threshold is first crossed.
+            Some((watermarks, warn))
+                if count > watermarks.irq_wakes_per_sec
+                    && count - (wakes as u32) <= watermarks.irq_wakes_per_sec =>
+            {
+                Some((
+                    warn,
+                    Warning::WakeStorm {
+                        irq: irq as u8,
+                        wakes_per_sec: count,
+                    },
+                ))
+            }
+            _ => None,
+        }
+    });
+    if let Some((warn, warning)) = warning {
+        warn(warning);
+    }
+}
+
+fn roll_window(counters: &mut Counters) {
+    let now = Instant::now();
+    let start = *counters.window_start.get_or_insert(now);
+    if (now - start).as_secs() < 1 {
+        return;
+    }
+    // If more than one window has passed, the counts were spread over that much time;
+    // reporting them as one second's worth would overstate the rate.
+    if (now - start).as_secs() > 1 {
+        counters.window = [0; IRQS];
+    }
+    let stats = &mut counters.stats;
+    for ((&count, rate), max) in counters
+        .window
+        .iter()
+        .zip(&mut stats.irq_wakes_per_sec)
+        .zip(&mut stats.irq_wakes_per_sec_max)
+    {
+        *rate = count;
+        *max = (*max).max(count);
+    }
+    counters.window = [0; IRQS];
+    counters.window_start = Some(now);
+}
+
+// Only what runs without the hardware: a task that isn't `Idle` is never put in a queue, so
+// these don't reach the queue lock or SEV.
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    // Sets its flag when dropped, so tests can see when the task's future goes.
+    struct Dropped(Rc<Cell<bool>>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    fn task(state: TaskState, dropped: &Rc<Cell<bool>>) -> ArcTask {
+        let guard = Dropped(dropped.clone());
+        Arc::new(Task {
+            info: None,
+            core: None,
+            state: AtomicU8::new(state as u8),
+            cancelled: AtomicBool::new(false),
+            future: UnsafeCell::new(Some(Box::pin(async move {
+                let _guard = guard;
+            }))),
+        })
+    }
+
+    #[test]
+    fn wake_while_running_queues_once() {
+        let dropped = Rc::new(Cell::new(false));
+        let task = task(TaskState::Running, &dropped);
+        let waker = Task::waker(&task);
+        waker.wake_by_ref();
+        assert_eq!(task.state(), TaskState::Queued);
+        // Already queued: nothing more to do.
+        waker.wake_by_ref();
+        assert_eq!(task.state(), TaskState::Queued);
+    }
+
+    #[test]
+    fn wake_after_completion_is_ignored() {
+        let dropped = Rc::new(Cell::new(false));
+        let task = task(TaskState::Completed, &dropped);
+        Task::waker(&task).wake();
+        assert_eq!(task.state(), TaskState::Completed);
+    }
+
+    #[test]
+    fn cancel_sets_the_flag_and_wakes() {
+        let dropped = Rc::new(Cell::new(false));
+        let task = task(TaskState::Running, &dropped);
+        Task::cancel(&task);
+        assert!(task.cancelled.load(Ordering::Acquire));
+        assert_eq!(task.state(), TaskState::Queued);
+    }
+
+    #[test]
+    fn wakers_hold_the_task() {
+        let dropped = Rc::new(Cell::new(false));
+        let task = task(TaskState::Running, &dropped);
+        let waker = Task::waker(&task);
+        let clone = waker.clone();
+        drop(task);
+        drop(waker);
+        assert!(!dropped.get());
+        // Waking by value gives up the waker's reference too.
+        clone.wake();
+        assert!(dropped.get());
+    }
+}
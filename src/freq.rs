@@ -0,0 +1,123 @@
+// Frequency and duty cycle of a digital input, measured with a PWM slice's counter: fan
+// tachometers, flow meters, and the like.
+//
+// Only PWM B pins (odd GPIOs) can drive a slice's counter, and the counter only has 16 bits,
+// so it's read every so often during the window and the differences added up.
+
+use core::time::Duration;
+
+use crate::{
+    resets,
+    time::{self, Instant},
+};
+
+// Longest the counter is left alone before the first reading, and the most it should count
+// between readings after that, so it can never wrap twice unseen.
+const FIRST_READ: Duration = Duration::from_millis(1);
+const MAX_COUNTS_PER_READ: u32 = 0x8000;
+// Clock divider in duty cycle mode, so the counter doesn't wrap too quickly.
+const DUTY_DIVIDER: u32 = 250;
+
+pub struct Counter {
+    pin: u8,
+    slice: usize,
+    sys_hz: u32,
+}
+
+enum Mode {
+    // Count rising edges on the B pin.
+    Edges,
+    // Count clk_sys / DUTY_DIVIDER cycles while the B pin is high.
+    High,
+}
+
+impl Counter {
+    // Measure on `pin`, which must be odd. `sys_hz` is the clk_sys frequency, only needed
+    // for duty cycles.
+    pub fn new(pin: u8, sys_hz: u32) -> Self {
+        assert!(
+            pin % 2 == 1 && pin < 30,
+            "frequency input must be a PWM B pin"
+        );
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        const PWM: u32 = 1 << 14;
+        resets::bring_up(PWM);
+        io.gpio[pin as usize]
+            .gpio_ctrl
+            .write(|w| unsafe { w.bits(4) }); // FUNCSEL = PWM
+        Counter {
+            pin,
+            slice: (pin as usize / 2) % 8,
+            sys_hz,
+        }
+    }
+
+    fn start(&mut self, mode: Mode) {
+        let ch = &unsafe { &*rp2040_pac::PWM::ptr() }.ch[self.slice];
+        let (divmode, divider) = match mode {
+            Mode::Edges => (2, 1),
+            Mode::High => (1, DUTY_DIVIDER),
+        };
+        ch.csr.write(|w| unsafe { w.bits(0) });
+        ch.div.write(|w| unsafe { w.bits(divider << 4) });
+        ch.top.write(|w| unsafe { w.bits(0xffff) });
+        ch.ctr.write(|w| unsafe { w.bits(0) });
+        ch.csr.write(|w| unsafe { w.bits(divmode << 4 | 1) });
+    }
+
+    fn count(&self) -> u16 {
+        let ch = &unsafe { &*rp2040_pac::PWM::ptr() }.ch[self.slice];
+        ch.ctr.read().bits() as u16
+    }
+
+    fn stop(&mut self) {
+        let ch = &unsafe { &*rp2040_pac::PWM::ptr() }.ch[self.slice];
+        ch.csr.write(|w| unsafe { w.bits(0) });
+    }
+
+    // Count for `window`, returning the total and how long it actually took.
+    async fn gather(&mut self, mode: Mode, window: Duration) -> (u64, Duration) {
+        self.start(mode);
+        let start = Instant::now();
+        let end = start + window;
+        let mut total = 0u64;
+        let mut last = 0u16;
+        let mut next = start + FIRST_READ.min(window);
+        loop {
+            time::sleep_until(next).await;
+            let now = Instant::now();
+            let count = self.count();
+            let delta = count.wrapping_sub(last) as u64;
+            total += delta;
+            last = count;
+            if now >= end {
+                self.stop();
+                return (total, now - start);
+            }
+            // Go as long as possible without risking a double wrap, at the rate seen so far.
+            let elapsed_us = (now - start).as_micros().max(1) as u64;
+            let rate = total.max(1) * 1_000_000 / elapsed_us;
+            let wait_us = MAX_COUNTS_PER_READ as u64 * 1_000_000 / rate.max(1);
+            next = end.min(now + Duration::from_micros(wait_us.max(100)));
+        }
+    }
+
+    // The input frequency in Hz, counted over `window`. Longer windows are more precise.
+    pub async fn measure(&mut self, window: Duration) -> u32 {
+        let (edges, elapsed) = self.gather(Mode::Edges, window).await;
+        (edges * 1_000_000 / elapsed.as_micros().max(1) as u64) as u32
+    }
+
+    // The fraction of `window` the input spent high, in parts per million.
+    pub async fn measure_duty(&mut self, window: Duration) -> u32 {
+        let (high, elapsed) = self.gather(Mode::High, window).await;
+        let total =
+            self.sys_hz as u64 / DUTY_DIVIDER as u64 * elapsed.as_micros() as u64 / 1_000_000;
+        (high * 1_000_000 / total.max(1)).min(1_000_000) as u32
+    }
+
+    pub fn release(mut self) -> u8 {
+        self.stop();
+        self.pin
+    }
+}
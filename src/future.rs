@@ -0,0 +1,79 @@
+// Building blocks for drivers that write their own futures against the reactor.
+//
+// Most waits on a peripheral look the same: see if the thing has happened, and if not,
+// register for the interrupt and look again. The second look covers the event landing
+// between the first look and the registration, which for a status bit that something else
+// clears (rather than a level-triggered interrupt that's still asserted when it's unmasked)
+// would otherwise be missed until the next one. `wait_irq` is that, so drivers don't each
+// write it out.
+//
+//     let byte = future::wait_irq(&self.irq, || match self.read_byte() {
+//         Some(byte) => Poll::Ready(byte),
+//         None => Poll::Pending,
+//     })
+//     .await;
+
+use core::{
+    future::Future,
+    pin::{pin, Pin},
+    task::{Context, Poll},
+};
+
+use crate::irq::{Irq, Line};
+
+pub use core::{future::poll_fn, task::ready};
+
+// Wait for `check` to be ready, looking again every time `irq` fires. `check` runs in the
+// task, not the handler, so it can touch whatever the driver likes.
+pub async fn wait_irq<L: Line, T>(irq: &Irq<L>, mut check: impl FnMut() -> Poll<T>) -> T {
+    poll_fn(|cx| {
+        if let Poll::Ready(value) = check() {
+            return Poll::Ready(value);
+        }
+        irq.register(cx.waker());
+        check()
+    })
+    .await
+}
+
+// Run `future` until it finishes or `timeout` does, whichever is first. For timeouts that
+// aren't a `time::sleep`, like a generic `DelayNs`; otherwise `time::timeout` says what it
+// means.
+pub async fn with_timeout<T>(
+    timeout: impl Future<Output = ()>,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    let mut timeout = pin!(timeout);
+    let mut future = pin!(future);
+    poll_fn(|cx| {
+        if let Poll::Ready(value) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(value));
+        }
+        timeout.as_mut().poll(cx).map(|()| None)
+    })
+    .await
+}
+
+// Pending the first time it's polled, and ready the next. It wakes itself first, so the task
+// goes to the back of the queue and everything else gets a turn: for long loops that
+// shouldn't hog the executor.
+pub fn pending_once() -> PendingOnce {
+    PendingOnce { polled: false }
+}
+
+pub struct PendingOnce {
+    polled: bool,
+}
+
+impl Future for PendingOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.polled {
+            return Poll::Ready(());
+        }
+        self.polled = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
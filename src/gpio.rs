@@ -0,0 +1,224 @@
+// Hardware-timed pulses on a GPIO: camera triggers, HC-SR04 trigger pulses, test equipment.
+// Edge timestamping is in `capture`, and inputs that tasks can wait on in `input`.
+//
+// A PIO state machine does the timing, so pulse widths are exact to a clk_sys cycle no matter
+// what the CPU is doing. A train is three words into the TX FIFO: the count (minus one), the
+// high time and the low time, both in delay loop iterations. The state machine raises an IRQ
+// flag once the last pulse is done, which is what the futures wait for.
+
+use core::time::Duration;
+
+use crate::{
+    clocks,
+    pio::{self, instr, Program, StateMachine},
+};
+
+pub mod capture;
+pub mod input;
+
+pub use capture::Capture;
+pub use input::{Input, Pull};
+
+const PROGRAM: [u16; 13] = [
+    instr::PULL_BLOCK,
+    instr::MOV_X_OSR,
+    instr::PULL_BLOCK,
+    instr::MOV_ISR_OSR,
+    instr::PULL_BLOCK,
+    // pulse:
+    instr::set_pins(1),
+    instr::MOV_Y_ISR,
+    instr::jmp_y_dec(7),
+    instr::set_pins(0),
+    instr::MOV_Y_OSR,
+    instr::jmp_y_dec(10),
+    instr::jmp_x_dec(5),
+    instr::irq_set_rel(0),
+];
+const DONE: u8 = 12;
+// Cycles spent outside the delay loops: the high time is the loop count plus 3, the low time
+// the loop count plus 4.
+const HIGH_OVERHEAD: u64 = 3;
+const LOW_OVERHEAD: u64 = 4;
+
+// A number of pulses of the same width, evenly spaced. Built up from a single pulse:
+//
+//     PulseTrain::new(Duration::from_micros(10)).count(8).gap(Duration::from_micros(90))
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PulseTrain {
+    width: Duration,
+    gap: Duration,
+    count: u32,
+}
+
+impl PulseTrain {
+    // A single pulse `width` long.
+    pub const fn new(width: Duration) -> Self {
+        PulseTrain {
+            width,
+            gap: Duration::ZERO,
+            count: 1,
+        }
+    }
+
+    // Send `count` pulses. A count of 0 sends nothing.
+    pub const fn count(self, count: u32) -> Self {
+        PulseTrain { count, ..self }
+    }
+
+    // Time the pin is low between pulses.
+    pub const fn gap(self, gap: Duration) -> Self {
+        PulseTrain { gap, ..self }
+    }
+
+    // Start a pulse every `period` instead of setting the gap directly. Saturates to no gap if
+    // `period` is shorter than the width.
+    pub const fn period(self, period: Duration) -> Self {
+        let gap = match period.checked_sub(self.width) {
+            Some(gap) => gap,
+            None => Duration::ZERO,
+        };
+        PulseTrain { gap, ..self }
+    }
+
+    // How long the whole train takes, from the first rising edge to the last falling one.
+    pub fn duration(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => self.width * n + self.gap * (n - 1),
+        }
+    }
+}
+
+// Couldn't find a PIO block with a free state machine and room for the program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unavailable;
+
+// Send one pulse `width` long on `pin`, borrowing a PIO state machine for the duration.
+pub async fn pulse(pin: u8, width: Duration) -> Result<(), Unavailable> {
+    pulse_train(pin, &PulseTrain::new(width)).await
+}
+
+// Send `train` on `pin`, borrowing a PIO state machine for the duration.
+pub async fn pulse_train(pin: u8, train: &PulseTrain) -> Result<(), Unavailable> {
+    let mut pulser = (0..2)
+        .find_map(|block| Pulser::new(block, pin))
+        .ok_or(Unavailable)?;
+    pulser.train(train).await;
+    Ok(())
+}
+
+// A pin kept set up for pulses, for when they're frequent enough that claiming a state machine
+// each time is wasteful. The pin idles low, or high if `set_active_low` is used.
+pub struct Pulser {
+    sm: StateMachine,
+    program: Program,
+    pin: u8,
+}
+
+impl Pulser {
+    // Returns `None` if `block` has no free state machine or not enough instruction memory.
+    pub fn new(block: u8, pin: u8) -> Option<Self> {
+        let mut sm = pio::claim(block)?;
+        let program = pio::load(block, &PROGRAM)?;
+        pio::use_pin(block, pin);
+        sm.configure(
+            &program,
+            &pio::Config {
+                wrap: DONE,
+                set_base: pin,
+                set_count: 1,
+                ..Default::default()
+            },
+        );
+        sm.set_pindirs(pin, 1, true);
+        sm.exec(instr::set_pins(0));
+        sm.set_enabled(true);
+        Some(Pulser { sm, program, pin })
+    }
+
+    // Invert the pin, so it idles high and pulses go low.
+    pub fn set_active_low(&mut self, active_low: bool) {
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        // OUTOVER: 1 inverts the peripheral's output.
+        io.gpio[self.pin as usize]
+            .gpio_ctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(3 << 8) | (active_low as u32) << 8) });
+    }
+
+    pub async fn pulse(&mut self, width: Duration) {
+        self.train(&PulseTrain::new(width)).await
+    }
+
+    // Send `train` and wait for the last pulse to finish. The timing is rounded to the nearest
+    // clk_sys cycle, and can't go below 3 cycles high or 4 low. Dropping the future stops the
+    // train at once and leaves the pin idle.
+    //
+    // Panics if a width or gap doesn't fit the state machine's 32 bit counters.
+    pub async fn train(&mut self, train: &PulseTrain) {
+        if train.count == 0 {
+            return;
+        }
+        let sys_hz = clocks::sys_hz() as u64;
+        let loops = |duration: Duration, overhead: u64| -> u32 {
+            let cycles = (duration.as_nanos() * sys_hz as u128 + 500_000_000) / 1_000_000_000;
+            let loops = (cycles as u64).saturating_sub(overhead);
+            loops.try_into().expect("pulse timing out of range")
+        };
+        let high = loops(train.width, HIGH_OVERHEAD);
+        let low = loops(train.gap, LOW_OVERHEAD);
+
+        let mut in_flight = InFlight {
+            pulser: self,
+            finished: false,
+        };
+        // The FIFO is empty between trains, so these never wait.
+        let sm = &mut in_flight.pulser.sm;
+        sm.push(train.count - 1).await;
+        sm.push(high).await;
+        sm.push(low).await;
+        let flag = sm.index();
+        sm.wait_irq(flag).await;
+        in_flight.finished = true;
+    }
+
+    // Stop whatever's being sent and go back to waiting for a train.
+    fn abort(&mut self) {
+        let sm = &mut self.sm;
+        sm.set_enabled(false);
+        sm.exec(instr::set_pins(0));
+        sm.clear_fifos();
+        sm.restart();
+        sm.exec(instr::jmp(self.program.offset()));
+        // The flag may have been set just before we stopped it.
+        pio::clear_irq(sm.block(), sm.index());
+        sm.set_enabled(true);
+    }
+
+    // Give back the state machine and instruction memory. Dropping the `Pulser` does the same.
+    pub fn release(self) -> u8 {
+        self.pin
+    }
+}
+
+impl Drop for Pulser {
+    fn drop(&mut self) {
+        self.sm.set_enabled(false);
+        pio::unload(self.program);
+        self.set_active_low(false);
+    }
+}
+
+// A train that's been started. If it's dropped before finishing, the train is aborted.
+struct InFlight<'a> {
+    pulser: &'a mut Pulser,
+    finished: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.pulser.abort();
+        }
+    }
+}
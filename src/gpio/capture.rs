@@ -0,0 +1,244 @@
+// Input capture: TIMER timestamps for GPIO edges, taken in the interrupt handler before any
+// task gets to run, so protocol decoders and tachometers see when the edge happened rather
+// than when someone got around to looking.
+//
+// Each `Capture` is a bounded queue that one or more pins feed. It has to be a static, since
+// the handler keeps a reference to it for as long as a pin is listening.
+
+use core::task::{Context, Poll};
+
+use cortex_m::peripheral::NVIC;
+use rp2040_pac::Interrupt;
+
+use crate::{
+    atomic::{AtomicU32, Ordering},
+    irq, reactor,
+    stream::Stream,
+    sync::{self, Channel, Mutex},
+    time::Instant,
+};
+
+const QUEUE: usize = 32;
+pub(super) const PINS: usize = 30;
+// Per pin in INTR and the INTE/INTS registers.
+pub(super) const LEVEL_LOW: u32 = 1 << 0;
+pub(super) const LEVEL_HIGH: u32 = 1 << 1;
+pub(super) const EDGE_LOW: u32 = 1 << 2;
+pub(super) const EDGE_HIGH: u32 = 1 << 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edges {
+    Rising,
+    Falling,
+    Both,
+}
+
+impl Edges {
+    fn bits(self) -> u32 {
+        match self {
+            Edges::Rising => EDGE_HIGH,
+            Edges::Falling => EDGE_LOW,
+            Edges::Both => EDGE_HIGH | EDGE_LOW,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub pin: u8,
+    pub edge: Edge,
+    pub at: Instant,
+}
+
+pub struct Capture {
+    events: Channel<Event, QUEUE>,
+    dropped: AtomicU32,
+}
+
+struct Listeners {
+    // Whether the handler is installed on each core.
+    installed: [bool; 2],
+    pins: [Option<&'static Capture>; PINS],
+    // Pins that are an `Input`, one bit each.
+    inputs: u32,
+}
+
+static LISTENERS: Mutex<Listeners, 30> = Mutex::new(Listeners {
+    installed: [false; 2],
+    pins: [None; PINS],
+    inputs: 0,
+});
+
+impl Capture {
+    pub const fn new() -> Self {
+        Capture {
+            events: Channel::new(),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    // Start timestamping `edges` on `pin`, which must already be set up as an input. The
+    // interrupt is taken on the calling core.
+    //
+    // Panics if another `Capture` is listening to `pin`, or it's an `Input`.
+    pub fn listen(&'static self, pin: u8, edges: Edges) {
+        let core = sync::core();
+        LISTENERS.with(|listeners| {
+            assert!(listeners.inputs & 1 << pin == 0, "pin is an Input");
+            let listener = &mut listeners.pins[pin as usize];
+            assert!(
+                listener.is_none_or(|other| core::ptr::eq(other, self)),
+                "pin is already being captured"
+            );
+            *listener = Some(self);
+            install_on(listeners, core);
+            // Don't report an edge from before we started listening.
+            clear(pin, EDGE_HIGH | EDGE_LOW);
+            set_enabled(core, pin, EDGE_HIGH | EDGE_LOW, false);
+            set_enabled(core, pin, edges.bits(), true);
+        })
+    }
+
+    // Stop timestamping `pin`. Events already in the queue stay there.
+    pub fn unlisten(&self, pin: u8) {
+        LISTENERS.with(|listeners| {
+            let listener = &mut listeners.pins[pin as usize];
+            if listener.is_some_and(|other| core::ptr::eq(other, self)) {
+                *listener = None;
+                for core in 0..2 {
+                    set_enabled(core, pin, EDGE_HIGH | EDGE_LOW, false);
+                }
+            }
+        })
+    }
+
+    // The next edge, waiting for one if there aren't any queued.
+    pub async fn next(&self) -> Event {
+        self.events.recv().await
+    }
+
+    pub fn try_next(&self) -> Option<Event> {
+        self.events.try_recv()
+    }
+
+    // Throw away everything queued, say before starting to decode a new frame.
+    pub fn clear(&self) {
+        while self.events.try_recv().is_some() {}
+    }
+
+    // Edges that happened while the queue was full, since the last call.
+    pub fn take_dropped(&self) -> u32 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+// Captures never end.
+impl Stream for &Capture {
+    type Item = Event;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.events.poll_recv(cx).map(Some)
+    }
+}
+
+// Mark `pin` as an `Input`. Panics if it already is one, or it's being captured.
+pub(super) fn claim_input(pin: u8) {
+    LISTENERS.with(|listeners| {
+        assert!(listeners.inputs & 1 << pin == 0, "pin is already an Input");
+        assert!(
+            listeners.pins[pin as usize].is_none(),
+            "pin is being captured"
+        );
+        listeners.inputs |= 1 << pin;
+    })
+}
+
+pub(super) fn release_input(pin: u8) {
+    LISTENERS.with(|listeners| listeners.inputs &= !(1 << pin))
+}
+
+// Put the IO_IRQ_BANK0 handler on the calling core, if it isn't there already. `Input` uses it
+// too.
+pub(super) fn install() {
+    LISTENERS.with(|listeners| install_on(listeners, sync::core()))
+}
+
+fn install_on(listeners: &mut Listeners, core: usize) {
+    if !listeners.installed[core] {
+        listeners.installed[core] = true;
+        irq::reserve(Interrupt::IO_IRQ_BANK0);
+        reactor::set_raw_handler(Interrupt::IO_IRQ_BANK0, handler);
+        // Safety: The handler is installed, and only touches LISTENERS and the input waiters.
+        unsafe { NVIC::unmask(Interrupt::IO_IRQ_BANK0) };
+    }
+}
+
+extern "C" fn handler() {
+    // Before anything else, so the timestamp is as close to the edge as it can be.
+    let at = Instant::now();
+    let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+    let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+    let core = sync::core();
+    LISTENERS.with(|listeners| {
+        for reg in 0..PINS.div_ceil(8) {
+            let pending = match core {
+                0 => io.proc0_ints[reg].read().bits(),
+                _ => io.proc1_ints[reg].read().bits(),
+            };
+            if pending == 0 {
+                continue;
+            }
+            io.intr[reg].write(|w| unsafe { w.bits(pending) });
+            let levels = sio.gpio_in.read().bits();
+            for pin in reg * 8..(reg * 8 + 8).min(PINS) {
+                let bits = pending >> (pin % 8 * 4);
+                let (rising, falling) = (bits & EDGE_HIGH != 0, bits & EDGE_LOW != 0);
+                let Some(capture) = listeners.pins[pin] else {
+                    continue;
+                };
+                // Both edges since the last time round: the pin's level now says which came
+                // first. They share a timestamp.
+                let high = levels & 1 << pin != 0;
+                let order = if high {
+                    [(falling, Edge::Falling), (rising, Edge::Rising)]
+                } else {
+                    [(rising, Edge::Rising), (falling, Edge::Falling)]
+                };
+                for (happened, edge) in order {
+                    if !happened {
+                        continue;
+                    }
+                    let event = Event {
+                        pin: pin as u8,
+                        edge,
+                        at,
+                    };
+                    if capture.events.try_send(event).is_err() {
+                        capture.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            super::input::on_interrupt(core, reg, pending);
+        }
+    })
+}
+
+pub(super) fn clear(pin: u8, bits: u32) {
+    let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+    io.intr[pin as usize / 8].write(|w| unsafe { w.bits(bits << (pin % 8 * 4)) });
+}
+
+pub(super) fn set_enabled(core: usize, pin: u8, bits: u32, enabled: bool) {
+    let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+    let (reg, bits) = (pin as usize / 8, bits << (pin % 8 * 4));
+    let update = |r: u32| if enabled { r | bits } else { r & !bits };
+    match core {
+        0 => io.proc0_inte[reg].modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        _ => io.proc1_inte[reg].modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+    }
+}
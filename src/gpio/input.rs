@@ -0,0 +1,246 @@
+// A GPIO input that tasks can wait on: for a level, or for an edge, without polling.
+//
+//     let mut button = Input::new(14, Pull::Up);
+//     loop {
+//         button.wait_for_falling_edge().await;
+//         ...
+//     }
+//
+// Waits arm the pin's interrupt on the calling core and are woken by the IO_IRQ_BANK0 handler
+// that `Capture` installs, which wakes only the task waiting on that pin for that event, rather
+// than everything waiting on the bank. A pin is either waited on here or captured, not both,
+// and only one `Input` can have it at a time; dropping the `Input` frees it.
+//
+// An edge wait only counts edges after it starts. A level wait returns at once if the pin is
+// already there.
+
+use core::{
+    convert::Infallible,
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use super::capture::{self, EDGE_HIGH, EDGE_LOW, LEVEL_HIGH, LEVEL_LOW, PINS};
+use crate::sync::{self, Mutex};
+
+// The four interrupt events a pin has, in the order of their bits.
+const EVENTS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+struct Waiter {
+    armed: bool,
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+const IDLE: Waiter = Waiter {
+    armed: false,
+    fired: false,
+    waker: None,
+};
+
+const PIN_IDLE: [Waiter; EVENTS] = [IDLE; EVENTS];
+
+static WAITERS: Mutex<[[Waiter; EVENTS]; PINS]> = Mutex::new([PIN_IDLE; PINS]);
+
+pub struct Input {
+    pin: u8,
+}
+
+impl Input {
+    // Make `pin` a plain SIO input with `pull`. Panics if `pin` is already an `Input` or is
+    // being captured.
+    pub fn new(pin: u8, pull: Pull) -> Self {
+        assert!((pin as usize) < PINS, "no such GPIO");
+        capture::claim_input(pin);
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+        let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+        let pulls = match pull {
+            Pull::None => 0,
+            Pull::Up => 1 << 3,
+            Pull::Down => 1 << 2,
+        };
+        // IE set, OD clear, and the pulls.
+        pads.gpio[pin as usize]
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 7 | 3 << 2) | 1 << 6 | pulls) });
+        sio.gpio_oe_clr.write(|w| unsafe { w.bits(1 << pin) });
+        io.gpio[pin as usize]
+            .gpio_ctrl
+            .write(|w| unsafe { w.bits(5) }); // FUNCSEL = SIO
+        Input { pin }
+    }
+
+    pub fn is_high(&self) -> bool {
+        let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+        sio.gpio_in.read().bits() & 1 << self.pin != 0
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    pub async fn wait_for_high(&mut self) {
+        if !self.is_high() {
+            self.wait_for(LEVEL_HIGH).await
+        }
+    }
+
+    pub async fn wait_for_low(&mut self) {
+        if !self.is_low() {
+            self.wait_for(LEVEL_LOW).await
+        }
+    }
+
+    pub async fn wait_for_rising_edge(&mut self) {
+        self.wait_for(EDGE_HIGH).await
+    }
+
+    pub async fn wait_for_falling_edge(&mut self) {
+        self.wait_for(EDGE_LOW).await
+    }
+
+    pub async fn wait_for_any_edge(&mut self) {
+        self.wait_for(EDGE_HIGH | EDGE_LOW).await
+    }
+
+    pub fn release(self) -> u8 {
+        self.pin
+    }
+
+    // Arm `events`, and wait for the handler to see any one of them.
+    async fn wait_for(&mut self, events: u32) {
+        let pin = self.pin;
+        let _armed = Armed { pin, events };
+        capture::install();
+        let core = sync::core();
+        WAITERS.with(|waiters| {
+            for (i, waiter) in waiters[pin as usize].iter_mut().enumerate() {
+                if events & 1 << i != 0 {
+                    *waiter = Waiter {
+                        armed: true,
+                        ..IDLE
+                    };
+                }
+            }
+            // Edges from before the wait don't count.
+            capture::clear(pin, events & (EDGE_HIGH | EDGE_LOW));
+            capture::set_enabled(core, pin, events, true);
+        });
+        poll_fn(|cx| {
+            WAITERS.with(|waiters| {
+                let mut fired = false;
+                for (i, waiter) in waiters[pin as usize].iter_mut().enumerate() {
+                    if events & 1 << i != 0 {
+                        fired |= waiter.fired;
+                        waiter.waker = Some(cx.waker().clone());
+                    }
+                }
+                if fired {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        capture::release_input(self.pin);
+    }
+}
+
+// Disarms a wait when it finishes or is dropped.
+struct Armed {
+    pin: u8,
+    events: u32,
+}
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        WAITERS.with(|waiters| {
+            for core in 0..2 {
+                capture::set_enabled(core, self.pin, self.events, false);
+            }
+            for (i, waiter) in waiters[self.pin as usize].iter_mut().enumerate() {
+                if self.events & 1 << i != 0 {
+                    *waiter = IDLE;
+                }
+            }
+        })
+    }
+}
+
+// Called by the IO_IRQ_BANK0 handler with the pending bits of one INTS register. Level
+// interrupts stay asserted for as long as the level lasts, so whatever fired is disabled until
+// the next wait arms it again.
+pub(super) fn on_interrupt(core: usize, reg: usize, pending: u32) {
+    let mut woken: [Option<Waker>; 8 * EVENTS] = [const { None }; 8 * EVENTS];
+    WAITERS.with(|waiters| {
+        for pin in reg * 8..(reg * 8 + 8).min(PINS) {
+            let bits = pending >> (pin % 8 * 4) & 0xf;
+            for (i, waiter) in waiters[pin].iter_mut().enumerate() {
+                if bits & 1 << i == 0 || !waiter.armed {
+                    continue;
+                }
+                capture::set_enabled(core, pin as u8, 1 << i, false);
+                waiter.armed = false;
+                waiter.fired = true;
+                woken[pin % 8 * EVENTS + i] = waiter.waker.take();
+            }
+        }
+    });
+    for waker in woken.into_iter().flatten() {
+        waker.wake();
+    }
+}
+
+impl embedded_hal::digital::ErrorType for Input {
+    type Error = Infallible;
+}
+
+impl embedded_hal::digital::InputPin for Input {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(Input::is_high(self))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(Input::is_low(self))
+    }
+}
+
+impl embedded_hal_async::digital::Wait for Input {
+    async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+        Input::wait_for_high(self).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+        Input::wait_for_low(self).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+        Input::wait_for_rising_edge(self).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+        Input::wait_for_falling_edge(self).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+        Input::wait_for_any_edge(self).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,428 @@
+// GPS receivers: NMEA parsing from any async byte stream, and PPS-disciplined time.
+//
+// Positions are fixed point (degrees * 10^7) since the M0+ has no FPU.
+
+use embedded_hal_async::digital::Wait;
+use embedded_io_async::Read;
+
+use crate::time::Instant;
+
+// NMEA 0183 caps sentences at 82 characters including the CR LF.
+const MAX_SENTENCE: usize = 82;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Read(E),
+    // The stream ended.
+    Eof,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+// GGA: fix data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fix {
+    pub time: Time,
+    pub latitude: i32,
+    pub longitude: i32,
+    // 0 = no fix, 1 = GPS, 2 = DGPS, ...
+    pub quality: u8,
+    pub satellites: u8,
+    pub altitude_mm: Option<i32>,
+}
+
+// RMC: recommended minimum data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rmc {
+    pub time: Time,
+    pub date: Date,
+    pub valid: bool,
+    pub latitude: i32,
+    pub longitude: i32,
+    // Knots * 1000.
+    pub speed_mknots: u32,
+    // Degrees * 100.
+    pub course_cdeg: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Sentence {
+    Gga(Fix),
+    Rmc(Rmc),
+}
+
+// Reads NMEA sentences from a GPS module's serial output.
+pub struct Gps<R> {
+    reader: R,
+    line: [u8; MAX_SENTENCE],
+    len: usize,
+    rx: [u8; 32],
+    rx_pos: usize,
+    rx_len: usize,
+}
+
+impl<R: Read> Gps<R> {
+    pub fn new(reader: R) -> Self {
+        Gps {
+            reader,
+            line: [0; MAX_SENTENCE],
+            len: 0,
+            rx: [0; 32],
+            rx_pos: 0,
+            rx_len: 0,
+        }
+    }
+
+    // Wait for the next GGA or RMC sentence with a valid checksum.
+    // Other sentence types and garbled lines are skipped.
+    pub async fn next_sentence(&mut self) -> Result<Sentence, Error<R::Error>> {
+        loop {
+            let byte = self.next_byte().await?;
+            match byte {
+                b'$' => self.len = 0,
+                b'\r' | b'\n' => {
+                    let len = core::mem::take(&mut self.len);
+                    if let Some(sentence) = parse(&self.line[..len]) {
+                        return Ok(sentence);
+                    }
+                }
+                _ if self.len < MAX_SENTENCE => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                }
+                // Too long to be NMEA; drop it and wait for the next '$'.
+                _ => self.len = 0,
+            }
+        }
+    }
+
+    // Wait for the next GGA sentence that reports a position fix.
+    pub async fn next_fix(&mut self) -> Result<Fix, Error<R::Error>> {
+        loop {
+            if let Sentence::Gga(fix) = self.next_sentence().await? {
+                if fix.quality > 0 {
+                    return Ok(fix);
+                }
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    async fn next_byte(&mut self) -> Result<u8, Error<R::Error>> {
+        if self.rx_pos == self.rx_len {
+            self.rx_len = self.reader.read(&mut self.rx).await.map_err(Error::Read)?;
+            self.rx_pos = 0;
+            if self.rx_len == 0 {
+                return Err(Error::Eof);
+            }
+        }
+        self.rx_pos += 1;
+        Ok(self.rx[self.rx_pos - 1])
+    }
+}
+
+// Parse a sentence without the leading '$' or the line ending.
+pub fn parse(line: &[u8]) -> Option<Sentence> {
+    let star = line.iter().rposition(|&b| b == b'*')?;
+    let (body, checksum) = (&line[..star], &line[star + 1..]);
+    let expected = u8::from_str_radix(core::str::from_utf8(checksum).ok()?, 16).ok()?;
+    if body.iter().fold(0, |sum, &b| sum ^ b) != expected {
+        return None;
+    }
+
+    let body = core::str::from_utf8(body).ok()?;
+    let mut fields = body.split(',');
+    let kind = fields.next()?;
+    // Skip the talker ID (GP, GN, GL...).
+    match kind.get(2..)? {
+        "GGA" => {
+            let time = parse_time(fields.next()?)?;
+            let latitude = parse_coordinate(fields.next()?, fields.next()?, 2)?;
+            let longitude = parse_coordinate(fields.next()?, fields.next()?, 3)?;
+            let quality = fields.next()?.parse().unwrap_or(0);
+            let satellites = fields.next()?.parse().unwrap_or(0);
+            let _hdop = fields.next()?;
+            let altitude_mm = parse_fixed(fields.next()?, 3).map(|mm| mm as i32);
+            Some(Sentence::Gga(Fix {
+                time,
+                latitude,
+                longitude,
+                quality,
+                satellites,
+                altitude_mm,
+            }))
+        }
+        "RMC" => {
+            let time = parse_time(fields.next()?)?;
+            let valid = fields.next()? == "A";
+            let latitude = parse_coordinate(fields.next()?, fields.next()?, 2)?;
+            let longitude = parse_coordinate(fields.next()?, fields.next()?, 3)?;
+            let speed_mknots = parse_fixed(fields.next()?, 3).unwrap_or(0) as u32;
+            let course_cdeg = parse_fixed(fields.next()?, 2).unwrap_or(0) as u16;
+            let date = parse_date(fields.next()?)?;
+            Some(Sentence::Rmc(Rmc {
+                time,
+                date,
+                valid,
+                latitude,
+                longitude,
+                speed_mknots,
+                course_cdeg,
+            }))
+        }
+        _ => None,
+    }
+}
+
+// hhmmss.sss
+fn parse_time(field: &str) -> Option<Time> {
+    let digits = field.as_bytes();
+    if digits.len() < 6 {
+        return None;
+    }
+    let millis = parse_fixed(field.get(6..).unwrap_or(""), 3).unwrap_or(0);
+    Some(Time {
+        hour: parse_digits(&digits[0..2])? as u8,
+        minute: parse_digits(&digits[2..4])? as u8,
+        second: parse_digits(&digits[4..6])? as u8,
+        millis: millis as u16,
+    })
+}
+
+// ddmmyy
+fn parse_date(field: &str) -> Option<Date> {
+    let digits = field.as_bytes();
+    if digits.len() != 6 {
+        return None;
+    }
+    Some(Date {
+        day: parse_digits(&digits[0..2])? as u8,
+        month: parse_digits(&digits[2..4])? as u8,
+        year: 2000 + parse_digits(&digits[4..6])? as u16,
+    })
+}
+
+// (d)ddmm.mmmm plus a hemisphere, to degrees * 10^7. Empty fields (no fix) are 0.
+fn parse_coordinate(field: &str, hemisphere: &str, degree_digits: usize) -> Option<i32> {
+    if field.is_empty() {
+        return Some(0);
+    }
+    let degrees = parse_digits(field.as_bytes().get(..degree_digits)?)? as i64;
+    let minutes_e5 = parse_fixed(field.get(degree_digits..)?, 5)?;
+    // minutes * 10^5 / 60 * 10^2 = degrees * 10^7
+    let value = degrees * 10_000_000 + minutes_e5 * 5 / 3;
+    match hemisphere {
+        "S" | "W" => Some(-value as i32),
+        _ => Some(value as i32),
+    }
+}
+
+// A decimal number scaled by 10^decimals, with extra decimals truncated.
+fn parse_fixed(field: &str, decimals: u32) -> Option<i64> {
+    if field.is_empty() {
+        return None;
+    }
+    let (negative, field) = match field.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, field),
+    };
+    let (int, frac) = field.split_once('.').unwrap_or((field, ""));
+    let mut value = if int.is_empty() {
+        0
+    } else {
+        parse_digits(int.as_bytes())? as i64
+    };
+    let frac = frac.as_bytes();
+    for i in 0..decimals as usize {
+        let digit = match frac.get(i) {
+            Some(&d) if d.is_ascii_digit() => (d - b'0') as i64,
+            Some(_) => return None,
+            None => 0,
+        };
+        value = value * 10 + digit;
+    }
+    Some(if negative { -value } else { value })
+}
+
+fn parse_digits(digits: &[u8]) -> Option<u32> {
+    digits.iter().try_fold(0u32, |value, &d| {
+        if d.is_ascii_digit() {
+            value.checked_mul(10)?.checked_add((d - b'0') as u32)
+        } else {
+            None
+        }
+    })
+}
+
+// Microseconds since the Unix epoch.
+pub fn unix_micros(date: Date, time: Time) -> u64 {
+    // Days from civil, from Howard Hinnant's date algorithms.
+    let (y, m, d) = (date.year as i64, date.month as i64, date.day as i64);
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let seconds =
+        days * 86400 + time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64;
+    seconds as u64 * 1_000_000 + time.millis as u64 * 1000
+}
+
+// Relates the TIMER to UTC using the PPS output.
+//
+// The PPS edge marks the start of a UTC second, and the RMC sentence that follows it says
+// which second that was. Feed both in and `utc_micros` converts any Instant to UTC.
+pub struct PpsClock {
+    last_pps: Option<Instant>,
+    // UTC minus TIMER, in microseconds.
+    offset: Option<i64>,
+}
+
+impl PpsClock {
+    pub const fn new() -> Self {
+        PpsClock {
+            last_pps: None,
+            offset: None,
+        }
+    }
+
+    // Wait for the next rising edge on the PPS pin and record when it happened.
+    // The timestamp includes task wakeup latency, typically tens of microseconds.
+    pub async fn wait_pps<P: Wait>(&mut self, pps: &mut P) -> Result<Instant, P::Error> {
+        pps.wait_for_rising_edge().await?;
+        let now = Instant::now();
+        self.last_pps = Some(now);
+        Ok(now)
+    }
+
+    // Feed in an RMC sentence received after a PPS edge.
+    pub fn on_rmc(&mut self, rmc: &Rmc) {
+        let pps = match self.last_pps {
+            Some(pps) if rmc.valid && pps.elapsed().as_millis() < 1000 => pps,
+            _ => return,
+        };
+        // The sentence describes the second that started at the PPS edge.
+        let time = Time {
+            millis: 0,
+            ..rmc.time
+        };
+        let utc = unix_micros(rmc.date, time) as i64;
+        self.offset = Some(utc - pps.as_micros() as i64);
+    }
+
+    pub fn utc_micros(&self, instant: Instant) -> Option<u64> {
+        Some((instant.as_micros() as i64 + self.offset?) as u64)
+    }
+
+    pub fn now_utc_micros(&self) -> Option<u64> {
+        self.utc_micros(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gga() {
+        let line = b"GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+        let Some(Sentence::Gga(fix)) = parse(line) else {
+            panic!("not parsed as GGA");
+        };
+        assert_eq!(
+            fix.time,
+            Time {
+                hour: 12,
+                minute: 35,
+                second: 19,
+                millis: 0,
+            }
+        );
+        assert_eq!(fix.latitude, 481_173_000);
+        assert_eq!(fix.longitude, 115_166_666);
+        assert_eq!(fix.quality, 1);
+        assert_eq!(fix.satellites, 8);
+        assert_eq!(fix.altitude_mm, Some(545_400));
+    }
+
+    #[test]
+    fn rmc() {
+        let line = b"GPRMC,123519,A,4807.038,N,01131.000,W,022.4,084.4,230394,003.1,W*6A";
+        // The same sentence with the longitude's hemisphere changed no longer checks out.
+        assert!(parse(line).is_none());
+        let line = b"GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+        let Some(Sentence::Rmc(rmc)) = parse(line) else {
+            panic!("not parsed as RMC");
+        };
+        assert!(rmc.valid);
+        assert_eq!(rmc.longitude, 115_166_666);
+        assert_eq!(rmc.speed_mknots, 22_400);
+        assert_eq!(rmc.course_cdeg, 8_440);
+        assert_eq!((rmc.date.day, rmc.date.month), (23, 3));
+    }
+
+    #[test]
+    fn rmc_without_fix() {
+        let Some(Sentence::Rmc(rmc)) = parse(b"GNRMC,235959.250,V,,,,,,,010124,,,N*53") else {
+            panic!("not parsed as RMC");
+        };
+        assert!(!rmc.valid);
+        assert_eq!((rmc.latitude, rmc.longitude), (0, 0));
+        assert_eq!(rmc.time.millis, 250);
+        assert_eq!(
+            rmc.date,
+            Date {
+                year: 2024,
+                month: 1,
+                day: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert!(parse(b"GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,").is_none());
+        assert!(parse(b"GPGGA,123519*zz").is_none());
+        assert!(parse(b"GPGSV,1,1,00*79").is_none());
+    }
+
+    #[test]
+    fn unix_time() {
+        let midnight = Time::default();
+        let epoch = Date {
+            year: 1970,
+            month: 1,
+            day: 1,
+        };
+        assert_eq!(unix_micros(epoch, midnight), 0);
+        let leap_day = Date {
+            year: 2024,
+            month: 2,
+            day: 29,
+        };
+        let time = Time {
+            hour: 12,
+            minute: 0,
+            second: 1,
+            millis: 500,
+        };
+        assert_eq!(unix_micros(leap_day, time), 1_709_208_001_500_000);
+    }
+}
@@ -0,0 +1,259 @@
+// An allocator wrapper that keeps statistics about the heap.
+//
+// Wrap the global allocator in `TracingHeap` (done in main.rs with the `heap-stats` feature)
+// and query it with `stats()`. With the `alloc-callsites` feature it also keeps live
+// allocation counts per call site, keyed by the return address of the allocation.
+
+use core::alloc::{GlobalAlloc, Layout};
+
+use alloc_cortex_m::CortexMHeap;
+use cortex_m::interrupt;
+
+use crate::sync::Mutex;
+
+// Allocation size classes: <= 8 bytes, <= 16, <= 32, ... <= 4096, > 4096.
+pub const BUCKETS: usize = 11;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    pub used: usize,
+    pub free: usize,
+    pub peak_used: usize,
+    // Size of the largest block that could be allocated right now.
+    pub largest_free: usize,
+    pub allocations: u32,
+    pub deallocations: u32,
+    pub failures: u32,
+    pub histogram: [u32; BUCKETS],
+}
+
+impl HeapStats {
+    pub fn live(&self) -> u32 {
+        self.allocations.wrapping_sub(self.deallocations)
+    }
+
+    // How much of the free space is unusable for a single allocation, in percent.
+    // 0 means all free memory is one contiguous block.
+    pub fn fragmentation_percent(&self) -> u8 {
+        if self.free == 0 {
+            return 0;
+        }
+        100usize.saturating_sub(self.largest_free * 100 / self.free) as u8
+    }
+}
+
+#[cfg(feature = "alloc-callsites")]
+pub const CALLSITES: usize = 16;
+
+#[cfg(feature = "alloc-callsites")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CallSite {
+    // Return address of the allocation; look it up in the ELF to find the caller.
+    pub address: usize,
+    pub live: u32,
+    pub total: u32,
+}
+
+#[cfg(feature = "alloc-callsites")]
+const UNTRACKED: u32 = u32::MAX;
+
+struct Counters {
+    peak_used: usize,
+    allocations: u32,
+    deallocations: u32,
+    failures: u32,
+    histogram: [u32; BUCKETS],
+    #[cfg(feature = "alloc-callsites")]
+    callsites: [CallSite; CALLSITES],
+}
+
+pub struct TracingHeap {
+    heap: CortexMHeap,
+    counters: Mutex<Counters, 11>,
+}
+
+impl TracingHeap {
+    pub const fn empty() -> Self {
+        TracingHeap {
+            heap: CortexMHeap::empty(),
+            counters: Mutex::new(Counters {
+                peak_used: 0,
+                allocations: 0,
+                deallocations: 0,
+                failures: 0,
+                histogram: [0; BUCKETS],
+                #[cfg(feature = "alloc-callsites")]
+                callsites: [CallSite {
+                    address: 0,
+                    live: 0,
+                    total: 0,
+                }; CALLSITES],
+            }),
+        }
+    }
+
+    // Safety: Same as CortexMHeap::init.
+    pub unsafe fn init(&self, start_addr: usize, size: usize) {
+        self.heap.init(start_addr, size)
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let largest_free = self.largest_free();
+        interrupt::free(|_| {
+            let counters = self.counters.lock();
+            HeapStats {
+                used: self.heap.used(),
+                free: self.heap.free(),
+                peak_used: counters.peak_used,
+                largest_free,
+                allocations: counters.allocations,
+                deallocations: counters.deallocations,
+                failures: counters.failures,
+                histogram: counters.histogram,
+            }
+        })
+    }
+
+    #[cfg(feature = "alloc-callsites")]
+    pub fn callsites(&self) -> [CallSite; CALLSITES] {
+        interrupt::free(|_| self.counters.lock().callsites)
+    }
+
+    // Binary search for the biggest block the heap can hand out right now.
+    // This really allocates, so it's slow-ish; only call it when reporting.
+    fn largest_free(&self) -> usize {
+        let (mut lo, mut hi) = (0, self.heap.free());
+        while lo < hi {
+            let size = (lo + hi + 1) / 2;
+            let layout = Layout::from_size_align(size, 4).unwrap();
+            let ptr = unsafe { self.heap.alloc(layout) };
+            if ptr.is_null() {
+                hi = size - 1;
+            } else {
+                unsafe { self.heap.dealloc(ptr, layout) };
+                lo = size;
+            }
+        }
+        lo
+    }
+
+    fn record_alloc(&self, layout: Layout, success: bool) {
+        interrupt::free(|_| {
+            let mut counters = self.counters.lock();
+            if !success {
+                counters.failures += 1;
+                return;
+            }
+            counters.allocations = counters.allocations.wrapping_add(1);
+            let bucket = bucket(layout.size());
+            counters.histogram[bucket] = counters.histogram[bucket].saturating_add(1);
+            counters.peak_used = counters.peak_used.max(self.heap.used());
+        })
+    }
+
+    fn record_dealloc(&self) {
+        interrupt::free(|_| {
+            let mut counters = self.counters.lock();
+            counters.deallocations = counters.deallocations.wrapping_add(1);
+        })
+    }
+}
+
+fn bucket(size: usize) -> usize {
+    let bits = usize::BITS - size.saturating_sub(1).leading_zeros();
+    (bits.saturating_sub(3) as usize).min(BUCKETS - 1)
+}
+
+#[cfg(not(feature = "alloc-callsites"))]
+unsafe impl GlobalAlloc for TracingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        self.record_alloc(layout, !ptr.is_null());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout);
+        self.record_dealloc();
+    }
+}
+
+// With call-site tracking, every allocation gets a small header in front of it that remembers
+// which call-site slot it was counted in, so that dealloc can decrement the right one.
+#[cfg(feature = "alloc-callsites")]
+impl TracingHeap {
+    fn with_header(layout: Layout) -> Option<(Layout, usize)> {
+        let offset = layout.align().max(4);
+        let size = layout.size().checked_add(offset)?;
+        Some((
+            Layout::from_size_align(size, layout.align().max(4)).ok()?,
+            offset,
+        ))
+    }
+
+    fn claim_callsite(&self, address: usize) -> u32 {
+        interrupt::free(|_| {
+            let mut counters = self.counters.lock();
+            let slot = counters
+                .callsites
+                .iter()
+                .position(|site| site.address == address)
+                .or_else(|| counters.callsites.iter().position(|site| site.total == 0));
+            match slot {
+                Some(slot) => {
+                    let site = &mut counters.callsites[slot];
+                    site.address = address;
+                    site.live += 1;
+                    site.total = site.total.wrapping_add(1);
+                    slot as u32
+                }
+                None => UNTRACKED,
+            }
+        })
+    }
+
+    fn release_callsite(&self, slot: u32) {
+        if slot == UNTRACKED {
+            return;
+        }
+        interrupt::free(|_| {
+            let mut counters = self.counters.lock();
+            let site = &mut counters.callsites[slot as usize];
+            site.live = site.live.saturating_sub(1);
+        })
+    }
+}
+
+#[cfg(feature = "alloc-callsites")]
+unsafe impl GlobalAlloc for TracingHeap {
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Read this first, before anything else can clobber LR.
+        let address: usize;
+        core::arch::asm!("mov {}, lr", out(reg) address, options(nomem, nostack, preserves_flags));
+
+        let (outer, offset) = match Self::with_header(layout) {
+            Some(header) => header,
+            None => {
+                self.record_alloc(layout, false);
+                return core::ptr::null_mut();
+            }
+        };
+        let ptr = self.heap.alloc(outer);
+        self.record_alloc(layout, !ptr.is_null());
+        if ptr.is_null() {
+            return ptr;
+        }
+        let ptr = ptr.add(offset);
+        ptr.cast::<u32>().sub(1).write(self.claim_callsite(address));
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // with_header succeeded when this was allocated, so it will again.
+        let (outer, offset) = Self::with_header(layout).unwrap();
+        self.release_callsite(ptr.cast::<u32>().sub(1).read());
+        self.heap.dealloc(ptr.sub(offset), outer);
+        self.record_dealloc();
+    }
+}
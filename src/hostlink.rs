@@ -0,0 +1,197 @@
+// Typed request/response messages with a host tool, over a USB CDC-ACM port or a UART.
+//
+// Each message type is an `Endpoint`: a kind number, plus the request and response types,
+// which are serde types encoded with postcard. Frames are COBS-delimited (see `codec`), so
+// the host side is a few lines of Python or Rust.
+//
+// A request frame is the sequence number and kind followed by the request, and the response
+// frame is the same sequence number and a `Status` followed by the response, if it's `Ok`. The
+// host picks the sequence numbers and uses them to match up responses. The firmware answers
+// requests one at a time, in order.
+//
+// The usual way in is `serve`:
+//
+//     link.serve(async |call| match call.kind() {
+//         GetStatus::KIND => {
+//             let () = call.decode::<GetStatus>()?;
+//             call.respond::<GetStatus>(&status()).await
+//         }
+//         _ => call.reject(Status::UnknownKind).await,
+//     })
+//     .await
+
+use embedded_io_async::{Read, Write};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::codec::{self, Cobs};
+
+pub trait Endpoint {
+    // Identifies the endpoint on the wire. Has to be unique in the firmware.
+    const KIND: u16;
+    type Request: DeserializeOwned;
+    type Response: Serialize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    Ok,
+    // No endpoint with the request's kind.
+    UnknownKind,
+    // The request didn't decode as the endpoint's request type.
+    BadRequest,
+    // The endpoint failed in some way of its own.
+    Failed,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    // The stream ended.
+    Eof,
+    // A response didn't fit in the frame buffer.
+    TooBig,
+    // A request didn't decode. `serve` answers these with `BadRequest` by itself.
+    Decode,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RequestHeader {
+    seq: u16,
+    kind: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResponseHeader {
+    seq: u16,
+    status: Status,
+}
+
+// The device end of the link. Requests and responses of up to N bytes, before COBS encoding.
+pub struct HostLink<T, const N: usize = 256> {
+    cobs: Cobs<T>,
+    rx: [u8; N],
+    tx: [u8; N],
+}
+
+// A request that's been received, to be answered with `respond` or `reject`. Dropping it
+// without answering leaves the host waiting until it times out.
+pub struct Call<'a, T, const N: usize> {
+    link: &'a mut HostLink<T, N>,
+    seq: u16,
+    kind: u16,
+    // Where the request starts and ends in the receive buffer.
+    body: (usize, usize),
+}
+
+impl<T: Read + Write, const N: usize> HostLink<T, N> {
+    pub fn new(io: T) -> Self {
+        HostLink {
+            cobs: Cobs::new(io),
+            rx: [0; N],
+            tx: [0; N],
+        }
+    }
+
+    // Wait for the next request. Frames that are too big or don't decode are dropped, since
+    // there's no telling who to answer.
+    pub async fn next_call(&mut self) -> Result<Call<'_, T, N>, Error<T::Error>> {
+        loop {
+            let len = match self.cobs.recv_frame(&mut self.rx).await {
+                Ok(len) => len,
+                Err(codec::Error::Io(e)) => return Err(Error::Io(e)),
+                Err(codec::Error::Eof) => return Err(Error::Eof),
+                Err(codec::Error::Overflow) | Err(codec::Error::Invalid) => {
+                    log::warn!("hostlink: dropped a bad frame");
+                    continue;
+                }
+            };
+            let Ok((header, rest)) = postcard::take_from_bytes::<RequestHeader>(&self.rx[..len])
+            else {
+                log::warn!("hostlink: dropped a frame with a bad header");
+                continue;
+            };
+            let body = (len - rest.len(), len);
+            return Ok(Call {
+                link: self,
+                seq: header.seq,
+                kind: header.kind,
+                body,
+            });
+        }
+    }
+
+    // Answer requests with `handler` forever. Requests that don't decode are rejected with
+    // `BadRequest`. Returns if the link fails.
+    pub async fn serve(
+        &mut self,
+        mut handler: impl AsyncFnMut(Call<'_, T, N>) -> Result<(), Error<T::Error>>,
+    ) -> Result<!, Error<T::Error>> {
+        loop {
+            let call = self.next_call().await?;
+            let seq = call.seq;
+            match handler(call).await {
+                Ok(()) => {}
+                Err(Error::Decode) => self.send(seq, Status::BadRequest, &()).await?,
+                // The host has already been told.
+                Err(Error::TooBig) => log::warn!("hostlink: response to {} too big", seq),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.cobs.into_inner()
+    }
+
+    async fn send(
+        &mut self,
+        seq: u16,
+        status: Status,
+        body: &impl Serialize,
+    ) -> Result<(), Error<T::Error>> {
+        let header = postcard::to_slice(&ResponseHeader { seq, status }, &mut self.tx)
+            .map_err(|_| Error::TooBig)?
+            .len();
+        let len = header
+            + postcard::to_slice(body, &mut self.tx[header..])
+                .map_err(|_| Error::TooBig)?
+                .len();
+        match self.cobs.send_frame(&self.tx[..len]).await {
+            Ok(()) => Ok(()),
+            Err(codec::Error::Io(e)) => Err(Error::Io(e)),
+            // Sending doesn't report anything else.
+            Err(_) => Err(Error::Eof),
+        }
+    }
+}
+
+impl<T: Read + Write, const N: usize> Call<'_, T, N> {
+    pub fn kind(&self) -> u16 {
+        self.kind
+    }
+
+    pub fn is<E: Endpoint>(&self) -> bool {
+        self.kind == E::KIND
+    }
+
+    pub fn decode<E: Endpoint>(&self) -> Result<E::Request, Error<T::Error>> {
+        let (start, end) = self.body;
+        postcard::from_bytes(&self.link.rx[start..end]).map_err(|_| Error::Decode)
+    }
+
+    // If the response doesn't fit, the host is told the call `Failed` instead, and this
+    // returns `TooBig`.
+    pub async fn respond<E: Endpoint>(self, response: &E::Response) -> Result<(), Error<T::Error>> {
+        match self.link.send(self.seq, Status::Ok, response).await {
+            Err(Error::TooBig) => {
+                self.link.send(self.seq, Status::Failed, &()).await?;
+                Err(Error::TooBig)
+            }
+            result => result,
+        }
+    }
+
+    pub async fn reject(self, status: Status) -> Result<(), Error<T::Error>> {
+        self.link.send(self.seq, status, &()).await
+    }
+}
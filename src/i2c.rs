@@ -0,0 +1,414 @@
+// The RP2040's two I2C controllers as bus masters, with transactions that sleep on the
+// controller's interrupt while bytes go out and come in, so a task polling a slow sensor
+// doesn't hold up the others.
+//
+//     let irq = Irq::<I2C0_IRQ>::take().unwrap();
+//     let mut i2c = I2c::new(pac.I2C0, irq, 4, 5, &Config::default(), sys_hz);
+//     let mut id = [0];
+//     i2c.write_read(0x76, &[0xd0], &mut id).await?;
+//
+// It implements embedded-hal-async's `I2c` too, for `bus::SharedI2c` and device drivers.
+// Transactions follow its rules: a START at the beginning, a repeated START wherever it
+// switches between writing and reading, and a STOP at the end.
+//
+// A NACK or lost arbitration makes the controller abort, send a STOP (unless it lost the bus)
+// and throw away whatever was queued; the transaction returns the reason as an `Error`.
+// Addresses are 7-bit. The controller can't send just an address with no data, so a
+// transaction has to have at least one byte in it.
+
+use core::{marker::PhantomData, task::Poll};
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation, SevenBitAddress};
+use rp2040_pac::i2c0::RegisterBlock;
+
+use crate::{
+    future,
+    irq::{self, Irq, Line},
+    resets,
+};
+
+const FIFO: u32 = 16;
+
+// IC_CON
+const CON_MASTER_MODE: u32 = 1 << 0;
+const CON_SPEED_FAST: u32 = 2 << 1;
+const CON_RESTART_EN: u32 = 1 << 5;
+const CON_SLAVE_DISABLE: u32 = 1 << 6;
+const CON_TX_EMPTY_CTRL: u32 = 1 << 8;
+const CON_RX_FIFO_FULL_HLD_CTRL: u32 = 1 << 9;
+
+// IC_DATA_CMD
+const CMD_READ: u32 = 1 << 8;
+const CMD_STOP: u32 = 1 << 9;
+const CMD_RESTART: u32 = 1 << 10;
+
+// IC_ENABLE
+const ENABLE: u32 = 1 << 0;
+const ENABLE_ABORT: u32 = 1 << 1;
+
+// IC_RAW_INTR_STAT and IC_INTR_MASK
+const INTR_RX_FULL: u32 = 1 << 2;
+const INTR_TX_EMPTY: u32 = 1 << 4;
+const INTR_TX_ABRT: u32 = 1 << 6;
+const INTR_STOP_DET: u32 = 1 << 9;
+
+// IC_TX_ABRT_SOURCE
+const ABRT_7B_ADDR_NOACK: u32 = 1 << 0;
+const ABRT_TXDATA_NOACK: u32 = 1 << 3;
+const ABRT_LOST: u32 = 1 << 12;
+
+const FUNCSEL_I2C: u32 = 3;
+
+// I2C0 or I2C1, as the PAC has them.
+pub trait Instance {
+    const INDEX: u8;
+    type Line: Line;
+    fn ptr() -> *const RegisterBlock;
+}
+
+impl Instance for rp2040_pac::I2C0 {
+    const INDEX: u8 = 0;
+    type Line = irq::I2C0_IRQ;
+    fn ptr() -> *const RegisterBlock {
+        rp2040_pac::I2C0::ptr()
+    }
+}
+
+impl Instance for rp2040_pac::I2C1 {
+    const INDEX: u8 = 1;
+    type Line = irq::I2C1_IRQ;
+    fn ptr() -> *const RegisterBlock {
+        rp2040_pac::I2C1::ptr()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    // SCL's frequency, up to 1 MHz.
+    pub frequency: u32,
+}
+
+// Standard mode, 100 kHz.
+impl Default for Config {
+    fn default() -> Self {
+        Config { frequency: 100_000 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // Nobody acknowledged the address.
+    AddressNack,
+    // The device stopped acknowledging the bytes written to it.
+    DataNack,
+    // Another master won the bus.
+    ArbitrationLost,
+    // Any other abort, with IC_TX_ABRT_SOURCE as it was.
+    Abort(u32),
+    // There were no bytes to send or receive.
+    Empty,
+}
+
+impl embedded_hal::i2c::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::AddressNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            Error::DataNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            Error::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            Error::Abort(_) => ErrorKind::Bus,
+            Error::Empty => ErrorKind::Other,
+        }
+    }
+}
+
+pub struct I2c<I: Instance> {
+    i2c: I,
+    irq: Irq<I::Line>,
+}
+
+impl<I: Instance> I2c<I> {
+    // SDA on `sda` and SCL on `scl`, which have to be ones the controller can have: SDA on a
+    // multiple of four and SCL one above for I2C0, and both two above that for I2C1. The pads'
+    // pull-ups are turned on, but they're weak; the bus should have its own. `sys_hz` is
+    // clk_sys's frequency, which the controller runs from.
+    pub fn new(i2c: I, irq: Irq<I::Line>, sda: u8, scl: u8, config: &Config, sys_hz: u32) -> Self {
+        assert!(
+            sda & 1 == 0 && scl == sda + 1 && (sda >> 1) & 1 == I::INDEX && scl < 30,
+            "pins aren't this I2C's"
+        );
+        resets::bring_up(1 << (3 + I::INDEX));
+        let regs = unsafe { &*I::ptr() };
+        regs.ic_enable.write(|w| unsafe { w.bits(0) });
+        regs.ic_con.write(|w| unsafe {
+            w.bits(
+                CON_MASTER_MODE
+                    | CON_SPEED_FAST
+                    | CON_RESTART_EN
+                    | CON_SLAVE_DISABLE
+                    | CON_TX_EMPTY_CTRL
+                    | CON_RX_FIFO_FULL_HLD_CTRL,
+            )
+        });
+        regs.ic_tx_tl.write(|w| unsafe { w.bits(FIFO / 2) });
+        regs.ic_rx_tl.write(|w| unsafe { w.bits(0) });
+        regs.ic_intr_mask.write(|w| unsafe { w.bits(0) });
+        set_timing(regs, config.frequency, sys_hz);
+        regs.ic_enable.write(|w| unsafe { w.bits(ENABLE) });
+
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+        for pin in [sda, scl] {
+            // IE set, OD clear, pull-up on and pull-down off.
+            pads.gpio[pin as usize]
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 7 | 1 << 2) | 1 << 6 | 1 << 3) });
+            io.gpio[pin as usize]
+                .gpio_ctrl
+                .write(|w| unsafe { w.bits(FUNCSEL_I2C) });
+        }
+        I2c { i2c, irq }
+    }
+
+    // Change SCL's frequency between transactions.
+    pub fn set_config(&mut self, config: &Config, sys_hz: u32) {
+        let regs = unsafe { &*I::ptr() };
+        regs.ic_enable.write(|w| unsafe { w.bits(0) });
+        set_timing(regs, config.frequency, sys_hz);
+        regs.ic_enable.write(|w| unsafe { w.bits(ENABLE) });
+    }
+
+    pub async fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.run(address, &mut [Operation::Write(bytes)]).await
+    }
+
+    pub async fn read(&mut self, address: u8, buf: &mut [u8]) -> Result<(), Error> {
+        self.run(address, &mut [Operation::Read(buf)]).await
+    }
+
+    // Write `bytes`, then a repeated START and read into `buf`, as register reads go.
+    pub async fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.run(
+            address,
+            &mut [Operation::Write(bytes), Operation::Read(buf)],
+        )
+        .await
+    }
+
+    // Disable the controller and hand back what it was made from.
+    pub fn release(self) -> (I, Irq<I::Line>) {
+        let regs = unsafe { &*I::ptr() };
+        regs.ic_intr_mask.write(|w| unsafe { w.bits(0) });
+        regs.ic_enable.write(|w| unsafe { w.bits(0) });
+        (self.i2c, self.irq)
+    }
+
+    async fn run(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Error> {
+        assert!(address < 0x80, "not a 7-bit address");
+        let total: usize = operations.iter().map(len).sum();
+        if total == 0 {
+            return Err(Error::Empty);
+        }
+        let regs = unsafe { &*I::ptr() };
+        regs.ic_enable.write(|w| unsafe { w.bits(0) });
+        regs.ic_tar.write(|w| unsafe { w.bits(address as u32) });
+        regs.ic_enable.write(|w| unsafe { w.bits(ENABLE) });
+        // Anything left over from a transaction that was abandoned.
+        while regs.ic_rxflr.read().bits() > 0 {
+            regs.ic_data_cmd.read();
+        }
+        regs.ic_clr_intr.read();
+        let mut transfer = Transfer::<I> {
+            done: false,
+            instance: PhantomData,
+        };
+
+        // Where the next command comes from, and where the next byte read goes, as an
+        // operation and an offset into it.
+        let mut next = (0, 0);
+        let mut into = (0, 0);
+        let mut issued = 0;
+        let mut reading = 0;
+        let mut previous_read = None;
+        loop {
+            if regs.ic_raw_intr_stat.read().bits() & INTR_TX_ABRT != 0 {
+                let error = self.abort().await;
+                transfer.done = true;
+                return Err(error);
+            }
+            while issued < total && regs.ic_txflr.read().bits() < FIFO && reading < FIFO as usize {
+                while len(&operations[next.0]) == 0 {
+                    next = (next.0 + 1, 0);
+                }
+                let mut cmd = 0;
+                let read = matches!(operations[next.0], Operation::Read(_));
+                if next.1 == 0 && previous_read.is_some_and(|previous| previous != read) {
+                    cmd |= CMD_RESTART;
+                }
+                if issued + 1 == total {
+                    cmd |= CMD_STOP;
+                }
+                match &operations[next.0] {
+                    Operation::Write(bytes) => cmd |= bytes[next.1] as u32,
+                    Operation::Read(_) => {
+                        cmd |= CMD_READ;
+                        reading += 1;
+                    }
+                }
+                regs.ic_data_cmd.write(|w| unsafe { w.bits(cmd) });
+                previous_read = Some(read);
+                issued += 1;
+                next.1 += 1;
+                if next.1 == len(&operations[next.0]) {
+                    next = (next.0 + 1, 0);
+                }
+            }
+            while reading > 0 && regs.ic_rxflr.read().bits() > 0 {
+                let buf = loop {
+                    match &mut operations[into.0] {
+                        Operation::Read(buf) if into.1 < buf.len() => break buf,
+                        _ => into = (into.0 + 1, 0),
+                    }
+                };
+                buf[into.1] = regs.ic_data_cmd.read().bits() as u8;
+                into.1 += 1;
+                reading -= 1;
+            }
+            if issued == total && reading == 0 {
+                break;
+            }
+
+            let mut sources = INTR_TX_ABRT;
+            // Held up by a full TX FIFO, rather than by reads that haven't come in yet.
+            if issued < total && reading < FIFO as usize {
+                sources |= INTR_TX_EMPTY;
+            }
+            if reading > 0 {
+                regs.ic_rx_tl
+                    .write(|w| unsafe { w.bits(reading.min(FIFO as usize) as u32 - 1) });
+                sources |= INTR_RX_FULL;
+            }
+            self.wait(sources).await;
+        }
+
+        self.wait(INTR_STOP_DET | INTR_TX_ABRT).await;
+        if regs.ic_raw_intr_stat.read().bits() & INTR_TX_ABRT != 0 {
+            let error = self.abort().await;
+            transfer.done = true;
+            return Err(error);
+        }
+        regs.ic_clr_stop_det.read();
+        transfer.done = true;
+        Ok(())
+    }
+
+    // Find out why the controller aborted, and wait for it to finish the STOP it sends.
+    async fn abort(&mut self) -> Error {
+        let regs = unsafe { &*I::ptr() };
+        let source = regs.ic_tx_abrt_source.read().bits();
+        regs.ic_clr_tx_abrt.read();
+        if source & ABRT_LOST != 0 {
+            // The bus is someone else's; they'll send the STOP.
+            return Error::ArbitrationLost;
+        }
+        self.wait(INTR_STOP_DET).await;
+        regs.ic_clr_stop_det.read();
+        if source & ABRT_7B_ADDR_NOACK != 0 {
+            Error::AddressNack
+        } else if source & ABRT_TXDATA_NOACK != 0 {
+            Error::DataNack
+        } else {
+            Error::Abort(source)
+        }
+    }
+
+    // Wait for one of the raw interrupts `sources`, with them unmasked in between and masked
+    // again however the wait ends.
+    async fn wait(&self, sources: u32) {
+        let regs = unsafe { &*I::ptr() };
+        let _armed = Armed::<I>(PhantomData);
+        future::wait_irq(&self.irq, || {
+            if regs.ic_raw_intr_stat.read().bits() & sources != 0 {
+                return Poll::Ready(());
+            }
+            regs.ic_intr_mask.write(|w| unsafe { w.bits(sources) });
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl<I: Instance> ErrorType for I2c<I> {
+    type Error = Error;
+}
+
+impl<I: Instance> embedded_hal_async::i2c::I2c<SevenBitAddress> for I2c<I> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        self.run(address, operations).await
+    }
+}
+
+fn len(operation: &Operation<'_>) -> usize {
+    match operation {
+        Operation::Read(buf) => buf.len(),
+        Operation::Write(bytes) => bytes.len(),
+    }
+}
+
+// SCL high and low counts for `hz`, with the low time 60% of the period as fast mode wants,
+// and the spike filter and SDA hold time to go with them.
+fn set_timing(regs: &RegisterBlock, hz: u32, sys_hz: u32) {
+    assert!(hz > 0 && hz <= 1_000_000, "I2C frequency out of range");
+    let period = (sys_hz + hz / 2) / hz;
+    let lcnt = period * 3 / 5;
+    let hcnt = period - lcnt;
+    assert!(
+        (8..=0xffff).contains(&hcnt) && (8..=0xffff).contains(&lcnt),
+        "I2C frequency out of range for clk_sys"
+    );
+    let spklen = if lcnt < 16 { 1 } else { lcnt / 16 };
+    // 300 ns of hold, or 120 ns in fast mode plus.
+    let hold = if hz < 1_000_000 {
+        sys_hz * 3 / 10_000_000 + 1
+    } else {
+        sys_hz * 3 / 25_000_000 + 1
+    };
+    regs.ic_fs_scl_hcnt.write(|w| unsafe { w.bits(hcnt) });
+    regs.ic_fs_scl_lcnt.write(|w| unsafe { w.bits(lcnt) });
+    regs.ic_fs_spklen.write(|w| unsafe { w.bits(spklen) });
+    regs.ic_sda_hold
+        .modify(|r, w| unsafe { w.bits(r.bits() & !0xffff | hold.min(lcnt - 2)) });
+}
+
+struct Armed<I: Instance>(PhantomData<I>);
+
+impl<I: Instance> Drop for Armed<I> {
+    fn drop(&mut self) {
+        let regs = unsafe { &*I::ptr() };
+        regs.ic_intr_mask.write(|w| unsafe { w.bits(0) });
+    }
+}
+
+// Aborts the transaction if the future is dropped before it's done, so the controller sends a
+// STOP and drops the rest of the queued commands rather than leaving the bus mid-transfer.
+struct Transfer<I: Instance> {
+    done: bool,
+    instance: PhantomData<I>,
+}
+
+impl<I: Instance> Drop for Transfer<I> {
+    fn drop(&mut self) {
+        if !self.done {
+            let regs = unsafe { &*I::ptr() };
+            regs.ic_enable
+                .write(|w| unsafe { w.bits(ENABLE | ENABLE_ABORT) });
+        }
+    }
+}
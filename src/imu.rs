@@ -0,0 +1,312 @@
+// 6-axis IMUs: InvenSense MPU-6050 and ICM-20948 over I2C.
+//
+// Both wait on the chip's INT pin for data-ready, so a task calling `next_sample` in a loop
+// runs once per sample at whatever rate the chip is set to. The MPU-6050 also buffers samples
+// in its FIFO and reads them out in bursts, so a late wakeup costs latency rather than data.
+//
+// Samples are scaled to milli-g and milli-degrees per second.
+
+use embedded_hal_async::{digital::Wait, i2c::I2c};
+
+#[derive(Debug)]
+pub enum Error<I, P> {
+    I2c(I),
+    Pin(P),
+    UnknownChip(u8),
+    // The chip's FIFO filled up before it was read; it's been reset and samples were lost.
+    Overflow,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sample {
+    pub accel_mg: [i32; 3],
+    pub gyro_mdps: [i32; 3],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AccelRange {
+    G2 = 0,
+    G4 = 1,
+    G8 = 2,
+    G16 = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GyroRange {
+    Dps250 = 0,
+    Dps500 = 1,
+    Dps1000 = 2,
+    Dps2000 = 3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub accel_range: AccelRange,
+    pub gyro_range: GyroRange,
+    // Output rate is 1 kHz / (1 + sample_rate_div) with the filters this driver uses
+    // (1.1 kHz on the ICM-20948).
+    pub sample_rate_div: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            accel_range: AccelRange::G4,
+            gyro_range: GyroRange::Dps500,
+            // 100 Hz.
+            sample_rate_div: 9,
+        }
+    }
+}
+
+// Accel and gyro registers, both chips use the same layout: big endian X, Y, Z each.
+fn scale(raw: &[u8; 12], accel_range: AccelRange, gyro_range: GyroRange) -> Sample {
+    let axis = |i: usize| i16::from_be_bytes([raw[2 * i], raw[2 * i + 1]]) as i32;
+    // Full scale is +-32768 counts.
+    let accel_full = 2000 << accel_range as i32;
+    let gyro_full = 250_000 << gyro_range as i64;
+    Sample {
+        accel_mg: core::array::from_fn(|i| axis(i) * accel_full / 32768),
+        gyro_mdps: core::array::from_fn(|i| (axis(3 + i) as i64 * gyro_full / 32768) as i32),
+    }
+}
+
+const MPU_ADDRESS: u8 = 0x68;
+const MPU_SMPLRT_DIV: u8 = 0x19;
+const MPU_CONFIG: u8 = 0x1A;
+const MPU_GYRO_CONFIG: u8 = 0x1B;
+const MPU_ACCEL_CONFIG: u8 = 0x1C;
+const MPU_FIFO_EN: u8 = 0x23;
+const MPU_INT_ENABLE: u8 = 0x38;
+const MPU_INT_STATUS: u8 = 0x3A;
+const MPU_USER_CTRL: u8 = 0x6A;
+const MPU_PWR_MGMT_1: u8 = 0x6B;
+const MPU_FIFO_COUNT_H: u8 = 0x72;
+const MPU_FIFO_R_W: u8 = 0x74;
+const MPU_WHO_AM_I: u8 = 0x75;
+
+const MPU_INT_FIFO_OFLOW: u8 = 0x10;
+const MPU_INT_DATA_RDY: u8 = 0x01;
+// Accel, then gyro X, Y, Z.
+const MPU_FIFO_ACCEL_GYRO: u8 = 0x78;
+const MPU_USER_FIFO_EN: u8 = 0x40;
+const MPU_USER_FIFO_RESET: u8 = 0x04;
+
+const SAMPLE_BYTES: usize = 12;
+// Samples read from the FIFO in one go.
+const BURST: usize = 8;
+
+pub struct Mpu6050<I, P> {
+    i2c: I,
+    int: P,
+    address: u8,
+    config: Config,
+    burst: [[u8; SAMPLE_BYTES]; BURST],
+    burst_pos: usize,
+    burst_len: usize,
+}
+
+impl<I: I2c, P: Wait> Mpu6050<I, P> {
+    // `address` is 0x68, or 0x69 with AD0 high.
+    pub async fn new(
+        i2c: I,
+        int: P,
+        address: u8,
+        config: Config,
+    ) -> Result<Self, Error<I::Error, P::Error>> {
+        let mut imu = Mpu6050 {
+            i2c,
+            int,
+            address,
+            config,
+            burst: [[0; SAMPLE_BYTES]; BURST],
+            burst_pos: 0,
+            burst_len: 0,
+        };
+        let who = imu.read(MPU_WHO_AM_I).await?;
+        if who != MPU_ADDRESS {
+            return Err(Error::UnknownChip(who));
+        }
+        // Wake up, clocked from the X gyro's PLL.
+        imu.write(MPU_PWR_MGMT_1, 0x01).await?;
+        // 184 Hz low pass, which also sets the gyro's output rate to 1 kHz.
+        imu.write(MPU_CONFIG, 0x01).await?;
+        imu.write(MPU_SMPLRT_DIV, config.sample_rate_div).await?;
+        imu.write(MPU_GYRO_CONFIG, (config.gyro_range as u8) << 3)
+            .await?;
+        imu.write(MPU_ACCEL_CONFIG, (config.accel_range as u8) << 3)
+            .await?;
+        imu.write(MPU_USER_CTRL, MPU_USER_FIFO_RESET).await?;
+        imu.write(MPU_USER_CTRL, MPU_USER_FIFO_EN).await?;
+        imu.write(MPU_FIFO_EN, MPU_FIFO_ACCEL_GYRO).await?;
+        imu.write(MPU_INT_ENABLE, MPU_INT_DATA_RDY | MPU_INT_FIFO_OFLOW)
+            .await?;
+        Ok(imu)
+    }
+
+    pub async fn next_sample(&mut self) -> Result<Sample, Error<I::Error, P::Error>> {
+        while self.burst_pos == self.burst_len {
+            self.fill().await?;
+        }
+        let raw = &self.burst[self.burst_pos];
+        self.burst_pos += 1;
+        Ok(scale(raw, self.config.accel_range, self.config.gyro_range))
+    }
+
+    pub fn release(self) -> (I, P) {
+        (self.i2c, self.int)
+    }
+
+    async fn fill(&mut self) -> Result<(), Error<I::Error, P::Error>> {
+        let mut count = [0; 2];
+        self.read_burst(MPU_FIFO_COUNT_H, &mut count).await?;
+        let mut samples = u16::from_be_bytes(count) as usize / SAMPLE_BYTES;
+        if samples == 0 {
+            self.int.wait_for_high().await.map_err(Error::Pin)?;
+            // Reading INT_STATUS clears the interrupt.
+            let status = self.read(MPU_INT_STATUS).await?;
+            if status & MPU_INT_FIFO_OFLOW != 0 {
+                self.write(MPU_USER_CTRL, MPU_USER_FIFO_EN | MPU_USER_FIFO_RESET)
+                    .await?;
+                return Err(Error::Overflow);
+            }
+            self.read_burst(MPU_FIFO_COUNT_H, &mut count).await?;
+            samples = u16::from_be_bytes(count) as usize / SAMPLE_BYTES;
+        }
+        let samples = samples.min(BURST);
+        let bytes = samples * SAMPLE_BYTES;
+        let mut buf = [0; BURST * SAMPLE_BYTES];
+        self.read_burst(MPU_FIFO_R_W, &mut buf[..bytes]).await?;
+        for (sample, chunk) in self.burst.iter_mut().zip(buf[..bytes].chunks(SAMPLE_BYTES)) {
+            sample.copy_from_slice(chunk);
+        }
+        self.burst_pos = 0;
+        self.burst_len = samples;
+        Ok(())
+    }
+
+    async fn read(&mut self, reg: u8) -> Result<u8, Error<I::Error, P::Error>> {
+        let mut value = [0];
+        self.read_burst(reg, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn read_burst(
+        &mut self,
+        reg: u8,
+        buf: &mut [u8],
+    ) -> Result<(), Error<I::Error, P::Error>> {
+        self.i2c
+            .write_read(self.address, &[reg], buf)
+            .await
+            .map_err(Error::I2c)
+    }
+
+    async fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<I::Error, P::Error>> {
+        self.i2c
+            .write(self.address, &[reg, value])
+            .await
+            .map_err(Error::I2c)
+    }
+}
+
+// ICM-20948 registers are split over four banks.
+const ICM_REG_BANK_SEL: u8 = 0x7F;
+const ICM_WHO_AM_I: u8 = 0x00;
+const ICM_PWR_MGMT_1: u8 = 0x06;
+const ICM_INT_PIN_CFG: u8 = 0x0F;
+const ICM_INT_ENABLE_1: u8 = 0x11;
+const ICM_INT_STATUS_1: u8 = 0x1A;
+const ICM_ACCEL_XOUT_H: u8 = 0x2D;
+// Bank 2.
+const ICM_GYRO_SMPLRT_DIV: u8 = 0x00;
+const ICM_GYRO_CONFIG_1: u8 = 0x01;
+const ICM_ACCEL_SMPLRT_DIV_2: u8 = 0x11;
+const ICM_ACCEL_CONFIG: u8 = 0x14;
+
+const ICM_ID: u8 = 0xEA;
+
+pub struct Icm20948<I, P> {
+    i2c: I,
+    int: P,
+    address: u8,
+    config: Config,
+}
+
+impl<I: I2c, P: Wait> Icm20948<I, P> {
+    // `address` is 0x69, or 0x68 with AD0 low.
+    pub async fn new(
+        i2c: I,
+        int: P,
+        address: u8,
+        config: Config,
+    ) -> Result<Self, Error<I::Error, P::Error>> {
+        let mut imu = Icm20948 {
+            i2c,
+            int,
+            address,
+            config,
+        };
+        imu.bank(0).await?;
+        let who = imu.read(ICM_WHO_AM_I).await?;
+        if who != ICM_ID {
+            return Err(Error::UnknownChip(who));
+        }
+        // Wake up with the best available clock.
+        imu.write(ICM_PWR_MGMT_1, 0x01).await?;
+        // Hold INT until the status is read, so a slow task can't miss the edge.
+        imu.write(ICM_INT_PIN_CFG, 0x20).await?;
+        imu.write(ICM_INT_ENABLE_1, 0x01).await?;
+
+        imu.bank(2).await?;
+        imu.write(ICM_GYRO_SMPLRT_DIV, config.sample_rate_div)
+            .await?;
+        // Low pass filter on (FCHOICE), at its widest.
+        imu.write(ICM_GYRO_CONFIG_1, (config.gyro_range as u8) << 1 | 0x01)
+            .await?;
+        imu.write(ICM_ACCEL_SMPLRT_DIV_2, config.sample_rate_div)
+            .await?;
+        imu.write(ICM_ACCEL_CONFIG, (config.accel_range as u8) << 1 | 0x01)
+            .await?;
+        imu.bank(0).await?;
+        Ok(imu)
+    }
+
+    pub async fn next_sample(&mut self) -> Result<Sample, Error<I::Error, P::Error>> {
+        self.int.wait_for_high().await.map_err(Error::Pin)?;
+        let mut raw = [0; 12];
+        self.i2c
+            .write_read(self.address, &[ICM_ACCEL_XOUT_H], &mut raw)
+            .await
+            .map_err(Error::I2c)?;
+        // Clears the latched interrupt.
+        self.read(ICM_INT_STATUS_1).await?;
+        Ok(scale(&raw, self.config.accel_range, self.config.gyro_range))
+    }
+
+    pub fn release(self) -> (I, P) {
+        (self.i2c, self.int)
+    }
+
+    async fn bank(&mut self, bank: u8) -> Result<(), Error<I::Error, P::Error>> {
+        self.write(ICM_REG_BANK_SEL, bank << 4).await
+    }
+
+    async fn read(&mut self, reg: u8) -> Result<u8, Error<I::Error, P::Error>> {
+        let mut value = [0];
+        self.i2c
+            .write_read(self.address, &[reg], &mut value)
+            .await
+            .map_err(Error::I2c)?;
+        Ok(value[0])
+    }
+
+    async fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<I::Error, P::Error>> {
+        self.i2c
+            .write(self.address, &[reg, value])
+            .await
+            .map_err(Error::I2c)
+    }
+}
@@ -0,0 +1,144 @@
+// Typed ownership of interrupt lines.
+//
+// Each of the RP2040's 26 interrupts has a marker type here, named as in the PAC, and an
+// `Irq<L>` is the right to use line `L`: to wait on it through the reactor, or to put a raw
+// handler on it. There's only ever one of each, so a driver that takes one in its constructor
+// knows nobody else is unmasking its interrupt or stealing its handler, and two drivers that
+// want the same line don't both get it.
+//
+//     let irq = Irq::<UART0_IRQ>::take().unwrap();
+//     let uart = Uart::new(pac.UART0, irq, 0, 1, &Config::default(), peri_hz);
+//
+// Some lines belong to the runtime as soon as it uses them: TIMER_IRQ_0 for the TIMER time
+// driver, IO_IRQ_BANK0 for `gpio::Capture` and `gpio::Input`, PIO0_IRQ_0 and PIO1_IRQ_0 for
+// `pio`, DMA_IRQ_0 and DMA_IRQ_1 for `dma`, and SIO_IRQ_PROC0 for `lockstep`.
+//
+// The types only guarantee that a line has one driver. Whether the runtime wants it too is
+// only known once it uses it, so that's checked at run time: a driver holding a line the
+// runtime later needs is a fatal error when the runtime gets there, not a compile error.
+
+#![allow(non_camel_case_types)]
+
+use core::{marker::PhantomData, task::Waker};
+
+use rp2040_pac::Interrupt;
+
+use crate::{
+    atomic::{self, AtomicU32, Ordering},
+    postmortem, reactor,
+};
+
+// An interrupt line, as a type.
+pub trait Line {
+    const INTERRUPT: Interrupt;
+}
+
+macro_rules! lines {
+    ($($name:ident),* $(,)?) => {
+        $(
+            pub enum $name {}
+
+            impl Line for $name {
+                const INTERRUPT: Interrupt = Interrupt::$name;
+            }
+        )*
+    };
+}
+
+lines!(
+    TIMER_IRQ_0,
+    TIMER_IRQ_1,
+    TIMER_IRQ_2,
+    TIMER_IRQ_3,
+    PWM_IRQ_WRAP,
+    USBCTRL_IRQ,
+    XIP_IRQ,
+    PIO0_IRQ_0,
+    PIO0_IRQ_1,
+    PIO1_IRQ_0,
+    PIO1_IRQ_1,
+    DMA_IRQ_0,
+    DMA_IRQ_1,
+    IO_IRQ_BANK0,
+    IO_IRQ_QSPI,
+    SIO_IRQ_PROC0,
+    SIO_IRQ_PROC1,
+    CLOCKS_IRQ,
+    SPI0_IRQ,
+    SPI1_IRQ,
+    UART0_IRQ,
+    UART1_IRQ,
+    ADC_IRQ_FIFO,
+    I2C0_IRQ,
+    I2C1_IRQ,
+    RTC_IRQ,
+);
+
+// A bit per line that has an owner, and the ones among those that the runtime has kept.
+static TAKEN: AtomicU32 = AtomicU32::new(0);
+static RUNTIME: AtomicU32 = AtomicU32::new(0);
+
+fn bit(irq: Interrupt) -> u32 {
+    1 << irq as u32
+}
+
+// Keep `irq` for the runtime. Fine to call again for a line it already has; fatal if a driver
+// has it.
+pub(crate) fn reserve(irq: Interrupt) {
+    let bit = bit(irq);
+    // In one go, so both cores reserving the same line at once can't see it half done.
+    let driver_has_it = atomic::with(|| {
+        if RUNTIME.load(Ordering::Relaxed) & bit != 0 {
+            return false;
+        }
+        if TAKEN.load(Ordering::Relaxed) & bit != 0 {
+            return true;
+        }
+        TAKEN.store(TAKEN.load(Ordering::Relaxed) | bit, Ordering::Relaxed);
+        RUNTIME.store(RUNTIME.load(Ordering::Relaxed) | bit, Ordering::Relaxed);
+        false
+    });
+    if driver_has_it {
+        postmortem::fatal("the runtime needs an IRQ a driver has taken");
+    }
+}
+
+// The right to use interrupt line `L`. Dropping it gives the line back.
+pub struct Irq<L: Line> {
+    line: PhantomData<L>,
+}
+
+impl<L: Line> Irq<L> {
+    // The line, if nobody has it.
+    pub fn take() -> Option<Self> {
+        let bit = bit(L::INTERRUPT);
+        if TAKEN.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            return None;
+        }
+        Some(Irq { line: PhantomData })
+    }
+
+    pub fn interrupt(&self) -> Interrupt {
+        L::INTERRUPT
+    }
+
+    // `reactor::register` for this line.
+    pub fn register(&self, waker: &Waker) {
+        reactor::register(L::INTERRUPT, waker)
+    }
+
+    // `reactor::set_raw_handler` for this line.
+    pub fn set_raw_handler(&mut self, handler: extern "C" fn()) -> Option<extern "C" fn()> {
+        reactor::set_raw_handler(L::INTERRUPT, handler)
+    }
+
+    pub fn clear_raw_handler(&mut self) -> Option<extern "C" fn()> {
+        reactor::clear_raw_handler(L::INTERRUPT)
+    }
+}
+
+impl<L: Line> Drop for Irq<L> {
+    fn drop(&mut self) {
+        TAKEN.fetch_and(!bit(L::INTERRUPT), Ordering::AcqRel);
+    }
+}
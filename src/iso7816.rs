@@ -0,0 +1,569 @@
+// Smart cards and secure elements with an ISO 7816-3 contact interface: reset and ATR, then
+// APDUs over T=0 or T=1.
+//
+// The I/O line is half duplex and open drain, so it's a `PioUartRx` and an open drain
+// `PioUartTx` on the same pin: everything sent is also received, and reading those echoes
+// back is how T=0's error signal is seen. A card that gets a character with bad parity pulls
+// the line low through the first guard bit, which looks like a bad stop bit on the echo, and
+// the character is sent again. Going the other way, the error signal would have to be sent
+// within half a bit of the parity bit arriving, which the FIFO makes impossible; a character
+// from the card with bad parity fails the exchange with `Error::Parity` instead.
+//
+// The card needs a clock, 1 to 5 MHz, which isn't this module's business:
+//
+//     let card_hz = clocks::start_gpout(0, 4_000_000);
+//     let mut card = SmartCard::new(0, 6, 7, card_hz, clocks::sys_hz())?;
+//     card.reset().await?;
+//     let n = card.transmit(&[0x00, 0xa4, 0x04, 0x00, 0x05, ...], &mut response).await?;
+//
+// The card runs at the default rate throughout: Fi = 372, Di = 1, no PPS. Only direct
+// convention cards are supported, and only short APDUs.
+
+use core::time::Duration;
+
+use crate::{
+    pio_uart::{self, Framing, Parity, PioUartRx, PioUartTx, Received},
+    time::{self, Elapsed},
+};
+
+// Clock cycles per bit before any PPS, which is all this does.
+const FI: u32 = 372;
+const MAX_ATR: usize = 33;
+const MAX_INF: usize = 254;
+// Bits from the last character one way to the first the other way; T=1's BGT, which covers
+// T=0's 16 too.
+const TURNAROUND_ETU: u32 = 22;
+// Attempts at a character or block before giving up.
+const RETRIES: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The card didn't answer in time.
+    Timeout,
+    Parity,
+    Framing,
+    Overrun,
+    // The ATR didn't make sense, or its check byte was wrong.
+    Atr,
+    // The card uses inverse convention.
+    InverseConvention,
+    // The card broke the protocol, or a T=1 block failed its check too many times.
+    Protocol,
+    // Not a short APDU.
+    BadApdu,
+    // The response didn't fit.
+    TooLong,
+}
+
+impl From<pio_uart::Error> for Error {
+    fn from(error: pio_uart::Error) -> Self {
+        match error {
+            pio_uart::Error::Framing => Error::Framing,
+            pio_uart::Error::Parity => Error::Parity,
+            pio_uart::Error::Overrun => Error::Overrun,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    T0,
+    T1,
+}
+
+// The card's answer to reset, and what it says about how to talk to it.
+#[derive(Clone, Copy, Debug)]
+pub struct Atr {
+    bytes: [u8; MAX_ATR],
+    len: u8,
+    historical: (u8, u8),
+    pub protocol: Protocol,
+    // Extra guard time between characters to the card, in bits (TC1).
+    pub extra_guard: u8,
+    // T=0's waiting time integer (TC2).
+    pub wi: u8,
+    // T=1's largest block the card takes, and its waiting time integers (TA3, TB3).
+    pub ifsc: u8,
+    pub bwi: u8,
+    pub cwi: u8,
+}
+
+impl Atr {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    // The card's own description of itself, after the interface bytes.
+    pub fn historical(&self) -> &[u8] {
+        &self.bytes[self.historical.0 as usize..self.historical.1 as usize]
+    }
+}
+
+// A T=1 block as received. The NAD is always 0 here and isn't kept.
+struct Block {
+    pcb: u8,
+    len: u8,
+    inf: [u8; MAX_INF],
+}
+
+impl Block {
+    fn inf(&self) -> &[u8] {
+        &self.inf[..self.len as usize]
+    }
+}
+
+pub struct SmartCard {
+    rx: PioUartRx,
+    tx: PioUartTx,
+    rst: u8,
+    etu: Duration,
+    atr: Option<Atr>,
+    // T=1 state: the next send sequence number, the one expected from the card, and the
+    // card's block size.
+    ns: bool,
+    nr: bool,
+    ifsc: usize,
+}
+
+impl SmartCard {
+    // I/O on `io_pin` through two state machines in `block`, and reset on `rst_pin`.
+    // `card_hz` is the clock the card is being given. The card is held in reset until
+    // `reset`. Returns `None` if `block` hasn't got two free state machines or room for the
+    // programs.
+    pub fn new(block: u8, io_pin: u8, rst_pin: u8, card_hz: u32, sys_hz: u32) -> Option<Self> {
+        let framing = Framing {
+            baud: card_hz / FI,
+            data_bits: 8,
+            parity: Parity::Even,
+            // The guard time.
+            stop_bits: 2,
+            break_len: Duration::ZERO,
+            mark_after_break: Duration::ZERO,
+        };
+        // The receiver first: it takes the pin over, and the transmitter makes it open drain.
+        let rx = PioUartRx::new(block, io_pin, &framing, sys_hz)?;
+        let tx = PioUartTx::new_open_drain(block, io_pin, &framing, sys_hz)?;
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << rst_pin) });
+        sio.gpio_oe_set.write(|w| unsafe { w.bits(1 << rst_pin) });
+        io.gpio[rst_pin as usize]
+            .gpio_ctrl
+            .write(|w| unsafe { w.bits(5) }); // FUNCSEL = SIO
+        Some(SmartCard {
+            rx,
+            tx,
+            rst: rst_pin,
+            etu: Duration::from_nanos(FI as u64 * 1_000_000_000 / card_hz as u64),
+            atr: None,
+            ns: false,
+            nr: false,
+            ifsc: 32,
+        })
+    }
+
+    // Reset the card (a warm reset if it was already running) and read its ATR.
+    pub async fn reset(&mut self) -> Result<&Atr, Error> {
+        let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+        self.atr = None;
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << self.rst) });
+        // At least 400 clock cycles.
+        time::sleep(self.etu * 2).await;
+        // Whatever came in before is nothing to do with this ATR.
+        while time::timeout(Duration::ZERO, self.rx.receive())
+            .await
+            .is_ok()
+        {}
+        sio.gpio_out_set.write(|w| unsafe { w.bits(1 << self.rst) });
+        let atr = self.read_atr().await?;
+        self.ns = false;
+        self.nr = false;
+        self.ifsc = atr.ifsc as usize;
+        Ok(self.atr.insert(atr))
+    }
+
+    pub fn atr(&self) -> Option<&Atr> {
+        self.atr.as_ref()
+    }
+
+    // Send the command APDU `apdu` and put the response, status bytes last, in `response`.
+    // Returns its length.
+    pub async fn transmit(&mut self, apdu: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        match self.atr.ok_or(Error::Protocol)?.protocol {
+            Protocol::T0 => self.t0_transmit(apdu, response).await,
+            Protocol::T1 => self.t1_transmit(apdu, response).await,
+        }
+    }
+
+    // Power the card down: reset held low. The clock is the caller's to stop.
+    pub fn deactivate(&mut self) {
+        let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << self.rst) });
+        self.atr = None;
+    }
+
+    pub fn release(self) -> (u8, u8) {
+        let rst = self.rst;
+        self.tx.release();
+        (self.rx.release(), rst)
+    }
+
+    async fn read_atr(&mut self) -> Result<Atr, Error> {
+        // The initial waiting time, which is what the card has between characters.
+        let wait = self.etu * 9600;
+        let mut atr = Atr {
+            bytes: [0; MAX_ATR],
+            len: 0,
+            historical: (0, 0),
+            protocol: Protocol::T0,
+            extra_guard: 0,
+            wi: 10,
+            ifsc: 32,
+            bwi: 4,
+            cwi: 13,
+        };
+        let mut next = async |atr: &mut Atr| -> Result<u8, Error> {
+            let byte = self.receive_byte(wait).await?;
+            *atr.bytes.get_mut(atr.len as usize).ok_or(Error::Atr)? = byte;
+            atr.len += 1;
+            Ok(byte)
+        };
+        match next(&mut atr).await? {
+            0x3b => {}
+            // 0x3f, read the wrong way round.
+            0x03 => return Err(Error::InverseConvention),
+            _ => return Err(Error::Atr),
+        }
+        let t0 = next(&mut atr).await?;
+        let historical = t0 & 0x0f;
+        let mut y = t0 >> 4;
+        let mut protocol = None;
+        let mut needs_tck = false;
+        // The interface bytes come in groups, each saying which of TA, TB, TC and TD follow.
+        for i in 1.. {
+            let ta = if y & 1 != 0 {
+                Some(next(&mut atr).await?)
+            } else {
+                None
+            };
+            let tb = if y & 2 != 0 {
+                Some(next(&mut atr).await?)
+            } else {
+                None
+            };
+            let tc = if y & 4 != 0 {
+                Some(next(&mut atr).await?)
+            } else {
+                None
+            };
+            let td = if y & 8 != 0 {
+                Some(next(&mut atr).await?)
+            } else {
+                None
+            };
+            match i {
+                // 255 is the minimum, which is what this does anyway.
+                1 => atr.extra_guard = tc.filter(|&n| n != 255).unwrap_or(0),
+                2 => atr.wi = tc.unwrap_or(10),
+                // The first group after T=1 is offered.
+                _ if protocol == Some(Protocol::T1) => {
+                    atr.ifsc = ta.unwrap_or(atr.ifsc);
+                    if let Some(tb) = tb {
+                        (atr.bwi, atr.cwi) = (tb >> 4, tb & 0x0f);
+                    }
+                }
+                _ => {}
+            }
+            let Some(td) = td else { break };
+            let offered = match td & 0x0f {
+                0 => Some(Protocol::T0),
+                1 => Some(Protocol::T1),
+                _ => None,
+            };
+            needs_tck |= td & 0x0f != 0;
+            // The first protocol offered is the one the card starts in.
+            if i == 1 {
+                atr.protocol = offered.ok_or(Error::Atr)?;
+            }
+            protocol = offered;
+            y = td >> 4;
+        }
+        let start = atr.len;
+        for _ in 0..historical {
+            next(&mut atr).await?;
+        }
+        atr.historical = (start, atr.len);
+        if needs_tck {
+            next(&mut atr).await?;
+            // TCK makes everything after TS XOR to zero.
+            if atr.bytes[1..atr.len as usize].iter().fold(0, |x, b| x ^ b) != 0 {
+                return Err(Error::Atr);
+            }
+        }
+        Ok(atr)
+    }
+
+    // Send `bytes` to the card, after the turnaround time.
+    async fn send(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let t0 = self.atr.is_some_and(|atr| atr.protocol == Protocol::T0);
+        let extra_guard = self.etu * self.atr.map_or(0, |atr| atr.extra_guard) as u32;
+        time::sleep(self.etu * TURNAROUND_ETU).await;
+        for &byte in bytes {
+            let mut tries = 0;
+            loop {
+                self.tx.write_char(byte).await;
+                // Our own character, off the shared line.
+                match self.receive_byte(self.etu * 24).await {
+                    Ok(_) => break,
+                    // The card signalled a parity error: send it again after the signal.
+                    Err(Error::Framing) if t0 && tries < RETRIES => {
+                        tries += 1;
+                        time::sleep(self.etu * 2).await;
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            if extra_guard > Duration::ZERO {
+                time::sleep(extra_guard).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive_byte(&mut self, timeout: Duration) -> Result<u8, Error> {
+        match time::timeout(timeout, self.rx.receive()).await {
+            Ok(Ok(Received::Char { data, .. })) => Ok(data),
+            // Nobody sends breaks on this line.
+            Ok(Ok(Received::Break { .. })) => Err(Error::Framing),
+            Ok(Err(error)) => Err(error.into()),
+            Err(Elapsed) => Err(Error::Timeout),
+        }
+    }
+
+    async fn t0_transmit(&mut self, apdu: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        let (data, le): (&[u8], _) = match apdu.len() {
+            0..4 => return Err(Error::BadApdu),
+            4 => (&[], None),
+            5 => (&[], Some(apdu[4])),
+            len => {
+                let lc = apdu[4] as usize;
+                if lc == 0 || (len != 5 + lc && len != 6 + lc) {
+                    return Err(Error::BadApdu);
+                }
+                (&apdu[5..5 + lc], apdu.get(5 + lc).copied())
+            }
+        };
+        let mut header = [apdu[0], apdu[1], apdu[2], apdu[3], 0];
+        header[4] = if data.is_empty() {
+            le.unwrap_or(0)
+        } else {
+            data.len() as u8
+        };
+        let mut received = 0;
+        let mut sw = self
+            .t0_command(header, data, response, &mut received)
+            .await?;
+        loop {
+            match sw[0] {
+                // Wrong Le; the right one is in SW2.
+                0x6c if data.is_empty() => {
+                    header[4] = sw[1];
+                    sw = self
+                        .t0_command(header, &[], response, &mut received)
+                        .await?;
+                }
+                // SW2 bytes of response are waiting: GET RESPONSE.
+                0x61 => {
+                    let get_response = [0x00, 0xc0, 0x00, 0x00, sw[1]];
+                    sw = self
+                        .t0_command(get_response, &[], response, &mut received)
+                        .await?;
+                }
+                _ => break,
+            }
+        }
+        response
+            .get_mut(received..received + 2)
+            .ok_or(Error::TooLong)?
+            .copy_from_slice(&sw);
+        Ok(received + 2)
+    }
+
+    // One T=0 command: the header, then data one way or the other as the card's procedure
+    // bytes ask, until it sends the status bytes. Received data goes into `response` from
+    // `received` on.
+    async fn t0_command(
+        &mut self,
+        header: [u8; 5],
+        data: &[u8],
+        response: &mut [u8],
+        received: &mut usize,
+    ) -> Result<[u8; 2], Error> {
+        let wait = self.etu * (960 * self.atr.map_or(10, |atr| atr.wi) as u32);
+        self.send(&header).await?;
+        let ins = header[1];
+        let mut sent = 0;
+        let mut left = match (data.is_empty(), header[4]) {
+            (false, _) => 0,
+            (true, 0) => 256,
+            (true, le) => le as usize,
+        };
+        loop {
+            let procedure = self.receive_byte(wait).await?;
+            match procedure {
+                // NULL: the card wants more time.
+                0x60 => {}
+                0x61..=0x6f | 0x90..=0x9f => {
+                    return Ok([procedure, self.receive_byte(wait).await?]);
+                }
+                // INS: all the rest; its complement: one byte.
+                _ if procedure == ins || procedure == !ins => {
+                    let all = procedure == ins;
+                    if sent < data.len() {
+                        let n = if all { data.len() - sent } else { 1 };
+                        self.send(&data[sent..sent + n]).await?;
+                        sent += n;
+                    } else if left > 0 {
+                        let n = if all { left } else { 1 };
+                        for _ in 0..n {
+                            let byte = self.receive_byte(wait).await?;
+                            *response.get_mut(*received).ok_or(Error::TooLong)? = byte;
+                            *received += 1;
+                        }
+                        left -= n;
+                    } else {
+                        return Err(Error::Protocol);
+                    }
+                }
+                _ => return Err(Error::Protocol),
+            }
+        }
+    }
+
+    async fn t1_transmit(&mut self, apdu: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        if apdu.len() < 4 {
+            return Err(Error::BadApdu);
+        }
+        // The command, chained in blocks of up to IFSC bytes. Each but the last is
+        // acknowledged with an R-block asking for the next.
+        let mut offset = 0;
+        let mut reply = loop {
+            let len = self.ifsc.min(apdu.len() - offset);
+            let more = offset + len < apdu.len();
+            let pcb = (self.ns as u8) << 6 | (more as u8) << 5;
+            let reply = self.t1_exchange(pcb, &apdu[offset..offset + len]).await?;
+            self.ns = !self.ns;
+            if !more {
+                break reply;
+            }
+            if !is_r_block(reply.pcb) || (reply.pcb & 1 << 4 != 0) != self.ns {
+                return Err(Error::Protocol);
+            }
+            offset += len;
+        };
+        // The response, which may be chained the other way.
+        let mut received = 0;
+        loop {
+            if reply.pcb & 0x80 != 0 || (reply.pcb & 1 << 6 != 0) != self.nr {
+                return Err(Error::Protocol);
+            }
+            self.nr = !self.nr;
+            let inf = reply.inf();
+            response
+                .get_mut(received..received + inf.len())
+                .ok_or(Error::TooLong)?
+                .copy_from_slice(inf);
+            received += inf.len();
+            if reply.pcb & 1 << 5 == 0 {
+                return Ok(received);
+            }
+            reply = self.t1_exchange(0x80 | (self.nr as u8) << 4, &[]).await?;
+        }
+    }
+
+    // Send a block and get the card's answer to it, dealing with waiting time extensions,
+    // IFS changes, and errors either way along the way.
+    async fn t1_exchange(&mut self, pcb: u8, inf: &[u8]) -> Result<Block, Error> {
+        let cwt = self.etu * (11 + (1 << self.atr.map_or(13, |atr| atr.cwi)));
+        let bwt = self.etu * (11 + 960 * (1 << self.atr.map_or(4, |atr| atr.bwi)));
+        let mut errors = 0;
+        let mut wtx = 1;
+        self.send_block(pcb, inf).await?;
+        loop {
+            let block = match self.receive_block(bwt * wtx, cwt).await {
+                Ok(block) => block,
+                Err(error) => {
+                    errors += 1;
+                    if errors > RETRIES {
+                        return Err(error);
+                    }
+                    // An R-block with the error bit: send that again.
+                    self.send_block(0x80 | (self.nr as u8) << 4 | 1, &[])
+                        .await?;
+                    continue;
+                }
+            };
+            wtx = 1;
+            match block.pcb {
+                // S(WTX request): the card needs this many BWTs for this one.
+                0xc3 => {
+                    wtx = block.inf().first().copied().unwrap_or(1).max(1) as u32;
+                    self.send_block(0xe3, block.inf()).await?;
+                }
+                // S(IFS request)
+                0xc1 => {
+                    self.ifsc = block.inf().first().copied().unwrap_or(32).max(1) as usize;
+                    self.send_block(0xe1, block.inf()).await?;
+                }
+                // An R-block asking again for the I-block just sent.
+                r if is_r_block(r)
+                    && pcb & 0x80 == 0
+                    && (r & 1 << 4 != 0) == (pcb & 1 << 6 != 0) =>
+                {
+                    errors += 1;
+                    if errors > RETRIES {
+                        return Err(Error::Protocol);
+                    }
+                    self.send_block(pcb, inf).await?;
+                }
+                _ => return Ok(block),
+            }
+        }
+    }
+
+    async fn send_block(&mut self, pcb: u8, inf: &[u8]) -> Result<(), Error> {
+        let mut block = [0; 3 + MAX_INF + 1];
+        let len = inf.len();
+        block[1] = pcb;
+        block[2] = len as u8;
+        block[3..3 + len].copy_from_slice(inf);
+        block[3 + len] = block[..3 + len].iter().fold(0, |x, b| x ^ b);
+        self.send(&block[..3 + len + 1]).await
+    }
+
+    // The first character has `bwt` to arrive, and the rest `cwt` each.
+    async fn receive_block(&mut self, bwt: Duration, cwt: Duration) -> Result<Block, Error> {
+        let nad = self.receive_byte(bwt).await?;
+        let pcb = self.receive_byte(cwt).await?;
+        let len = self.receive_byte(cwt).await?;
+        let mut block = Block {
+            pcb,
+            len,
+            inf: [0; MAX_INF],
+        };
+        let mut lrc = nad ^ pcb ^ len;
+        for i in 0..len as usize {
+            let byte = self.receive_byte(cwt).await?;
+            *block.inf.get_mut(i).ok_or(Error::Protocol)? = byte;
+            lrc ^= byte;
+        }
+        if self.receive_byte(cwt).await? != lrc {
+            return Err(Error::Parity);
+        }
+        Ok(block)
+    }
+}
+
+fn is_r_block(pcb: u8) -> bool {
+    pcb & 0xc0 == 0x80
+}
@@ -2,11 +2,9 @@
 // MIT License Copyright (c) 2021 rp-rs organization
 // Run a function on the second thread
 
-use core::{
-    mem::ManuallyDrop,
-    panic,
-    sync::atomic::{compiler_fence, Ordering},
-};
+use core::{mem::ManuallyDrop, panic};
+
+use crate::sync::StaticCell;
 
 /// Data type for a properly aligned stack of N 32-bit (usize) words
 #[repr(C, align(32))]
@@ -15,16 +13,11 @@ pub struct Stack<const SIZE: usize> {
     pub mem: [usize; SIZE],
 }
 
-impl<const SIZE: usize> Stack<SIZE> {
-    /// Construct a stack of length SIZE, initialized to 0
-    pub const fn new() -> Stack<SIZE> {
-        Stack { mem: [0; SIZE] }
-    }
-}
+const STACK_WORDS: usize = 4096;
 
-static mut STACK: Stack<4096> = Stack::new();
+static STACK: StaticCell<Stack<STACK_WORDS>> = StaticCell::new();
 
-/// Spawn a function on this core.
+/// Spawn a function on the second core. Can only be done once.
 pub fn spawn<F>(entry: F)
 where
     F: FnOnce() -> ! + Send + 'static,
@@ -38,10 +31,12 @@ where
         entry: &mut ManuallyDrop<F>,
         stack_bottom: *mut usize,
     ) -> ! {
-        let core = unsafe { rp2040_pac::CorePeripherals::steal() };
+        // Only this function touches core 1's MPU, so there's no need to claim its core
+        // peripherals and keep them from the closure.
+        let mpu = unsafe { &*cortex_m::peripheral::MPU::PTR };
 
         // Trap if MPU is already configured
-        if core.MPU.ctrl.read() != 0 {
+        if mpu.ctrl.read() != 0 {
             cortex_m::asm::udf();
         }
 
@@ -51,9 +46,9 @@ where
         // Mask is 1 bit per 32 bytes of the 256 byte range... clear the bit for the segment we want
         let subregion_select = 0xff ^ (1 << ((addr >> 5) & 7));
         unsafe {
-            core.MPU.ctrl.write(5); // enable mpu with background default map
-            core.MPU.rbar.write((addr & !0xff) | 0x8);
-            core.MPU.rasr.write(
+            mpu.ctrl.write(5); // enable mpu with background default map
+            mpu.rbar.write((addr & !0xff) | 0x8);
+            mpu.rasr.write(
                 1 // enable region
                | (0x7 << 1) // size 2^(7 + 1) = 256
                | (subregion_select << 8)
@@ -61,6 +56,8 @@ where
             );
         }
 
+        // Pairs with the `publish` on core 0 before it sent us the stack pointer.
+        crate::barrier::acquire();
         let entry = unsafe { ManuallyDrop::take(entry) };
 
         // Signal that it's safe for core 0 to get rid of the original value now.
@@ -74,6 +71,9 @@ where
         sio.fifo_wr.write(|w| unsafe { w.bits(1) });
         cortex_m::asm::sev();
 
+        // We booted with core 0's vector table; give this core a copy of its own.
+        crate::vectors::init();
+
         entry()
     }
 
@@ -86,10 +86,12 @@ where
     }
     psm.frce_off.modify(|_, w| w.proc1().clear_bit());
 
-    let stack = unsafe { &mut STACK.mem };
+    // Left uninitialized: it's only ever written before it's read, and 16 KiB is too much to
+    // move through this core's stack.
+    let stack = STACK.uninit().as_mut_ptr().cast::<usize>();
 
     // Set up the stack
-    let mut stack_ptr = unsafe { stack.as_mut_ptr().add(stack.len()) };
+    let mut stack_ptr = unsafe { stack.add(STACK_WORDS) };
 
     // We don't want to drop this, since it's getting moved to the other core.
     let mut entry = ManuallyDrop::new(entry);
@@ -98,24 +100,18 @@ where
     unsafe {
         // Push `stack_bottom`.
         stack_ptr = stack_ptr.sub(1);
-        stack_ptr.cast::<*mut usize>().write(stack.as_mut_ptr());
+        stack_ptr.cast::<*mut usize>().write(stack);
 
         // Push `entry`.
         stack_ptr = stack_ptr.sub(1);
         stack_ptr.cast::<&mut ManuallyDrop<F>>().write(&mut entry);
     }
 
-    // Make sure the compiler does not reorder the stack writes after to after the
-    // below FIFO writes, which would result in them not being seen by the second
-    // core.
-    //
-    // From the compiler perspective, this doesn't guarantee that the second core
-    // actually sees those writes. However, we know that the RP2040 doesn't have
-    // memory caches, and writes happen in-order.
-    compiler_fence(Ordering::Release);
-
-    let ppb = unsafe { &(*rp2040_pac::PPB::ptr()) };
-    let vector_table = ppb.vtor.read().bits();
+    // The stack writes have to be visible to the second core before the FIFO writes
+    // below tell it where to find them.
+    crate::barrier::publish();
+
+    let vector_table = crate::vectors::current();
 
     // After reset, core 1 is waiting to receive commands over FIFO.
     // This is the sequence to have it jump to some code.
@@ -0,0 +1,170 @@
+//! An async runtime for the RP2040, without a HAL.
+//!
+//! Tasks run on a cooperative executor (`executor`), and futures that wait on peripherals are
+//! woken by interrupts through the reactor (`reactor`). `jumpstart` starts the second core,
+//! and `sync` has the locks, channels and cells that work across both cores and interrupt
+//! handlers. Everything else is drivers and utilities built on those.
+//!
+//! Firmware calls `init` first thing, then hands the core to the executor:
+//!
+//! ```ignore
+//! #[cortex_m_rt::entry]
+//! fn main() -> ! {
+//!     static HEAP: sync::StaticCell<[u8; 128 * 1024]> = sync::StaticCell::new();
+//!     rp2040_async::init_heap(HEAP.uninit());
+//!     rp2040_async::init();
+//!     rp2040_async::executor::run(app())
+//! }
+//! ```
+//!
+//! By default the crate provides the global allocator, over whatever heap `init_heap` is
+//! given, and the panic handler, which records the crash for `postmortem` and reboots if
+//! `safemode` is in use. Turn off the `global-allocator` and `panic-handler` features to bring
+//! your own, e.g. for panic-probe.
+
+// Host unit tests get std, and its allocator and panic handler in place of ours.
+#![cfg_attr(not(test), no_std)]
+#![feature(allocator_api)]
+#![feature(never_type)]
+#![feature(vec_push_within_capacity)]
+// `new` is const so things can go in statics, and `Default` wouldn't be; unsafe functions say
+// what they need in a plain comment; and a `pio::Program` is never empty.
+#![allow(
+    clippy::new_without_default,
+    clippy::missing_safety_doc,
+    clippy::len_without_is_empty
+)]
+
+use core::mem::MaybeUninit;
+#[cfg(all(feature = "panic-handler", not(test)))]
+use core::panic::PanicInfo;
+
+#[cfg(not(feature = "heap-stats"))]
+use alloc_cortex_m::CortexMHeap;
+
+pub mod adc;
+pub mod assets;
+pub mod atecc608;
+pub mod atomic;
+pub mod barrier;
+pub mod bus;
+pub mod clocks;
+pub mod codec;
+pub mod command;
+pub mod crypto;
+pub mod datalog;
+#[cfg(feature = "deadlock-detect")]
+pub mod deadlock;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+pub mod dma;
+pub mod dmx;
+pub mod dsp;
+pub mod esp_at;
+pub mod executor;
+pub mod freq;
+pub mod future;
+pub mod gpio;
+pub mod gps;
+#[cfg(feature = "heap-stats")]
+pub mod heapstats;
+pub mod hostlink;
+pub mod i2c;
+pub mod imu;
+pub mod irq;
+pub mod iso7816;
+pub mod jumpstart;
+pub mod lockstep;
+pub mod logger;
+pub mod lora;
+pub mod math;
+pub mod memory;
+pub mod metrics;
+pub mod persist;
+pub mod pio;
+pub mod pio_uart;
+pub mod postmortem;
+pub mod power;
+pub mod priority;
+pub mod profile;
+pub mod psram;
+pub mod pubsub;
+pub mod radio;
+pub mod ramcheck;
+pub mod rc;
+pub mod reactor;
+pub mod resets;
+pub mod retry;
+pub mod safemode;
+pub mod sampler;
+#[cfg(feature = "sensors")]
+pub mod sensors;
+pub mod sink;
+pub mod soft_uart;
+pub mod spi;
+pub mod spi_flash;
+pub mod stepper;
+pub mod stream;
+pub mod sync;
+pub mod taskinfo;
+pub mod thermal;
+pub mod time;
+pub mod tls;
+pub mod touch;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod uart;
+pub mod ultrasonic;
+pub mod vectors;
+pub mod watchdog;
+
+#[cfg(not(feature = "heap-stats"))]
+#[cfg_attr(all(feature = "global-allocator", not(test)), global_allocator)]
+pub static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+
+#[cfg(feature = "heap-stats")]
+#[cfg_attr(all(feature = "global-allocator", not(test)), global_allocator)]
+pub static ALLOCATOR: heapstats::TracingHeap = heapstats::TracingHeap::empty();
+
+// Give `ALLOCATOR` its heap, as big as the firmware wants to make it. Once, before anything
+// allocates.
+pub fn init_heap<const N: usize>(heap: &'static mut MaybeUninit<[u8; N]>) {
+    static DONE: atomic::AtomicBool = atomic::AtomicBool::new(false);
+    if DONE.swap(true, atomic::Ordering::AcqRel) {
+        postmortem::fatal("init_heap called twice");
+    }
+    // Safety: The memory is ours for good, and the allocator hasn't been given any before.
+    unsafe { ALLOCATOR.init(heap.as_mut_ptr() as usize, N) }
+}
+
+// Bring the runtime up on core 0: the RAM vector table and, with `systick-time`, the time
+// driver. Call it before anything else but `init_heap`, once.
+pub fn init() {
+    #[cfg(feature = "ram-test")]
+    if ramcheck::test_unused().is_err() {
+        postmortem::fatal("RAM test failed");
+    }
+    vectors::init();
+    #[cfg(feature = "systick-time")]
+    time::start();
+}
+
+#[cfg(all(feature = "panic-handler", not(feature = "panic-free"), not(test)))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    postmortem::record(info);
+    safemode::crashed()
+}
+
+// Any panic that survives optimization calls this, and the symbol it calls doesn't exist, so
+// the build fails at link time with the symbol's name as the error. Only meaningful in
+// release builds; debug builds keep every panic path.
+#[cfg(all(feature = "panic-free", not(test)))]
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    extern "Rust" {
+        #[link_name = "\n\nerror: a panic is reachable in a `panic-free` build. Build without the feature and look for callers of core::panicking to find it.\n"]
+        fn panic_is_reachable() -> !;
+    }
+    unsafe { panic_is_reachable() }
+}
@@ -0,0 +1,179 @@
+// Dual-core lockstep: the same computation run on both cores from the same inputs, starting at
+// the same microsecond, with the results compared. A transient fault on either core (a bit
+// flipped in a register, a corrupted stack) shows up as a divergence rather than as a wrong
+// answer that gets acted on.
+//
+//     let mut lockstep = Lockstep::start().unwrap();
+//     match lockstep.run(&control_law, [setpoint, measured]).await {
+//         Ok([output]) => actuator.set(output),
+//         Err(divergence) => enter_safe_state(divergence),
+//     }
+//
+// Core 1 is given over to this: `start` launches it with `jumpstart`, so nothing else can run
+// there, `dsp::spawn_on_core1` included. Each run sends core 1 the computation and its inputs
+// through the SIO FIFO, then a start time on the TIMER a little way ahead; both cores wait for
+// it, compute, and core 1 sends its result words back. Core 0 computes its copy in the calling
+// task, so the executor is held up for as long as the computation takes, as with any inline
+// computation; the wait for core 1's answer sleeps on the FIFO's interrupt.
+//
+// The computation only gets what comes through the FIFO, so it has to be a pure function of
+// its input words: anything it reads from memory could change between the two cores reading
+// it, and a fault in shared memory is the same on both. It's passed as a `&'static` reference,
+// since core 1 may still be running it if a run is abandoned.
+
+use core::{future::poll_fn, task::Poll, time::Duration};
+
+use rp2040_pac::Interrupt;
+
+use crate::{
+    atomic::{AtomicBool, Ordering},
+    irq, jumpstart, reactor, time,
+};
+
+// How far ahead the start time is when it's sent, after the inputs. Core 1 only has to read
+// that one word before it's ready.
+const LEAD_US: u32 = 20;
+
+// How much longer than core 0 took core 1 can take to answer, on top of core 0's own time.
+const SLACK: Duration = Duration::from_millis(1);
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Divergence<const M: usize> {
+    // The cores got different results.
+    Mismatch { core0: [u32; M], core1: [u32; M] },
+    // Core 1 didn't answer in time, or an earlier run was abandoned before it did. Either way
+    // what it's doing is unknown, and every run from now on fails this way.
+    NoAnswer,
+}
+
+pub struct Lockstep {
+    // A run has been started and not finished, so core 1's answer could still turn up.
+    poisoned: bool,
+}
+
+impl Lockstep {
+    // Launch core 1 to run computations for this. `None` if it's been started already.
+    pub fn start() -> Option<Self> {
+        if STARTED.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        jumpstart::spawn(serve);
+        Some(Lockstep { poisoned: false })
+    }
+
+    // Run `f` on `input` on both cores, and return its result if they agree.
+    pub async fn run<F, const N: usize, const M: usize>(
+        &mut self,
+        f: &'static F,
+        input: [u32; N],
+    ) -> Result<[u32; M], Divergence<M>>
+    where
+        F: Fn(&[u32; N]) -> [u32; M] + Sync,
+    {
+        if self.poisoned {
+            return Err(Divergence::NoAnswer);
+        }
+        self.poisoned = true;
+        push(replica::<F, N, M> as fn(usize) as usize as u32);
+        push(f as *const F as usize as u32);
+        for word in input {
+            push(word);
+        }
+        let start = timer_us().wrapping_add(LEAD_US);
+        push(start);
+        wait_until(start);
+        let began = time::Instant::now();
+        let core0 = f(&input);
+        let took = began.elapsed();
+
+        let answer = async {
+            let mut core1 = [0; M];
+            for word in &mut core1 {
+                *word = pop().await;
+            }
+            core1
+        };
+        let core1 = time::timeout(took + SLACK, answer)
+            .await
+            .map_err(|_| Divergence::NoAnswer)?;
+        self.poisoned = false;
+        if core1 != core0 {
+            return Err(Divergence::Mismatch { core0, core1 });
+        }
+        Ok(core0)
+    }
+}
+
+// Core 1's side of `Lockstep::run`, for one computation type: read the inputs and the start
+// time from the FIFO, run `f` (an `&F`) when it comes, and send the result back.
+fn replica<F, const N: usize, const M: usize>(f: usize)
+where
+    F: Fn(&[u32; N]) -> [u32; M] + Sync,
+{
+    let f = unsafe { &*(f as *const F) };
+    let mut input = [0; N];
+    for word in &mut input {
+        *word = pop_blocking();
+    }
+    wait_until(pop_blocking());
+    for word in f(&input) {
+        push(word);
+    }
+}
+
+// Core 1: run computations as they come in, forever.
+fn serve() -> ! {
+    loop {
+        let replica = pop_blocking() as usize;
+        let f = pop_blocking() as usize;
+        // Safety: core 0 only sends `replica` instantiations, with a pointer to the matching
+        // `F`, which is 'static.
+        let replica = unsafe { core::mem::transmute::<usize, fn(usize)>(replica) };
+        replica(f);
+    }
+}
+
+// The low word of the TIMER's microsecond count, which both cores can read, whichever time
+// driver is in use.
+fn timer_us() -> u32 {
+    let timer = unsafe { &*rp2040_pac::TIMER::ptr() };
+    timer.timerawl.read().bits()
+}
+
+fn wait_until(start: u32) {
+    while (start.wrapping_sub(timer_us()) as i32) > 0 {}
+}
+
+fn push(word: u32) {
+    let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+    while !sio.fifo_st.read().rdy().bit_is_set() {
+        cortex_m::asm::nop();
+    }
+    sio.fifo_wr.write(|w| unsafe { w.bits(word) });
+    cortex_m::asm::sev();
+}
+
+fn pop_blocking() -> u32 {
+    let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+    while !sio.fifo_st.read().vld().bit_is_set() {
+        // The other core sends an event with every word.
+        cortex_m::asm::wfe();
+    }
+    sio.fifo_rd.read().bits()
+}
+
+// A word from core 1, sleeping on core 0's FIFO interrupt until there is one.
+async fn pop() -> u32 {
+    let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+    poll_fn(|cx| {
+        if sio.fifo_st.read().vld().bit_is_set() {
+            return Poll::Ready(sio.fifo_rd.read().bits());
+        }
+        irq::reserve(Interrupt::SIO_IRQ_PROC0);
+        reactor::register(Interrupt::SIO_IRQ_PROC0, cx.waker());
+        Poll::Pending
+    })
+    .await
+}
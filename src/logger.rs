@@ -0,0 +1,183 @@
+use core::{
+    fmt::{self, Write},
+    future::poll_fn,
+    mem,
+    task::{Poll, Waker},
+};
+
+use cortex_m::interrupt;
+
+use crate::{sync::Mutex, time::Instant};
+
+// Longest formatted line; anything past this is cut off.
+const LINE_LEN: usize = 128;
+
+// Where drained log output goes: a UART, a USB CDC-ACM port, an RTT channel...
+#[allow(async_fn_in_trait)]
+pub trait Sink {
+    async fn write(&mut self, data: &[u8]);
+}
+
+struct Queue<const CAP: usize> {
+    buf: [u8; CAP],
+    head: usize,
+    len: usize,
+    dropped: usize,
+    drain: Option<Waker>,
+}
+
+impl<const CAP: usize> Queue<CAP> {
+    fn push(&mut self, data: &[u8]) -> bool {
+        if CAP - self.len < data.len() {
+            return false;
+        }
+        for &byte in data {
+            self.buf[(self.head + self.len) % CAP] = byte;
+            self.len += 1;
+        }
+        true
+    }
+
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let len = self.len.min(out.len());
+        for byte in out[..len].iter_mut() {
+            *byte = self.buf[self.head];
+            self.head = (self.head + 1) % CAP;
+        }
+        self.len -= len;
+        len
+    }
+}
+
+// A `log` backend that formats messages into a bounded in-RAM queue of CAP bytes.
+// Logging never waits for the output: when the queue is full the message is dropped
+// and counted, and the count is reported by the drain task once there's room again.
+// Safe to use from tasks and interrupt handlers on either core.
+pub struct Logger<const CAP: usize> {
+    queue: Mutex<Queue<CAP>, 9>,
+}
+
+impl<const CAP: usize> Logger<CAP> {
+    pub const fn new() -> Self {
+        Logger {
+            queue: Mutex::new(Queue {
+                buf: [0; CAP],
+                head: 0,
+                len: 0,
+                dropped: 0,
+                drain: None,
+            }),
+        }
+    }
+
+    // Install this logger as the `log` backend.
+    // Must be called once, before the second core is started.
+    pub fn init(&'static self, level: log::LevelFilter) {
+        // Safety: thumbv6m has no compare-and-swap, so we have to use the racy variants.
+        // Nothing else is running yet, so there's nothing to race with.
+        unsafe {
+            let _ = log::set_logger_racy(self);
+            log::set_max_level_racy(level);
+        }
+    }
+
+    // Write queued output to `sink` forever. Run this as its own task.
+    pub async fn drain<S: Sink>(&self, sink: &mut S) -> ! {
+        let mut chunk = [0; 64];
+        loop {
+            let (len, dropped) = poll_fn(|cx| {
+                interrupt::free(|_| {
+                    let mut queue = self.queue.lock();
+                    let len = queue.pop(&mut chunk);
+                    let dropped = mem::take(&mut queue.dropped);
+                    if len == 0 && dropped == 0 {
+                        queue.drain = Some(cx.waker().clone());
+                        Poll::Pending
+                    } else {
+                        Poll::Ready((len, dropped))
+                    }
+                })
+            })
+            .await;
+            sink.write(&chunk[..len]).await;
+            if dropped > 0 {
+                let mut line = Line::new();
+                let _ = writeln!(line, "[{} log messages dropped]", dropped);
+                sink.write(line.as_bytes()).await;
+            }
+        }
+    }
+}
+
+impl<const CAP: usize> log::Log for Logger<CAP> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = Line::new();
+        let _ = write!(
+            line,
+            "[{} {}] {}",
+            Instant::now().as_micros(),
+            record.level(),
+            record.args()
+        );
+        line.terminate();
+
+        // Interrupts are masked while the queue is locked, so an interrupt handler that logs
+        // can never spin on a lock held by the code it preempted.
+        interrupt::free(|_| {
+            let mut queue = self.queue.lock();
+            if queue.push(line.as_bytes()) {
+                if let Some(drain) = queue.drain.take() {
+                    drain.wake();
+                }
+            } else {
+                queue.dropped += 1;
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+// A fixed-size formatting buffer that truncates instead of failing.
+struct Line {
+    buf: [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            buf: [0; LINE_LEN],
+            len: 0,
+        }
+    }
+
+    // End the line with a newline, overwriting the last byte if it was truncated.
+    fn terminate(&mut self) {
+        if self.len == LINE_LEN {
+            self.len -= 1;
+        }
+        self.buf[self.len] = b'\n';
+        self.len += 1;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(LINE_LEN - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
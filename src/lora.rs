@@ -0,0 +1,347 @@
+// LoRa radios based on the Semtech SX1276/77/78/79 (RFM95/96/98 modules and friends).
+//
+// The chip is driven over any embedded-hal-async SPI device, and its DIO0 line is awaited to
+// find out when a packet has gone out or come in, so the task sleeps in the meantime.
+//
+// Besides plain send/receive, the knobs a LoRaWAN stack needs between transmissions are
+// exposed: frequency, data rate, power, IQ inversion, sync word, and a random source.
+//
+// The newer SX126x chips use a command interface instead of registers and aren't supported yet.
+
+use embedded_hal_async::{
+    digital::Wait,
+    spi::{Operation, SpiDevice},
+};
+
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_OCP: u8 = 0x0B;
+const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+const REG_FIFO_TX_BASE_ADDR: u8 = 0x0E;
+const REG_FIFO_RX_BASE_ADDR: u8 = 0x0F;
+const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_NB_BYTES: u8 = 0x13;
+const REG_PKT_SNR_VALUE: u8 = 0x19;
+const REG_PKT_RSSI_VALUE: u8 = 0x1A;
+const REG_MODEM_CONFIG_1: u8 = 0x1D;
+const REG_MODEM_CONFIG_2: u8 = 0x1E;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_RSSI_WIDEBAND: u8 = 0x2C;
+const REG_INVERT_IQ: u8 = 0x33;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_INVERT_IQ_2: u8 = 0x3B;
+const REG_DIO_MAPPING_1: u8 = 0x40;
+const REG_VERSION: u8 = 0x42;
+const REG_PA_DAC: u8 = 0x4D;
+
+const MODE_LONG_RANGE: u8 = 0x80;
+const MODE_SLEEP: u8 = 0x00;
+const MODE_STANDBY: u8 = 0x01;
+const MODE_TX: u8 = 0x03;
+const MODE_RX_CONTINUOUS: u8 = 0x05;
+
+const IRQ_RX_DONE: u8 = 0x40;
+const IRQ_PAYLOAD_CRC_ERROR: u8 = 0x20;
+const IRQ_TX_DONE: u8 = 0x08;
+
+// DIO0 function, in the top two bits of REG_DIO_MAPPING_1.
+const DIO0_RX_DONE: u8 = 0x00;
+const DIO0_TX_DONE: u8 = 0x40;
+
+const VERSION: u8 = 0x12;
+const FXOSC: u64 = 32_000_000;
+// Bands above this use the high frequency port, which changes the RSSI offset.
+const HF_THRESHOLD: u32 = 779_000_000;
+
+pub const MAX_PAYLOAD: usize = 255;
+
+#[derive(Debug)]
+pub enum Error<S, P> {
+    Spi(S),
+    Pin(P),
+    // REG_VERSION didn't read back as an SX127x.
+    UnknownChip(u8),
+    // A packet arrived with a bad CRC. It's been dropped.
+    Crc,
+    PayloadTooLong,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Bandwidth {
+    Khz7_8 = 0,
+    Khz10_4 = 1,
+    Khz15_6 = 2,
+    Khz20_8 = 3,
+    Khz31_25 = 4,
+    Khz41_7 = 5,
+    Khz62_5 = 6,
+    Khz125 = 7,
+    Khz250 = 8,
+    Khz500 = 9,
+}
+
+// 4/5 to 4/8: how many bits are sent for every 4 bits of data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CodingRate {
+    Cr4_5 = 1,
+    Cr4_6 = 2,
+    Cr4_7 = 3,
+    Cr4_8 = 4,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub frequency_hz: u32,
+    pub bandwidth: Bandwidth,
+    // 6 to 12.
+    pub spreading_factor: u8,
+    pub coding_rate: CodingRate,
+    // 2 to 20 dBm, through the PA_BOOST pin that RFM9x modules use.
+    pub tx_power_dbm: i8,
+    // 0x12 for private networks, 0x34 for LoRaWAN.
+    pub sync_word: u8,
+    pub preamble_len: u16,
+    pub crc: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            frequency_hz: 868_100_000,
+            bandwidth: Bandwidth::Khz125,
+            spreading_factor: 7,
+            coding_rate: CodingRate::Cr4_5,
+            tx_power_dbm: 14,
+            sync_word: 0x12,
+            preamble_len: 8,
+            crc: true,
+        }
+    }
+}
+
+// What the radio measured about a received packet.
+#[derive(Clone, Copy, Debug)]
+pub struct Received {
+    pub len: usize,
+    pub rssi_dbm: i16,
+    // Quarter dB.
+    pub snr_qdb: i8,
+}
+
+pub struct Sx127x<S, P> {
+    spi: S,
+    dio0: P,
+    frequency_hz: u32,
+}
+
+impl<S: SpiDevice, P: Wait> Sx127x<S, P> {
+    // Check the chip is there, switch it to LoRa mode and apply `config`.
+    // The chip should have been reset (pulse NRESET low) beforehand.
+    pub async fn new(spi: S, dio0: P, config: &Config) -> Result<Self, Error<S::Error, P::Error>> {
+        let mut radio = Sx127x {
+            spi,
+            dio0,
+            frequency_hz: config.frequency_hz,
+        };
+        let version = radio.read(REG_VERSION).await?;
+        if version != VERSION {
+            return Err(Error::UnknownChip(version));
+        }
+        // The LoRa bit can only be changed in sleep mode.
+        radio.write(REG_OP_MODE, MODE_SLEEP).await?;
+        radio
+            .write(REG_OP_MODE, MODE_LONG_RANGE | MODE_SLEEP)
+            .await?;
+        // Use the whole FIFO for each direction; only one is used at a time.
+        radio.write(REG_FIFO_TX_BASE_ADDR, 0).await?;
+        radio.write(REG_FIFO_RX_BASE_ADDR, 0).await?;
+        radio.set_mode(MODE_STANDBY).await?;
+        radio.configure(config).await?;
+        Ok(radio)
+    }
+
+    pub async fn configure(&mut self, config: &Config) -> Result<(), Error<S::Error, P::Error>> {
+        self.set_frequency(config.frequency_hz).await?;
+        self.set_modulation(
+            config.bandwidth,
+            config.spreading_factor,
+            config.coding_rate,
+        )
+        .await?;
+        self.set_tx_power(config.tx_power_dbm).await?;
+        self.set_sync_word(config.sync_word).await?;
+        let [msb, lsb] = config.preamble_len.to_be_bytes();
+        self.write_burst(REG_PREAMBLE_MSB, &[msb, lsb]).await?;
+        let config2 = self.read(REG_MODEM_CONFIG_2).await?;
+        let crc = if config.crc { 0x04 } else { 0 };
+        self.write(REG_MODEM_CONFIG_2, (config2 & !0x04) | crc)
+            .await
+    }
+
+    pub async fn set_frequency(&mut self, hz: u32) -> Result<(), Error<S::Error, P::Error>> {
+        // The synthesizer step is FXOSC / 2^19, about 61 Hz.
+        let frf = ((hz as u64) << 19) / FXOSC;
+        let [_, msb, mid, lsb] = (frf as u32).to_be_bytes();
+        self.write_burst(REG_FRF_MSB, &[msb, mid, lsb]).await?;
+        self.frequency_hz = hz;
+        Ok(())
+    }
+
+    pub async fn set_modulation(
+        &mut self,
+        bandwidth: Bandwidth,
+        spreading_factor: u8,
+        coding_rate: CodingRate,
+    ) -> Result<(), Error<S::Error, P::Error>> {
+        let sf = spreading_factor.clamp(6, 12);
+        // Explicit header mode.
+        self.write(
+            REG_MODEM_CONFIG_1,
+            (bandwidth as u8) << 4 | (coding_rate as u8) << 1,
+        )
+        .await?;
+        let config2 = self.read(REG_MODEM_CONFIG_2).await?;
+        self.write(REG_MODEM_CONFIG_2, (config2 & 0x0F) | sf << 4)
+            .await?;
+        // Symbols longer than 16 ms need the low data rate optimization.
+        let slow = (sf >= 11 && bandwidth <= Bandwidth::Khz125)
+            || (sf == 12 && bandwidth == Bandwidth::Khz250);
+        let ldro = if slow { 0x08 } else { 0 };
+        // AGC on.
+        self.write(REG_MODEM_CONFIG_3, ldro | 0x04).await
+    }
+
+    pub async fn set_tx_power(&mut self, dbm: i8) -> Result<(), Error<S::Error, P::Error>> {
+        let dbm = dbm.clamp(2, 20);
+        if dbm > 17 {
+            // +20 dBm mode: enable the high power DAC and raise the current limit.
+            self.write(REG_PA_DAC, 0x87).await?;
+            self.write(REG_OCP, 0x20 | 18).await?;
+            self.write(REG_PA_CONFIG, 0x80 | (dbm - 5) as u8).await
+        } else {
+            self.write(REG_PA_DAC, 0x84).await?;
+            self.write(REG_OCP, 0x20 | 11).await?;
+            self.write(REG_PA_CONFIG, 0x80 | (dbm - 2) as u8).await
+        }
+    }
+
+    pub async fn set_sync_word(&mut self, sync_word: u8) -> Result<(), Error<S::Error, P::Error>> {
+        self.write(REG_SYNC_WORD, sync_word).await
+    }
+
+    // LoRaWAN downlinks are sent with inverted IQ so end devices don't hear each other.
+    pub async fn set_invert_iq(&mut self, invert: bool) -> Result<(), Error<S::Error, P::Error>> {
+        let (iq, iq2) = if invert { (0x66, 0x19) } else { (0x27, 0x1D) };
+        self.write(REG_INVERT_IQ, iq).await?;
+        self.write(REG_INVERT_IQ_2, iq2).await
+    }
+
+    pub async fn transmit(&mut self, payload: &[u8]) -> Result<(), Error<S::Error, P::Error>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::PayloadTooLong);
+        }
+        self.set_mode(MODE_STANDBY).await?;
+        self.write(REG_FIFO_ADDR_PTR, 0).await?;
+        self.write_burst(REG_FIFO, payload).await?;
+        self.write(REG_PAYLOAD_LENGTH, payload.len() as u8).await?;
+        self.write(REG_DIO_MAPPING_1, DIO0_TX_DONE).await?;
+        self.set_mode(MODE_TX).await?;
+        self.dio0.wait_for_high().await.map_err(Error::Pin)?;
+        self.write(REG_IRQ_FLAGS, IRQ_TX_DONE).await?;
+        // The chip drops back to standby by itself.
+        Ok(())
+    }
+
+    // Listen until a packet arrives and copy it into `buf`, truncating if it doesn't fit.
+    // Packets with a bad CRC are reported as errors so a LoRaWAN stack can count them.
+    pub async fn receive(&mut self, buf: &mut [u8]) -> Result<Received, Error<S::Error, P::Error>> {
+        self.write(REG_DIO_MAPPING_1, DIO0_RX_DONE).await?;
+        self.set_mode(MODE_RX_CONTINUOUS).await?;
+        self.dio0.wait_for_high().await.map_err(Error::Pin)?;
+        let flags = self.read(REG_IRQ_FLAGS).await?;
+        self.write(REG_IRQ_FLAGS, flags).await?;
+        self.set_mode(MODE_STANDBY).await?;
+        if flags & IRQ_PAYLOAD_CRC_ERROR != 0 || flags & IRQ_RX_DONE == 0 {
+            return Err(Error::Crc);
+        }
+
+        let len = self.read(REG_RX_NB_BYTES).await? as usize;
+        let start = self.read(REG_FIFO_RX_CURRENT_ADDR).await?;
+        self.write(REG_FIFO_ADDR_PTR, start).await?;
+        let copied = len.min(buf.len());
+        self.read_burst(REG_FIFO, &mut buf[..copied]).await?;
+
+        let snr_qdb = self.read(REG_PKT_SNR_VALUE).await? as i8;
+        let rssi = self.read(REG_PKT_RSSI_VALUE).await? as i16;
+        let offset = if self.frequency_hz >= HF_THRESHOLD {
+            -157
+        } else {
+            -164
+        };
+        Ok(Received {
+            len: copied,
+            rssi_dbm: offset + rssi,
+            snr_qdb,
+        })
+    }
+
+    // Random bits from wideband RSSI noise, e.g. for LoRaWAN DevNonces and backoff jitter.
+    pub async fn random(&mut self) -> Result<u32, Error<S::Error, P::Error>> {
+        self.write(REG_DIO_MAPPING_1, DIO0_RX_DONE).await?;
+        self.set_mode(MODE_RX_CONTINUOUS).await?;
+        let mut value = 0;
+        for _ in 0..32 {
+            value = value << 1 | (self.read(REG_RSSI_WIDEBAND).await? & 1) as u32;
+        }
+        self.set_mode(MODE_STANDBY).await?;
+        Ok(value)
+    }
+
+    // Lowest power mode. Configuration is kept; the next transmit or receive wakes it.
+    pub async fn sleep(&mut self) -> Result<(), Error<S::Error, P::Error>> {
+        self.set_mode(MODE_SLEEP).await
+    }
+
+    pub fn release(self) -> (S, P) {
+        (self.spi, self.dio0)
+    }
+
+    async fn set_mode(&mut self, mode: u8) -> Result<(), Error<S::Error, P::Error>> {
+        self.write(REG_OP_MODE, MODE_LONG_RANGE | mode).await
+    }
+
+    async fn read(&mut self, reg: u8) -> Result<u8, Error<S::Error, P::Error>> {
+        let mut value = [0];
+        self.read_burst(reg, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn read_burst(
+        &mut self,
+        reg: u8,
+        buf: &mut [u8],
+    ) -> Result<(), Error<S::Error, P::Error>> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[reg & 0x7F]), Operation::Read(buf)])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    async fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<S::Error, P::Error>> {
+        self.write_burst(reg, &[value]).await
+    }
+
+    async fn write_burst(&mut self, reg: u8, data: &[u8]) -> Result<(), Error<S::Error, P::Error>> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[reg | 0x80]), Operation::Write(data)])
+            .await
+            .map_err(Error::Spi)
+    }
+}
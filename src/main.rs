@@ -1,32 +1,16 @@
+// The smallest firmware on top of the library: bring the runtime up and run the executor.
+// Real firmware passes its top-level future to `run`.
+
 #![no_std]
 #![no_main]
-#![feature(never_type)]
-
-use core::panic::PanicInfo;
 
-use alloc_cortex_m::CortexMHeap;
 use cortex_m_rt::entry;
-
-mod executor;
-mod jumpstart;
-mod reactor;
-mod sync;
-
-#[global_allocator]
-static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+use rp2040_async::{executor, sync::StaticCell};
 
 #[entry]
 fn main() -> ! {
-    {
-        use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 1024 * 128; // 128 KiB
-        static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-        unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
-    }
-    loop {}
-}
-
-#[panic_handler]
-fn panic(_: &PanicInfo) -> ! {
-    loop {}
+    static HEAP: StaticCell<[u8; 128 * 1024]> = StaticCell::new();
+    rp2040_async::init_heap(HEAP.uninit());
+    rp2040_async::init();
+    executor::run(async {})
 }
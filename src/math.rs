@@ -0,0 +1,3 @@
+// Numeric helpers for a core with no FPU and no hardware multiply-high.
+
+pub mod fixed;
@@ -0,0 +1,328 @@
+// Q15 and Q31 fixed-point numbers, and the trig and square roots that go with them.
+//
+// Both represent values in [-1, 1). Arithmetic saturates instead of wrapping, since a filter
+// that clips degrades gracefully and one that wraps produces garbage. Angles are "binary
+// angles": a u16 where 65536 is a full turn, so they wrap around for free.
+
+use core::ops::{Add, Mul, Neg, Sub};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct Q15(pub i16);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct Q31(pub i32);
+
+impl Q15 {
+    pub const ZERO: Q15 = Q15(0);
+    // Just under 1.
+    pub const MAX: Q15 = Q15(i16::MAX);
+    pub const MIN: Q15 = Q15(i16::MIN);
+
+    // Out of range values saturate.
+    pub const fn from_f32(value: f32) -> Self {
+        Q15((value * 32768.0) as i16)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / 32768.0
+    }
+
+    pub const fn saturating_add(self, rhs: Self) -> Self {
+        Q15(self.0.saturating_add(rhs.0))
+    }
+
+    pub const fn saturating_sub(self, rhs: Self) -> Self {
+        Q15(self.0.saturating_sub(rhs.0))
+    }
+
+    // Rounded. Only -1 * -1 saturates.
+    pub const fn saturating_mul(self, rhs: Self) -> Self {
+        let product = (self.0 as i32 * rhs.0 as i32 + (1 << 14)) >> 15;
+        Q15(saturate_i16(product))
+    }
+
+    // Scale an integer, e.g. a sample, by this factor.
+    pub const fn scale(self, x: i32) -> i32 {
+        ((self.0 as i64 * x as i64 + (1 << 14)) >> 15) as i32
+    }
+
+    // 1 - self, saturating at MAX.
+    pub const fn complement(self) -> Self {
+        Q15(saturate_i16(32768 - self.0 as i32))
+    }
+
+    pub const fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Q15::ZERO;
+        }
+        Q15(isqrt((self.0 as u64) << 15) as i16)
+    }
+}
+
+impl Q31 {
+    pub const ZERO: Q31 = Q31(0);
+    pub const MAX: Q31 = Q31(i32::MAX);
+    pub const MIN: Q31 = Q31(i32::MIN);
+
+    pub const fn from_f32(value: f32) -> Self {
+        Q31((value * 2147483648.0) as i32)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / 2147483648.0
+    }
+
+    pub const fn saturating_add(self, rhs: Self) -> Self {
+        Q31(self.0.saturating_add(rhs.0))
+    }
+
+    pub const fn saturating_sub(self, rhs: Self) -> Self {
+        Q31(self.0.saturating_sub(rhs.0))
+    }
+
+    pub const fn saturating_mul(self, rhs: Self) -> Self {
+        let product = (self.0 as i64 * rhs.0 as i64 + (1 << 30)) >> 31;
+        Q31(if product > i32::MAX as i64 {
+            i32::MAX
+        } else {
+            product as i32
+        })
+    }
+
+    pub const fn scale(self, x: i32) -> i32 {
+        ((self.0 as i64 * x as i64 + (1 << 30)) >> 31) as i32
+    }
+
+    pub const fn complement(self) -> Self {
+        let value = (1i64 << 31) - self.0 as i64;
+        Q31(if value > i32::MAX as i64 {
+            i32::MAX
+        } else {
+            value as i32
+        })
+    }
+
+    pub const fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Q31::ZERO;
+        }
+        Q31(isqrt((self.0 as u64) << 31) as i32)
+    }
+}
+
+impl From<Q15> for Q31 {
+    fn from(value: Q15) -> Self {
+        Q31((value.0 as i32) << 16)
+    }
+}
+
+impl From<Q31> for Q15 {
+    // Rounds to the nearest Q15.
+    fn from(value: Q31) -> Self {
+        let rounded = (value.0 as i64 + (1 << 15)) >> 16;
+        Q15(saturate_i16(rounded.min(i16::MAX as i64) as i32))
+    }
+}
+
+macro_rules! ops {
+    ($t:ident) => {
+        impl Add for $t {
+            type Output = $t;
+            fn add(self, rhs: $t) -> $t {
+                self.saturating_add(rhs)
+            }
+        }
+
+        impl Sub for $t {
+            type Output = $t;
+            fn sub(self, rhs: $t) -> $t {
+                self.saturating_sub(rhs)
+            }
+        }
+
+        impl Mul for $t {
+            type Output = $t;
+            fn mul(self, rhs: $t) -> $t {
+                self.saturating_mul(rhs)
+            }
+        }
+
+        impl Neg for $t {
+            type Output = $t;
+            fn neg(self) -> $t {
+                $t(self.0.saturating_neg())
+            }
+        }
+    };
+}
+
+ops!(Q15);
+ops!(Q31);
+
+const fn saturate_i16(value: i32) -> i16 {
+    if value > i16::MAX as i32 {
+        i16::MAX
+    } else if value < i16::MIN as i32 {
+        i16::MIN
+    } else {
+        value as i16
+    }
+}
+
+// Floor of the square root, bit by bit: no division, which the M0+ would do in software.
+pub const fn isqrt(mut value: u64) -> u32 {
+    let mut root = 0u64;
+    let mut bit = 1u64 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if value >= root + bit {
+            value -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root as u32
+}
+
+// Quarter turns are split into this many table steps.
+const SIN_STEPS: usize = 256;
+
+// sin over the first quarter turn, Q15, one extra entry so interpolation never runs off
+// the end. Built at compile time from a Taylor series, accurate to well under 1 LSB.
+static SIN_TABLE: [i16; SIN_STEPS + 1] = {
+    let mut table = [0; SIN_STEPS + 1];
+    let mut i = 0;
+    while i <= SIN_STEPS {
+        table[i] = sin_series(i);
+        i += 1;
+    }
+    table
+};
+
+// sin(i / SIN_STEPS * pi / 2) in Q15, using Q60 intermediates.
+const fn sin_series(i: usize) -> i16 {
+    const ONE: i128 = 1 << 60;
+    // pi / 2 in Q60.
+    const HALF_PI: i128 = 1_811_004_864_519_280_710;
+    let x = HALF_PI * i as i128 / SIN_STEPS as i128;
+    let x2 = x * x / ONE;
+    // x - x^3/3! + x^5/5! - ..., up to x^11.
+    let mut term = x;
+    let mut sum = x;
+    let mut n = 1;
+    while n < 11 {
+        term = -term * x2 / ONE / ((n + 1) * (n + 2));
+        sum += term;
+        n += 2;
+    }
+    let value = (sum * 32768 + ONE / 2) / ONE;
+    if value > i16::MAX as i128 {
+        i16::MAX
+    } else {
+        value as i16
+    }
+}
+
+// Sine of a binary angle.
+pub fn sin(angle: u16) -> Q15 {
+    let quadrant = angle >> 14;
+    let mut offset = angle & 0x3FFF;
+    // Second and fourth quadrants run the table backwards.
+    if quadrant & 1 != 0 {
+        offset = 0x4000 - offset;
+    }
+    // 64 angle units per table step.
+    let index = (offset >> 6) as usize;
+    let frac = (offset & 0x3F) as i32;
+    let value = if index == SIN_STEPS {
+        SIN_TABLE[SIN_STEPS] as i32
+    } else {
+        let (a, b) = (SIN_TABLE[index] as i32, SIN_TABLE[index + 1] as i32);
+        a + (((b - a) * frac + 32) >> 6)
+    };
+    let value = if quadrant >= 2 { -value } else { value };
+    Q15(value as i16)
+}
+
+pub fn cos(angle: u16) -> Q15 {
+    sin(angle.wrapping_add(0x4000))
+}
+
+// Binary angle from millidegrees, e.g. for angles coming out of `dsp`.
+pub const fn angle_from_mdeg(mdeg: i32) -> u16 {
+    (mdeg as i64 * 65536 / 360_000) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF: Q15 = Q15(0x4000);
+
+    #[test]
+    fn q15_arithmetic() {
+        assert_eq!(HALF * HALF, Q15(0x2000));
+        assert_eq!(HALF + HALF, Q15::MAX);
+        assert_eq!(Q15::MIN - HALF, Q15::MIN);
+        assert_eq!(Q15::MIN * Q15::MIN, Q15::MAX);
+        assert_eq!(-Q15::MIN, Q15::MAX);
+        assert_eq!(HALF.complement(), HALF);
+        assert_eq!(Q15::MIN.complement(), Q15::MAX);
+        assert_eq!(HALF.scale(1001), 501);
+        assert_eq!(Q15::from_f32(-0.25), Q15(-0x2000));
+        assert_eq!(Q15::from_f32(2.0), Q15::MAX);
+        assert_eq!(Q15(0x2000).to_f32(), 0.25);
+    }
+
+    #[test]
+    fn q31_arithmetic() {
+        let half = Q31(1 << 30);
+        assert_eq!(half * half, Q31(1 << 29));
+        assert_eq!(half + half, Q31::MAX);
+        assert_eq!(Q31::MIN * Q31::MIN, Q31::MAX);
+        assert_eq!(Q31::MIN.complement(), Q31::MAX);
+        assert_eq!(half.scale(-1001), -500);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Q31::from(HALF), Q31(1 << 30));
+        assert_eq!(Q15::from(Q31(1 << 30)), HALF);
+        // Rounds to nearest, and saturates rather than wrapping up to -1.
+        assert_eq!(Q15::from(Q31(0x8000)), Q15(1));
+        assert_eq!(Q15::from(Q31::MAX), Q15::MAX);
+    }
+
+    #[test]
+    fn square_roots() {
+        assert_eq!(isqrt(0), 0);
+        assert_eq!(isqrt(99), 9);
+        assert_eq!(isqrt(u64::MAX), u32::MAX);
+        assert_eq!(Q15(0x1000).sqrt(), Q15(0x2d41));
+        assert_eq!(HALF.sqrt(), Q15(23170));
+        assert_eq!(Q15(-1).sqrt(), Q15::ZERO);
+        assert_eq!(Q31(1 << 29).sqrt(), Q31(1 << 30));
+    }
+
+    #[test]
+    fn trig() {
+        assert_eq!(sin(0), Q15::ZERO);
+        assert_eq!(sin(0x4000), Q15::MAX);
+        assert_eq!(sin(0x8000), Q15::ZERO);
+        assert_eq!(sin(0xC000), -Q15::MAX);
+        assert_eq!(cos(0), Q15::MAX);
+        for angle in (0..=u16::MAX).step_by(97) {
+            let exact = (angle as f64 / 65536.0 * core::f64::consts::TAU).sin() * 32768.0;
+            let error = (sin(angle).0 as f64 - exact.min(32767.0)).abs();
+            assert!(error <= 2.0, "sin({angle}) off by {error}");
+        }
+        assert_eq!(angle_from_mdeg(90_000), 0x4000);
+        assert_eq!(angle_from_mdeg(-90_000), 0xC000);
+    }
+}
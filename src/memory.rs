@@ -0,0 +1,143 @@
+// Heaps in particular SRAM banks, for memory that shouldn't share a bank with everything else.
+//
+// The RP2040's main 256 KiB is striped across SRAM0-3 a word at a time, so ordinary data ends
+// up in all four banks and every bus master contends for all of them. Buffers that one master
+// uses all the time (a DMA ring, core 1's working set, a framebuffer being scanned out) go
+// faster, and leave the rest alone, if they have a bank to themselves: SRAM4 and SRAM5, or
+// one of SRAM0-3 through its unstriped alias.
+//
+// Nothing decides the memory map here; that's up to the linker script. Reserve a section in
+// the bank, put a `StaticCell` there and hand it to `add`:
+//
+//     #[link_section = ".sram4"]
+//     static DMA_RAM: StaticCell<[u8; 4096]> = StaticCell::new();
+//     memory::add(Region::Sram4, DMA_RAM.uninit())?;
+//     let ring = Vec::with_capacity_in(1024, In(Region::Sram4));
+//
+// Allocations from a region only ever come from the memory given to it, and fail once it's
+// used up rather than spilling into the global heap.
+
+extern crate alloc;
+
+use core::{
+    alloc::{AllocError, Allocator, GlobalAlloc, Layout},
+    mem::MaybeUninit,
+    ptr::NonNull,
+};
+
+use alloc::boxed::Box;
+use alloc_cortex_m::CortexMHeap;
+
+use crate::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    // The main 256 KiB, striped across SRAM0-3.
+    Striped,
+    // SRAM0-3 on their own, through the unstriped aliases. The same memory as `Striped`, so
+    // the linker script has to keep ordinary data out of whatever is given to these.
+    Sram0,
+    Sram1,
+    Sram2,
+    Sram3,
+    // The two 4 KiB banks above the striped ones.
+    Sram4,
+    Sram5,
+}
+
+const REGIONS: usize = 7;
+
+impl Region {
+    // Where the region is in the address map.
+    pub const fn range(self) -> (usize, usize) {
+        match self {
+            Region::Striped => (0x2000_0000, 0x2004_0000),
+            Region::Sram0 => (0x2100_0000, 0x2101_0000),
+            Region::Sram1 => (0x2101_0000, 0x2102_0000),
+            Region::Sram2 => (0x2102_0000, 0x2103_0000),
+            Region::Sram3 => (0x2103_0000, 0x2104_0000),
+            Region::Sram4 => (0x2004_0000, 0x2004_1000),
+            Region::Sram5 => (0x2004_1000, 0x2004_2000),
+        }
+    }
+
+    // The region `address` is in, if it's in SRAM at all.
+    pub fn of(address: usize) -> Option<Region> {
+        use Region::*;
+        [Striped, Sram0, Sram1, Sram2, Sram3, Sram4, Sram5]
+            .into_iter()
+            .find(|region| {
+                let (start, end) = region.range();
+                (start..end).contains(&address)
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddError {
+    // The memory isn't all inside the region.
+    WrongRegion,
+    // The region already has its memory.
+    AlreadyAdded,
+}
+
+static HEAPS: [CortexMHeap; REGIONS] = [const { CortexMHeap::empty() }; REGIONS];
+// A bit per region that's been given memory.
+static ADDED: AtomicU8 = AtomicU8::new(0);
+
+// Give `region` the memory to allocate from. Each region can only be given memory once.
+pub fn add(region: Region, memory: &'static mut [MaybeUninit<u8>]) -> Result<(), AddError> {
+    let (start, end) = region.range();
+    let address = memory.as_ptr() as usize;
+    if address < start || address + memory.len() > end {
+        return Err(AddError::WrongRegion);
+    }
+    let bit = 1 << region as u8;
+    if ADDED.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+        return Err(AddError::AlreadyAdded);
+    }
+    // Safety: The memory is ours for good, and this heap hasn't been given any before.
+    unsafe { HEAPS[region as usize].init(address, memory.len()) };
+    Ok(())
+}
+
+pub fn alloc_in(region: Region, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+    // A region that hasn't been given memory yet is an empty heap, so this fails cleanly.
+    let ptr = unsafe { HEAPS[region as usize].alloc(layout) };
+    let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+    Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+}
+
+// Safety: `ptr` came from `alloc_in` with the same region and layout, and hasn't been freed.
+pub unsafe fn dealloc_in(region: Region, ptr: NonNull<u8>, layout: Layout) {
+    unsafe { HEAPS[region as usize].dealloc(ptr.as_ptr(), layout) }
+}
+
+// Bytes allocated from `region`, and bytes left.
+pub fn used(region: Region) -> usize {
+    HEAPS[region as usize].used()
+}
+
+pub fn free(region: Region) -> usize {
+    HEAPS[region as usize].free()
+}
+
+// An allocator for the standard containers that takes from one region:
+// `Box::new_in(value, In(Region::Sram5))`, `Vec::with_capacity_in(n, In(Region::Sram0))`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct In(pub Region);
+
+unsafe impl Allocator for In {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        alloc_in(self.0, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { dealloc_in(self.0, ptr, layout) }
+    }
+}
+
+// `Box::try_new_in`, for when the region being full isn't fatal.
+pub fn boxed_in<T>(region: Region, value: T) -> Result<Box<T, In>, AllocError> {
+    Box::try_new_in(value, In(region))
+}
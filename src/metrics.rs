@@ -0,0 +1,196 @@
+// Counters and gauges for monitoring devices in the field.
+//
+// Declare metrics as statics and update them from anywhere, interrupt handlers included:
+//
+//     static PACKETS: Counter = Counter::new("radio.packets");
+//     PACKETS.inc();
+//
+// A metric joins the registry the first time it's updated. `snapshot` reads them all, plus
+// the runtime's own (heap use, run queue depth, interrupt wake rates), and the snapshot
+// serializes with postcard as the metric count followed by a name and value for each. Hosts
+// can poll it over `hostlink` with the `GetMetrics` endpoint, or `export` can push it
+// somewhere, an MQTT topic say, every so often.
+
+use core::time::Duration;
+
+use serde::{ser::SerializeSeq, Serialize, Serializer};
+
+use crate::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    executor, hostlink,
+    sync::Mutex,
+    time,
+};
+
+pub const MAX_METRICS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Value {
+    // Only goes up, wrapping round.
+    Counter(u32),
+    Gauge(i32),
+}
+
+struct Metric {
+    name: &'static str,
+    gauge: bool,
+    value: AtomicU32,
+    registered: AtomicBool,
+}
+
+impl Metric {
+    const fn new(name: &'static str, gauge: bool) -> Self {
+        Metric {
+            name,
+            gauge,
+            value: AtomicU32::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    fn value(&self) -> Value {
+        let bits = self.value.load(Ordering::Relaxed);
+        if self.gauge {
+            Value::Gauge(bits as i32)
+        } else {
+            Value::Counter(bits)
+        }
+    }
+
+    fn touch(&'static self) {
+        if self.registered.load(Ordering::Relaxed) || self.registered.swap(true, Ordering::Relaxed)
+        {
+            return;
+        }
+        let added = REGISTRY.with(|registry| {
+            let free = registry.iter_mut().find(|slot| slot.is_none())?;
+            *free = Some(self);
+            Some(())
+        });
+        if added.is_none() {
+            log::warn!("metrics: registry full, {} not exported", self.name);
+        }
+    }
+}
+
+#[repr(transparent)]
+pub struct Counter(Metric);
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Self {
+        Counter(Metric::new(name, false))
+    }
+
+    pub fn inc(&'static self) {
+        self.add(1)
+    }
+
+    pub fn add(&'static self, n: u32) {
+        self.0.touch();
+        self.0.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.value.load(Ordering::Relaxed)
+    }
+}
+
+#[repr(transparent)]
+pub struct Gauge(Metric);
+
+impl Gauge {
+    pub const fn new(name: &'static str) -> Self {
+        Gauge(Metric::new(name, true))
+    }
+
+    pub fn set(&'static self, value: i32) {
+        self.0.touch();
+        self.0.value.store(value as u32, Ordering::Relaxed);
+    }
+
+    pub fn add(&'static self, delta: i32) {
+        self.0.touch();
+        self.0.value.fetch_add(delta as u32, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i32 {
+        self.0.value.load(Ordering::Relaxed) as i32
+    }
+}
+
+static REGISTRY: Mutex<[Option<&'static Metric>; MAX_METRICS], 2> = Mutex::new([None; MAX_METRICS]);
+
+// The runtime's own metrics, brought up to date by `snapshot`.
+static HEAP_USED: Gauge = Gauge::new("heap.used");
+static QUEUE_HIGH_WATER: Gauge = Gauge::new("executor.queue_high_water");
+static IRQ_WAKES: Gauge = Gauge::new("executor.irq_wakes_per_sec");
+
+// Every registered metric's name and value at one point in time.
+#[derive(Clone, Copy)]
+pub struct Snapshot {
+    entries: [(&'static str, Value); MAX_METRICS],
+    len: usize,
+}
+
+impl Snapshot {
+    pub fn entries(&self) -> &[(&'static str, Value)] {
+        &self.entries[..self.len]
+    }
+}
+
+impl Serialize for Snapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for entry in self.entries() {
+            seq.serialize_element(entry)?;
+        }
+        seq.end()
+    }
+}
+
+pub fn snapshot() -> Snapshot {
+    update_builtin();
+    let metrics = REGISTRY.with(|registry| *registry);
+    let mut snapshot = Snapshot {
+        entries: [("", Value::Counter(0)); MAX_METRICS],
+        len: 0,
+    };
+    for metric in metrics.into_iter().flatten() {
+        snapshot.entries[snapshot.len] = (metric.name, metric.value());
+        snapshot.len += 1;
+    }
+    snapshot
+}
+
+fn update_builtin() {
+    #[cfg(not(feature = "heap-stats"))]
+    HEAP_USED.set(crate::ALLOCATOR.used() as i32);
+    #[cfg(feature = "heap-stats")]
+    HEAP_USED.set(crate::ALLOCATOR.stats().used as i32);
+    let stats = executor::stats();
+    QUEUE_HIGH_WATER.set(stats.queue_high_water as i32);
+    IRQ_WAKES.set(stats.irq_wakes_per_sec.iter().sum::<u32>() as i32);
+}
+
+// Answers with a snapshot of every metric.
+pub struct GetMetrics;
+
+impl hostlink::Endpoint for GetMetrics {
+    // Kinds from 0xff00 up are left for the runtime's own endpoints.
+    const KIND: u16 = 0xff00;
+    type Request = ();
+    type Response = Snapshot;
+}
+
+// Take a snapshot every `interval`, encode it with postcard and hand it to `publish`, forever.
+// `buf` has to fit the encoded snapshot: a few bytes per metric plus the names. Snapshots that
+// don't fit are skipped.
+pub async fn export(interval: Duration, buf: &mut [u8], mut publish: impl AsyncFnMut(&[u8])) -> ! {
+    loop {
+        match postcard::to_slice(&snapshot(), buf) {
+            Ok(encoded) => publish(encoded).await,
+            Err(_) => log::warn!("metrics: snapshot doesn't fit in {} bytes", buf.len()),
+        }
+        time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,260 @@
+// A queue of byte records that survives a soft reset or watchdog reboot, for telemetry that
+// hasn't been sent yet or the last few log lines before a crash.
+//
+// The records live in RAM that the startup code doesn't touch, so it takes two statics: the
+// storage, placed in `.uninit` by the caller, and the ring that manages it.
+//
+//     #[link_section = ".uninit.TELEMETRY"]
+//     static TELEMETRY_RAM: persist::Storage<2048> = persist::Storage::new();
+//     static TELEMETRY: PersistentRing<2048> = PersistentRing::new(&TELEMETRY_RAM);
+//
+// On first use after boot the ring checks what it finds. After a cold boot that's noise and
+// the ring starts empty; after a reset it's the records from before, up to the last one that
+// was completely written. `open` says which. When the ring is full, the oldest records are
+// dropped to make room.
+//
+// Each record is stored as its length (u16) and CRC-16 (u16), then the bytes, wrapping round
+// the end of the buffer. The header saying where the records are is only updated once a
+// record is completely written, so a reset part way through loses at most that record.
+
+use core::{cell::UnsafeCell, mem::MaybeUninit};
+
+use crate::sync::{Mutex, Signal};
+
+const MAGIC: u32 = 0x5045_5253;
+const RECORD_HEADER: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooLarge;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Recovery {
+    // Records that made it through the reset.
+    pub records: usize,
+    // Whether anything was thrown away: everything, after a cold boot or if the header was
+    // damaged, or the records after the first one that failed its CRC.
+    pub discarded: bool,
+}
+
+#[repr(C)]
+struct Raw<const N: usize> {
+    magic: u32,
+    // Where the oldest record starts, and how many bytes of records there are.
+    tail: u32,
+    used: u32,
+    check: u32,
+    data: [u8; N],
+}
+
+impl<const N: usize> Raw<N> {
+    fn header_check(&self) -> u32 {
+        [self.magic, self.tail, self.used, N as u32]
+            .iter()
+            .fold(0x811C_9DC5, |hash, &word| {
+                (hash ^ word).wrapping_mul(0x0100_0193)
+            })
+    }
+
+    fn commit(&mut self) {
+        self.magic = MAGIC;
+        self.check = self.header_check();
+    }
+
+    fn reset(&mut self) {
+        self.tail = 0;
+        self.used = 0;
+        self.commit();
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.data[(self.tail as usize + offset) % N]
+    }
+
+    // The length and CRC of the record `offset` bytes in, if there's a whole record there.
+    fn record_at(&self, offset: usize) -> Option<(usize, u16)> {
+        if offset + RECORD_HEADER > self.used as usize {
+            return None;
+        }
+        let len = u16::from_le_bytes([self.byte(offset), self.byte(offset + 1)]) as usize;
+        let crc = u16::from_le_bytes([self.byte(offset + 2), self.byte(offset + 3)]);
+        (offset + RECORD_HEADER + len <= self.used as usize).then_some((len, crc))
+    }
+
+    fn crc_at(&self, offset: usize, len: usize) -> u16 {
+        crc16((0..len).map(|i| self.byte(offset + RECORD_HEADER + i)))
+    }
+
+    fn drop_oldest(&mut self) {
+        let Some((len, _)) = self.record_at(0) else {
+            self.reset();
+            return;
+        };
+        let size = RECORD_HEADER + len;
+        self.tail = ((self.tail as usize + size) % N) as u32;
+        self.used -= size as u32;
+        self.commit();
+    }
+
+    fn recover(&mut self) -> Recovery {
+        if self.magic != MAGIC
+            || self.check != self.header_check()
+            || self.tail as usize >= N
+            || self.used as usize > N
+        {
+            self.reset();
+            return Recovery {
+                records: 0,
+                discarded: true,
+            };
+        }
+        let (mut offset, mut records) = (0, 0);
+        while let Some((len, crc)) = self.record_at(offset) {
+            if self.crc_at(offset, len) != crc {
+                break;
+            }
+            offset += RECORD_HEADER + len;
+            records += 1;
+        }
+        let discarded = offset != self.used as usize;
+        self.used = offset as u32;
+        self.commit();
+        Recovery { records, discarded }
+    }
+}
+
+// The memory a `PersistentRing` keeps its records in. Put it in `.uninit`; anywhere else it's
+// zeroed at boot like everything else, and the ring starts empty every time.
+pub struct Storage<const N: usize> {
+    raw: UnsafeCell<MaybeUninit<Raw<N>>>,
+}
+
+// Safety: Only `PersistentRing` touches the contents, with its lock held.
+unsafe impl<const N: usize> Sync for Storage<N> {}
+
+impl<const N: usize> Storage<N> {
+    pub const fn new() -> Self {
+        Storage {
+            raw: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+pub struct PersistentRing<const N: usize> {
+    storage: &'static Storage<N>,
+    // What `open` found, once it's been called. Shares a spinlock with `DataLog`.
+    opened: Mutex<Option<Recovery>, 8>,
+    ready: Signal<()>,
+}
+
+impl<const N: usize> PersistentRing<N> {
+    pub const fn new(storage: &'static Storage<N>) -> Self {
+        PersistentRing {
+            storage,
+            opened: Mutex::new(None),
+            ready: Signal::new(),
+        }
+    }
+
+    // Check the records left from before the reset. Called by everything else on first use;
+    // call it first to find out what was recovered.
+    pub fn open(&self) -> Recovery {
+        self.with(|_, recovery| recovery)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Raw<N>, Recovery) -> R) -> R {
+        self.opened.with(|opened| {
+            // Safety: The lock is held. Every bit pattern is a valid `Raw`; `recover` deals
+            // with the ones that don't make sense.
+            let raw = unsafe { &mut *(*self.storage.raw.get()).as_mut_ptr() };
+            let recovery = *opened.get_or_insert_with(|| raw.recover());
+            f(raw, recovery)
+        })
+    }
+
+    // Add a record, dropping the oldest ones if there isn't room.
+    pub fn push(&self, record: &[u8]) -> Result<(), TooLarge> {
+        let size = RECORD_HEADER + record.len();
+        if size > N || record.len() > u16::MAX as usize {
+            return Err(TooLarge);
+        }
+        self.with(|raw, _| {
+            while raw.used as usize + size > N {
+                raw.drop_oldest();
+            }
+            let start = (raw.tail + raw.used) as usize;
+            let len = (record.len() as u16).to_le_bytes();
+            let crc = crc16(record.iter().copied()).to_le_bytes();
+            let bytes = len.iter().chain(&crc).chain(record);
+            for (i, &byte) in bytes.enumerate() {
+                raw.data[(start + i) % N] = byte;
+            }
+            raw.used += size as u32;
+            raw.commit();
+        });
+        self.ready.signal(());
+        Ok(())
+    }
+
+    // Copy the oldest record into `buf` without removing it, and return its length. A record
+    // that doesn't fit in `buf` is cut short.
+    pub fn peek(&self, buf: &mut [u8]) -> Option<usize> {
+        self.with(|raw, _| {
+            let (len, _) = raw.record_at(0)?;
+            for (i, byte) in buf.iter_mut().take(len).enumerate() {
+                *byte = raw.byte(RECORD_HEADER + i);
+            }
+            Some(len)
+        })
+    }
+
+    // Remove the oldest record. With `peek`, for records that should only go once they've
+    // been sent somewhere.
+    pub fn remove(&self) {
+        self.with(|raw, _| {
+            if raw.used > 0 {
+                raw.drop_oldest();
+            }
+        })
+    }
+
+    pub fn try_pop(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = self.peek(buf)?;
+        self.remove();
+        Some(len)
+    }
+
+    // Wait for a record, then take it out like `try_pop`.
+    pub async fn pop(&self, buf: &mut [u8]) -> usize {
+        loop {
+            if let Some(len) = self.try_pop(buf) {
+                return len;
+            }
+            // A push since `try_pop` looked leaves the signal set, so this doesn't miss it.
+            self.ready.wait().await;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.with(|raw, _| raw.used == 0)
+    }
+
+    pub fn clear(&self) {
+        self.with(|raw, _| raw.reset())
+    }
+}
+
+// CRC-16/CCITT-FALSE, as in `datalog`.
+fn crc16(data: impl Iterator<Item = u8>) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
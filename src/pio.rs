@@ -0,0 +1,628 @@
+// Loading programs into the PIO blocks and running them on state machines.
+//
+// Instruction memory and state machines are handed out first come first served, so drivers
+// built on PIO can share the two blocks without knowing about each other. Waiting on a state
+// machine (FIFO space, data, an IRQ flag) goes through the reactor on the block's IRQ 0.
+//
+// A running state machine can be switched to another program with `StateMachine::swap`,
+// e.g. to turn a pin from WS2812 output into a UART, without resetting the block and
+// disturbing the other three.
+
+use core::{future::poll_fn, task::Poll, time::Duration};
+
+use rp2040_pac::{pio0::RegisterBlock, Interrupt};
+
+use crate::{reactor, resets, sync::Mutex, time};
+
+pub const BLOCKS: usize = 2;
+pub const STATE_MACHINES: usize = 4;
+const INSTRUCTIONS: usize = 32;
+
+struct Allocations {
+    // Used instruction slots, one bit each.
+    instructions: [u32; BLOCKS],
+    // Claimed state machines, one bit each.
+    state_machines: [u8; BLOCKS],
+}
+
+// Also held while changing interrupt enables, which are shared by the whole block.
+static ALLOCATIONS: Mutex<Allocations, 29> = Mutex::new(Allocations {
+    instructions: [0; BLOCKS],
+    state_machines: [0; BLOCKS],
+});
+
+fn regs(block: u8) -> &'static RegisterBlock {
+    match block {
+        0 => unsafe { &*rp2040_pac::PIO0::ptr() },
+        1 => unsafe { &*rp2040_pac::PIO1::ptr() },
+        _ => panic!("no such PIO block"),
+    }
+}
+
+fn irq(block: u8) -> Interrupt {
+    match block {
+        0 => Interrupt::PIO0_IRQ_0,
+        _ => Interrupt::PIO1_IRQ_0,
+    }
+}
+
+// A program loaded into a block's instruction memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Program {
+    block: u8,
+    offset: u8,
+    len: u8,
+}
+
+impl Program {
+    pub fn block(&self) -> u8 {
+        self.block
+    }
+
+    // Where the program starts. Jumps were relocated when it was loaded; this is for anything
+    // else that needs an absolute address, like a `jmp` executed with `StateMachine::exec`.
+    pub fn offset(&self) -> u8 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u8 {
+        self.len
+    }
+}
+
+// Load `code`, assembled to start at address 0, anywhere it fits in `block`. Returns `None` if
+// there isn't room.
+pub fn load(block: u8, code: &[u16]) -> Option<Program> {
+    assert!(!code.is_empty() && code.len() <= INSTRUCTIONS);
+    resets::bring_up(1 << (10 + block));
+    let len = code.len();
+    let mask = ((1u64 << len) - 1) as u32;
+    let offset = ALLOCATIONS.with(|allocations| {
+        let used = &mut allocations.instructions[block as usize];
+        let offset = (0..=INSTRUCTIONS - len).find(|offset| *used & mask << offset == 0)?;
+        *used |= mask << offset;
+        Some(offset)
+    })?;
+    let pio = regs(block);
+    for (i, &instr) in code.iter().enumerate() {
+        // JMP is the only instruction with an address in it.
+        let instr = if instr & 0xe000 == 0 {
+            instr & !0x1f | ((instr & 0x1f) + offset as u16) & 0x1f
+        } else {
+            instr
+        };
+        pio.instr_mem[offset + i].write(|w| unsafe { w.bits(instr as u32) });
+    }
+    Some(Program {
+        block,
+        offset: offset as u8,
+        len: len as u8,
+    })
+}
+
+// Free the program's instruction memory. Nothing may still be running it.
+pub fn unload(program: Program) {
+    let mask = (((1u64 << program.len) - 1) as u32) << program.offset;
+    ALLOCATIONS.with(|allocations| allocations.instructions[program.block as usize] &= !mask);
+}
+
+// Hand `pin` over to `block`.
+pub fn use_pin(block: u8, pin: u8) {
+    let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+    let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+    // IE set, OD clear, so the state machine can both read and drive it.
+    pads.gpio[pin as usize].modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 7) | 1 << 6) });
+    // FUNCSEL: 6 is PIO0, 7 is PIO1.
+    io.gpio[pin as usize]
+        .gpio_ctrl
+        .write(|w| unsafe { w.bits(6 + block as u32) });
+}
+
+// Claim a free state machine in `block`, or `None` if they're all in use.
+pub fn claim(block: u8) -> Option<StateMachine> {
+    resets::bring_up(1 << (10 + block));
+    let sm = ALLOCATIONS.with(|allocations| {
+        let claimed = &mut allocations.state_machines[block as usize];
+        let sm = (0..STATE_MACHINES as u8).find(|sm| *claimed & 1 << sm == 0)?;
+        *claimed |= 1 << sm;
+        Some(sm)
+    })?;
+    let mut sm = StateMachine { block, sm };
+    sm.set_enabled(false);
+    Some(sm)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Join {
+    None,
+    // One 8 deep TX FIFO, no RX.
+    Tx,
+    // One 8 deep RX FIFO, no TX.
+    Rx,
+}
+
+// How a state machine runs a program. Wrap addresses and pins are as the program sees them,
+// relative to its start and GPIO0 respectively.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    // Clock divider from clk_sys, 16.8 fixed point.
+    pub clkdiv_int: u16,
+    pub clkdiv_frac: u8,
+    pub wrap_target: u8,
+    pub wrap: u8,
+    pub set_base: u8,
+    pub set_count: u8,
+    pub out_base: u8,
+    pub out_count: u8,
+    pub in_base: u8,
+    pub sideset_base: u8,
+    // Including the enable bit, if `sideset_optional`.
+    pub sideset_count: u8,
+    pub sideset_optional: bool,
+    pub jmp_pin: u8,
+    pub out_shift_right: bool,
+    pub in_shift_right: bool,
+    pub autopull: bool,
+    pub autopush: bool,
+    // 32 is written as 0, like the hardware does.
+    pub pull_threshold: u8,
+    pub push_threshold: u8,
+    pub join: Join,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            clkdiv_int: 1,
+            clkdiv_frac: 0,
+            wrap_target: 0,
+            wrap: INSTRUCTIONS as u8 - 1,
+            set_base: 0,
+            set_count: 0,
+            out_base: 0,
+            out_count: 0,
+            in_base: 0,
+            sideset_base: 0,
+            sideset_count: 0,
+            sideset_optional: false,
+            jmp_pin: 0,
+            out_shift_right: true,
+            in_shift_right: true,
+            autopull: false,
+            autopush: false,
+            pull_threshold: 0,
+            push_threshold: 0,
+            join: Join::None,
+        }
+    }
+}
+
+pub struct StateMachine {
+    block: u8,
+    sm: u8,
+}
+
+impl StateMachine {
+    pub fn block(&self) -> u8 {
+        self.block
+    }
+
+    pub fn index(&self) -> u8 {
+        self.sm
+    }
+
+    fn regs(&self) -> &'static RegisterBlock {
+        regs(self.block)
+    }
+
+    // Set the state machine up to run `program` from its start. Leaves it disabled.
+    pub fn configure(&mut self, program: &Program, config: &Config) {
+        self.set_enabled(false);
+        self.write_config(program, config);
+        self.clear_fifos();
+        self.restart();
+        // Start from the beginning of the program.
+        self.exec(program.offset as u16);
+    }
+
+    fn write_config(&mut self, program: &Program, config: &Config) {
+        assert_eq!(
+            program.block, self.block,
+            "program is in the other PIO block"
+        );
+        let sm = &self.regs().sm[self.sm as usize];
+        sm.sm_clkdiv.write(|w| unsafe {
+            w.bits((config.clkdiv_int as u32) << 16 | (config.clkdiv_frac as u32) << 8)
+        });
+        let wrap_target = (program.offset + config.wrap_target) as u32;
+        let wrap = (program.offset + config.wrap.min(program.len - 1)) as u32;
+        sm.sm_execctrl.write(|w| unsafe {
+            w.bits(
+                (config.sideset_optional as u32) << 30
+                    | (config.jmp_pin as u32) << 24
+                    | wrap << 12
+                    | wrap_target << 7,
+            )
+        });
+        let join = match config.join {
+            Join::None => 0,
+            Join::Tx => 1 << 30,
+            Join::Rx => 1 << 31,
+        };
+        sm.sm_shiftctrl.write(|w| unsafe {
+            w.bits(
+                join | ((config.pull_threshold & 0x1f) as u32) << 25
+                    | ((config.push_threshold & 0x1f) as u32) << 20
+                    | (config.out_shift_right as u32) << 19
+                    | (config.in_shift_right as u32) << 18
+                    | (config.autopull as u32) << 17
+                    | (config.autopush as u32) << 16,
+            )
+        });
+        sm.sm_pinctrl.write(|w| unsafe {
+            w.bits(
+                (config.sideset_count as u32) << 29
+                    | (config.set_count as u32) << 26
+                    | (config.out_count as u32) << 20
+                    | (config.in_base as u32) << 15
+                    | (config.sideset_base as u32) << 10
+                    | (config.set_base as u32) << 5
+                    | config.out_base as u32,
+            )
+        });
+    }
+
+    // Stop the state machine once `quiesce` says it's safe, and start it again running
+    // `program` with `config` from its start. Words the old program pushed are handed back
+    // rather than left for the new one. Words still waiting in the TX FIFO are kept for the
+    // new program if the FIFO join stays the same; changing it empties the FIFOs, and those
+    // words are counted in `Leftover::tx_dropped`.
+    //
+    // Pin directions are left alone. The old program stays loaded; `unload` it afterwards if
+    // nothing else runs it.
+    pub async fn swap(&mut self, program: &Program, config: &Config, quiesce: Quiesce) -> Leftover {
+        match quiesce {
+            Quiesce::Now => {}
+            Quiesce::TxDrained => self.flush().await,
+            Quiesce::Irq(flag) => self.wait_irq(flag).await,
+        }
+        self.set_enabled(false);
+        let mut leftover = Leftover {
+            rx: [0; 8],
+            rx_len: 0,
+            tx_dropped: 0,
+        };
+        while let Some(word) = self.try_pull() {
+            leftover.rx[leftover.rx_len as usize] = word;
+            leftover.rx_len += 1;
+        }
+        let sm = &self.regs().sm[self.sm as usize];
+        let joined = sm.sm_shiftctrl.read().bits() & 0b11 << 30;
+        let join = match config.join {
+            Join::None => 0,
+            Join::Tx => 1 << 30,
+            Join::Rx => 1 << 31,
+        };
+        if join != joined {
+            leftover.tx_dropped = self.tx_level();
+        }
+        self.write_config(program, config);
+        self.restart();
+        self.exec(instr::jmp(program.offset));
+        self.set_enabled(true);
+        leftover
+    }
+
+    // Move the wrap points within `program`, which must be the one running, without stopping
+    // it. Both are relative to the program's start.
+    pub fn set_wrap(&mut self, program: &Program, wrap_target: u8, wrap: u8) {
+        assert_eq!(
+            program.block, self.block,
+            "program is in the other PIO block"
+        );
+        let wrap_target = (program.offset + wrap_target) as u32;
+        let wrap = (program.offset + wrap.min(program.len - 1)) as u32;
+        let sm = &self.regs().sm[self.sm as usize];
+        sm.sm_execctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() & !0x1ff80 | wrap << 12 | wrap_target << 7) });
+    }
+
+    // Words waiting in the TX FIFO.
+    pub fn tx_level(&self) -> u8 {
+        (self.regs().flevel.read().bits() >> (8 * self.sm) & 0xf) as u8
+    }
+
+    // Words waiting in the RX FIFO.
+    pub fn rx_level(&self) -> u8 {
+        (self.regs().flevel.read().bits() >> (8 * self.sm + 4) & 0xf) as u8
+    }
+
+    // Whether the program has stalled pushing to a full RX FIFO since the last call.
+    pub fn take_rx_stalled(&mut self) -> bool {
+        let pio = self.regs();
+        let stalled = 1 << self.sm;
+        let fdebug = pio.fdebug.read().bits();
+        pio.fdebug.write(|w| unsafe { w.bits(stalled) });
+        fdebug & stalled != 0
+    }
+
+    // Wait for the TX FIFO to empty and the program to stall pulling from it, i.e. for
+    // everything pushed to have been dealt with. There's no interrupt for either, so this
+    // polls. Never finishes for a program that doesn't pull.
+    pub async fn flush(&mut self) {
+        let pio = self.regs();
+        let stalled = 1 << (24 + self.sm);
+        // TXSTALL is sticky; clear whatever's left from earlier.
+        pio.fdebug.write(|w| unsafe { w.bits(stalled) });
+        while self.tx_level() != 0 || pio.fdebug.read().bits() & stalled == 0 {
+            time::sleep(Duration::from_micros(20)).await;
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        let pio = self.regs();
+        let bit = 1 << self.sm;
+        // CTRL is shared by all four state machines.
+        ALLOCATIONS.with(|_| {
+            pio.ctrl.modify(|r, w| unsafe {
+                w.bits(if enabled {
+                    r.bits() | bit
+                } else {
+                    r.bits() & !bit
+                })
+            })
+        });
+    }
+
+    // Reset the state machine's internal state (shift counters, delays, a stalled instruction)
+    // and its clock divider phase. Registers and the program counter are kept.
+    pub fn restart(&mut self) {
+        let pio = self.regs();
+        let bits = 1 << (4 + self.sm) | 1 << (8 + self.sm);
+        // The restart bits are self-clearing, so write them along with the current enables.
+        ALLOCATIONS.with(|_| {
+            pio.ctrl
+                .modify(|r, w| unsafe { w.bits(r.bits() & 0xf | bits) })
+        });
+    }
+
+    // Empty both FIFOs, by flipping the join setting back and forth.
+    pub fn clear_fifos(&mut self) {
+        let sm = &self.regs().sm[self.sm as usize];
+        sm.sm_shiftctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() ^ 1 << 31) });
+        sm.sm_shiftctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() ^ 1 << 31) });
+    }
+
+    // Run one instruction straight away, e.g. a `jmp` to move the program counter.
+    pub fn exec(&mut self, instr: u16) {
+        let sm = &self.regs().sm[self.sm as usize];
+        sm.sm_instr.write(|w| unsafe { w.bits(instr as u32) });
+    }
+
+    // The program counter.
+    pub fn pc(&self) -> u8 {
+        self.regs().sm[self.sm as usize].sm_addr.read().bits() as u8
+    }
+
+    // Set pins `base..base + count` to inputs or outputs for this state machine, by running
+    // `set pindirs` with the set pins temporarily pointed at them.
+    pub fn set_pindirs(&mut self, base: u8, count: u8, output: bool) {
+        let sm = &self.regs().sm[self.sm as usize];
+        let pinctrl = sm.sm_pinctrl.read().bits();
+        for pin in base..base + count {
+            sm.sm_pinctrl
+                .write(|w| unsafe { w.bits(1 << 26 | (pin as u32) << 5) });
+            self.exec(instr::set_pindirs(output as u8));
+        }
+        sm.sm_pinctrl.write(|w| unsafe { w.bits(pinctrl) });
+    }
+
+    // The TX FIFO's register, for a DMA channel to write to.
+    pub fn tx_fifo(&self) -> *mut u32 {
+        &self.regs().txf[self.sm as usize] as *const _ as *mut u32
+    }
+
+    pub fn try_push(&mut self, word: u32) -> bool {
+        let pio = self.regs();
+        if pio.fstat.read().bits() & 1 << (16 + self.sm) != 0 {
+            return false;
+        }
+        pio.txf[self.sm as usize].write(|w| unsafe { w.bits(word) });
+        true
+    }
+
+    pub fn try_pull(&mut self) -> Option<u32> {
+        let pio = self.regs();
+        if pio.fstat.read().bits() & 1 << (8 + self.sm) != 0 {
+            return None;
+        }
+        Some(pio.rxf[self.sm as usize].read().bits())
+    }
+
+    // Write to the TX FIFO, waiting for room.
+    pub async fn push(&mut self, word: u32) {
+        let (block, sm) = (self.block, self.sm);
+        // TXNFULL
+        wait_for(block, 1 << (4 + sm), || self.try_push(word)).await
+    }
+
+    // Read from the RX FIFO, waiting for data.
+    pub async fn pull(&mut self) -> u32 {
+        let (block, sm) = (self.block, self.sm);
+        let mut word = None;
+        // RXNEMPTY
+        wait_for(block, 1 << sm, || {
+            word = self.try_pull();
+            word.is_some()
+        })
+        .await;
+        word.unwrap()
+    }
+
+    // Wait for IRQ flag `flag` (0-3) of this block to be set, and clear it.
+    pub async fn wait_irq(&mut self, flag: u8) {
+        wait_irq(self.block, flag).await
+    }
+}
+
+// When `StateMachine::swap` stops the old program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quiesce {
+    // Straight away, wherever it is.
+    Now,
+    // Once everything pushed to it has been pulled and the program is waiting for more. Never
+    // happens for a program that doesn't pull.
+    TxDrained,
+    // Once it sets IRQ flag `flag` (0-3), for programs that signal a safe point themselves.
+    Irq(u8),
+}
+
+// What `StateMachine::swap` took out of the old program.
+#[derive(Clone, Copy, Debug)]
+pub struct Leftover {
+    rx: [u32; 8],
+    rx_len: u8,
+    // Words the old program hadn't pulled yet that were lost because the join changed.
+    pub tx_dropped: u8,
+}
+
+impl Leftover {
+    // What was in the RX FIFO, oldest first.
+    pub fn rx(&self) -> &[u32] {
+        &self.rx[..self.rx_len as usize]
+    }
+}
+
+impl Drop for StateMachine {
+    fn drop(&mut self) {
+        self.set_enabled(false);
+        ALLOCATIONS
+            .with(|allocations| allocations.state_machines[self.block as usize] &= !(1 << self.sm));
+    }
+}
+
+// Wait for IRQ flag `flag` (0-3) of `block` to be set, and clear it. Only flags 0-3 can raise
+// an interrupt.
+pub async fn wait_irq(block: u8, flag: u8) {
+    assert!(flag < 4, "only IRQ flags 0-3 reach the NVIC");
+    let pio = regs(block);
+    wait_for(block, 1 << (8 + flag), || {
+        if pio.irq.read().bits() & 1 << flag == 0 {
+            return false;
+        }
+        clear_irq(block, flag);
+        true
+    })
+    .await
+}
+
+pub fn clear_irq(block: u8, flag: u8) {
+    regs(block).irq.write(|w| unsafe { w.bits(1 << flag) });
+}
+
+// Whether IRQ flag `flag` of `block` is set, leaving it as it is.
+pub fn irq_is_set(block: u8, flag: u8) -> bool {
+    regs(block).irq.read().bits() & 1 << flag != 0
+}
+
+// Wait until `ready` returns true, sleeping on interrupt source `source` (a bit in
+// IRQ0_INTE) in between. The source is only enabled while someone's waiting on it, since most
+// of them (FIFO not full, say) stay asserted until something else changes.
+async fn wait_for(block: u8, source: u32, mut ready: impl FnMut() -> bool) {
+    let pio = regs(block);
+    let set_enabled = |enabled: bool| {
+        ALLOCATIONS.with(|_| {
+            pio.sm_irq[0].irq_inte.modify(|r, w| unsafe {
+                w.bits(if enabled {
+                    r.bits() | source
+                } else {
+                    r.bits() & !source
+                })
+            })
+        })
+    };
+    poll_fn(|cx| {
+        if ready() {
+            set_enabled(false);
+            return Poll::Ready(());
+        }
+        set_enabled(true);
+        // If it became ready in the meantime, the interrupt fires as soon as this unmasks it.
+        crate::irq::reserve(irq(block));
+        reactor::register(irq(block), cx.waker());
+        Poll::Pending
+    })
+    .await
+}
+
+// Encoders for the instructions drivers need to run with `exec` or build programs from.
+pub mod instr {
+    pub const fn jmp(addr: u8) -> u16 {
+        (addr & 0x1f) as u16
+    }
+
+    // `instr [cycles]`. Only for state machines without side-set, which shares the field.
+    pub const fn delay(instr: u16, cycles: u8) -> u16 {
+        instr | ((cycles & 0x1f) as u16) << 8
+    }
+
+    // `jmp x-- addr`
+    pub const fn jmp_x_dec(addr: u8) -> u16 {
+        0x0040 | (addr & 0x1f) as u16
+    }
+
+    // `jmp y-- addr`
+    pub const fn jmp_y_dec(addr: u8) -> u16 {
+        0x0080 | (addr & 0x1f) as u16
+    }
+
+    // `jmp !y addr`: jumps if Y is zero.
+    pub const fn jmp_not_y(addr: u8) -> u16 {
+        0x0060 | (addr & 0x1f) as u16
+    }
+
+    // `jmp pin addr`: jumps while the state machine's jmp pin is high.
+    pub const fn jmp_pin(addr: u8) -> u16 {
+        0x00c0 | (addr & 0x1f) as u16
+    }
+
+    // `wait polarity pin index`, with `index` relative to the in pins.
+    pub const fn wait_pin(polarity: bool, index: u8) -> u16 {
+        0x2020 | (polarity as u16) << 7 | (index & 0x1f) as u16
+    }
+
+    pub const PULL_BLOCK: u16 = 0x80a0;
+    pub const PUSH_BLOCK: u16 = 0x8020;
+    pub const PUSH_NOBLOCK: u16 = 0x8000;
+    pub const MOV_X_OSR: u16 = 0xa027;
+    pub const MOV_X_NOT_NULL: u16 = 0xa02b;
+    pub const MOV_Y_NOT_NULL: u16 = 0xa04b;
+    pub const MOV_Y_OSR: u16 = 0xa047;
+    pub const MOV_Y_ISR: u16 = 0xa046;
+    pub const MOV_ISR_X: u16 = 0xa0c1;
+    pub const MOV_ISR_NOT_X: u16 = 0xa0c9;
+    pub const MOV_ISR_NOT_Y: u16 = 0xa0ca;
+    pub const IN_PINS_1: u16 = 0x4001;
+    pub const OUT_PINS_1: u16 = 0x6001;
+    pub const OUT_PINDIRS_1: u16 = 0x6081;
+    pub const MOV_ISR_OSR: u16 = 0xa0c7;
+
+    // `irq set flag rel`: sets flag + the state machine's index.
+    pub const fn irq_set_rel(flag: u8) -> u16 {
+        0xc010 | (flag & 0x7) as u16
+    }
+
+    pub const fn set_pins(value: u8) -> u16 {
+        0xe000 | (value & 0x1f) as u16
+    }
+
+    pub const fn set_x(value: u8) -> u16 {
+        0xe020 | (value & 0x1f) as u16
+    }
+
+    pub const fn set_pindirs(value: u8) -> u16 {
+        0xe080 | (value & 0x1f) as u16
+    }
+}
@@ -0,0 +1,469 @@
+// A UART on two PIO state machines, with the framing that lighting and automotive buses add
+// on top of plain serial: breaks, the mark after them, and the idle time between characters.
+//
+// The receiver times the idle line itself: while it waits for a start bit it counts, and the
+// count goes into the RX FIFO ahead of the character. So `PioUartRx::receive` knows how long
+// the gap before each character was to a quarter of a bit, however late the task gets to it,
+// which is what DMX512 and LIN receivers need to find the start of a packet. A character
+// whose data and stop bits are all low is reported as a break.
+//
+// Breaks are sent by holding the pin low with the state machine stopped, timed by the time
+// driver, so the break and mark-after-break in `Framing` are minimums.
+//
+//     let uart = PioUart::new(0, 4, 5, &Framing::DMX512, sys_hz)?;
+//     let (mut tx, mut rx) = uart.split();
+//     tx.send_packet(&universe).await;
+//
+// DALI isn't a UART (it's Manchester coded), so it isn't covered here.
+
+use core::{convert::Infallible, time::Duration};
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+
+use crate::{
+    pio::{self, instr, Join, Program, StateMachine},
+    time,
+};
+
+// State machine cycles per bit.
+const CYCLES_PER_BIT: u32 = 8;
+// Idle-count loop iterations per bit; the loop is two instructions.
+const COUNTS_PER_BIT: u64 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Framing {
+    pub baud: u32,
+    // 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    // 1 or 2.
+    pub stop_bits: u8,
+    // How long `send_break` holds the line low, and then high again before the next character.
+    pub break_len: Duration,
+    pub mark_after_break: Duration,
+}
+
+impl Framing {
+    // DMX512-A: 250 kbaud 8N2, a break of at least 92 µs and a mark after it of at least 12 µs.
+    // These are the values most consoles use.
+    pub const DMX512: Framing = Framing {
+        baud: 250_000,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 2,
+        break_len: Duration::from_micros(176),
+        mark_after_break: Duration::from_micros(16),
+    };
+
+    // LIN 2.x at 19200 baud: 8N1, a break of 13 bits and a one bit delimiter.
+    pub const LIN: Framing = Framing {
+        baud: 19_200,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+        break_len: Duration::from_micros(13 * 1_000_000 / 19_200 + 1),
+        mark_after_break: Duration::from_micros(1_000_000 / 19_200 + 1),
+    };
+
+    // Data bits plus the parity bit, if any.
+    fn bits(&self) -> u8 {
+        self.data_bits + (self.parity != Parity::None) as u8
+    }
+
+    fn parity_bit(&self, data: u32) -> u32 {
+        match self.parity {
+            Parity::None => 0,
+            Parity::Even => data.count_ones() & 1,
+            Parity::Odd => !data.count_ones() & 1,
+        }
+    }
+
+    fn clock(&self, sys_hz: u32) -> pio::Config {
+        // 16.8 fixed point.
+        let div = (sys_hz as u64 * 256 / (CYCLES_PER_BIT as u64 * self.baud as u64)) as u32;
+        assert!(div >= 256, "baud rate too high for clk_sys");
+        pio::Config {
+            clkdiv_int: (div >> 8) as u16,
+            clkdiv_frac: div as u8,
+            ..Default::default()
+        }
+    }
+}
+
+// Waits for a word, then sends a start bit, `bits` bits LSB first and the stop bits. The
+// line stays high while it waits. Open drain, it drives the pin's direction instead, with the
+// output held low: the bits go out inverted.
+fn tx_program(framing: &Framing, open_drain: bool) -> [u16; 6] {
+    let (set, out) = if open_drain {
+        (instr::set_pindirs as fn(u8) -> u16, instr::OUT_PINDIRS_1)
+    } else {
+        (instr::set_pins as fn(u8) -> u16, instr::OUT_PINS_1)
+    };
+    let (low, high) = if open_drain { (1, 0) } else { (0, 1) };
+    [
+        instr::PULL_BLOCK,
+        instr::set_x(framing.bits() - 1),
+        instr::delay(set(low), 7),
+        // bit:
+        instr::delay(out, 6),
+        instr::jmp_x_dec(3),
+        // Counting the pull and `set x` at the top, which happen while the line is high.
+        instr::delay(set(high), framing.stop_bits * 8 - 3),
+    ]
+}
+const TX_WRAP: u8 = 5;
+
+// Counts while the line is idle. On a start bit it pushes the count, samples `bits` bits and
+// the stop bit in the middle of each, and pushes those; then waits for the line to go high
+// again, in case it was a break.
+fn rx_program(framing: &Framing) -> [u16; 11] {
+    [
+        instr::MOV_Y_NOT_NULL,
+        // idle:
+        instr::jmp_pin(IDLE_COUNT),
+        instr::MOV_ISR_NOT_Y,
+        instr::PUSH_BLOCK,
+        // The start bit was seen up to two cycles late, so this lands 1.4 to 1.6 bits in.
+        instr::delay(instr::set_x(framing.bits()), 7),
+        // bit:
+        instr::delay(instr::IN_PINS_1, 6),
+        instr::jmp_x_dec(5),
+        instr::PUSH_BLOCK,
+        instr::wait_pin(true, 0),
+        // IDLE_COUNT:
+        instr::jmp_y_dec(1),
+        instr::jmp(1),
+    ]
+}
+const IDLE_COUNT: u8 = 9;
+const RX_WRAP: u8 = 8;
+
+pub struct PioUart {
+    tx: PioUartTx,
+    rx: PioUartRx,
+}
+
+impl PioUart {
+    // Send on `tx_pin` and receive on `rx_pin`, with two state machines from `block`.
+    // `sys_hz` is the clk_sys frequency. Returns `None` if the block hasn't got two free state
+    // machines or room for both programs.
+    pub fn new(block: u8, tx_pin: u8, rx_pin: u8, framing: &Framing, sys_hz: u32) -> Option<Self> {
+        let tx = PioUartTx::new(block, tx_pin, framing, sys_hz)?;
+        let rx = PioUartRx::new(block, rx_pin, framing, sys_hz)?;
+        Some(PioUart { tx, rx })
+    }
+
+    pub fn split(self) -> (PioUartTx, PioUartRx) {
+        (self.tx, self.rx)
+    }
+}
+
+pub struct PioUartTx {
+    sm: StateMachine,
+    program: Program,
+    pin: u8,
+    framing: Framing,
+    open_drain: bool,
+}
+
+impl PioUartTx {
+    pub fn new(block: u8, pin: u8, framing: &Framing, sys_hz: u32) -> Option<Self> {
+        Self::with_drive(block, pin, framing, sys_hz, false)
+    }
+
+    // Only ever pull the line low, and let the pull-up (the pad's, and any on the board) take
+    // it high, so it can share a wire with other open drain transmitters: the half-duplex
+    // line of a smart card, say, with a `PioUartRx` on the same pin. Make the receiver first,
+    // since setting it up takes the pin over again.
+    pub fn new_open_drain(block: u8, pin: u8, framing: &Framing, sys_hz: u32) -> Option<Self> {
+        Self::with_drive(block, pin, framing, sys_hz, true)
+    }
+
+    fn with_drive(
+        block: u8,
+        pin: u8,
+        framing: &Framing,
+        sys_hz: u32,
+        open_drain: bool,
+    ) -> Option<Self> {
+        assert!((5..=8).contains(&framing.data_bits) && (1..=2).contains(&framing.stop_bits));
+        let mut sm = pio::claim(block)?;
+        let program = pio::load(block, &tx_program(framing, open_drain))?;
+        pio::use_pin(block, pin);
+        sm.configure(
+            &program,
+            &pio::Config {
+                wrap: TX_WRAP,
+                set_base: pin,
+                set_count: 1,
+                out_base: pin,
+                out_count: 1,
+                join: Join::Tx,
+                ..framing.clock(sys_hz)
+            },
+        );
+        if open_drain {
+            let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+            let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+            // Pull-up on, pull-down off.
+            pads.gpio[pin as usize].modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 2) | 1 << 3) });
+            // OUTOVER: drive low whenever the direction is output.
+            io.gpio[pin as usize]
+                .gpio_ctrl
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << 8) | 2 << 8) });
+            sm.set_pindirs(pin, 1, false);
+        } else {
+            sm.exec(instr::set_pins(1));
+            sm.set_pindirs(pin, 1, true);
+        }
+        sm.set_enabled(true);
+        Some(PioUartTx {
+            sm,
+            program,
+            pin,
+            framing: *framing,
+            open_drain,
+        })
+    }
+
+    // `set` that takes the line to `high`.
+    fn set_level(&self, high: bool) -> u16 {
+        if self.open_drain {
+            instr::set_pindirs(!high as u8)
+        } else {
+            instr::set_pins(high as u8)
+        }
+    }
+
+    pub async fn write_char(&mut self, data: u8) {
+        let data = data as u32 & ((1 << self.framing.data_bits) - 1);
+        let word = data | self.framing.parity_bit(data) << self.framing.data_bits;
+        self.sm
+            .push(if self.open_drain { !word } else { word })
+            .await;
+    }
+
+    // Wait for everything written to have gone out, stop bits included.
+    pub async fn flush(&mut self) {
+        self.sm.flush().await;
+    }
+
+    // Finish sending what's been written, then send a break and the mark after it.
+    pub async fn send_break(&mut self) {
+        self.sm.flush().await;
+        self.sm.set_enabled(false);
+        self.sm.exec(self.set_level(false));
+        time::sleep(self.framing.break_len).await;
+        self.sm.exec(self.set_level(true));
+        time::sleep(self.framing.mark_after_break).await;
+        // It was waiting on the pull at the top, which the `exec`s cancelled.
+        self.sm.exec(instr::jmp(self.program.offset()));
+        self.sm.set_enabled(true);
+    }
+
+    // A break followed by `data`, as DMX512 and LIN frame their packets. Returns once it's all
+    // gone out.
+    pub async fn send_packet(&mut self, data: &[u8]) {
+        self.send_break().await;
+        for &byte in data {
+            self.write_char(byte).await;
+        }
+        self.sm.flush().await;
+    }
+
+    pub fn release(self) -> u8 {
+        let (pin, program) = (self.pin, self.program);
+        drop(self.sm);
+        pio::unload(program);
+        pin
+    }
+}
+
+impl ErrorType for PioUartTx {
+    type Error = Infallible;
+}
+
+impl Write for PioUartTx {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        for &byte in buf {
+            self.write_char(byte).await;
+        }
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        self.sm.flush().await;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Received {
+    // A character, and how long the line was idle before its start bit.
+    Char { data: u8, idle: Duration },
+    // The line was low for a whole character, stop bits included.
+    Break { idle: Duration },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The stop bit was low, but it wasn't a break.
+    Framing,
+    Parity,
+    // The FIFO was full when a character came in, so at least one was lost.
+    Overrun,
+}
+
+impl embedded_io_async::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Framing | Error::Parity => ErrorKind::InvalidData,
+            Error::Overrun => ErrorKind::Other,
+        }
+    }
+}
+
+pub struct PioUartRx {
+    sm: StateMachine,
+    program: Program,
+    pin: u8,
+    framing: Framing,
+    // An error from a character after the first in a read, reported by the next one instead.
+    deferred: Option<Error>,
+}
+
+impl PioUartRx {
+    pub fn new(block: u8, pin: u8, framing: &Framing, sys_hz: u32) -> Option<Self> {
+        assert!((5..=8).contains(&framing.data_bits));
+        let mut sm = pio::claim(block)?;
+        let program = pio::load(block, &rx_program(framing))?;
+        let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+        pio::use_pin(block, pin);
+        // Pull-up, so an unconnected line idles high.
+        pads.gpio[pin as usize].modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 2) | 1 << 3) });
+        sm.configure(
+            &program,
+            &pio::Config {
+                wrap: RX_WRAP,
+                in_base: pin,
+                jmp_pin: pin,
+                join: Join::Rx,
+                ..framing.clock(sys_hz)
+            },
+        );
+        sm.set_pindirs(pin, 1, false);
+        sm.set_enabled(true);
+        Some(PioUartRx {
+            sm,
+            program,
+            pin,
+            framing: *framing,
+            deferred: None,
+        })
+    }
+
+    // Invert the pin's input before the state machine sees it, for lines that idle low, like
+    // SBUS. The pull is flipped to match, so an unconnected line still looks idle.
+    pub fn set_inverted(&mut self, inverted: bool) {
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+        let pull = if inverted { 1 << 2 } else { 1 << 3 };
+        pads.gpio[self.pin as usize]
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << 2) | pull) });
+        // INOVER
+        io.gpio[self.pin as usize]
+            .gpio_ctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << 16) | (inverted as u32) << 16) });
+    }
+
+    // The next character or break. An error is about one character; the next call carries on
+    // with the one after it.
+    pub async fn receive(&mut self) -> Result<Received, Error> {
+        if self.sm.take_rx_stalled() {
+            // The idle count and the character are pushed separately, so a stalled push could
+            // leave them out of step. Start again from an empty FIFO and an idle line.
+            self.sm.set_enabled(false);
+            self.sm.clear_fifos();
+            self.sm.restart();
+            self.sm.exec(instr::jmp(self.program.offset()));
+            self.sm.set_enabled(true);
+            return Err(Error::Overrun);
+        }
+        let idle = self.sm.pull().await;
+        let word = self.sm.pull().await;
+        let idle = Duration::from_nanos(
+            idle as u64 * 1_000_000_000 / (COUNTS_PER_BIT * self.framing.baud as u64),
+        );
+        let bits = self.framing.bits() as u32;
+        // Shifted in from the top: the data bits, parity, then the stop bit.
+        let word = word >> (31 - bits);
+        let data = word & ((1 << self.framing.data_bits) - 1);
+        if word & 1 << bits == 0 {
+            return if word == 0 {
+                Ok(Received::Break { idle })
+            } else {
+                Err(Error::Framing)
+            };
+        }
+        if self.framing.parity != Parity::None
+            && word >> self.framing.data_bits & 1 != self.framing.parity_bit(data)
+        {
+            return Err(Error::Parity);
+        }
+        Ok(Received::Char {
+            data: data as u8,
+            idle,
+        })
+    }
+
+    pub fn release(self) -> u8 {
+        let (pin, program) = (self.pin, self.program);
+        drop(self.sm);
+        pio::unload(program);
+        pin
+    }
+}
+
+impl ErrorType for PioUartRx {
+    type Error = Error;
+}
+
+impl Read for PioUartRx {
+    // Waits for one character, then takes any more that have already arrived. Breaks are
+    // skipped; use `receive` to see them.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(error) = self.deferred.take() {
+            return Err(error);
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            // Each character is two words: the idle count, then the bits.
+            if n > 0 && self.sm.rx_level() < 2 {
+                break;
+            }
+            match self.receive().await {
+                Ok(Received::Char { data, .. }) => {
+                    buf[n] = data;
+                    n += 1;
+                }
+                Ok(Received::Break { .. }) => {}
+                Err(error) if n == 0 => return Err(error),
+                Err(error) => {
+                    self.deferred = Some(error);
+                    break;
+                }
+            }
+        }
+        Ok(n)
+    }
+}
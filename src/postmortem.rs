@@ -0,0 +1,188 @@
+// Crash reports that survive a reset.
+//
+// The panic handler stores the panic message, the core and task it happened on and a snapshot
+// of the top of the stack in a RAM region that the runtime doesn't zero on boot.
+// The next boot can then pick it up with `last_crash` and report it.
+//
+// `fatal` records a crash the same way without going through the panic machinery, for the
+// runtime's own unrecoverable errors in `panic-free` builds.
+
+use core::{
+    fmt::{self, Write},
+    mem::{size_of, MaybeUninit},
+    panic::PanicInfo,
+    ptr, slice,
+};
+
+const MESSAGE_LEN: usize = 192;
+const TASK_LEN: usize = 64;
+const STACK_WORDS: usize = 32;
+const MAGIC: u32 = 0xDEAD_C0DE;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Crash {
+    message: [u8; MESSAGE_LEN],
+    message_len: usize,
+    core: u32,
+    task: [u8; TASK_LEN],
+    task_len: usize,
+    stack: [u32; STACK_WORDS],
+    stack_len: usize,
+}
+
+impl Crash {
+    // The formatted panic message, cut off at MESSAGE_LEN bytes.
+    pub fn message(&self) -> &str {
+        truncated_str(&self.message[..self.message_len.min(MESSAGE_LEN)])
+    }
+
+    // The name of the task that was being polled, cut off at TASK_LEN bytes.
+    // None if the panic happened outside of a task.
+    pub fn task(&self) -> Option<&str> {
+        match self.task_len.min(TASK_LEN) {
+            0 => None,
+            len => Some(truncated_str(&self.task[..len])),
+        }
+    }
+
+    // Which core panicked: 0 or 1.
+    pub fn core(&self) -> u32 {
+        self.core
+    }
+
+    // The words at the top of the stack when the panic handler ran, starting at the stack pointer.
+    pub fn stack(&self) -> &[u32] {
+        &self.stack[..self.stack_len.min(STACK_WORDS)]
+    }
+}
+
+#[repr(C)]
+struct Slot {
+    magic: u32,
+    checksum: u32,
+    crash: Crash,
+}
+
+// `.uninit` is left alone by cortex-m-rt's startup code, so this survives a soft reset.
+#[link_section = ".uninit.POSTMORTEM"]
+static mut SLOT: MaybeUninit<Slot> = MaybeUninit::uninit();
+
+// Return the crash recorded before the last reset, if there was one.
+// The record is cleared, so each crash is only reported once.
+pub fn last_crash() -> Option<Crash> {
+    cortex_m::interrupt::free(|_| unsafe {
+        let slot = ptr::addr_of_mut!(SLOT).cast::<Slot>();
+        // On a cold boot this is whatever the RAM powered up with, hence the checksum.
+        if ptr::read_volatile(ptr::addr_of!((*slot).magic)) != MAGIC {
+            return None;
+        }
+        ptr::write_volatile(ptr::addr_of_mut!((*slot).magic), 0);
+        let crash = ptr::read_volatile(ptr::addr_of!((*slot).crash));
+        if ptr::read_volatile(ptr::addr_of!((*slot).checksum)) != checksum(&crash) {
+            return None;
+        }
+        Some(crash)
+    })
+}
+
+// Save `info` for the next boot. Called from the panic handler.
+pub fn record(info: &PanicInfo) {
+    save(|writer| {
+        let _ = write!(writer, "{}", info);
+    });
+}
+
+// Give up on something the runtime can't recover from, like running out of memory to queue a
+// task. The same as panicking with `reason`, except that in `panic-free` builds it records the
+// crash and stops (or reboots, for `safemode`) itself, so that the panic handler is never
+// linked in.
+#[cfg(not(feature = "panic-free"))]
+pub fn fatal(reason: &'static str) -> ! {
+    panic!("{}", reason)
+}
+
+#[cfg(feature = "panic-free")]
+pub fn fatal(reason: &'static str) -> ! {
+    save(|writer| {
+        let _ = writer.write_str(reason);
+    });
+    crate::safemode::crashed()
+}
+
+// Everything in here has to be free of panics too, since `fatal` uses it.
+fn save(write_message: impl FnOnce(&mut MessageWriter)) {
+    cortex_m::interrupt::disable();
+
+    let mut crash = Crash {
+        message: [0; MESSAGE_LEN],
+        message_len: 0,
+        core: crate::sync::core() as u32,
+        task: [0; TASK_LEN],
+        task_len: 0,
+        stack: [0; STACK_WORDS],
+        stack_len: 0,
+    };
+
+    write_message(&mut MessageWriter { crash: &mut crash });
+
+    if let Some(task) = crate::taskinfo::current_name() {
+        crash.task_len = task.len().min(TASK_LEN);
+        for (dst, src) in crash.task.iter_mut().zip(task.bytes()) {
+            *dst = src;
+        }
+    }
+
+    extern "C" {
+        static _stack_start: u32;
+    }
+    let sp = cortex_m::register::msp::read() as *const u32;
+    let top = ptr::addr_of!(_stack_start);
+    // Both cores' stacks live below the top of RAM, so copying up to there never faults.
+    let available = (top as usize).saturating_sub(sp as usize) / size_of::<u32>();
+    crash.stack_len = available.min(STACK_WORDS);
+    for (i, word) in crash.stack.iter_mut().take(crash.stack_len).enumerate() {
+        *word = unsafe { ptr::read_volatile(sp.add(i)) };
+    }
+
+    unsafe {
+        let slot = ptr::addr_of_mut!(SLOT).cast::<Slot>();
+        ptr::write_volatile(ptr::addr_of_mut!((*slot).crash), crash);
+        ptr::write_volatile(ptr::addr_of_mut!((*slot).checksum), checksum(&crash));
+        ptr::write_volatile(ptr::addr_of_mut!((*slot).magic), MAGIC);
+    }
+}
+
+fn truncated_str(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        // Truncation may have split a multi-byte character.
+        Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+    }
+}
+
+// FNV-1a over the raw bytes of the record.
+fn checksum(crash: &Crash) -> u32 {
+    // Safety: Crash is repr(C) with no padding, so all of its bytes are initialized.
+    let bytes =
+        unsafe { slice::from_raw_parts(crash as *const Crash as *const u8, size_of::<Crash>()) };
+    bytes.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+struct MessageWriter<'a> {
+    crash: &'a mut Crash,
+}
+
+impl<'a> Write for MessageWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = self.crash.message_len;
+        let len = s.len().min(MESSAGE_LEN.saturating_sub(start));
+        for (dst, src) in self.crash.message.iter_mut().skip(start).zip(s.bytes()) {
+            *dst = src;
+        }
+        self.crash.message_len += len;
+        Ok(())
+    }
+}
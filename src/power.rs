@@ -0,0 +1,419 @@
+// Supply monitoring: the brown-out detector, the core regulator, and VSYS.
+//
+// The brown-out detector resets the chip outright; there's no interrupt to catch first. To get
+// a warning in time to flush the data logger or finish a flash write, `run` watches VSYS
+// through the ADC instead and reports when it drops below a threshold, well before the
+// regulator gives up. After the fact, `reset_reason` tells whether a reset was power related.
+
+use core::time::Duration;
+
+use embedded_hal_async::delay::DelayNs;
+
+use crate::{
+    adc,
+    sync::{self, Channel, Mutex, Watch},
+    time::Instant,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    // VSYS dropped below `Thresholds::low_mv`.
+    SupplyLow { millivolts: u32 },
+    // VSYS came back above `Thresholds::restore_mv`.
+    SupplyRestored { millivolts: u32 },
+    // The core regulator reports it's out of regulation.
+    RegulatorFault,
+    RegulatorRestored,
+    // 5V showed up on VBUS, e.g. a USB cable was plugged in.
+    VbusConnected,
+    VbusDisconnected,
+    // A USB host is talking to us, not just a charger. Only seen when the USB controller is
+    // running (and sensing VBUS, see `VbusSense::UsbController`).
+    HostConnected,
+    HostDisconnected,
+}
+
+// Events from `run`. If nobody's receiving and it fills up, newer events are dropped.
+pub static EVENTS: Channel<Event, 8> = Channel::new();
+
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub low_mv: u32,
+    pub restore_mv: u32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            low_mv: 3000,
+            restore_mv: 3200,
+        }
+    }
+}
+
+// Where VSYS can be measured. The default is the Pico's: a 1/3 divider on GPIO29.
+//
+// On the Pico W, GPIO29 is also the wireless chip's SPI clock, so it can only be sampled while
+// that's idle.
+#[derive(Clone, Copy, Debug)]
+pub struct Vsys {
+    pub channel: u8,
+    pub divider: u32,
+}
+
+impl Default for Vsys {
+    fn default() -> Self {
+        Vsys {
+            channel: 3,
+            divider: 3,
+        }
+    }
+}
+
+impl Vsys {
+    pub fn read_mv(&self) -> u32 {
+        adc::read_mv(self.channel, 4) * self.divider
+    }
+}
+
+// How to tell whether VBUS is present.
+#[derive(Clone, Copy, Debug)]
+pub enum VbusSense {
+    // Not wired up; the supply is always treated as battery.
+    None,
+    // A GPIO that reads high when VBUS is present, like GPIO24 on the Pico. On the Pico W it's
+    // on the wireless chip instead, so use `None` there and send the state yourself.
+    Gpio(u8),
+    // The USB controller's own VBUS_DETECT input. Only works while the controller is out of
+    // reset, and only on boards that wire the pin up; the Pico doesn't.
+    UsbController,
+}
+
+impl VbusSense {
+    fn is_present(&self) -> bool {
+        match *self {
+            VbusSense::None => false,
+            VbusSense::Gpio(pin) => {
+                let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+                let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+                // IE set, OD set: input only.
+                pads.gpio[pin as usize]
+                    .modify(|r, w| unsafe { w.bits(r.bits() | 1 << 6 | 1 << 7) });
+                sio.gpio_in.read().bits() & 1 << pin != 0
+            }
+            VbusSense::UsbController => usb_status().is_some_and(|status| status & 1 != 0),
+        }
+    }
+}
+
+// SIE_STATUS, or `None` if the USB controller isn't running.
+fn usb_status() -> Option<u32> {
+    let resets = unsafe { &*rp2040_pac::RESETS::ptr() };
+    let usb = unsafe { &*rp2040_pac::USBCTRL_REGS::ptr() };
+    const USBCTRL: u32 = 1 << 24;
+    if resets.reset.read().bits() & USBCTRL != 0 || usb.main_ctrl.read().bits() & 1 == 0 {
+        return None;
+    }
+    Some(usb.sie_status.read().bits())
+}
+
+// Whether a host has configured the bus: connected (the pull-up is on and the host has reset
+// us) and not suspended.
+fn host_present() -> bool {
+    usb_status().is_some_and(|status| status & 1 << 16 != 0 && status & 1 << 4 == 0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Battery,
+    // VBUS is present, but nothing's talking USB; a charger or a power bank.
+    Usb,
+    UsbHost,
+}
+
+// Where power is coming from, for switching between charging and performance profiles.
+// Updated by `run` whenever it changes.
+pub static SOURCE: Watch<Source> = Watch::new();
+
+fn vreg_and_chip_reset() -> &'static rp2040_pac::vreg_and_chip_reset::RegisterBlock {
+    unsafe { &*rp2040_pac::VREG_AND_CHIP_RESET::ptr() }
+}
+
+// Whether the core regulator is in regulation (VREG.ROK).
+pub fn regulator_ok() -> bool {
+    vreg_and_chip_reset().vreg.read().bits() & 1 << 12 != 0
+}
+
+// Brown-out thresholds are on DVDD, the 1.1V core supply, in steps of 43mV from 473mV.
+const BOD_BASE_MV: u32 = 473;
+const BOD_STEP_MV: u32 = 43;
+
+// Set the brown-out threshold, rounded down to the nearest step, or `None` to disable the
+// detector. The reset default is 860mV. Returns the threshold actually set.
+pub fn set_brownout(threshold_mv: Option<u32>) -> Option<u32> {
+    let regs = vreg_and_chip_reset();
+    match threshold_mv {
+        None => {
+            regs.bod.write(|w| unsafe { w.bits(0) });
+            None
+        }
+        Some(mv) => {
+            let vsel = (mv.saturating_sub(BOD_BASE_MV) / BOD_STEP_MV).min(15);
+            regs.bod.write(|w| unsafe { w.bits(vsel << 4 | 1) });
+            Some(BOD_BASE_MV + vsel * BOD_STEP_MV)
+        }
+    }
+}
+
+// The current brown-out threshold, or `None` if the detector is disabled.
+pub fn brownout() -> Option<u32> {
+    let bits = vreg_and_chip_reset().bod.read().bits();
+    (bits & 1 != 0).then_some(BOD_BASE_MV + (bits >> 4 & 0xf) * BOD_STEP_MV)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    // Power-on or brown-out; the hardware doesn't tell them apart.
+    PowerOn,
+    // The RUN pin was pulled low.
+    RunPin,
+    // A debugger restarted the chip through the power-on state machine.
+    Debugger,
+    // None of the above, e.g. the watchdog.
+    Other,
+}
+
+pub fn reset_reason() -> ResetReason {
+    let bits = vreg_and_chip_reset().chip_reset.read().bits();
+    if bits & 1 << 8 != 0 {
+        ResetReason::PowerOn
+    } else if bits & 1 << 16 != 0 {
+        ResetReason::RunPin
+    } else if bits & 1 << 20 != 0 {
+        ResetReason::Debugger
+    } else {
+        ResetReason::Other
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub vsys: Vsys,
+    pub thresholds: Thresholds,
+    pub vbus: VbusSense,
+    pub interval_ms: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            vsys: Vsys::default(),
+            thresholds: Thresholds::default(),
+            vbus: VbusSense::None,
+            interval_ms: 100,
+        }
+    }
+}
+
+// Sample the supplies every `config.interval_ms` and report changes to `EVENTS` and `SOURCE`,
+// forever.
+pub async fn run<D: DelayNs>(mut delay: D, config: Config) -> ! {
+    let mut supply_low = false;
+    let mut regulator_ok = true;
+    let mut vbus = false;
+    let mut host = false;
+    SOURCE.send(Source::Battery);
+    loop {
+        let millivolts = config.vsys.read_mv();
+        if !supply_low && millivolts < config.thresholds.low_mv {
+            supply_low = true;
+            let _ = EVENTS.try_send(Event::SupplyLow { millivolts });
+        } else if supply_low && millivolts >= config.thresholds.restore_mv {
+            supply_low = false;
+            let _ = EVENTS.try_send(Event::SupplyRestored { millivolts });
+        }
+
+        let ok = self::regulator_ok();
+        if ok != regulator_ok {
+            regulator_ok = ok;
+            let _ = EVENTS.try_send(if ok {
+                Event::RegulatorRestored
+            } else {
+                Event::RegulatorFault
+            });
+        }
+
+        let now_vbus = config.vbus.is_present();
+        if now_vbus != vbus {
+            vbus = now_vbus;
+            let _ = EVENTS.try_send(if vbus {
+                Event::VbusConnected
+            } else {
+                Event::VbusDisconnected
+            });
+        }
+        let now_host = vbus && host_present();
+        if now_host != host {
+            host = now_host;
+            let _ = EVENTS.try_send(if host {
+                Event::HostConnected
+            } else {
+                Event::HostDisconnected
+            });
+        }
+        SOURCE.send_if_changed(match (vbus, host) {
+            (_, true) => Source::UsbHost,
+            (true, false) => Source::Usb,
+            (false, false) => Source::Battery,
+        });
+
+        delay.delay_ms(config.interval_ms).await;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepMode {
+    // Waiting for an event, like the executor does when there's nothing to poll.
+    Wfe,
+    // The SLEEP state: WFI with SLEEPDEEP set and clocks gated through SLEEP_EN.
+    Sleep,
+    // The DORMANT state, with the oscillators stopped.
+    Dormant,
+}
+
+// Time spent in each state by one core, in microseconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoreStats {
+    pub active_us: u64,
+    pub wfe_us: u64,
+    pub sleep_us: u64,
+    pub dormant_us: u64,
+}
+
+impl CoreStats {
+    fn asleep_us(&self) -> u64 {
+        self.wfe_us + self.sleep_us + self.dormant_us
+    }
+
+    // Fraction of the time spent awake, in parts per million.
+    pub fn duty_cycle_ppm(&self) -> u32 {
+        let total = self.active_us + self.asleep_us();
+        if total == 0 {
+            return 0;
+        }
+        (self.active_us * 1_000_000 / total) as u32
+    }
+
+    // Average current over the whole period, given what the board draws in each state.
+    pub fn average_current_ua(&self, model: &CurrentModel) -> u32 {
+        let total = self.active_us + self.asleep_us();
+        if total == 0 {
+            return 0;
+        }
+        let charge = self.active_us * model.active_ua as u64
+            + self.wfe_us * model.wfe_ua as u64
+            + self.sleep_us * model.sleep_ua as u64
+            + self.dormant_us * model.dormant_ua as u64;
+        (charge / total) as u32
+    }
+}
+
+// What the board draws in each state, in microamps. Measure these on your own hardware; the
+// point is to compare firmware versions, not to get the datasheet numbers back.
+#[derive(Clone, Copy, Debug)]
+pub struct CurrentModel {
+    pub active_ua: u32,
+    pub wfe_ua: u32,
+    pub sleep_ua: u32,
+    pub dormant_ua: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub cores: [CoreStats; 2],
+    // How long the counts cover.
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    // Estimated battery life in seconds at the average current of both cores. Both cores share
+    // one supply, but most of what they draw is common, so this takes the busier of the two.
+    pub fn battery_life_secs(&self, model: &CurrentModel, capacity_mah: u32) -> Option<u64> {
+        let current_ua = self
+            .cores
+            .iter()
+            .map(|core| core.average_current_ua(model))
+            .max()?;
+        (current_ua > 0).then(|| capacity_mah as u64 * 1000 * 3600 / current_ua as u64)
+    }
+}
+
+struct SleepCounters {
+    since: Option<Instant>,
+    // Time asleep per core, indexed by `SleepMode`.
+    asleep_us: [[u64; 3]; 2],
+    hook: Option<fn(usize, SleepMode, Duration)>,
+}
+
+static SLEEP: Mutex<SleepCounters, 23> = Mutex::new(SleepCounters {
+    since: None,
+    asleep_us: [[0; 3]; 2],
+    hook: None,
+});
+
+// Run `sleep`, counting the time it takes against `mode` on this core.
+//
+// TIMER stops in DORMANT, so time spent there can't be measured this way; use `record` with a
+// duration from the RTC or whatever woke the chip instead.
+pub fn measure<R>(mode: SleepMode, sleep: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let ret = sleep();
+    record(mode, start.elapsed());
+    ret
+}
+
+// Count `duration` spent in `mode` on this core.
+pub fn record(mode: SleepMode, duration: Duration) {
+    let core = sync::core();
+    let hook = SLEEP.with(|counters| {
+        counters.since.get_or_insert_with(Instant::now);
+        let total = &mut counters.asleep_us[core][mode as usize];
+        *total = total.saturating_add(duration.as_micros() as u64);
+        counters.hook
+    });
+    // Don't hold the lock while calling out.
+    if let Some(hook) = hook {
+        hook(core, mode, duration);
+    }
+}
+
+// Call `hook` with the core, mode and duration after every sleep. It runs on the core that
+// just woke up, in the middle of the executor loop, so keep it short.
+pub fn set_sleep_hook(hook: Option<fn(usize, SleepMode, Duration)>) {
+    SLEEP.with(|counters| counters.hook = hook);
+}
+
+// Time spent asleep and awake on each core since the first sleep, or since `reset_stats`.
+pub fn stats() -> Stats {
+    SLEEP.with(|counters| {
+        let elapsed = counters
+            .since
+            .map_or(Duration::ZERO, |since| since.elapsed());
+        let elapsed_us = elapsed.as_micros() as u64;
+        let mut cores = [CoreStats::default(); 2];
+        for (stats, asleep) in cores.iter_mut().zip(counters.asleep_us) {
+            stats.wfe_us = asleep[SleepMode::Wfe as usize];
+            stats.sleep_us = asleep[SleepMode::Sleep as usize];
+            stats.dormant_us = asleep[SleepMode::Dormant as usize];
+            stats.active_us = elapsed_us.saturating_sub(stats.asleep_us());
+        }
+        Stats { cores, elapsed }
+    })
+}
+
+pub fn reset_stats() {
+    SLEEP.with(|counters| {
+        counters.since = Some(Instant::now());
+        counters.asleep_us = [[0; 3]; 2];
+    })
+}
@@ -0,0 +1,70 @@
+// Interrupt priorities used by the runtime.
+//
+// The M0+ only has four levels, and lower numbers preempt higher ones. Each kind of handler
+// gets its own level so the ordering is always the same: a raw handler can preempt an
+// interrupt executor, which can preempt the reactor, never the other way around. That way
+// something holding a lock at a lower level can't be spun on by a handler it preempted.
+//
+// The registration functions set these themselves; `set` is for anything outside the runtime.
+
+use core::ptr;
+
+use cortex_m::peripheral::NVIC;
+use rp2040_pac::Interrupt;
+
+// NVIC_IPR0; the rest follow it, each holding the priorities of four IRQs.
+const NVIC_IPR: *mut u32 = 0xe000_e400 as *mut u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    // Above everything the runtime does. Never used by the runtime itself.
+    Highest = 0,
+    // Handlers installed with `reactor::set_raw_handler`.
+    Raw = 1,
+    // Interrupts that run an executor.
+    Executor = 2,
+    // Interrupts that only wake tasks through the reactor. The reset default.
+    Reactor = 3,
+}
+
+impl Priority {
+    // The value as stored in the NVIC, which only implements the top two bits.
+    const fn bits(self) -> u8 {
+        (self as u8) << 6
+    }
+
+    const fn from_bits(bits: u8) -> Self {
+        match bits >> 6 {
+            0 => Priority::Highest,
+            1 => Priority::Raw,
+            2 => Priority::Executor,
+            _ => Priority::Reactor,
+        }
+    }
+}
+
+// The current priority of `irq` on this core.
+pub fn get(irq: Interrupt) -> Priority {
+    Priority::from_bits(NVIC::get_priority(irq))
+}
+
+// Set the priority of `irq` on this core. NVIC priorities are per core.
+pub fn set(irq: Interrupt, priority: Priority) {
+    if get(irq) == priority {
+        return;
+    }
+    // The M0+ can only write whole priority registers, which hold four IRQs each,
+    // so make sure nothing else on this core is halfway through changing one.
+    // That's all the exclusion there is to have, so this writes the register itself rather
+    // than stealing the NVIC from whoever claimed it.
+    let number = irq as usize;
+    let shift = 8 * (number % 4);
+    cortex_m::interrupt::free(|_| unsafe {
+        let ipr = NVIC_IPR.add(number / 4);
+        let value = ptr::read_volatile(ipr) & !(0xff << shift);
+        // Safety: Every level keeps the ordering described above, so this can't break
+        // anything that relies on one handler not preempting another.
+        ptr::write_volatile(ipr, value | (priority.bits() as u32) << shift);
+    })
+}
@@ -0,0 +1,117 @@
+// Named profiling scopes.
+//
+// `profile_scope!("name")` times the rest of the enclosing block and adds it to a
+// global table, which can be read back with `entries` or written to the log with `report`.
+
+use crate::{sync::Mutex, time::Stopwatch};
+
+pub const MAX_SCOPES: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub name: &'static str,
+    pub count: u32,
+    pub total_us: u64,
+    pub max_us: u32,
+}
+
+const EMPTY: Entry = Entry {
+    name: "",
+    count: 0,
+    total_us: 0,
+    max_us: 0,
+};
+
+struct Table {
+    entries: [Entry; MAX_SCOPES],
+    len: usize,
+    // Scopes that didn't fit in the table.
+    dropped: u32,
+}
+
+static TABLE: Mutex<Table, 16> = Mutex::new(Table {
+    entries: [EMPTY; MAX_SCOPES],
+    len: 0,
+    dropped: 0,
+});
+
+// Time the rest of the enclosing block under `name`.
+#[macro_export]
+macro_rules! profile_scope {
+    ($name:expr) => {
+        let _profile_scope = $crate::profile::Scope::new($name);
+    };
+}
+
+// Records the time between its creation and drop. Use `profile_scope!` instead of naming it.
+pub struct Scope {
+    name: &'static str,
+    stopwatch: Stopwatch,
+}
+
+impl Scope {
+    pub fn new(name: &'static str) -> Self {
+        Scope {
+            name,
+            stopwatch: Stopwatch::start(),
+        }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let elapsed = self.stopwatch.elapsed().as_micros() as u64;
+        TABLE.with(|table| {
+            let len = table.len;
+            let entry = match table.entries[..len]
+                .iter()
+                .position(|entry| entry.name == self.name)
+            {
+                Some(i) => &mut table.entries[i],
+                None if len < MAX_SCOPES => {
+                    table.len += 1;
+                    table.entries[len].name = self.name;
+                    &mut table.entries[len]
+                }
+                None => {
+                    table.dropped = table.dropped.saturating_add(1);
+                    return;
+                }
+            };
+            entry.count = entry.count.saturating_add(1);
+            entry.total_us = entry.total_us.saturating_add(elapsed);
+            entry.max_us = entry.max_us.max(elapsed.min(u32::MAX as u64) as u32);
+        })
+    }
+}
+
+// A copy of the table. Unused entries have a count of 0.
+pub fn entries() -> [Entry; MAX_SCOPES] {
+    TABLE.with(|table| table.entries)
+}
+
+pub fn reset() {
+    TABLE.with(|table| {
+        table.entries = [EMPTY; MAX_SCOPES];
+        table.len = 0;
+        table.dropped = 0;
+    })
+}
+
+// Write the table to the log, one line per scope.
+pub fn report() {
+    let (entries, dropped) = TABLE.with(|table| (table.entries, table.dropped));
+    for entry in entries.iter().filter(|entry| entry.count > 0) {
+        log::info!(
+            "{}: {} calls, {} us total, {} us avg, {} us max",
+            entry.name,
+            entry.count,
+            entry.total_us,
+            entry.total_us / entry.count as u64,
+            entry.max_us
+        );
+    }
+    if dropped > 0 {
+        log::info!("{} samples from scopes that didn't fit", dropped);
+    }
+}
@@ -0,0 +1,277 @@
+// External SPI PSRAM (APS6404 and compatibles, e.g. the 8 MiB chips on many RP2040 boards).
+//
+// The RP2040 can't memory-map a PSRAM chip, so it's accessed through async read/write calls
+// on any embedded-hal-async SPI device. `PsramHeap` hands out regions of it for large buffers
+// (framebuffers, audio) that don't fit in SRAM.
+//
+// On this crate's `spi::Spi`, give the bus DMA channels so bursts don't go through the CPU a
+// FIFO's worth at a time:
+//
+//     spi.set_dma(dma::claim().unwrap(), dma::claim().unwrap());
+//     let bus = SharedSpi::new(spi);
+//     let mut psram = Psram::new(bus.device(cs).unwrap()).await?;
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use embedded_hal_async::spi::{Operation, SpiDevice};
+
+const CMD_READ_FAST: u8 = 0x0B;
+const CMD_WRITE: u8 = 0x02;
+const CMD_RESET_ENABLE: u8 = 0x66;
+const CMD_RESET: u8 = 0x99;
+const CMD_READ_ID: u8 = 0x9F;
+
+const MANUFACTURER_ID: u8 = 0x0D;
+const KNOWN_GOOD_DIE: u8 = 0x5D;
+
+// Bursts may not cross a 1 KiB page.
+const PAGE_SIZE: u32 = 1024;
+
+pub const SIZE: u32 = 8 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Spi(E),
+    // The ID didn't match an APS6404-style chip, or the die isn't marked good.
+    UnknownChip { manufacturer: u8, kgd: u8 },
+    // The access runs past the end of the chip.
+    OutOfRange,
+}
+
+pub struct Psram<S> {
+    spi: S,
+    max_burst: u32,
+}
+
+impl<S: SpiDevice> Psram<S> {
+    // Reset the chip and check its ID.
+    pub async fn new(spi: S) -> Result<Self, Error<S::Error>> {
+        let mut psram = Psram {
+            spi,
+            max_burst: PAGE_SIZE,
+        };
+        psram.command(&[CMD_RESET_ENABLE]).await?;
+        psram.command(&[CMD_RESET]).await?;
+
+        let mut id = [0; 2];
+        psram
+            .spi
+            .transaction(&mut [
+                Operation::Write(&[CMD_READ_ID, 0, 0, 0]),
+                Operation::Read(&mut id),
+            ])
+            .await
+            .map_err(Error::Spi)?;
+        if id != [MANUFACTURER_ID, KNOWN_GOOD_DIE] {
+            return Err(Error::UnknownChip {
+                manufacturer: id[0],
+                kgd: id[1],
+            });
+        }
+        Ok(psram)
+    }
+
+    // Limit how many bytes are moved with chip select held low.
+    // The chip needs CS high every 8 us (tCEM) above 85 C to refresh itself;
+    // at room temperature a whole page is fine.
+    pub fn set_max_burst(&mut self, bytes: u32) {
+        self.max_burst = bytes.clamp(1, PAGE_SIZE);
+    }
+
+    pub async fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<S::Error>> {
+        check_range(addr, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let at = addr + done as u32;
+            let len = self.burst_len(at, buf.len() - done);
+            let [_, a2, a1, a0] = at.to_be_bytes();
+            self.spi
+                .transaction(&mut [
+                    // Fast read has one dummy byte after the address.
+                    Operation::Write(&[CMD_READ_FAST, a2, a1, a0, 0]),
+                    Operation::Read(&mut buf[done..done + len]),
+                ])
+                .await
+                .map_err(Error::Spi)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub async fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error<S::Error>> {
+        check_range(addr, data.len())?;
+        let mut done = 0;
+        while done < data.len() {
+            let at = addr + done as u32;
+            let len = self.burst_len(at, data.len() - done);
+            let [_, a2, a1, a0] = at.to_be_bytes();
+            self.spi
+                .transaction(&mut [
+                    Operation::Write(&[CMD_WRITE, a2, a1, a0]),
+                    Operation::Write(&data[done..done + len]),
+                ])
+                .await
+                .map_err(Error::Spi)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn release(self) -> S {
+        self.spi
+    }
+
+    fn burst_len(&self, addr: u32, remaining: usize) -> usize {
+        let to_page_end = PAGE_SIZE - addr % PAGE_SIZE;
+        (remaining as u32).min(to_page_end).min(self.max_burst) as usize
+    }
+
+    async fn command(&mut self, cmd: &[u8]) -> Result<(), Error<S::Error>> {
+        self.spi.write(cmd).await.map_err(Error::Spi)
+    }
+}
+
+fn check_range<E>(addr: u32, len: usize) -> Result<(), Error<E>> {
+    match addr.checked_add(len as u32) {
+        Some(end) if end <= SIZE => Ok(()),
+        _ => Err(Error::OutOfRange),
+    }
+}
+
+// A block of PSRAM handed out by a PsramHeap.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Region {
+    pub addr: u32,
+    pub len: u32,
+}
+
+impl Region {
+    pub async fn read<S: SpiDevice>(
+        &self,
+        psram: &mut Psram<S>,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<S::Error>> {
+        self.check(offset, buf.len())?;
+        psram.read(self.addr + offset, buf).await
+    }
+
+    pub async fn write<S: SpiDevice>(
+        &self,
+        psram: &mut Psram<S>,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), Error<S::Error>> {
+        self.check(offset, data.len())?;
+        psram.write(self.addr + offset, data).await
+    }
+
+    fn check<E>(&self, offset: u32, len: usize) -> Result<(), Error<E>> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+}
+
+// First-fit allocator over the PSRAM address space.
+// The bookkeeping lives in SRAM; the chip itself only holds the data.
+pub struct PsramHeap {
+    // Free (addr, len) blocks, sorted by address.
+    free: Vec<(u32, u32)>,
+}
+
+impl PsramHeap {
+    pub fn new(size: u32) -> Self {
+        let mut free = Vec::new();
+        free.push((0, size));
+        PsramHeap { free }
+    }
+
+    // Allocate `len` bytes aligned to `align` (a power of two).
+    pub fn alloc(&mut self, len: u32, align: u32) -> Option<Region> {
+        let mask = align.max(1) - 1;
+        for i in 0..self.free.len() {
+            let (start, size) = self.free[i];
+            let addr = (start + mask) & !mask;
+            let padding = addr - start;
+            if size < padding.checked_add(len)? {
+                continue;
+            }
+            let rest = size - padding - len;
+            match (padding, rest) {
+                (0, 0) => {
+                    self.free.remove(i);
+                }
+                (0, _) => self.free[i] = (addr + len, rest),
+                (_, 0) => self.free[i] = (start, padding),
+                (_, _) => {
+                    self.free[i] = (start, padding);
+                    self.free.insert(i + 1, (addr + len, rest));
+                }
+            }
+            return Some(Region { addr, len });
+        }
+        None
+    }
+
+    pub fn free(&mut self, region: Region) {
+        let i = self.free.partition_point(|&(addr, _)| addr < region.addr);
+        self.free.insert(i, (region.addr, region.len));
+        // Merge with the following block, then the preceding one.
+        if i + 1 < self.free.len() && self.free[i].0 + self.free[i].1 == self.free[i + 1].0 {
+            self.free[i].1 += self.free[i + 1].1;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == self.free[i].0 {
+            self.free[i - 1].1 += self.free[i].1;
+            self.free.remove(i);
+        }
+    }
+
+    pub fn free_bytes(&self) -> u32 {
+        self.free.iter().map(|&(_, len)| len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_and_free_coalesce() {
+        let mut heap = PsramHeap::new(4096);
+        let a = heap.alloc(100, 1).unwrap();
+        let b = heap.alloc(100, 256).unwrap();
+        // The padding before `b` is free and gets used first.
+        let c = heap.alloc(156, 1).unwrap();
+        let d = heap.alloc(100, 1).unwrap();
+        assert_eq!((a.addr, b.addr, c.addr, d.addr), (0, 256, 100, 356));
+        assert_eq!(heap.free_bytes(), 4096 - 456);
+
+        // Nothing to merge with at first, then `d` joins up with the blocks either side, and
+        // `c` fills the last gap.
+        heap.free(b);
+        heap.free(a);
+        assert_eq!(heap.free.len(), 3);
+        heap.free(d);
+        assert_eq!(heap.free, [(0, 100), (256, 3840)]);
+        heap.free(c);
+        assert_eq!(heap.free, [(0, 4096)]);
+        assert_eq!(heap.alloc(4096, 4096), Some(Region { addr: 0, len: 4096 }));
+        assert_eq!(heap.alloc(1, 1), None);
+    }
+
+    #[test]
+    fn too_big() {
+        let mut heap = PsramHeap::new(SIZE);
+        assert!(heap.alloc(SIZE + 1, 1).is_none());
+        assert!(heap.alloc(u32::MAX, 1).is_none());
+        let small = heap.alloc(1, 1).unwrap();
+        assert!(heap.alloc(SIZE, 1).is_none());
+        heap.free(small);
+        assert!(heap.alloc(SIZE, 1).is_some());
+    }
+}
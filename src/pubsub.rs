@@ -0,0 +1,138 @@
+// Publish/subscribe topics, for decoupling the tasks that produce events from the ones that act
+// on them. A driver publishes to a topic without knowing who's listening, and any number of
+// tasks subscribe, each getting every message published after it subscribed.
+//
+//     static TEMPERATURE: Topic<f32, 4> = Topic::new();
+//
+//     // in the sensor task
+//     TEMPERATURE.publish(reading);
+//
+//     // in any number of others
+//     let mut readings = TEMPERATURE.subscribe();
+//     while let Some(celsius) = readings.next().await { ... }
+//
+// Topics are statics, declared wherever the message type is, so a topic and what goes on it are
+// fixed at compile time and there's nothing to register at startup. Subscribers are streams,
+// so they go through `stream::merge` and the rest like any other.
+//
+// `publish` never waits, so drivers and interrupt handlers can publish too. A topic keeps its
+// last CAP messages; a subscriber that falls further behind than that skips the ones it missed
+// and picks up from the oldest still kept, and `lagged` says how many it lost.
+
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+};
+
+use crate::{
+    stream::Stream,
+    sync::{Mutex, WaitQueue},
+};
+
+struct State<T, const CAP: usize> {
+    buf: [Option<T>; CAP],
+    // Where the next message goes.
+    head: usize,
+    // How many messages have ever been published.
+    published: u32,
+    subscribers: WaitQueue,
+}
+
+pub struct Topic<T, const CAP: usize> {
+    state: Mutex<State<T, CAP>>,
+}
+
+impl<T: Clone, const CAP: usize> Topic<T, CAP> {
+    pub const fn new() -> Self {
+        if CAP == 0 {
+            panic!("a topic has to keep at least one message");
+        }
+        Topic {
+            state: Mutex::new(State {
+                buf: [const { None }; CAP],
+                head: 0,
+                published: 0,
+                subscribers: WaitQueue::new(),
+            }),
+        }
+    }
+
+    // Send `message` to everyone subscribed. The oldest message kept is dropped to make room.
+    pub fn publish(&self, message: T) {
+        let old = self.state.with(|state| {
+            let old = state.buf[state.head].replace(message);
+            state.head = (state.head + 1) % CAP;
+            state.published = state.published.wrapping_add(1);
+            state.subscribers.wake_all();
+            old
+        });
+        // Don't hold the lock while dropping it.
+        drop(old);
+    }
+
+    // A subscriber that gets every message published from now on.
+    pub fn subscribe(&self) -> Subscriber<'_, T, CAP> {
+        Subscriber {
+            topic: self,
+            next: self.state.with(|state| state.published),
+            lagged: 0,
+        }
+    }
+}
+
+pub struct Subscriber<'a, T, const CAP: usize> {
+    topic: &'a Topic<T, CAP>,
+    // The number of the next message this subscriber is due.
+    next: u32,
+    lagged: u32,
+}
+
+impl<T: Clone, const CAP: usize> Subscriber<'_, T, CAP> {
+    // Wait for the next message.
+    pub async fn recv(&mut self) -> T {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+        let topic = self.topic;
+        topic.state.with(|state| match self.take(state) {
+            Some(message) => Poll::Ready(message),
+            None => {
+                state.subscribers.register(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+
+    // The next message, if one has been published since the last.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let topic = self.topic;
+        topic.state.with(|state| self.take(state))
+    }
+
+    // How many messages this subscriber has missed by falling behind, since it subscribed.
+    pub fn lagged(&self) -> u32 {
+        self.lagged
+    }
+
+    fn take(&mut self, state: &mut State<T, CAP>) -> Option<T> {
+        let mut behind = state.published.wrapping_sub(self.next) as usize;
+        if behind == 0 {
+            return None;
+        }
+        if behind > CAP {
+            self.lagged = self.lagged.saturating_add((behind - CAP) as u32);
+            behind = CAP;
+        }
+        self.next = state.published.wrapping_sub(behind as u32 - 1);
+        state.buf[(state.head + CAP - behind) % CAP].clone()
+    }
+}
+
+// Topics never end.
+impl<T: Clone, const CAP: usize> Stream for Subscriber<'_, T, CAP> {
+    type Item = T;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.poll_recv(cx).map(Some)
+    }
+}
@@ -0,0 +1,81 @@
+// Packet radios behind one interface, so telemetry and mesh code doesn't care which chip it's
+// running on. Each driver also has its own methods for chip-specific settings.
+
+use embedded_hal_async::{digital::Wait, spi::SpiDevice};
+
+use crate::lora::{self, Sx127x};
+
+pub mod nrf24;
+pub mod rfm69;
+
+pub use nrf24::Nrf24;
+pub use rfm69::Rfm69;
+
+// Big enough for the largest of the supported radios (LoRa).
+pub const MAX_PACKET: usize = 255;
+
+#[derive(Clone)]
+pub struct Packet {
+    data: [u8; MAX_PACKET],
+    len: u8,
+    // Signal strength it was received at, if the radio measures it.
+    pub rssi_dbm: Option<i16>,
+}
+
+impl Packet {
+    // Returns None if `data` is longer than MAX_PACKET.
+    pub fn new(data: &[u8]) -> Option<Self> {
+        let mut packet = Packet::empty();
+        packet.data.get_mut(..data.len())?.copy_from_slice(data);
+        packet.len = data.len() as u8;
+        Some(packet)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn empty() -> Self {
+        Packet {
+            data: [0; MAX_PACKET],
+            len: 0,
+            rssi_dbm: None,
+        }
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait Radio {
+    type Error;
+    // The largest payload this radio can send in one packet.
+    const MAX_PAYLOAD: usize;
+
+    async fn send(&mut self, packet: &Packet) -> Result<(), Self::Error>;
+    // Wait for the next packet addressed to us.
+    async fn receive(&mut self) -> Result<Packet, Self::Error>;
+}
+
+impl<S: SpiDevice, P: Wait> Radio for Sx127x<S, P> {
+    type Error = lora::Error<S::Error, P::Error>;
+    const MAX_PAYLOAD: usize = lora::MAX_PAYLOAD;
+
+    async fn send(&mut self, packet: &Packet) -> Result<(), Self::Error> {
+        self.transmit(packet.as_slice()).await
+    }
+
+    async fn receive(&mut self) -> Result<Packet, Self::Error> {
+        let mut packet = Packet::empty();
+        let received = Sx127x::receive(self, &mut packet.data).await?;
+        packet.len = received.len as u8;
+        packet.rssi_dbm = Some(received.rssi_dbm);
+        Ok(packet)
+    }
+}
@@ -0,0 +1,296 @@
+// Nordic nRF24L01+ 2.4 GHz transceivers.
+//
+// Uses Enhanced ShockBurst with dynamic payload lengths and auto-acknowledge, so `send` only
+// succeeds once the receiver has acknowledged the packet. Pipe 0 is used both for receiving
+// and for the acknowledgements, so both ends are configured with the same address.
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::{
+    delay::DelayNs,
+    digital::Wait,
+    spi::{Operation, SpiDevice},
+};
+
+use super::{Packet, Radio};
+
+const R_REGISTER: u8 = 0x00;
+const W_REGISTER: u8 = 0x20;
+const R_RX_PL_WID: u8 = 0x60;
+const R_RX_PAYLOAD: u8 = 0x61;
+const W_TX_PAYLOAD: u8 = 0xA0;
+const FLUSH_TX: u8 = 0xE1;
+const FLUSH_RX: u8 = 0xE2;
+
+const CONFIG: u8 = 0x00;
+const EN_AA: u8 = 0x01;
+const EN_RXADDR: u8 = 0x02;
+const SETUP_AW: u8 = 0x03;
+const SETUP_RETR: u8 = 0x04;
+const RF_CH: u8 = 0x05;
+const RF_SETUP: u8 = 0x06;
+const STATUS: u8 = 0x07;
+const RX_ADDR_P0: u8 = 0x0A;
+const TX_ADDR: u8 = 0x10;
+const FIFO_STATUS: u8 = 0x17;
+const DYNPD: u8 = 0x1C;
+const FEATURE: u8 = 0x1D;
+
+// CONFIG bits. CRC is always on, two bytes.
+const EN_CRC: u8 = 0x08;
+const CRCO: u8 = 0x04;
+const PWR_UP: u8 = 0x02;
+const PRIM_RX: u8 = 0x01;
+
+// STATUS bits.
+const RX_DR: u8 = 0x40;
+const TX_DS: u8 = 0x20;
+const MAX_RT: u8 = 0x10;
+
+const RX_EMPTY: u8 = 0x01;
+
+pub const MAX_PAYLOAD: usize = 32;
+
+#[derive(Debug)]
+pub enum Error<S, C, I> {
+    Spi(S),
+    Ce(C),
+    Irq(I),
+    // No acknowledgement after all retries.
+    NoAck,
+    PayloadTooLong,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRate {
+    Kbps250,
+    Mbps1,
+    Mbps2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Power {
+    Dbm18Minus = 0,
+    Dbm12Minus = 1,
+    Dbm6Minus = 2,
+    Dbm0 = 3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    // 2400 + channel MHz, 0 to 125.
+    pub channel: u8,
+    pub address: [u8; 5],
+    pub data_rate: DataRate,
+    pub power: Power,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            channel: 76,
+            address: *b"rp2rx",
+            data_rate: DataRate::Mbps1,
+            power: Power::Dbm0,
+        }
+    }
+}
+
+pub struct Nrf24<S, C, I, D> {
+    spi: S,
+    ce: C,
+    irq: I,
+    delay: D,
+}
+
+impl<S, C, I, D> Nrf24<S, C, I, D>
+where
+    S: SpiDevice,
+    C: OutputPin,
+    I: Wait,
+    D: DelayNs,
+{
+    pub async fn new(
+        spi: S,
+        ce: C,
+        irq: I,
+        delay: D,
+        config: &Config,
+    ) -> Result<Self, Error<S::Error, C::Error, I::Error>> {
+        let mut radio = Nrf24 {
+            spi,
+            ce,
+            irq,
+            delay,
+        };
+        radio.ce.set_low().map_err(Error::Ce)?;
+        radio.write(SETUP_AW, 0x03).await?;
+        radio.write(EN_AA, 0x01).await?;
+        radio.write(EN_RXADDR, 0x01).await?;
+        // Up to 15 retries, 1 ms apart; long enough for an ack at 250 kbps.
+        radio.write(SETUP_RETR, 0x3F).await?;
+        radio.write(FEATURE, 0x04).await?;
+        radio.write(DYNPD, 0x01).await?;
+        radio.configure(config).await?;
+        radio.command(FLUSH_TX).await?;
+        radio.command(FLUSH_RX).await?;
+        radio.write(STATUS, RX_DR | TX_DS | MAX_RT).await?;
+        radio.write(CONFIG, EN_CRC | CRCO | PWR_UP).await?;
+        // Crystal startup.
+        radio.delay.delay_us(1500).await;
+        Ok(radio)
+    }
+
+    pub async fn configure(
+        &mut self,
+        config: &Config,
+    ) -> Result<(), Error<S::Error, C::Error, I::Error>> {
+        self.write(RF_CH, config.channel.min(125)).await?;
+        let rate = match config.data_rate {
+            DataRate::Kbps250 => 0x20,
+            DataRate::Mbps1 => 0x00,
+            DataRate::Mbps2 => 0x08,
+        };
+        self.write(RF_SETUP, rate | (config.power as u8) << 1)
+            .await?;
+        self.write_burst(RX_ADDR_P0, &config.address).await?;
+        self.write_burst(TX_ADDR, &config.address).await
+    }
+
+    // Send a payload and wait for it to be acknowledged.
+    pub async fn transmit(
+        &mut self,
+        payload: &[u8],
+    ) -> Result<(), Error<S::Error, C::Error, I::Error>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::PayloadTooLong);
+        }
+        self.ce.set_low().map_err(Error::Ce)?;
+        self.write(CONFIG, EN_CRC | CRCO | PWR_UP).await?;
+        self.command(FLUSH_TX).await?;
+        self.spi
+            .transaction(&mut [Operation::Write(&[W_TX_PAYLOAD]), Operation::Write(payload)])
+            .await
+            .map_err(Error::Spi)?;
+        // A CE pulse of at least 10 us starts the transmission.
+        self.ce.set_high().map_err(Error::Ce)?;
+        self.delay.delay_us(15).await;
+        self.ce.set_low().map_err(Error::Ce)?;
+
+        self.irq.wait_for_low().await.map_err(Error::Irq)?;
+        let status = self.read(STATUS).await?;
+        self.write(STATUS, TX_DS | MAX_RT).await?;
+        if status & MAX_RT != 0 {
+            self.command(FLUSH_TX).await?;
+            return Err(Error::NoAck);
+        }
+        Ok(())
+    }
+
+    // Listen until a payload arrives, then copy it into `buf`. Returns its length.
+    pub async fn listen(
+        &mut self,
+        buf: &mut [u8; MAX_PAYLOAD],
+    ) -> Result<usize, Error<S::Error, C::Error, I::Error>> {
+        self.write(CONFIG, EN_CRC | CRCO | PWR_UP | PRIM_RX).await?;
+        self.ce.set_high().map_err(Error::Ce)?;
+        loop {
+            if self.read(FIFO_STATUS).await? & RX_EMPTY != 0 {
+                self.irq.wait_for_low().await.map_err(Error::Irq)?;
+            }
+            self.write(STATUS, RX_DR).await?;
+            let mut len = [0];
+            self.spi
+                .transaction(&mut [Operation::Write(&[R_RX_PL_WID]), Operation::Read(&mut len)])
+                .await
+                .map_err(Error::Spi)?;
+            let len = len[0] as usize;
+            if len > MAX_PAYLOAD {
+                // Corrupt length; the datasheet says to drop the whole FIFO.
+                self.command(FLUSH_RX).await?;
+                continue;
+            }
+            self.spi
+                .transaction(&mut [
+                    Operation::Write(&[R_RX_PAYLOAD]),
+                    Operation::Read(&mut buf[..len]),
+                ])
+                .await
+                .map_err(Error::Spi)?;
+            self.ce.set_low().map_err(Error::Ce)?;
+            return Ok(len);
+        }
+    }
+
+    // Standby with the oscillator off, about 1 uA. Configuration is kept.
+    pub async fn power_down(&mut self) -> Result<(), Error<S::Error, C::Error, I::Error>> {
+        self.ce.set_low().map_err(Error::Ce)?;
+        self.write(CONFIG, EN_CRC | CRCO).await
+    }
+
+    pub fn release(self) -> (S, C, I, D) {
+        (self.spi, self.ce, self.irq, self.delay)
+    }
+
+    async fn command(&mut self, command: u8) -> Result<(), Error<S::Error, C::Error, I::Error>> {
+        self.spi.write(&[command]).await.map_err(Error::Spi)
+    }
+
+    async fn read(&mut self, reg: u8) -> Result<u8, Error<S::Error, C::Error, I::Error>> {
+        let mut value = [0];
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[R_REGISTER | reg]),
+                Operation::Read(&mut value),
+            ])
+            .await
+            .map_err(Error::Spi)?;
+        Ok(value[0])
+    }
+
+    async fn write(
+        &mut self,
+        reg: u8,
+        value: u8,
+    ) -> Result<(), Error<S::Error, C::Error, I::Error>> {
+        self.write_burst(reg, &[value]).await
+    }
+
+    async fn write_burst(
+        &mut self,
+        reg: u8,
+        data: &[u8],
+    ) -> Result<(), Error<S::Error, C::Error, I::Error>> {
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[W_REGISTER | reg]),
+                Operation::Write(data),
+            ])
+            .await
+            .map_err(Error::Spi)
+    }
+}
+
+impl<S, C, I, D> Radio for Nrf24<S, C, I, D>
+where
+    S: SpiDevice,
+    C: OutputPin,
+    I: Wait,
+    D: DelayNs,
+{
+    type Error = Error<S::Error, C::Error, I::Error>;
+    const MAX_PAYLOAD: usize = MAX_PAYLOAD;
+
+    async fn send(&mut self, packet: &Packet) -> Result<(), Self::Error> {
+        self.transmit(packet.as_slice()).await
+    }
+
+    async fn receive(&mut self) -> Result<Packet, Self::Error> {
+        let mut packet = Packet::empty();
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = self.listen(&mut buf).await?;
+        packet.data[..len].copy_from_slice(&buf[..len]);
+        packet.len = len as u8;
+        Ok(packet)
+    }
+}
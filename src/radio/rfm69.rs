@@ -0,0 +1,277 @@
+// HopeRF RFM69 (Semtech SX1231) sub-GHz FSK transceivers.
+//
+// Variable length packets with CRC, woken by DIO0 (PacketSent / PayloadReady). The high power
+// RFM69HW/HCW modules only transmit through PA_BOOST, so say which one you have in Config.
+
+use embedded_hal_async::{
+    digital::Wait,
+    spi::{Operation, SpiDevice},
+};
+
+use super::{Packet, Radio};
+
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_DATA_MODUL: u8 = 0x02;
+const REG_BITRATE_MSB: u8 = 0x03;
+const REG_FDEV_MSB: u8 = 0x05;
+const REG_FRF_MSB: u8 = 0x07;
+const REG_VERSION: u8 = 0x10;
+const REG_PA_LEVEL: u8 = 0x11;
+const REG_OCP: u8 = 0x13;
+const REG_RX_BW: u8 = 0x19;
+const REG_RSSI_VALUE: u8 = 0x24;
+const REG_DIO_MAPPING_1: u8 = 0x25;
+const REG_IRQ_FLAGS_1: u8 = 0x27;
+const REG_SYNC_CONFIG: u8 = 0x2E;
+const REG_SYNC_VALUE_1: u8 = 0x2F;
+const REG_PACKET_CONFIG_1: u8 = 0x37;
+const REG_PAYLOAD_LENGTH: u8 = 0x38;
+const REG_FIFO_THRESH: u8 = 0x3C;
+const REG_PACKET_CONFIG_2: u8 = 0x3D;
+const REG_TEST_PA_1: u8 = 0x5A;
+const REG_TEST_PA_2: u8 = 0x5C;
+const REG_TEST_DAGC: u8 = 0x6F;
+
+const MODE_SLEEP: u8 = 0x00;
+const MODE_STANDBY: u8 = 0x04;
+const MODE_TX: u8 = 0x0C;
+const MODE_RX: u8 = 0x10;
+
+const IRQ_MODE_READY: u8 = 0x80;
+
+// DIO0 function, in the top two bits of REG_DIO_MAPPING_1. Its meaning depends on the mode.
+const DIO0_PACKET_SENT: u8 = 0x00;
+const DIO0_PAYLOAD_READY: u8 = 0x40;
+
+const VERSION: u8 = 0x24;
+const FXOSC: u64 = 32_000_000;
+
+// The FIFO is 66 bytes and also holds the length byte; leave room for the address byte
+// that other RFM69 libraries add, so packets interoperate.
+pub const MAX_PAYLOAD: usize = 64;
+
+#[derive(Debug)]
+pub enum Error<S, P> {
+    Spi(S),
+    Pin(P),
+    // REG_VERSION didn't read back as an SX1231.
+    UnknownChip(u8),
+    PayloadTooLong,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub frequency_hz: u32,
+    pub bitrate: u32,
+    pub deviation_hz: u32,
+    // -18 to 13 dBm on regular modules, -2 to 20 dBm on high power ones.
+    pub tx_power_dbm: i8,
+    pub high_power: bool,
+    // Both ends need the same one; it also acts as a network id.
+    pub sync_word: [u8; 2],
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            frequency_hz: 868_000_000,
+            bitrate: 55_555,
+            deviation_hz: 50_000,
+            tx_power_dbm: 13,
+            high_power: false,
+            sync_word: [0x2D, 0xD4],
+        }
+    }
+}
+
+pub struct Rfm69<S, P> {
+    spi: S,
+    dio0: P,
+    high_power: bool,
+    tx_power_dbm: i8,
+}
+
+impl<S: SpiDevice, P: Wait> Rfm69<S, P> {
+    // Check the chip is there and apply `config`. Leaves it in standby.
+    pub async fn new(spi: S, dio0: P, config: &Config) -> Result<Self, Error<S::Error, P::Error>> {
+        let mut radio = Rfm69 {
+            spi,
+            dio0,
+            high_power: config.high_power,
+            tx_power_dbm: config.tx_power_dbm,
+        };
+        let version = radio.read(REG_VERSION).await?;
+        if version != VERSION {
+            return Err(Error::UnknownChip(version));
+        }
+        radio.set_mode(MODE_STANDBY).await?;
+        // Packet mode, FSK, no shaping.
+        radio.write(REG_DATA_MODUL, 0x00).await?;
+        // 125 kHz receiver bandwidth, enough for the default deviation.
+        radio.write(REG_RX_BW, 0x42).await?;
+        // Variable length, whitening, CRC on, no address filtering.
+        radio.write(REG_PACKET_CONFIG_1, 0xD0).await?;
+        radio.write(REG_PAYLOAD_LENGTH, MAX_PAYLOAD as u8).await?;
+        // Start sending as soon as there's anything in the FIFO.
+        radio.write(REG_FIFO_THRESH, 0x8F).await?;
+        // Restart the receiver automatically after a packet, no AES.
+        radio.write(REG_PACKET_CONFIG_2, 0x02).await?;
+        // Improved fading margin, per the datasheet.
+        radio.write(REG_TEST_DAGC, 0x30).await?;
+        radio.configure(config).await?;
+        Ok(radio)
+    }
+
+    pub async fn configure(&mut self, config: &Config) -> Result<(), Error<S::Error, P::Error>> {
+        self.set_frequency(config.frequency_hz).await?;
+        let bitrate = (FXOSC / config.bitrate.max(1) as u64) as u16;
+        self.write_burst(REG_BITRATE_MSB, &bitrate.to_be_bytes())
+            .await?;
+        // Same 61 Hz step as the synthesizer.
+        let fdev = (((config.deviation_hz as u64) << 19) / FXOSC) as u16;
+        self.write_burst(REG_FDEV_MSB, &fdev.to_be_bytes()).await?;
+        // Sync word on, two bytes.
+        self.write(REG_SYNC_CONFIG, 0x88).await?;
+        self.write_burst(REG_SYNC_VALUE_1, &config.sync_word)
+            .await?;
+        self.high_power = config.high_power;
+        self.set_tx_power(config.tx_power_dbm).await
+    }
+
+    pub async fn set_frequency(&mut self, hz: u32) -> Result<(), Error<S::Error, P::Error>> {
+        let frf = ((hz as u64) << 19) / FXOSC;
+        let [_, msb, mid, lsb] = (frf as u32).to_be_bytes();
+        self.write_burst(REG_FRF_MSB, &[msb, mid, lsb]).await
+    }
+
+    pub async fn set_tx_power(&mut self, dbm: i8) -> Result<(), Error<S::Error, P::Error>> {
+        let level = if self.high_power {
+            match dbm.clamp(-2, 20) {
+                // PA1 alone.
+                dbm @ -2..=13 => 0x40 | (dbm + 18) as u8,
+                // PA1 and PA2.
+                dbm @ 14..=17 => 0x60 | (dbm + 14) as u8,
+                // PA1 and PA2 with the high power settings, applied while transmitting.
+                dbm => 0x60 | (dbm + 11) as u8,
+            }
+        } else {
+            // PA0 alone.
+            0x80 | (dbm.clamp(-18, 13) + 18) as u8
+        };
+        self.tx_power_dbm = dbm;
+        self.write(REG_PA_LEVEL, level).await
+    }
+
+    pub async fn transmit(&mut self, payload: &[u8]) -> Result<(), Error<S::Error, P::Error>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::PayloadTooLong);
+        }
+        self.set_mode(MODE_STANDBY).await?;
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[REG_FIFO | 0x80, payload.len() as u8]),
+                Operation::Write(payload),
+            ])
+            .await
+            .map_err(Error::Spi)?;
+        let boost = self.high_power && self.tx_power_dbm > 17;
+        if boost {
+            // Overcurrent protection off and the +20 dBm test settings, only while sending.
+            self.write(REG_OCP, 0x0F).await?;
+            self.write(REG_TEST_PA_1, 0x5D).await?;
+            self.write(REG_TEST_PA_2, 0x7C).await?;
+        }
+        self.write(REG_DIO_MAPPING_1, DIO0_PACKET_SENT).await?;
+        self.set_mode(MODE_TX).await?;
+        let sent = self.dio0.wait_for_high().await.map_err(Error::Pin);
+        self.set_mode(MODE_STANDBY).await?;
+        if boost {
+            self.write(REG_TEST_PA_1, 0x55).await?;
+            self.write(REG_TEST_PA_2, 0x70).await?;
+            self.write(REG_OCP, 0x1A).await?;
+        }
+        sent
+    }
+
+    // Listen until a packet arrives and copy it into `buf`. Returns its length and RSSI.
+    pub async fn listen(
+        &mut self,
+        buf: &mut [u8; MAX_PAYLOAD],
+    ) -> Result<(usize, i16), Error<S::Error, P::Error>> {
+        self.write(REG_DIO_MAPPING_1, DIO0_PAYLOAD_READY).await?;
+        self.set_mode(MODE_RX).await?;
+        self.dio0.wait_for_high().await.map_err(Error::Pin)?;
+        // RSSI is latched at the sync word, but only readable until leaving RX.
+        let rssi_dbm = -(self.read(REG_RSSI_VALUE).await? as i16) / 2;
+        self.set_mode(MODE_STANDBY).await?;
+        let mut len = [0];
+        self.read_burst(REG_FIFO, &mut len).await?;
+        let len = (len[0] as usize).min(MAX_PAYLOAD);
+        self.read_burst(REG_FIFO, &mut buf[..len]).await?;
+        Ok((len, rssi_dbm))
+    }
+
+    // About 0.1 uA. Configuration is kept.
+    pub async fn sleep(&mut self) -> Result<(), Error<S::Error, P::Error>> {
+        self.set_mode(MODE_SLEEP).await
+    }
+
+    pub fn release(self) -> (S, P) {
+        (self.spi, self.dio0)
+    }
+
+    async fn set_mode(&mut self, mode: u8) -> Result<(), Error<S::Error, P::Error>> {
+        self.write(REG_OP_MODE, mode).await?;
+        // Mode changes take tens of microseconds; wait until the chip says it's done.
+        while self.read(REG_IRQ_FLAGS_1).await? & IRQ_MODE_READY == 0 {}
+        Ok(())
+    }
+
+    async fn read(&mut self, reg: u8) -> Result<u8, Error<S::Error, P::Error>> {
+        let mut value = [0];
+        self.read_burst(reg, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn read_burst(
+        &mut self,
+        reg: u8,
+        buf: &mut [u8],
+    ) -> Result<(), Error<S::Error, P::Error>> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[reg & 0x7F]), Operation::Read(buf)])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    async fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<S::Error, P::Error>> {
+        self.write_burst(reg, &[value]).await
+    }
+
+    async fn write_burst(&mut self, reg: u8, data: &[u8]) -> Result<(), Error<S::Error, P::Error>> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[reg | 0x80]), Operation::Write(data)])
+            .await
+            .map_err(Error::Spi)
+    }
+}
+
+impl<S: SpiDevice, P: Wait> Radio for Rfm69<S, P> {
+    type Error = Error<S::Error, P::Error>;
+    const MAX_PAYLOAD: usize = MAX_PAYLOAD;
+
+    async fn send(&mut self, packet: &Packet) -> Result<(), Self::Error> {
+        self.transmit(packet.as_slice()).await
+    }
+
+    async fn receive(&mut self) -> Result<Packet, Self::Error> {
+        let mut packet = Packet::empty();
+        let mut buf = [0; MAX_PAYLOAD];
+        let (len, rssi_dbm) = self.listen(&mut buf).await?;
+        packet.data[..len].copy_from_slice(&buf[..len]);
+        packet.len = len as u8;
+        packet.rssi_dbm = Some(rssi_dbm);
+        Ok(packet)
+    }
+}
@@ -0,0 +1,223 @@
+// Looking for RAM that's gone bad, for boards in electrically noisy places.
+//
+// `test` is a march test for memory nothing is using yet: it writes patterns up and down the
+// range and checks every word reads back, which finds stuck bits, bits that flip their
+// neighbours and addresses that alias. It destroys what was there. `test_unused` runs it over
+// the RAM between the end of the statics and the stack, and the `ram-test` feature does that
+// at boot.
+//
+// Memory in use can't be tested like that, but memory that's meant to stay the same can be
+// checked. `guard` remembers a CRC of a static, and `scrub` goes round every so often checking
+// it's still the same. Anything that does change a guarded static legitimately does it inside
+// `update`. The runtime guards both cores' vector tables.
+
+use core::{mem::size_of_val, ptr, time::Duration};
+
+use crate::{metrics::Counter, sync::Mutex, time};
+
+pub const MAX_GUARDED: usize = 16;
+
+// Room left below the stack pointer by `test_unused`, for its own frames.
+const STACK_MARGIN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fault {
+    pub address: usize,
+    pub expected: u32,
+    pub found: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Corruption {
+    pub name: &'static str,
+    pub address: usize,
+    pub len: usize,
+}
+
+static CORRUPTIONS: Counter = Counter::new("ram.corruptions");
+
+// Run the march test over `memory`, leaving it zeroed.
+//
+// Safety: Nothing else may use `memory` while this runs, including interrupt handlers and the
+// other core.
+pub unsafe fn test(memory: *mut u32, words: usize) -> Result<(), Fault> {
+    let word = |i: usize| unsafe { memory.add(i) };
+    let check = |i: usize, expected: u32| {
+        let found = unsafe { ptr::read_volatile(word(i)) };
+        if found == expected {
+            Ok(())
+        } else {
+            Err(Fault {
+                address: word(i) as usize,
+                expected,
+                found,
+            })
+        }
+    };
+    let write = |i: usize, value: u32| unsafe { ptr::write_volatile(word(i), value) };
+
+    // March C-: up writing 0; up reading 0 writing 1; up reading 1 writing 0; down reading 0
+    // writing 1; down reading 1 writing 0; reading 0.
+    for i in 0..words {
+        write(i, 0);
+    }
+    for (from, to) in [(0, !0), (!0, 0)] {
+        for i in 0..words {
+            check(i, from)?;
+            write(i, to);
+        }
+    }
+    for (from, to) in [(0, !0), (!0, 0)] {
+        for i in (0..words).rev() {
+            check(i, from)?;
+            write(i, to);
+        }
+    }
+    // Each word its own address, which catches two addresses landing on the same word.
+    for i in 0..words {
+        write(i, word(i) as u32);
+    }
+    for i in 0..words {
+        check(i, word(i) as u32)?;
+        write(i, 0);
+    }
+    Ok(())
+}
+
+// Test the RAM that nothing has been put in: from the end of the statics (`.uninit` included,
+// so `postmortem` and `persist` keep their records) to a little below the stack pointer. Only
+// before core 1 is started; interrupts are masked while it runs.
+pub fn test_unused() -> Result<(), Fault> {
+    extern "C" {
+        // From cortex-m-rt's linker script: the first address after the statics.
+        static __sheap: u32;
+    }
+    let start = ptr::addr_of!(__sheap) as usize;
+    let end = cortex_m::register::msp::read() as usize - STACK_MARGIN;
+    let words = end.saturating_sub(start) / 4;
+    // Safety: Nothing lives between the statics and the stack yet, and with interrupts masked
+    // no handler's frame can land there.
+    cortex_m::interrupt::free(|_| unsafe { test(start as *mut u32, words) })
+}
+
+#[derive(Clone, Copy)]
+struct Guarded {
+    name: &'static str,
+    address: usize,
+    len: usize,
+    // None while an `update` is under way.
+    crc: Option<u32>,
+    // Bumped by every `update`, so `scrub` can tell a change happened while it was reading.
+    generation: u32,
+}
+
+static GUARDED: Mutex<[Option<Guarded>; MAX_GUARDED]> = Mutex::new([None; MAX_GUARDED]);
+
+fn bounds<T: ?Sized>(value: &T) -> (usize, usize) {
+    (value as *const T as *const u8 as usize, size_of_val(value))
+}
+
+// Remember what `value` holds now, for `scrub` to check. Returns false if there's no room left
+// to guard it, or it's guarded already.
+pub fn guard<T: ?Sized>(name: &'static str, value: &'static T) -> bool {
+    let (address, len) = bounds(value);
+    let crc = crc32(address, len);
+    GUARDED.with(|guarded| {
+        if guarded.iter().flatten().any(|g| g.address == address) {
+            return false;
+        }
+        let Some(free) = guarded.iter_mut().find(|g| g.is_none()) else {
+            return false;
+        };
+        *free = Some(Guarded {
+            name,
+            address,
+            len,
+            crc: Some(crc),
+            generation: 0,
+        });
+        true
+    })
+}
+
+// Change a guarded static with `f`, so `scrub` doesn't take the change for corruption. Does
+// nothing special if `value` isn't guarded.
+pub fn update<T: ?Sized, R>(value: &T, f: impl FnOnce() -> R) -> R {
+    let (address, len) = bounds(value);
+    let set = |crc: Option<u32>| {
+        GUARDED.with(|guarded| {
+            let entry = guarded.iter_mut().flatten().find(|g| g.address == address);
+            if let Some(entry) = entry {
+                entry.crc = crc;
+                entry.generation = entry.generation.wrapping_add(1);
+            }
+        })
+    };
+    set(None);
+    let ret = f();
+    set(Some(crc32(address, len)));
+    ret
+}
+
+// Check one guarded static every `interval`, going round them all, and call `report` for any
+// that changed outside `update`. Each is reported once; after that its new contents are what
+// it's checked against.
+pub async fn scrub(interval: Duration, mut report: impl FnMut(Corruption)) -> ! {
+    let mut next = 0;
+    loop {
+        time::sleep(interval).await;
+        let entry = GUARDED.with(|guarded| {
+            let found = (0..MAX_GUARDED)
+                .map(|i| (next + i) % MAX_GUARDED)
+                .find_map(|i| Some((i, guarded[i]?)));
+            if let Some((i, _)) = found {
+                next = i + 1;
+            }
+            found
+        });
+        let Some((i, before)) = entry else {
+            continue;
+        };
+        let Some(expected) = before.crc else {
+            continue;
+        };
+        let crc = crc32(before.address, before.len);
+        let corrupted = crc != expected
+            && GUARDED.with(|guarded| match &mut guarded[i] {
+                // Only if nobody's been through `update` while we were reading.
+                Some(now)
+                    if now.address == before.address && now.generation == before.generation =>
+                {
+                    now.crc = Some(crc);
+                    true
+                }
+                _ => false,
+            });
+        if corrupted {
+            CORRUPTIONS.inc();
+            report(Corruption {
+                name: before.name,
+                address: before.address,
+                len: before.len,
+            });
+        }
+    }
+}
+
+// CRC-32 (the zlib one), read a byte at a time with volatile reads: the memory may be
+// changing under us, and that's the point.
+fn crc32(address: usize, len: usize) -> u32 {
+    let mut crc = !0u32;
+    for i in 0..len {
+        let byte = unsafe { ptr::read_volatile((address + i) as *const u8) };
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,301 @@
+// RC receiver serial protocols: SBUS, IBUS and CRSF, decoded from any async byte stream into
+// channel frames.
+//
+// Each protocol's frames are found by their header and checked (length, footer, checksum or
+// CRC), so the decoder locks on from anywhere in the stream and skips garbage. Channels come
+// out in microseconds of the equivalent servo pulse, 1000 to 2000 with 1500 centred, whatever
+// the protocol sends on the wire.
+//
+//     let mut rx = PioUartRx::new(0, 5, &Protocol::Sbus.framing(), sys_hz)?;
+//     rx.set_inverted(Protocol::Sbus.inverted());
+//     let mut receiver = Receiver::new(rx, Protocol::Sbus);
+//     loop {
+//         let frame = receiver.next_frame().await?;
+//         if frame.failsafe { ... } else { mix(&frame.channels) }
+//     }
+//
+// Failsafe is whatever the receiver says it is (SBUS has a flag for it) or no valid frame for
+// `FAILSAFE_AFTER`, which is how IBUS and CRSF receivers signal a lost link: they go quiet.
+
+use core::time::Duration;
+
+use embedded_io_async::Read;
+
+use crate::{
+    pio_uart::{Framing, Parity},
+    time::{self, Elapsed},
+};
+
+pub const CHANNELS: usize = 16;
+pub const FAILSAFE_AFTER: Duration = Duration::from_millis(100);
+// The longest CRSF frame, which is the longest of the three.
+const MAX_FRAME: usize = 64;
+
+const SBUS_HEADER: u8 = 0x0f;
+const SBUS_LEN: usize = 25;
+const IBUS_LEN: u8 = 0x20;
+const IBUS_COMMAND: u8 = 0x40;
+// Frames addressed to the flight controller.
+const CRSF_ADDRESS: u8 = 0xc8;
+const CRSF_RC_CHANNELS: u8 = 0x16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    // Futaba SBUS, and FrSky's: 16 11-bit channels every 7 or 14 ms, inverted.
+    Sbus,
+    // FlySky IBUS: 14 channels every 7 ms.
+    Ibus,
+    // TBS Crossfire and ExpressLRS.
+    Crsf,
+}
+
+impl Protocol {
+    pub fn framing(&self) -> Framing {
+        let (baud, parity, stop_bits) = match self {
+            Protocol::Sbus => (100_000, Parity::Even, 2),
+            Protocol::Ibus => (115_200, Parity::None, 1),
+            Protocol::Crsf => (420_000, Parity::None, 1),
+        };
+        Framing {
+            baud,
+            data_bits: 8,
+            parity,
+            stop_bits,
+            break_len: Duration::ZERO,
+            mark_after_break: Duration::ZERO,
+        }
+    }
+
+    // Whether the line idles low, so the UART's input has to be inverted.
+    pub fn inverted(&self) -> bool {
+        *self == Protocol::Sbus
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    // In microseconds. Only the first `len` mean anything.
+    pub channels: [u16; CHANNELS],
+    pub len: u8,
+    // The receiver has lost the transmitter. `channels` are the last ones received, or the
+    // receiver's failsafe positions if it sends those.
+    pub failsafe: bool,
+    // SBUS only: the receiver missed a frame from the transmitter and repeated the last one.
+    pub frame_lost: bool,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Read(E),
+    // The stream ended.
+    Eof,
+}
+
+pub struct Receiver<R> {
+    reader: R,
+    protocol: Protocol,
+    frame: [u8; MAX_FRAME],
+    len: usize,
+    rx: [u8; 32],
+    rx_pos: usize,
+    rx_len: usize,
+    last: Frame,
+}
+
+impl<R: Read> Receiver<R> {
+    pub fn new(reader: R, protocol: Protocol) -> Self {
+        Receiver {
+            reader,
+            protocol,
+            frame: [0; MAX_FRAME],
+            len: 0,
+            rx: [0; 32],
+            rx_pos: 0,
+            rx_len: 0,
+            last: Frame {
+                channels: [1500; CHANNELS],
+                len: 0,
+                failsafe: true,
+                frame_lost: false,
+            },
+        }
+    }
+
+    // The next frame of channels, or the last one again marked as failsafe if nothing valid
+    // arrives for `FAILSAFE_AFTER`. Frames other than channels (CRSF telemetry and link
+    // statistics) are skipped.
+    pub async fn next_frame(&mut self) -> Result<Frame, Error<R::Error>> {
+        match time::timeout(FAILSAFE_AFTER, self.decode()).await {
+            Ok(result) => {
+                self.last = result?;
+                Ok(self.last)
+            }
+            Err(Elapsed) => {
+                // What was half received when the link went is no good any more.
+                self.len = 0;
+                self.last.failsafe = true;
+                Ok(self.last)
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    async fn decode(&mut self) -> Result<Frame, Error<R::Error>> {
+        loop {
+            let byte = self.next_byte().await?;
+            if self.len == 0 && !self.is_header(byte) {
+                continue;
+            }
+            self.frame[self.len] = byte;
+            self.len += 1;
+            let Some(expected) = self.expected_len() else {
+                // A CRSF length that can't be right.
+                self.resync();
+                continue;
+            };
+            if self.len < expected {
+                continue;
+            }
+            match self.parse(expected) {
+                Some(Some(frame)) => {
+                    self.len = 0;
+                    return Ok(frame);
+                }
+                // Valid, but not channels.
+                Some(None) => self.len = 0,
+                None => self.resync(),
+            }
+        }
+    }
+
+    fn is_header(&self, byte: u8) -> bool {
+        match self.protocol {
+            Protocol::Sbus => byte == SBUS_HEADER,
+            Protocol::Ibus => byte == IBUS_LEN,
+            Protocol::Crsf => byte == CRSF_ADDRESS,
+        }
+    }
+
+    // How long the frame being received is, once enough of it is in to tell.
+    fn expected_len(&self) -> Option<usize> {
+        match self.protocol {
+            Protocol::Sbus => Some(SBUS_LEN),
+            Protocol::Ibus => Some(IBUS_LEN as usize),
+            Protocol::Crsf if self.len < 2 => Some(2),
+            // The length byte counts the type, payload and CRC.
+            Protocol::Crsf => match self.frame[1] as usize {
+                len @ 2..=62 => Some(len + 2),
+                _ => None,
+            },
+        }
+    }
+
+    // A complete frame's channels; `Some(None)` for a valid frame without any, `None` if it
+    // doesn't check out.
+    fn parse(&self, len: usize) -> Option<Option<Frame>> {
+        let frame = &self.frame[..len];
+        let mut channels = [0; CHANNELS];
+        match self.protocol {
+            Protocol::Sbus => {
+                // SBUS2 receivers vary the low bits of the footer for telemetry slots.
+                if frame[24] != 0 && frame[24] & 0x0f != 0x04 {
+                    return None;
+                }
+                unpack_11bit(&frame[1..23], &mut channels);
+                let flags = frame[23];
+                Some(Some(Frame {
+                    channels,
+                    len: CHANNELS as u8,
+                    failsafe: flags & 1 << 3 != 0,
+                    frame_lost: flags & 1 << 2 != 0,
+                }))
+            }
+            Protocol::Ibus => {
+                let word = |i: usize| u16::from_le_bytes([frame[i], frame[i + 1]]);
+                let sum = frame[..30]
+                    .iter()
+                    .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+                if frame[1] != IBUS_COMMAND || word(30) != 0xffff - sum {
+                    return None;
+                }
+                for (i, channel) in channels.iter_mut().take(14).enumerate() {
+                    // The top nibble carries extra channels on some receivers.
+                    *channel = word(2 + 2 * i) & 0x0fff;
+                }
+                Some(Some(Frame {
+                    channels,
+                    len: 14,
+                    failsafe: false,
+                    frame_lost: false,
+                }))
+            }
+            Protocol::Crsf => {
+                if crc8_dvb_s2(&frame[2..len - 1]) != frame[len - 1] {
+                    return None;
+                }
+                if frame[2] != CRSF_RC_CHANNELS || len != 26 {
+                    return Some(None);
+                }
+                unpack_11bit(&frame[3..25], &mut channels);
+                Some(Some(Frame {
+                    channels,
+                    len: CHANNELS as u8,
+                    failsafe: false,
+                    frame_lost: false,
+                }))
+            }
+        }
+    }
+
+    // The frame didn't check out, so its header wasn't one. Look for the next header in what
+    // was received after it.
+    fn resync(&mut self) {
+        let next = (1..self.len)
+            .find(|&i| self.is_header(self.frame[i]))
+            .unwrap_or(self.len);
+        self.frame.copy_within(next..self.len, 0);
+        self.len -= next;
+    }
+
+    async fn next_byte(&mut self) -> Result<u8, Error<R::Error>> {
+        if self.rx_pos == self.rx_len {
+            self.rx_len = self.reader.read(&mut self.rx).await.map_err(Error::Read)?;
+            self.rx_pos = 0;
+            if self.rx_len == 0 {
+                return Err(Error::Eof);
+            }
+        }
+        self.rx_pos += 1;
+        Ok(self.rx[self.rx_pos - 1])
+    }
+}
+
+// 16 11-bit channels, LSB first, as SBUS and CRSF pack them, scaled to microseconds: 172 is
+// 988 µs, 992 is 1500 and 1811 is 2012.
+fn unpack_11bit(data: &[u8], channels: &mut [u16; CHANNELS]) {
+    for (i, channel) in channels.iter_mut().enumerate() {
+        let bit = i * 11;
+        let byte = |at: usize| *data.get(at).unwrap_or(&0) as u32;
+        let bits = byte(bit / 8) | byte(bit / 8 + 1) << 8 | byte(bit / 8 + 2) << 16;
+        let raw = (bits >> (bit % 8) & 0x7ff) as i32;
+        *channel = (1500 + (raw - 992) * 5 / 8) as u16;
+    }
+}
+
+fn crc8_dvb_s2(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0xd5
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
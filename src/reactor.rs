@@ -1,27 +1,188 @@
-use core::task::Waker;
+use core::{mem, task::Waker};
 
 extern crate alloc;
 
 use alloc::vec::Vec;
+use cortex_m::{interrupt::InterruptNumber, peripheral::NVIC};
 use cortex_m_rt::exception;
+use rp2040_pac::Interrupt;
+use serde::Serialize;
 
-use crate::sync::Mutex;
+use crate::{
+    hostlink, postmortem,
+    priority::{self, Priority},
+    sync::{self, Mutex},
+    time::Instant,
+    vectors,
+};
+
+const IRQS: usize = 26;
 
 type WakerList = Vec<Waker>;
 const WAKER_LIST: WakerList = WakerList::new();
-pub static WAKERS: Mutex<[WakerList; 26], 7> = Mutex::new([WAKER_LIST; 26]);
+pub static WAKERS: Mutex<[WakerList; IRQS], 7> = Mutex::new([WAKER_LIST; IRQS]);
+
+// What the reactor's handler has done for one interrupt, since boot or `reset_stats`.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct IrqStats {
+    pub count: u32,
+    // Wakers woken, in total and the most at once.
+    pub wakes: u32,
+    pub max_fanout: u32,
+    // Time spent in the handler, in microseconds.
+    pub busy_us: u32,
+    pub max_us: u32,
+    // Times it fired with nobody waiting: something unmasked it without going through
+    // `register`, or it was pending from before. Usually a driver bug.
+    pub spurious: u32,
+}
+
+static STATS: Mutex<[IrqStats; IRQS]> = Mutex::new(
+    [IrqStats {
+        count: 0,
+        wakes: 0,
+        max_fanout: 0,
+        busy_us: 0,
+        max_us: 0,
+        spurious: 0,
+    }; IRQS],
+);
+
+pub fn stats() -> [IrqStats; IRQS] {
+    STATS.with(|stats| *stats)
+}
+
+pub fn reset_stats() {
+    STATS.with(|stats| *stats = [IrqStats::default(); IRQS])
+}
+
+// The interrupts that have fired with nobody waiting, a bit each.
+pub fn spurious() -> u32 {
+    stats()
+        .iter()
+        .enumerate()
+        .filter(|(_, irq)| irq.spurious > 0)
+        .fold(0, |bits, (irqn, _)| bits | 1 << irqn)
+}
+
+// Answers with `stats()`, for poking at interrupts from the host while bringing up a driver.
+pub struct GetIrqStats;
+
+impl hostlink::Endpoint for GetIrqStats {
+    const KIND: u16 = 0xff01;
+    type Request = ();
+    type Response = [IrqStats; IRQS];
+}
+
+// Wake `waker` the next time `irq` fires. `irq` is moved to `Priority::Reactor` and unmasked.
+// Drivers get at this through the `irq::Irq` they own.
+//
+// Peripheral interrupts are level triggered and stay asserted until the peripheral is dealt
+// with, which only happens once the woken task runs. So the handler masks the interrupt again
+// after waking everyone, and it stays masked until something registers for it again. Check the
+// peripheral's status before registering, since an interrupt that's already pending fires as
+// soon as it's unmasked.
+//
+// Panics if a raw handler is installed for `irq`, since it would never be woken.
+pub(crate) fn register(irq: Interrupt, waker: &Waker) {
+    let irqn = irq as usize;
+    priority::set(irq, Priority::Reactor);
+    WAKERS.with(|wakers| {
+        if !vectors::is_default(irq) {
+            postmortem::fatal("IRQ has a raw handler installed");
+        }
+        let Some(list) = wakers.get_mut(irqn) else {
+            return;
+        };
+        if !list.iter().any(|w| w.will_wake(waker)) {
+            sync::push_or_fatal(list, waker.clone());
+        }
+        // Safety: The reactor's handler masks it again when it fires.
+        unsafe { NVIC::unmask(irq) };
+    })
+}
+
+// Run `handler` directly whenever `irq` fires, instead of waking tasks.
+// Returns the previously installed handler. `irq` is moved to `Priority::Raw`.
+//
+// The handler goes straight into the RAM vector table, so it doesn't pass through the reactor
+// at all. For the few things that need the lowest possible latency; everything else should
+// await. Any futures already waiting on `irq` are woken so they see it's been taken over.
+pub(crate) fn set_raw_handler(irq: Interrupt, handler: extern "C" fn()) -> Option<extern "C" fn()> {
+    priority::set(irq, Priority::Raw);
+    replace_handler(irq, || vectors::set_handler(irq, handler))
+}
+
+// Go back to waking tasks when `irq` fires. Returns the handler that was installed.
+pub(crate) fn clear_raw_handler(irq: Interrupt) -> Option<extern "C" fn()> {
+    let old = replace_handler(irq, || vectors::reset_handler(irq));
+    priority::set(irq, Priority::Reactor);
+    old
+}
+
+fn replace_handler(irq: Interrupt, replace: impl FnOnce() -> usize) -> Option<extern "C" fn()> {
+    // Registration checks the vector table with the lock held, so hold it here too.
+    let (old, waiting) = WAKERS.with(|wakers| {
+        let was_default = vectors::is_default(irq);
+        let old = replace();
+        (
+            (!was_default).then_some(old),
+            mem::take(&mut wakers[irq as usize]),
+        )
+    });
+    for waker in waiting {
+        waker.wake();
+    }
+    // Safety: Anything but the default handler was installed from an `extern "C" fn()`.
+    old.map(|old| unsafe { mem::transmute::<usize, extern "C" fn()>(old) })
+}
+
+// The handler only gets the number, not an `Interrupt`.
+#[derive(Clone, Copy)]
+struct IrqNumber(u16);
+
+// Safety: Only constructed from the number of the interrupt being handled.
+unsafe impl InterruptNumber for IrqNumber {
+    fn number(self) -> u16 {
+        self.0
+    }
+}
 
 #[exception]
 unsafe fn DefaultHandler(irqn: i16) {
     if irqn < 0 {
         // Not an interrupt; return immediately.
         return;
-    } else {
-        // Interrupt; handle it.
-        let mut wakers = WAKERS.lock();
-        let waker_list = wakers[irqn as usize];
-        for waker in waker_list.iter() {
-            waker.wake();
-        }
     }
+    let start = Instant::now();
+    #[cfg(feature = "trace")]
+    crate::trace::record(crate::trace::Kind::Irq, irqn as u32);
+    // Futures register again when they're polled, so the list is emptied here, and the
+    // interrupt stays masked until they do.
+    let waker_list = WAKERS.with(|wakers| {
+        NVIC::mask(IrqNumber(irqn as u16));
+        wakers
+            .get_mut(irqn as usize)
+            .map(mem::take)
+            .unwrap_or_default()
+    });
+    let wakes = waker_list.len();
+    for waker in waker_list {
+        waker.wake();
+    }
+    crate::executor::record_irq_wakes(irqn as usize, wakes);
+    let elapsed = start.elapsed().as_micros() as u32;
+    STATS.with(|stats| {
+        let Some(irq) = stats.get_mut(irqn as usize) else {
+            return;
+        };
+        irq.count = irq.count.wrapping_add(1);
+        irq.wakes = irq.wakes.wrapping_add(wakes as u32);
+        irq.max_fanout = irq.max_fanout.max(wakes as u32);
+        irq.busy_us = irq.busy_us.wrapping_add(elapsed);
+        irq.max_us = irq.max_us.max(elapsed);
+        if wakes == 0 {
+            irq.spurious = irq.spurious.wrapping_add(1);
+        }
+    });
 }
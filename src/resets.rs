@@ -0,0 +1,19 @@
+// Taking peripherals out of reset, which they're all held in from power-on until something
+// releases them.
+
+// Release the blocks whose bits are set in `bits` (RESET's layout, as in the datasheet) and
+// wait for them to come out, if they're still held. Ones already running are left alone, so
+// drivers call this from their constructors without disturbing another driver's block.
+pub fn bring_up(bits: u32) {
+    let resets = unsafe { &*rp2040_pac::RESETS::ptr() };
+    cortex_m::interrupt::free(|_| {
+        if resets.reset.read().bits() & bits != 0 {
+            resets
+                .reset
+                .modify(|r, w| unsafe { w.bits(r.bits() & !bits) });
+            while resets.reset_done.read().bits() & bits != bits {
+                cortex_m::asm::nop();
+            }
+        }
+    })
+}
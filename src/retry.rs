@@ -0,0 +1,92 @@
+// Retrying fallible async operations with exponential backoff.
+//
+// Waits grow by `multiplier` each attempt up to `max`. With jitter, each wait is picked at
+// random from the upper half of that, so devices that failed together (after a shared access
+// point or server went away, say) don't all come back at the same moment.
+
+use core::{future::Future, time::Duration};
+
+use crate::time;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    // Attempts in total, including the first one.
+    pub attempts: u32,
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+    pub jitter: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            attempts: 5,
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2,
+            jitter: true,
+        }
+    }
+}
+
+impl Policy {
+    // How long to wait after the given failed attempt, counting from 0.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 0..attempt {
+            delay = delay.saturating_mul(self.multiplier).min(self.max);
+        }
+        let delay = delay.min(self.max);
+        if !self.jitter {
+            return delay;
+        }
+        let half = delay / 2;
+        let spread = (delay - half).as_micros() as u64;
+        half + Duration::from_micros(random() as u64 % (spread + 1))
+    }
+}
+
+// Run `op` until it succeeds or `policy.attempts` have failed, returning the last error.
+pub async fn retry<T, E, F, Fut>(policy: Policy, op: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_if(policy, op, |_| true).await
+}
+
+// Like `retry`, but gives up straight away on errors `retryable` says no to, e.g. ones that
+// mean the link is gone rather than that the other end is busy.
+pub async fn retry_if<T, E, F, Fut>(
+    policy: Policy,
+    mut op: F,
+    retryable: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt + 1 >= policy.attempts || !retryable(&e) => return Err(e),
+            Err(_) => {
+                time::sleep(policy.backoff(attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// 32 bits from the ring oscillator's random bit. Not good enough for keys, but plenty for
+// spreading out retries.
+fn random() -> u32 {
+    let rosc = unsafe { &*rp2040_pac::ROSC::ptr() };
+    let mut value = 0;
+    for _ in 0..32 {
+        value = value << 1 | (rosc.randombit.read().bits() & 1);
+    }
+    value
+}
@@ -0,0 +1,102 @@
+// A way back for devices in the field that crash every time they boot.
+//
+// `boot` counts boots in a watchdog scratch register, which survives everything but a power
+// cycle, and the firmware calls `healthy` once it's been up long enough to trust. A crash,
+// watchdog timeout or reset before then leaves the count where it was, so after `max_boots`
+// of those in a row `boot` says to come up in safe mode: just what it takes to get a fix on
+// (a shell, an update task) and none of the code that keeps crashing.
+//
+//     match safemode::boot(3) {
+//         Mode::Normal => spawn_everything(),
+//         Mode::Safe { .. } => spawn_recovery(),
+//     }
+//     watchdog::start(Duration::from_secs(2));
+//     ...
+//     spawn(safemode::healthy_after(Duration::from_secs(60)));
+//
+// Once `boot` has been called the panic handler reboots instead of stopping, so a crash
+// counts and the device comes back up without anyone there to press reset. Safe mode lasts
+// until `leave`, which the recovery code calls once it has put things right.
+
+use core::time::Duration;
+
+use crate::{
+    atomic::{AtomicBool, Ordering},
+    time,
+    watchdog::{self, Scratch},
+};
+
+// The top half of the scratch register says the bottom half is ours.
+const MAGIC: u32 = 0x5afe_0000;
+const SAFE: u32 = 1 << 15;
+const COUNT: u32 = 0xff;
+
+const REGISTER: Scratch = Scratch::S0;
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Normal,
+    // Up in safe mode, after `boots` boots that never got as far as `healthy`.
+    Safe { boots: u8 },
+}
+
+fn state() -> u32 {
+    match watchdog::scratch(REGISTER) {
+        state if state & 0xffff_0000 == MAGIC => state,
+        // A power cycle, or something else using the register.
+        _ => MAGIC,
+    }
+}
+
+// Count this boot and say which mode to come up in: safe if this is boot `max_boots` or more
+// since the last `healthy`, or safe mode hasn't been left yet. Call first thing in `main`.
+pub fn boot(max_boots: u8) -> Mode {
+    let state = state();
+    let boots = ((state & COUNT) + 1).min(COUNT);
+    let safe = state & SAFE != 0 || boots >= max_boots as u32;
+    watchdog::set_scratch(REGISTER, MAGIC | if safe { SAFE } else { 0 } | boots);
+    ARMED.store(true, Ordering::Relaxed);
+    mode()
+}
+
+pub fn mode() -> Mode {
+    let state = state();
+    if state & SAFE != 0 {
+        Mode::Safe {
+            boots: (state & COUNT) as u8,
+        }
+    } else {
+        Mode::Normal
+    }
+}
+
+// This boot has gone well enough that it shouldn't count against the next one. Doesn't get
+// out of safe mode; that's what `leave` is for.
+pub fn healthy() {
+    if mode() == Mode::Normal {
+        watchdog::set_scratch(REGISTER, MAGIC);
+    }
+}
+
+// `healthy`, once the firmware has stayed up for `uptime`.
+pub async fn healthy_after(uptime: Duration) {
+    time::sleep(uptime).await;
+    healthy();
+}
+
+// Forget the crashes and reboot into normal mode.
+pub fn leave() -> ! {
+    watchdog::set_scratch(REGISTER, MAGIC);
+    watchdog::reboot()
+}
+
+// Where the panic handler ends up: a reboot, if `boot` has been called, so the crash counts.
+// Otherwise it stops here, as it always has.
+pub fn crashed() -> ! {
+    if ARMED.load(Ordering::Relaxed) {
+        watchdog::reboot()
+    }
+    loop {}
+}
@@ -0,0 +1,132 @@
+// Scheduling for periodic sensor sampling that wakes the chip as seldom as it can.
+//
+// Each sensor gets a slot with its own interval and a slack: how early it's willing to be
+// read. `next` sleeps until the first slot is due and returns every slot that's due or within
+// its slack, so sensors on different intervals are read in one burst instead of one wake
+// each. Deadlines are multiples of the interval from when the slot was added, so slots with
+// related intervals (1 s and 10 s, say) keep coinciding, and taking a slot early doesn't move
+// its later deadlines.
+//
+// The caller reads the due sensors back to back and keeps the results together:
+//
+//     let air = sampler.add(Duration::from_secs(10), Duration::from_secs(1)).unwrap();
+//     let soil = sampler.add(Duration::from_secs(60), Duration::from_secs(5)).unwrap();
+//     loop {
+//         let due = sampler.next().await;
+//         if due.contains(air) { ... }
+//         if due.contains(soil) { ... }
+//     }
+
+use core::time::Duration;
+
+use crate::time::{self, Instant};
+
+pub const MAX_SLOTS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    interval: Duration,
+    slack: Duration,
+    due: Instant,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub wakes: u32,
+    // Slot readings handed out. Divided by `wakes`, how many sensors each wake served.
+    pub readings: u32,
+}
+
+// The slots due at one wake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Due {
+    pub at: Instant,
+    slots: u32,
+}
+
+impl Due {
+    pub fn contains(&self, slot: usize) -> bool {
+        slot < MAX_SLOTS && self.slots & 1 << slot != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_SLOTS).filter(|&slot| self.contains(slot))
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.count_ones() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots == 0
+    }
+}
+
+pub struct Sampler {
+    slots: [Option<Slot>; MAX_SLOTS],
+    stats: Stats,
+}
+
+impl Sampler {
+    pub const fn new() -> Self {
+        Sampler {
+            slots: [None; MAX_SLOTS],
+            stats: Stats {
+                wakes: 0,
+                readings: 0,
+            },
+        }
+    }
+
+    // A slot that's first due now, then every `interval`. It can be taken up to `slack` early
+    // to share a wake with another slot; more slack means fewer wakes. Returns `None` if all
+    // the slots are taken.
+    pub fn add(&mut self, interval: Duration, slack: Duration) -> Option<usize> {
+        let slot = self.slots.iter().position(|slot| slot.is_none())?;
+        self.slots[slot] = Some(Slot {
+            interval: interval.max(Duration::from_micros(1)),
+            slack: slack.min(interval),
+            due: Instant::now(),
+        });
+        Some(slot)
+    }
+
+    pub fn remove(&mut self, slot: usize) {
+        if let Some(slot) = self.slots.get_mut(slot) {
+            *slot = None;
+        }
+    }
+
+    // Wait for the next slot to come due, and return it with any others that can go with it.
+    // Returns straight away if slots were missed; each is returned once however many of its
+    // deadlines went by. With no slots, waits forever.
+    pub async fn next(&mut self) -> Due {
+        let first = self.slots.iter().flatten().map(|slot| slot.due).min();
+        let Some(first) = first else {
+            return core::future::pending().await;
+        };
+        time::sleep_until(first).await;
+        let at = Instant::now();
+        let mut due: u32 = 0;
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else {
+                continue;
+            };
+            if slot.due > at + slot.slack {
+                continue;
+            }
+            due |= 1 << i;
+            // On to the first deadline after this one that hasn't gone by.
+            let interval = slot.interval.as_micros() as u64;
+            let behind = at.as_micros().saturating_sub(slot.due.as_micros());
+            slot.due = slot.due + Duration::from_micros((behind / interval + 1) * interval);
+        }
+        self.stats.wakes = self.stats.wakes.wrapping_add(1);
+        self.stats.readings = self.stats.readings.wrapping_add(due.count_ones());
+        Due { at, slots: due }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
@@ -0,0 +1,22 @@
+// Drivers for common I2C environment sensors, generic over embedded-hal-async.
+//
+// All of them take a single measurement on request and sleep in between. Results are fixed
+// point: temperatures in hundredths of a degree C, pressures in pascals, humidity in
+// thousandths of a percent.
+
+pub mod bme280;
+pub mod bmp388;
+pub mod sht4x;
+
+pub use bme280::Bme280;
+pub use bmp388::Bmp388;
+pub use sht4x::Sht4x;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    // The ID register didn't match the chip the driver is for.
+    UnknownChip(u8),
+    // A reading failed its checksum.
+    Crc,
+}
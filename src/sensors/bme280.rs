@@ -0,0 +1,191 @@
+// Bosch BME280 temperature, pressure and humidity sensor.
+// Compensation is the datasheet's integer version.
+
+use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+use super::Error;
+
+// 0x77 if SDO is pulled high.
+pub const ADDRESS: u8 = 0x76;
+
+const REG_CALIB_00: u8 = 0x88;
+const REG_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_CALIB_26: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_DATA: u8 = 0xF7;
+
+const CHIP_ID: u8 = 0x60;
+const STATUS_MEASURING: u8 = 0x08;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    pub temperature_cdeg: i32,
+    pub pressure_pa: u32,
+    pub humidity_mpct: u32,
+}
+
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p: [i16; 8],
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+pub struct Bme280<I, D> {
+    i2c: I,
+    address: u8,
+    delay: D,
+    calibration: Calibration,
+}
+
+impl<I: I2c, D: DelayNs> Bme280<I, D> {
+    pub async fn new(i2c: I, address: u8, delay: D) -> Result<Self, Error<I::Error>> {
+        let mut sensor = Bme280 {
+            i2c,
+            address,
+            delay,
+            calibration: Calibration {
+                t1: 0,
+                t2: 0,
+                t3: 0,
+                p1: 0,
+                p: [0; 8],
+                h1: 0,
+                h2: 0,
+                h3: 0,
+                h4: 0,
+                h5: 0,
+                h6: 0,
+            },
+        };
+        let mut id = [0];
+        sensor.read(REG_ID, &mut id).await?;
+        if id[0] != CHIP_ID {
+            return Err(Error::UnknownChip(id[0]));
+        }
+        sensor.write(REG_RESET, 0xB6).await?;
+        // Startup time, while the calibration is copied out of NVM.
+        sensor.delay.delay_ms(2).await;
+
+        let mut a = [0; 26];
+        sensor.read(REG_CALIB_00, &mut a).await?;
+        let mut b = [0; 7];
+        sensor.read(REG_CALIB_26, &mut b).await?;
+        let u = |i: usize| u16::from_le_bytes([a[i], a[i + 1]]);
+        let s = |i: usize| u(i) as i16;
+        sensor.calibration = Calibration {
+            t1: u(0),
+            t2: s(2),
+            t3: s(4),
+            p1: u(6),
+            p: core::array::from_fn(|i| s(8 + 2 * i)),
+            h1: a[25],
+            h2: i16::from_le_bytes([b[0], b[1]]),
+            h3: b[2],
+            h4: (b[3] as i8 as i16) << 4 | (b[4] & 0x0F) as i16,
+            h5: (b[5] as i8 as i16) << 4 | (b[4] >> 4) as i16,
+            h6: b[6] as i8,
+        };
+        Ok(sensor)
+    }
+
+    // Take one measurement with 1x oversampling and go back to sleep.
+    pub async fn measure(&mut self) -> Result<Measurement, Error<I::Error>> {
+        // ctrl_hum only takes effect after a write to ctrl_meas.
+        self.write(REG_CTRL_HUM, 0x01).await?;
+        // Temperature x1, pressure x1, forced mode.
+        self.write(REG_CTRL_MEAS, 0x01 << 5 | 0x01 << 2 | 0x01)
+            .await?;
+        loop {
+            // Typically 8 ms with these settings.
+            self.delay.delay_ms(2).await;
+            let mut status = [0];
+            self.read(REG_STATUS, &mut status).await?;
+            if status[0] & STATUS_MEASURING == 0 {
+                break;
+            }
+        }
+        let mut data = [0; 8];
+        self.read(REG_DATA, &mut data).await?;
+        let adc_p = (data[0] as i32) << 12 | (data[1] as i32) << 4 | (data[2] as i32) >> 4;
+        let adc_t = (data[3] as i32) << 12 | (data[4] as i32) << 4 | (data[5] as i32) >> 4;
+        let adc_h = (data[6] as i32) << 8 | data[7] as i32;
+
+        let t_fine = self.t_fine(adc_t);
+        Ok(Measurement {
+            temperature_cdeg: (t_fine * 5 + 128) >> 8,
+            pressure_pa: self.pressure(adc_p, t_fine) >> 8,
+            humidity_mpct: self.humidity(adc_h, t_fine) * 1000 / 1024,
+        })
+    }
+
+    pub fn release(self) -> (I, D) {
+        (self.i2c, self.delay)
+    }
+
+    fn t_fine(&self, adc_t: i32) -> i32 {
+        let c = &self.calibration;
+        let (t1, t2, t3) = (c.t1 as i32, c.t2 as i32, c.t3 as i32);
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * t2) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * t3) >> 14;
+        var1 + var2
+    }
+
+    // Pascals in Q24.8.
+    fn pressure(&self, adc_p: i32, t_fine: i32) -> u32 {
+        let c = &self.calibration;
+        let p = c.p.map(|p| p as i64);
+        let mut var1 = t_fine as i64 - 128000;
+        let mut var2 = var1 * var1 * p[4];
+        var2 += (var1 * p[3]) << 17;
+        var2 += p[2] << 35;
+        var1 = ((var1 * var1 * p[1]) >> 8) + ((var1 * p[0]) << 12);
+        var1 = (((1i64 << 47) + var1) * c.p1 as i64) >> 33;
+        if var1 == 0 {
+            // Would divide by zero; only happens with a broken calibration.
+            return 0;
+        }
+        let mut pressure = 1048576 - adc_p as i64;
+        pressure = (((pressure << 31) - var2) * 3125) / var1;
+        var1 = (p[7] * (pressure >> 13) * (pressure >> 13)) >> 25;
+        var2 = (p[6] * pressure) >> 19;
+        (((pressure + var1 + var2) >> 8) + (p[5] << 4)) as u32
+    }
+
+    // Percent in Q22.10.
+    fn humidity(&self, adc_h: i32, t_fine: i32) -> u32 {
+        let c = &self.calibration;
+        let (h1, h2, h3) = (c.h1 as i32, c.h2 as i32, c.h3 as i32);
+        let (h4, h5, h6) = (c.h4 as i32, c.h5 as i32, c.h6 as i32);
+        let mut v = t_fine - 76800;
+        v = ((((adc_h << 14) - (h4 << 20) - (h5 * v)) + 16384) >> 15)
+            * (((((((v * h6) >> 10) * (((v * h3) >> 11) + 32768)) >> 10) + 2097152) * h2 + 8192)
+                >> 14);
+        v -= ((((v >> 15) * (v >> 15)) >> 7) * h1) >> 4;
+        (v.clamp(0, 419430400) >> 12) as u32
+    }
+
+    async fn read(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), Error<I::Error>> {
+        self.i2c
+            .write_read(self.address, &[reg], buf)
+            .await
+            .map_err(Error::I2c)
+    }
+
+    async fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<I::Error>> {
+        self.i2c
+            .write(self.address, &[reg, value])
+            .await
+            .map_err(Error::I2c)
+    }
+}
@@ -0,0 +1,175 @@
+// Bosch BMP388/BMP390 pressure and temperature sensor.
+// Compensation follows the integer version of Bosch's BMP3 driver.
+
+use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+use super::Error;
+
+// 0x76 if SDO is pulled low.
+pub const ADDRESS: u8 = 0x77;
+
+const REG_CHIP_ID: u8 = 0x00;
+const REG_STATUS: u8 = 0x03;
+const REG_DATA: u8 = 0x04;
+const REG_PWR_CTRL: u8 = 0x1B;
+const REG_OSR: u8 = 0x1C;
+const REG_CALIB: u8 = 0x31;
+const REG_CMD: u8 = 0x7E;
+
+const CHIP_IDS: [u8; 2] = [0x50, 0x60];
+const STATUS_DRDY_PRESS: u8 = 0x20;
+const STATUS_DRDY_TEMP: u8 = 0x40;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    pub temperature_cdeg: i32,
+    pub pressure_pa: u32,
+}
+
+struct Calibration {
+    t1: i64,
+    t2: i64,
+    t3: i64,
+    // P1 to P11.
+    p: [i64; 11],
+}
+
+pub struct Bmp388<I, D> {
+    i2c: I,
+    address: u8,
+    delay: D,
+    calibration: Calibration,
+}
+
+impl<I: I2c, D: DelayNs> Bmp388<I, D> {
+    pub async fn new(i2c: I, address: u8, delay: D) -> Result<Self, Error<I::Error>> {
+        let mut sensor = Bmp388 {
+            i2c,
+            address,
+            delay,
+            calibration: Calibration {
+                t1: 0,
+                t2: 0,
+                t3: 0,
+                p: [0; 11],
+            },
+        };
+        let mut id = [0];
+        sensor.read(REG_CHIP_ID, &mut id).await?;
+        if !CHIP_IDS.contains(&id[0]) {
+            return Err(Error::UnknownChip(id[0]));
+        }
+        sensor.write(REG_CMD, 0xB6).await?;
+        sensor.delay.delay_ms(2).await;
+        // Pressure x8, temperature x1: Bosch's "standard resolution".
+        sensor.write(REG_OSR, 0x03).await?;
+
+        let mut c = [0; 21];
+        sensor.read(REG_CALIB, &mut c).await?;
+        let u = |i: usize| u16::from_le_bytes([c[i], c[i + 1]]) as i64;
+        let s = |i: usize| i16::from_le_bytes([c[i], c[i + 1]]) as i64;
+        let b = |i: usize| c[i] as i8 as i64;
+        sensor.calibration = Calibration {
+            t1: u(0),
+            t2: u(2),
+            t3: b(4),
+            p: [
+                s(5),
+                s(7),
+                b(9),
+                b(10),
+                u(11),
+                u(13),
+                b(15),
+                b(16),
+                s(17),
+                b(19),
+                b(20),
+            ],
+        };
+        Ok(sensor)
+    }
+
+    // Take one measurement in forced mode; the sensor sleeps again afterwards.
+    pub async fn measure(&mut self) -> Result<Measurement, Error<I::Error>> {
+        // Pressure and temperature on, forced mode.
+        self.write(REG_PWR_CTRL, 0x13).await?;
+        loop {
+            // About 20 ms with the oversampling set in `new`.
+            self.delay.delay_ms(5).await;
+            let mut status = [0];
+            self.read(REG_STATUS, &mut status).await?;
+            if status[0] & (STATUS_DRDY_PRESS | STATUS_DRDY_TEMP)
+                == STATUS_DRDY_PRESS | STATUS_DRDY_TEMP
+            {
+                break;
+            }
+        }
+        let mut data = [0; 6];
+        self.read(REG_DATA, &mut data).await?;
+        let raw_p = u32::from_le_bytes([data[0], data[1], data[2], 0]) as i64;
+        let raw_t = u32::from_le_bytes([data[3], data[4], data[5], 0]) as i64;
+
+        let t_lin = self.t_lin(raw_t);
+        Ok(Measurement {
+            temperature_cdeg: (t_lin * 25 / 16384) as i32,
+            pressure_pa: (self.pressure(raw_p, t_lin) / 100) as u32,
+        })
+    }
+
+    pub fn release(self) -> (I, D) {
+        (self.i2c, self.delay)
+    }
+
+    fn t_lin(&self, raw_t: i64) -> i64 {
+        let c = &self.calibration;
+        let partial1 = raw_t - 256 * c.t1;
+        let partial2 = c.t2 * partial1;
+        let partial3 = partial1 * partial1;
+        let partial4 = partial3 * c.t3;
+        (partial2 * 262144 + partial4) / 4294967296
+    }
+
+    // Hundredths of a pascal.
+    fn pressure(&self, raw_p: i64, t_lin: i64) -> i64 {
+        let p = &self.calibration.p;
+        let partial1 = t_lin * t_lin;
+        let partial2 = partial1 / 64;
+        let partial3 = partial2 * t_lin / 256;
+        let partial4 = p[7] * partial3 / 32;
+        let partial5 = p[6] * partial1 * 16;
+        let partial6 = p[5] * t_lin * 4194304;
+        let offset = p[4] * 140737488355328 + partial4 + partial5 + partial6;
+
+        let partial2 = p[3] * partial3 / 32;
+        let partial4 = p[2] * partial1 * 4;
+        let partial5 = (p[1] - 16384) * t_lin * 2097152;
+        let sensitivity = (p[0] - 16384) * 70368744177664 + partial2 + partial4 + partial5;
+
+        let partial1 = sensitivity / 16777216 * raw_p;
+        let partial2 = p[9] * t_lin;
+        let partial3 = partial2 + 65536 * p[8];
+        let partial4 = partial3 * raw_p / 8192;
+        // Divided by 10 first and multiplied back after, so raw_p * partial4 can't overflow.
+        let partial5 = raw_p * (partial4 / 10) / 512 * 10;
+        let partial6 = raw_p * raw_p;
+        let partial2 = p[10] * partial6 / 65536;
+        let partial3 = partial2 * raw_p / 128;
+        let partial4 = offset / 4 + partial1 + partial5 + partial3;
+        (partial4 as u64 * 25 / 1099511627776) as i64
+    }
+
+    async fn read(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), Error<I::Error>> {
+        self.i2c
+            .write_read(self.address, &[reg], buf)
+            .await
+            .map_err(Error::I2c)
+    }
+
+    async fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<I::Error>> {
+        self.i2c
+            .write(self.address, &[reg, value])
+            .await
+            .map_err(Error::I2c)
+    }
+}
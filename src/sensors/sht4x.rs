@@ -0,0 +1,101 @@
+// Sensirion SHT40/41/45 temperature and humidity sensors.
+
+use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+use super::Error;
+
+pub const ADDRESS: u8 = 0x44;
+
+const CMD_MEASURE_HIGH_PRECISION: u8 = 0xFD;
+const CMD_READ_SERIAL: u8 = 0x89;
+const CMD_SOFT_RESET: u8 = 0x94;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    pub temperature_cdeg: i32,
+    pub humidity_mpct: u32,
+}
+
+pub struct Sht4x<I, D> {
+    i2c: I,
+    address: u8,
+    delay: D,
+}
+
+impl<I: I2c, D: DelayNs> Sht4x<I, D> {
+    pub async fn new(i2c: I, address: u8, delay: D) -> Result<Self, Error<I::Error>> {
+        let mut sensor = Sht4x {
+            i2c,
+            address,
+            delay,
+        };
+        sensor.command(CMD_SOFT_RESET).await?;
+        sensor.delay.delay_ms(1).await;
+        Ok(sensor)
+    }
+
+    pub async fn serial_number(&mut self) -> Result<u32, Error<I::Error>> {
+        self.command(CMD_READ_SERIAL).await?;
+        self.delay.delay_ms(1).await;
+        let [high, low] = self.read_words().await?;
+        Ok((high as u32) << 16 | low as u32)
+    }
+
+    pub async fn measure(&mut self) -> Result<Measurement, Error<I::Error>> {
+        self.command(CMD_MEASURE_HIGH_PRECISION).await?;
+        // 8.3 ms at most.
+        self.delay.delay_ms(9).await;
+        let [t, rh] = self.read_words().await?;
+        // T = -45 + 175 * t / 65535, RH = -6 + 125 * rh / 65535, clamped to 0-100%.
+        let temperature_cdeg = -4500 + (17500 * t as i32) / 65535;
+        let humidity_mpct = (-6000 + (125000 * rh as i32) / 65535).clamp(0, 100_000) as u32;
+        Ok(Measurement {
+            temperature_cdeg,
+            humidity_mpct,
+        })
+    }
+
+    pub fn release(self) -> (I, D) {
+        (self.i2c, self.delay)
+    }
+
+    async fn command(&mut self, command: u8) -> Result<(), Error<I::Error>> {
+        self.i2c
+            .write(self.address, &[command])
+            .await
+            .map_err(Error::I2c)
+    }
+
+    // Two 16-bit words, each followed by a CRC.
+    async fn read_words(&mut self) -> Result<[u16; 2], Error<I::Error>> {
+        let mut data = [0; 6];
+        self.i2c
+            .read(self.address, &mut data)
+            .await
+            .map_err(Error::I2c)?;
+        let mut words = [0; 2];
+        for (word, chunk) in words.iter_mut().zip(data.chunks(3)) {
+            if crc8(&chunk[..2]) != chunk[2] {
+                return Err(Error::Crc);
+            }
+            *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        }
+        Ok(words)
+    }
+}
+
+// CRC-8, polynomial 0x31, initial value 0xFF.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,37 @@
+use core::convert::Infallible;
+
+use crate::sync::Channel;
+
+// Something values can be pushed into, with backpressure.
+//
+// `send` may return as soon as the value is queued; `flush` waits until everything sent so far
+// has actually been consumed (written to the wire, taken by the receiver...). `close` flushes
+// and then releases whatever is on the other end; sending after closing is an error or a no-op,
+// depending on the sink.
+#[allow(async_fn_in_trait)]
+pub trait Sink<T> {
+    type Error;
+    async fn send(&mut self, item: T) -> Result<(), Self::Error>;
+    async fn flush(&mut self) -> Result<(), Self::Error>;
+    async fn close(&mut self) -> Result<(), Self::Error>;
+}
+
+impl<T, const CAP: usize> Sink<T> for &Channel<T, CAP> {
+    type Error = Infallible;
+
+    async fn send(&mut self, item: T) -> Result<(), Infallible> {
+        Channel::send(self, item).await;
+        Ok(())
+    }
+
+    // Wait until the receivers have taken everything out of the channel.
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Channel::flush(self).await;
+        Ok(())
+    }
+
+    // Channels live forever, so there's nothing to release.
+    async fn close(&mut self) -> Result<(), Infallible> {
+        self.flush().await
+    }
+}
@@ -0,0 +1,162 @@
+// Bit-banged UART receive on any pin, for when the hardware UARTs and PIO state machines are
+// all spoken for. 8N1 only.
+//
+// Bits are decoded from edge timestamps (see `gpio::capture`) rather than by sampling the pin,
+// so it copes with task latency as long as the capture queue doesn't fill up. Every edge costs
+// an interrupt, so keep it to low baud rates: 9600 and below.
+
+use embedded_io_async::{ErrorKind, ErrorType, Read};
+
+use crate::{
+    gpio::capture::{Capture, Edge, Edges, Event},
+    time::{self, Instant},
+};
+
+// Data bits per frame. The stop bit is sampled straight after them.
+const DATA_BITS: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The stop bit was low: wrong baud rate, noise, or a break.
+    Framing,
+    // Edges were lost because the capture queue was full, so bytes are missing or garbled.
+    Overrun,
+}
+
+impl embedded_io_async::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Framing => ErrorKind::InvalidData,
+            Error::Overrun => ErrorKind::Other,
+        }
+    }
+}
+
+pub struct SoftUartRx {
+    capture: &'static Capture,
+    pin: u8,
+    bit_ns: u64,
+    // An edge that was read while finishing the last frame and belongs to the next one.
+    pending: Option<Event>,
+    // An error from a byte after the first in a read, reported by the next one instead.
+    deferred: Option<Error>,
+}
+
+impl SoftUartRx {
+    // Receive on `pin` through `capture`, which shouldn't be shared with pins that are busy
+    // while this is in use, since their edges take up queue space.
+    pub fn new(capture: &'static Capture, pin: u8, baud: u32) -> Self {
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+        // IE and pull-up set, pull-down clear, so an unconnected line idles high.
+        pads.gpio[pin as usize]
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 2) | 1 << 6 | 1 << 3) });
+        io.gpio[pin as usize]
+            .gpio_ctrl
+            .write(|w| unsafe { w.bits(5) }); // FUNCSEL = SIO
+        capture.listen(pin, Edges::Both);
+        SoftUartRx {
+            capture,
+            pin,
+            bit_ns: 1_000_000_000 / baud.max(1) as u64,
+            pending: None,
+            deferred: None,
+        }
+    }
+
+    pub fn release(self) -> u8 {
+        self.capture.unlisten(self.pin);
+        self.pin
+    }
+
+    async fn next_edge(&mut self, until: Option<Instant>) -> Option<Event> {
+        if let Some(event) = self.pending.take() {
+            return Some(event);
+        }
+        loop {
+            let event = match until {
+                Some(until) => time::with_deadline(until, self.capture.next()).await.ok()?,
+                None => self.capture.next().await,
+            };
+            if event.pin == self.pin {
+                return Some(event);
+            }
+        }
+    }
+
+    async fn read_byte(&mut self) -> Result<u8, Error> {
+        if self.capture.take_dropped() > 0 {
+            self.pending = None;
+            return Err(Error::Overrun);
+        }
+        let start = loop {
+            let event = self.next_edge(None).await.unwrap();
+            if event.edge == Edge::Falling {
+                break event.at;
+            }
+        };
+        let mut high = false;
+        let mut byte = 0;
+        // Sample each bit in the middle, going by the last edge before that point.
+        for bit in 1..=DATA_BITS + 1 {
+            let offset_ns = (2 * bit as u64 + 1) * self.bit_ns / 2;
+            let sample_at = Instant::from_micros(start.as_micros() + offset_ns / 1000);
+            while let Some(event) = self.next_edge(Some(sample_at)).await {
+                if event.at > sample_at {
+                    self.pending = Some(event);
+                    break;
+                }
+                high = event.edge == Edge::Rising;
+            }
+            if bit <= DATA_BITS {
+                // LSB first.
+                byte |= (high as u8) << (bit - 1);
+            } else if !high {
+                return Err(Error::Framing);
+            }
+        }
+        Ok(byte)
+    }
+}
+
+impl ErrorType for SoftUartRx {
+    type Error = Error;
+}
+
+impl Read for SoftUartRx {
+    // Waits for one byte, then carries on with any frames that have already started.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(error) = self.deferred.take() {
+            return Err(error);
+        }
+        buf[0] = self.read_byte().await?;
+        let mut n = 1;
+        while n < buf.len() {
+            while self.pending.is_none() {
+                match self.capture.try_next() {
+                    Some(event) if event.pin == self.pin => self.pending = Some(event),
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+            if !self
+                .pending
+                .is_some_and(|event| event.edge == Edge::Falling)
+            {
+                break;
+            }
+            match self.read_byte().await {
+                Ok(byte) => buf[n] = byte,
+                Err(error) => {
+                    self.deferred = Some(error);
+                    break;
+                }
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
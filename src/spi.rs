@@ -0,0 +1,386 @@
+// The RP2040's two SPI controllers as bus masters, with transfers that sleep on the SPI's
+// interrupt while the FIFOs fill and drain, so other tasks run in the meantime.
+//
+//     let irq = Irq::<SPI0_IRQ>::take().unwrap();
+//     let mut spi = Spi::new(pac.SPI0, irq, 18, 19, Some(16), &Config::default(), peri_hz);
+//     spi.transfer(&mut frame).await;
+//
+// It implements embedded-hal-async's `SpiBus`, so it goes under a `bus::SharedSpi` or any
+// device driver as it is. Chip selects are the device's business, not the bus's.
+//
+// Every byte sent clocks one in, so the receive side paces everything: no more than the
+// FIFO's eight bytes are ever in flight, and the task sleeps until the RX FIFO is half full
+// (SSPRXINTR) or, for the last few bytes of a transfer, until it times out after 32 bit times
+// with something in it. At the top clock rates a byte takes less time than a wakeup, so short
+// transfers there are mostly spent in the executor; that's the price of not spinning.
+//
+// Given two DMA channels with `set_dma`, reads, writes and transfers are moved by DMA instead,
+// and the task sleeps until the last byte is in. In-place transfers stay on the CPU, since the
+// DMA would need the buffer borrowed for reading and writing at once.
+
+use core::{convert::Infallible, marker::PhantomData, ptr, task::Poll};
+
+use embedded_hal::spi::{ErrorType, Mode, Phase, Polarity, MODE_0};
+use embedded_hal_async::spi::SpiBus;
+use rp2040_pac::spi0::RegisterBlock;
+
+use crate::{
+    dma::{self, Dreq},
+    future,
+    irq::{self, Irq, Line},
+    resets,
+};
+
+const FIFO: usize = 8;
+
+// SSPCR0
+const CR0_DSS_8: u32 = 7;
+const CR0_SPO: u32 = 1 << 6;
+const CR0_SPH: u32 = 1 << 7;
+const CR0_SCR_SHIFT: u32 = 8;
+
+// SSPCR1
+const CR1_SSE: u32 = 1 << 1;
+
+// SSPSR
+const SR_TNF: u32 = 1 << 1;
+const SR_RNE: u32 = 1 << 2;
+
+// Where the data register is, for the DMA.
+const SSPDR: usize = 0x08;
+
+// SSPIMSC bits, and where the register is for the atomic set and clear aliases.
+const IM_RT: u32 = 1 << 1;
+const IM_RX: u32 = 1 << 2;
+const SSPIMSC: usize = 0x14;
+const ALIAS_SET: usize = 0x2000;
+const ALIAS_CLR: usize = 0x3000;
+
+// SSPICR: receive overrun and timeout.
+const ICR_ALL: u32 = 0b11;
+
+const FUNCSEL_SPI: u32 = 1;
+
+// What a read sends, and a write's received bytes go nowhere.
+const FILL: u8 = 0;
+
+// SPI0 or SPI1, as the PAC has them.
+pub trait Instance {
+    const INDEX: u8;
+    const TX_DREQ: Dreq;
+    const RX_DREQ: Dreq;
+    type Line: Line;
+    fn ptr() -> *const RegisterBlock;
+}
+
+impl Instance for rp2040_pac::SPI0 {
+    const INDEX: u8 = 0;
+    const TX_DREQ: Dreq = Dreq::SPI0_TX;
+    const RX_DREQ: Dreq = Dreq::SPI0_RX;
+    type Line = irq::SPI0_IRQ;
+    fn ptr() -> *const RegisterBlock {
+        rp2040_pac::SPI0::ptr()
+    }
+}
+
+impl Instance for rp2040_pac::SPI1 {
+    const INDEX: u8 = 1;
+    const TX_DREQ: Dreq = Dreq::SPI1_TX;
+    const RX_DREQ: Dreq = Dreq::SPI1_RX;
+    type Line = irq::SPI1_IRQ;
+    fn ptr() -> *const RegisterBlock {
+        rp2040_pac::SPI1::ptr()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    // The fastest SCK can go. It ends up at clk_peri divided by an even number, at or below.
+    pub frequency: u32,
+    pub mode: Mode,
+}
+
+// 1 MHz, mode 0.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            frequency: 1_000_000,
+            mode: MODE_0,
+        }
+    }
+}
+
+pub struct Spi<S: Instance> {
+    spi: S,
+    irq: Irq<S::Line>,
+    frequency: u32,
+    // Transmit and receive channels, if transfers go by DMA.
+    dma: Option<(dma::Channel, dma::Channel)>,
+}
+
+impl<S: Instance> Spi<S> {
+    // Clock on `sck`, send on `mosi` and receive on `miso`, if there's anything to receive.
+    // The pins have to be ones the controller can have: SCK on GPIO 2, 6, 18 or 22 for SPI0
+    // and 10, 14 or 26 for SPI1, MOSI one above an SCK pin and MISO two below. `peri_hz` is
+    // clk_peri's frequency, which `clocks::frequencies` measures; usually it's clk_sys.
+    pub fn new(
+        spi: S,
+        irq: Irq<S::Line>,
+        sck: u8,
+        mosi: u8,
+        miso: Option<u8>,
+        config: &Config,
+        peri_hz: u32,
+    ) -> Self {
+        let pins_ok = sck & 3 == 2
+            && mosi == sck + 1
+            && miso.is_none_or(|miso| miso + 2 == sck)
+            && (sck >> 3) & 1 == S::INDEX
+            && sck < 28;
+        assert!(pins_ok, "pins aren't this SPI's");
+        resets::bring_up(1 << (16 + S::INDEX));
+        let mut spi = Spi {
+            spi,
+            irq,
+            frequency: 0,
+            dma: None,
+        };
+        spi.set_config(config, peri_hz);
+
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+        for pin in [Some(sck), Some(mosi), miso].into_iter().flatten() {
+            // IE set, OD clear.
+            pads.gpio[pin as usize].modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 7) | 1 << 6) });
+            io.gpio[pin as usize]
+                .gpio_ctrl
+                .write(|w| unsafe { w.bits(FUNCSEL_SPI) });
+        }
+        spi
+    }
+
+    // Change the clock rate and mode between transfers, for devices on a shared bus that want
+    // different ones. Returns the frequency SCK actually runs at.
+    pub fn set_config(&mut self, config: &Config, peri_hz: u32) -> u32 {
+        let (prescale, postdiv) = divisors(peri_hz, config.frequency);
+        let mut cr0 = CR0_DSS_8 | (postdiv - 1) << CR0_SCR_SHIFT;
+        if config.mode.polarity == Polarity::IdleHigh {
+            cr0 |= CR0_SPO;
+        }
+        if config.mode.phase == Phase::CaptureOnSecondTransition {
+            cr0 |= CR0_SPH;
+        }
+        let regs = unsafe { &*S::ptr() };
+        regs.sspcr1.write(|w| unsafe { w.bits(0) });
+        regs.sspcpsr.write(|w| unsafe { w.bits(prescale) });
+        regs.sspcr0.write(|w| unsafe { w.bits(cr0) });
+        regs.sspimsc.write(|w| unsafe { w.bits(0) });
+        regs.sspcr1.write(|w| unsafe { w.bits(CR1_SSE) });
+        self.frequency = peri_hz / (prescale * postdiv);
+        self.frequency
+    }
+
+    // What SCK runs at.
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    // Move reads, writes and transfers by DMA from now on, `tx` feeding the TX FIFO and `rx`
+    // draining the RX FIFO. Worth it for long transfers, like a PSRAM's or a display's.
+    pub fn set_dma(&mut self, tx: dma::Channel, rx: dma::Channel) {
+        self.dma = Some((tx, rx));
+    }
+
+    // Send `buf` and replace it with what comes back.
+    pub async fn transfer(&mut self, buf: &mut [u8]) {
+        self.exchange(&mut InPlace(buf)).await
+    }
+
+    // Disable the controller and hand back what it was made from. DMA channels from `set_dma`
+    // are freed.
+    pub fn release(self) -> (S, Irq<S::Line>) {
+        let regs = unsafe { &*S::ptr() };
+        regs.sspimsc.write(|w| unsafe { w.bits(0) });
+        regs.sspcr1.write(|w| unsafe { w.bits(0) });
+        (self.spi, self.irq)
+    }
+
+    // Clock `buffers.len()` bytes each way.
+    async fn exchange(&mut self, buffers: &mut impl Buffers) {
+        let regs = unsafe { &*S::ptr() };
+        let status = || regs.sspsr.read().bits();
+        let len = buffers.len();
+        let mut sent = 0;
+        let mut received = 0;
+        while received < len {
+            while sent < len && sent - received < FIFO && status() & SR_TNF != 0 {
+                regs.sspdr
+                    .write(|w| unsafe { w.bits(buffers.send(sent) as u32) });
+                sent += 1;
+            }
+            let before = received;
+            while received < sent && status() & SR_RNE != 0 {
+                buffers.receive(received, regs.sspdr.read().bits() as u8);
+                received += 1;
+            }
+            if received == before {
+                wait::<S>(&self.irq, || status() & SR_RNE != 0).await;
+            }
+        }
+    }
+
+    // Clock the longer of `read` and `write` each way, on the CPU or by DMA.
+    async fn split(&mut self, read: &mut [u8], write: &[u8]) {
+        let Some((tx, rx)) = &mut self.dma else {
+            return self.exchange(&mut Split { read, write }).await;
+        };
+        let both = read.len().min(write.len());
+        let (read, read_rest) = read.split_at_mut(both);
+        let (write, write_rest) = write.split_at(both);
+        let dr = (S::ptr() as usize + SSPDR) as *mut u8;
+        let (padding, mut discarded) = (read_rest.len(), 0);
+        // Each transfer starts when it's made, the receiving one first so it's ready for the
+        // first byte. Receiving finishes last, but sending is awaited first so it can't have
+        // stopped on an error and left receiving waiting for bytes that never come.
+        unsafe {
+            let received = rx.read_from(dr, S::RX_DREQ, read);
+            let sent = tx.write_to(write, dr, S::TX_DREQ);
+            sent.await.expect("SPI DMA");
+            received.await.expect("SPI DMA");
+            let (received, sent) = if !read_rest.is_empty() {
+                (
+                    rx.read_from(dr, S::RX_DREQ, read_rest),
+                    tx.write_repeated(&FILL, padding, dr, S::TX_DREQ),
+                )
+            } else {
+                (
+                    rx.read_repeated(dr, S::RX_DREQ, write_rest.len(), &mut discarded),
+                    tx.write_to(write_rest, dr, S::TX_DREQ),
+                )
+            };
+            sent.await.expect("SPI DMA");
+            received.await.expect("SPI DMA");
+        }
+    }
+}
+
+impl<S: Instance> ErrorType for Spi<S> {
+    type Error = Infallible;
+}
+
+impl<S: Instance> SpiBus for Spi<S> {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        self.split(words, &[]).await;
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        self.split(&mut [], words).await;
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+        self.split(read, write).await;
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        Spi::transfer(self, words).await;
+        Ok(())
+    }
+
+    // Transfers don't return until the last byte is in, so there's never anything to wait for.
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+// Where an exchange's bytes come from and go to. Byte `i` is always sent before it's received.
+trait Buffers {
+    fn len(&self) -> usize;
+    fn send(&self, i: usize) -> u8;
+    fn receive(&mut self, i: usize, byte: u8);
+}
+
+struct InPlace<'a>(&'a mut [u8]);
+
+impl Buffers for InPlace<'_> {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn send(&self, i: usize) -> u8 {
+        self.0[i]
+    }
+
+    fn receive(&mut self, i: usize, byte: u8) {
+        self.0[i] = byte;
+    }
+}
+
+// The longer of the two decides the length, as `SpiBus::transfer` has it.
+struct Split<'a> {
+    read: &'a mut [u8],
+    write: &'a [u8],
+}
+
+impl Buffers for Split<'_> {
+    fn len(&self) -> usize {
+        self.read.len().max(self.write.len())
+    }
+
+    fn send(&self, i: usize) -> u8 {
+        self.write.get(i).copied().unwrap_or(FILL)
+    }
+
+    fn receive(&mut self, i: usize, byte: u8) {
+        if let Some(slot) = self.read.get_mut(i) {
+            *slot = byte;
+        }
+    }
+}
+
+// Wait for `ready`, with the RX interrupts enabled in between and disabled again however the
+// wait ends.
+async fn wait<S: Instance>(irq: &Irq<S::Line>, ready: impl Fn() -> bool) {
+    let _armed = Armed::<S>(PhantomData);
+    future::wait_irq(irq, || {
+        if ready() {
+            return Poll::Ready(());
+        }
+        set_rx_sources::<S>(true);
+        Poll::Pending
+    })
+    .await
+}
+
+struct Armed<S: Instance>(PhantomData<S>);
+
+impl<S: Instance> Drop for Armed<S> {
+    fn drop(&mut self) {
+        set_rx_sources::<S>(false);
+        let regs = unsafe { &*S::ptr() };
+        regs.sspicr.write(|w| unsafe { w.bits(ICR_ALL) });
+    }
+}
+
+fn set_rx_sources<S: Instance>(enabled: bool) {
+    let alias = if enabled { ALIAS_SET } else { ALIAS_CLR };
+    let imsc = (S::ptr() as usize + alias + SSPIMSC) as *mut u32;
+    unsafe { ptr::write_volatile(imsc, IM_RX | IM_RT) };
+}
+
+// The even prescaler (2-254) and the post-divider (1-256) that get closest to `hz` without
+// going over, preferring the smallest prescaler.
+fn divisors(peri_hz: u32, hz: u32) -> (u32, u32) {
+    let (peri_hz, hz) = (peri_hz as u64, hz.max(1) as u64);
+    let prescale = (2..=254)
+        .step_by(2)
+        .find(|&prescale| peri_hz < (prescale + 2) * 256 * hz)
+        .expect("SPI frequency too low for clk_peri");
+    let postdiv = (2..=256)
+        .rev()
+        .find(|&postdiv| peri_hz / (prescale * (postdiv - 1)) > hz)
+        .unwrap_or(1);
+    (prescale as u32, postdiv as u32)
+}
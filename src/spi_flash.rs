@@ -0,0 +1,327 @@
+// A second SPI NOR flash chip, for assets or logs, on any embedded-hal-async SPI device.
+//
+// This isn't the boot flash: that one is behind the XIP cache and needs the ROM routines and
+// both cores out of the way to write. A chip on an ordinary SPI bus can be read, erased and
+// programmed while everything keeps running, a sleep between status polls rather than a
+// busy-wait.
+//
+// `new` works out the chip's size, page size and 4 KiB erase instruction from its SFDP
+// tables, falling back to the JEDEC ID for chips that don't have them, and switches chips
+// bigger than 16 MiB to 4-byte addresses. It implements embedded-storage-async's `NorFlash`,
+// so anything written against that (a key-value store, a log) can use it.
+
+use core::time::Duration;
+
+use embedded_hal_async::spi::{Operation, SpiDevice};
+use embedded_storage_async::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::time;
+
+const CMD_RESET_ENABLE: u8 = 0x66;
+const CMD_RESET: u8 = 0x99;
+const CMD_READ_ID: u8 = 0x9F;
+const CMD_READ_SFDP: u8 = 0x5A;
+const CMD_READ_FAST: u8 = 0x0B;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_ENTER_4_BYTE: u8 = 0xB7;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+
+// STATUS: write in progress.
+const BUSY: u8 = 1 << 0;
+
+pub const SECTOR_SIZE: u32 = 4096;
+
+// How long to sleep between status polls. A page program takes a millisecond or so, a sector
+// erase tens of milliseconds.
+const PROGRAM_POLL: Duration = Duration::from_micros(200);
+const ERASE_POLL: Duration = Duration::from_millis(5);
+
+const SFDP_SIGNATURE: u32 = 0x5044_4653;
+// Basic Flash Parameter table: ID 0xFF00, the top byte stored last in its header.
+const BFPT_ID: u16 = 0xff00;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Spi(E),
+    // Nothing answered the ID command, or what did can't erase 4 KiB at a time.
+    UnknownChip { jedec_id: [u8; 3] },
+    OutOfRange,
+    NotAligned,
+}
+
+impl<E: core::fmt::Debug> NorFlashError for Error<E> {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::OutOfRange => NorFlashErrorKind::OutOfBounds,
+            Error::NotAligned => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub jedec_id: [u8; 3],
+    pub size: u32,
+    pub page_size: u32,
+    // The instruction that erases a 4 KiB sector.
+    pub sector_erase: u8,
+    pub four_byte_addresses: bool,
+}
+
+pub struct SpiFlash<S> {
+    spi: S,
+    geometry: Geometry,
+}
+
+impl<S: SpiDevice> SpiFlash<S> {
+    // Reset the chip and find out what it is.
+    pub async fn new(spi: S) -> Result<Self, Error<S::Error>> {
+        let mut flash = SpiFlash {
+            spi,
+            geometry: Geometry {
+                jedec_id: [0; 3],
+                size: 0,
+                page_size: 256,
+                sector_erase: CMD_SECTOR_ERASE,
+                four_byte_addresses: false,
+            },
+        };
+        flash.command(&[CMD_RESET_ENABLE]).await?;
+        flash.command(&[CMD_RESET]).await?;
+        // tRST: up to 30 µs on most parts before it'll listen again.
+        time::sleep(Duration::from_micros(50)).await;
+
+        let mut jedec_id = [0; 3];
+        flash
+            .spi
+            .transaction(&mut [
+                Operation::Write(&[CMD_READ_ID]),
+                Operation::Read(&mut jedec_id),
+            ])
+            .await
+            .map_err(Error::Spi)?;
+        if jedec_id == [0; 3] || jedec_id == [0xff; 3] {
+            return Err(Error::UnknownChip { jedec_id });
+        }
+        flash.geometry.jedec_id = jedec_id;
+
+        if !flash.read_sfdp_geometry().await? {
+            // Most chips put log2 of their size in bytes in the last ID byte.
+            if !(16..=32).contains(&jedec_id[2]) {
+                return Err(Error::UnknownChip { jedec_id });
+            }
+            flash.geometry.size = 1u32.checked_shl(jedec_id[2] as u32).unwrap_or(0);
+        }
+        if flash.geometry.size == 0 {
+            return Err(Error::UnknownChip { jedec_id });
+        }
+        if flash.geometry.size > 1 << 24 {
+            flash.command(&[CMD_ENTER_4_BYTE]).await?;
+            flash.geometry.four_byte_addresses = true;
+        }
+        Ok(flash)
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    pub fn release(self) -> S {
+        self.spi
+    }
+
+    pub async fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<S::Error>> {
+        self.check_range(addr, buf.len())?;
+        let (header, len) = self.header(CMD_READ_FAST, addr);
+        self.spi
+            .transaction(&mut [
+                // Fast read has one dummy byte after the address.
+                Operation::Write(&header[..len + 1]),
+                Operation::Read(buf),
+            ])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    // Program `data` at `addr`. Only clears bits, so the range should have been erased.
+    pub async fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error<S::Error>> {
+        self.check_range(addr, data.len())?;
+        let page = self.geometry.page_size;
+        let mut done = 0;
+        while done < data.len() {
+            let at = addr + done as u32;
+            let len = (data.len() - done).min((page - at % page) as usize);
+            self.command(&[CMD_WRITE_ENABLE]).await?;
+            let (header, header_len) = self.header(CMD_PAGE_PROGRAM, at);
+            self.spi
+                .transaction(&mut [
+                    Operation::Write(&header[..header_len]),
+                    Operation::Write(&data[done..done + len]),
+                ])
+                .await
+                .map_err(Error::Spi)?;
+            self.wait_idle(PROGRAM_POLL).await?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    // Erase the sectors from `from` up to `to`, both multiples of SECTOR_SIZE.
+    pub async fn erase(&mut self, from: u32, to: u32) -> Result<(), Error<S::Error>> {
+        if !from.is_multiple_of(SECTOR_SIZE) || !to.is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::NotAligned);
+        }
+        if from > to {
+            return Err(Error::OutOfRange);
+        }
+        self.check_range(from, (to - from) as usize)?;
+        for sector in (from..to).step_by(SECTOR_SIZE as usize) {
+            self.command(&[CMD_WRITE_ENABLE]).await?;
+            let (header, len) = self.header(self.geometry.sector_erase, sector);
+            self.command(&header[..len]).await?;
+            self.wait_idle(ERASE_POLL).await?;
+        }
+        Ok(())
+    }
+
+    // An instruction followed by an address, and how many bytes of it to send. Room for a
+    // dummy byte after.
+    fn header(&self, cmd: u8, addr: u32) -> ([u8; 6], usize) {
+        let [a3, a2, a1, a0] = addr.to_be_bytes();
+        if self.geometry.four_byte_addresses {
+            ([cmd, a3, a2, a1, a0, 0], 5)
+        } else {
+            ([cmd, a2, a1, a0, 0, 0], 4)
+        }
+    }
+
+    fn check_range(&self, addr: u32, len: usize) -> Result<(), Error<S::Error>> {
+        match addr.checked_add(len as u32) {
+            Some(end) if end <= self.geometry.size => Ok(()),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+
+    async fn wait_idle(&mut self, poll: Duration) -> Result<(), Error<S::Error>> {
+        loop {
+            let mut status = [0];
+            self.spi
+                .transaction(&mut [
+                    Operation::Write(&[CMD_READ_STATUS]),
+                    Operation::Read(&mut status),
+                ])
+                .await
+                .map_err(Error::Spi)?;
+            if status[0] & BUSY == 0 {
+                return Ok(());
+            }
+            time::sleep(poll).await;
+        }
+    }
+
+    async fn command(&mut self, cmd: &[u8]) -> Result<(), Error<S::Error>> {
+        self.spi.write(cmd).await.map_err(Error::Spi)
+    }
+
+    async fn read_sfdp(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<S::Error>> {
+        let [_, a2, a1, a0] = addr.to_be_bytes();
+        self.spi
+            .transaction(&mut [
+                // Always 3-byte addresses and a dummy byte, whatever mode the chip is in.
+                Operation::Write(&[CMD_READ_SFDP, a2, a1, a0, 0]),
+                Operation::Read(buf),
+            ])
+            .await
+            .map_err(Error::Spi)
+    }
+
+    // Fill in the geometry from the Basic Flash Parameter table (JESD216). False if the chip
+    // doesn't have one.
+    async fn read_sfdp_geometry(&mut self) -> Result<bool, Error<S::Error>> {
+        let mut header = [0; 8];
+        self.read_sfdp(0, &mut header).await?;
+        if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != SFDP_SIGNATURE {
+            return Ok(false);
+        }
+        // Parameter headers follow, 8 bytes each: ID low byte, minor and major version,
+        // length in words, a 3-byte pointer and the ID high byte.
+        let headers = header[6] as u32 + 1;
+        let mut table = None;
+        for i in 0..headers {
+            let mut param = [0; 8];
+            self.read_sfdp(8 + 8 * i, &mut param).await?;
+            let id = u16::from_le_bytes([param[0], param[7]]);
+            if id == BFPT_ID {
+                let pointer = u32::from_le_bytes([param[4], param[5], param[6], 0]);
+                table = Some((pointer, param[3] as usize));
+                break;
+            }
+        }
+        let Some((pointer, words)) = table else {
+            return Ok(false);
+        };
+        let mut bfpt = [0u32; 11];
+        let words = words.min(bfpt.len());
+        for (i, word) in bfpt.iter_mut().enumerate().take(words) {
+            let mut bytes = [0; 4];
+            self.read_sfdp(pointer + 4 * i as u32, &mut bytes).await?;
+            *word = u32::from_le_bytes(bytes);
+        }
+
+        // Word 1: 4 KiB erase supported if bits 1:0 are 01, with its instruction in 15:8.
+        if bfpt[0] & 0b11 != 0b01 {
+            return Err(Error::UnknownChip {
+                jedec_id: self.geometry.jedec_id,
+            });
+        }
+        self.geometry.sector_erase = (bfpt[0] >> 8) as u8;
+        // Word 2: the size in bits, minus one, or log2 of it if bit 31 is set.
+        let bits = bfpt[1];
+        self.geometry.size = if bits & 1 << 31 == 0 {
+            (bits / 8).saturating_add(1)
+        } else {
+            1u32.checked_shl((bits & 0x7fff_ffff).saturating_sub(3))
+                .unwrap_or(0)
+        };
+        // Word 11 (JESD216A on): log2 of the page size in bits 7:4.
+        let page_bits = (bfpt[10] >> 4) & 0xf;
+        if words >= 11 && page_bits != 0 {
+            self.geometry.page_size = 1 << page_bits;
+        }
+        Ok(true)
+    }
+}
+
+impl<S: SpiDevice> ErrorType for SpiFlash<S> {
+    type Error = Error<S::Error>;
+}
+
+impl<S: SpiDevice> ReadNorFlash for SpiFlash<S> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        SpiFlash::read(self, offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.geometry.size as usize
+    }
+}
+
+impl<S: SpiDevice> NorFlash for SpiFlash<S> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        SpiFlash::erase(self, from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        SpiFlash::write(self, offset, bytes).await
+    }
+}
@@ -0,0 +1,272 @@
+// Step pulses for stepper motor drivers (A4988, DRV8825, TMC2209 in step/dir mode...),
+// generated by a PIO state machine so their timing doesn't depend on the CPU at all.
+//
+// A move is a train of segments, each some number of pulses at one rate, so a ramp up and down
+// is one move. A DMA channel feeds the segments to the state machine's TX FIFO three words at
+// a time: the pulse count, the half period, and whether it's the last. The state machine raises
+// an IRQ flag after the last one, which is what `run` waits for.
+
+use embedded_hal::delay::DelayNs;
+
+use crate::{
+    dma::{self, Dreq},
+    pio::{self, instr, Program, StateMachine},
+    time::Delay,
+};
+
+// Loops until pulled a count (minus one) and a delay, then pulses that many times, then pulls
+// whether that was the last segment.
+const PROGRAM: [u16; 14] = [
+    instr::PULL_BLOCK,
+    instr::MOV_X_OSR,
+    instr::PULL_BLOCK,
+    // pulse:
+    instr::set_pins(1),
+    instr::MOV_Y_OSR,
+    instr::jmp_y_dec(5),
+    instr::set_pins(0),
+    instr::MOV_Y_OSR,
+    instr::jmp_y_dec(8),
+    instr::jmp_x_dec(3),
+    instr::PULL_BLOCK,
+    instr::MOV_Y_OSR,
+    instr::jmp_not_y(0),
+    instr::irq_set_rel(0),
+];
+const PULSE_START: u8 = 3;
+const LAST_PULL: u8 = 10;
+const DONE: u8 = 13;
+// Cycles per pulse on top of twice the delay count.
+const OVERHEAD_CYCLES: u32 = 7;
+// Most step/dir drivers want the direction settled a few microseconds before a step.
+const DIR_SETUP_US: u32 = 5;
+
+// Some number of pulses at one rate, as the state machine pulls them. Made by
+// `Stepper::segment`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Segment {
+    count_minus_one: u32,
+    half_period: u32,
+    // Set by `run` on the last segment of a train.
+    last: u32,
+}
+
+impl Segment {
+    pub fn steps(&self) -> u32 {
+        self.count_minus_one + 1
+    }
+}
+
+pub struct Stepper {
+    sm: StateMachine,
+    dma: dma::Channel,
+    program: Program,
+    step_pin: u8,
+    dir_pin: Option<u8>,
+    sys_hz: u32,
+    position: i64,
+}
+
+impl Stepper {
+    // `sys_hz` is the clk_sys frequency, which the pulse rate is derived from. Returns `None`
+    // if `block` has no free state machine or not enough instruction memory, or there's no
+    // free DMA channel.
+    pub fn new(block: u8, step_pin: u8, dir_pin: Option<u8>, sys_hz: u32) -> Option<Self> {
+        let dma = dma::claim()?;
+        let mut sm = pio::claim(block)?;
+        let program = pio::load(block, &PROGRAM)?;
+        pio::use_pin(block, step_pin);
+        sm.configure(
+            &program,
+            &pio::Config {
+                wrap: DONE,
+                set_base: step_pin,
+                set_count: 1,
+                ..Default::default()
+            },
+        );
+        sm.set_pindirs(step_pin, 1, true);
+        sm.exec(instr::set_pins(0));
+        sm.set_enabled(true);
+        if let Some(pin) = dir_pin {
+            let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+            let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+            io.gpio[pin as usize]
+                .gpio_ctrl
+                .write(|w| unsafe { w.bits(5) }); // FUNCSEL = SIO
+            sio.gpio_oe_set.write(|w| unsafe { w.bits(1 << pin) });
+        }
+        Some(Stepper {
+            sm,
+            dma,
+            program,
+            step_pin,
+            dir_pin,
+            sys_hz,
+            position: 0,
+        })
+    }
+
+    // Steps taken, counting backwards moves as negative.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    // `steps` pulses at `rate_hz` steps per second, for `run`. Panics if there are no steps or
+    // the rate is faster than the state machine can go (clk_sys / 7).
+    pub fn segment(&self, steps: u32, rate_hz: u32) -> Segment {
+        assert!(steps > 0, "empty step segment");
+        let cycles = self.sys_hz / rate_hz.max(1);
+        assert!(cycles >= OVERHEAD_CYCLES, "step rate too high");
+        Segment {
+            count_minus_one: steps - 1,
+            half_period: (cycles - OVERHEAD_CYCLES) / 2,
+            last: 0,
+        }
+    }
+
+    // Move `steps` steps at `rate_hz` steps per second, backwards if negative, and wait for
+    // the last pulse to finish. Panics if the rate is too high, as with `segment`.
+    pub async fn step(&mut self, steps: i32, rate_hz: u32) {
+        if steps == 0 {
+            return;
+        }
+        let mut train = [self.segment(steps.unsigned_abs(), rate_hz)];
+        self.run(steps > 0, &mut train).await
+    }
+
+    // Send `train`'s segments one after the other, in one direction, and wait for the last
+    // pulse to finish. Going from one segment to the next adds a few clk_sys cycles.
+    //
+    // Dropping the future stops the pulses at once, and `position` counts the ones that went
+    // out.
+    pub async fn run(&mut self, forward: bool, train: &mut [Segment]) {
+        let Some((last, rest)) = train.split_last_mut() else {
+            return;
+        };
+        for segment in rest {
+            segment.last = 0;
+        }
+        last.last = 1;
+
+        if let Some(pin) = self.dir_pin {
+            let sio = unsafe { &*rp2040_pac::SIO::ptr() };
+            if forward {
+                sio.gpio_out_set.write(|w| unsafe { w.bits(1 << pin) });
+            } else {
+                sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << pin) });
+            }
+            Delay.delay_us(DIR_SETUP_US);
+        }
+
+        // Segment is three u32s with nothing in between.
+        let train: &[Segment] = train;
+        let words =
+            unsafe { core::slice::from_raw_parts(train.as_ptr() as *const u32, train.len() * 3) };
+        let (fifo, dreq) = (
+            self.sm.tx_fifo(),
+            Dreq::pio_tx(self.sm.block(), self.sm.index()),
+        );
+        let mut in_flight = InFlight {
+            stepper: self,
+            train,
+            forward,
+            finished: false,
+        };
+        // Dropped before `in_flight`, so the channel has stopped by the time it aborts.
+        let transfer = unsafe { in_flight.stepper.dma.write_to(words, fifo, dreq) };
+        if transfer.await.is_err() {
+            return;
+        }
+        let flag = in_flight.stepper.sm.index();
+        in_flight.stepper.sm.wait_irq(flag).await;
+        let sent = in_flight.train.iter().map(Segment::steps).sum();
+        in_flight.finish(sent);
+    }
+
+    // Stop pulsing and get back to waiting for a move. Returns how many pulses of `train` were
+    // sent. The DMA channel has to have been stopped already.
+    fn abort(&mut self, train: &[Segment]) -> u32 {
+        let sm = &mut self.sm;
+        sm.set_enabled(false);
+        let all: u32 = train.iter().map(Segment::steps).sum();
+        let sent = if pio::irq_is_set(sm.block(), sm.index()) {
+            // Finished, and the program may have wrapped back to the start since.
+            all
+        } else {
+            // Words written by the DMA, less those still in the FIFO.
+            let pulled = train.len() * 3 - self.dma.remaining() - sm.tx_level() as usize;
+            let (done, partial) = (pulled / 3, pulled % 3);
+            let mut sent: u32 = train[..done].iter().map(Segment::steps).sum();
+            // With the count and delay of the next segment pulled, it's pulsing or has finished.
+            if partial == 2 {
+                let count = train[done].steps();
+                let pc = sm.pc().wrapping_sub(self.program.offset());
+                sent += match pc {
+                    PULSE_START..LAST_PULL => {
+                        // X counts the pulses left after the current one.
+                        sm.clear_fifos();
+                        sm.exec(instr::MOV_ISR_X);
+                        sm.exec(instr::PUSH_NOBLOCK);
+                        let left = sm.try_pull().unwrap_or(count - 1);
+                        (count - 1).saturating_sub(left)
+                    }
+                    _ => count,
+                };
+            }
+            sent
+        };
+        sm.exec(instr::set_pins(0));
+        sm.clear_fifos();
+        sm.restart();
+        sm.exec(instr::jmp(self.program.offset()));
+        // The flag may have been set just before we stopped it.
+        pio::clear_irq(sm.block(), sm.index());
+        sm.set_enabled(true);
+        sent
+    }
+
+    // Give the pins back. Dropping the stepper frees the state machine, program and DMA
+    // channel without them.
+    pub fn release(self) -> (u8, Option<u8>) {
+        (self.step_pin, self.dir_pin)
+    }
+}
+
+impl Drop for Stepper {
+    fn drop(&mut self) {
+        self.sm.set_enabled(false);
+        self.sm.exec(instr::set_pins(0));
+        pio::unload(self.program);
+    }
+}
+
+// A move that's been started. If it's dropped before finishing, the move is aborted.
+struct InFlight<'a> {
+    stepper: &'a mut Stepper,
+    train: &'a [Segment],
+    forward: bool,
+    finished: bool,
+}
+
+impl InFlight<'_> {
+    fn finish(&mut self, sent: u32) {
+        self.finished = true;
+        let sent = sent as i64;
+        self.stepper.position += if self.forward { sent } else { -sent };
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let sent = self.stepper.abort(self.train);
+            self.finish(sent);
+        }
+    }
+}
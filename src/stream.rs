@@ -0,0 +1,273 @@
+// Streams of values arriving over time, and ways to combine them.
+//
+// Streams are polled rather than having an async `next` in the trait, so that combinators like
+// `merge` can wait on several at once without losing a value when another one wins the race.
+// Anything that can register a waker and hand a value back later can be a stream.
+
+use core::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{
+    sink::Sink,
+    sync::{Channel, WatchReceiver},
+    time::{self, Sleep},
+};
+
+pub trait Stream {
+    type Item;
+
+    // `Ready(None)` means the stream has ended, and it shouldn't be polled again.
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+
+    fn next(&mut self) -> impl Future<Output = Option<Self::Item>> + '_ {
+        poll_fn(move |cx| self.poll_next(cx))
+    }
+
+    // Turn each item into something else, e.g. into one variant of an event enum, so that
+    // streams of different types can be merged.
+    fn map<U, F: FnMut(Self::Item) -> U>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+    {
+        Map { stream: self, f }
+    }
+
+    // Only pass an item on once `quiet` has gone by without another one arriving; items that
+    // are followed too soon are dropped. For things like a bouncing button, where only the
+    // value it settles on matters.
+    fn debounce(self, quiet: Duration) -> Debounce<Self>
+    where
+        Self: Sized,
+    {
+        Debounce {
+            stream: Some(self),
+            quiet,
+            pending: None,
+        }
+    }
+
+    // Pass on at most one item per `period`. The first item goes through straight away; of the
+    // ones arriving during the rest of the period, only the latest is kept and passed on when
+    // the period ends. For reporting a noisy value without flooding the link.
+    fn throttle(self, period: Duration) -> Throttle<Self>
+    where
+        Self: Sized,
+    {
+        Throttle {
+            stream: Some(self),
+            period,
+            window: None,
+            pending: None,
+        }
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for &mut S {
+    type Item = S::Item;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        (**self).poll_next(cx)
+    }
+}
+
+// Channels never end.
+impl<T, const CAP: usize> Stream for &Channel<T, CAP> {
+    type Item = T;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.poll_recv(cx).map(Some)
+    }
+}
+
+// Every new value sent to the watch; like `changed`, values sent in between are skipped.
+impl<T: Clone> Stream for WatchReceiver<'_, T> {
+    type Item = T;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.poll_changed(cx).map(Some)
+    }
+}
+
+pub struct Map<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<S: Stream, U, F: FnMut(S::Item) -> U> Stream for Map<S, F> {
+    type Item = U;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<U>> {
+        self.stream.poll_next(cx).map(|item| item.map(&mut self.f))
+    }
+}
+
+pub struct Debounce<S: Stream> {
+    // `None` once the inner stream has ended.
+    stream: Option<S>,
+    quiet: Duration,
+    // The latest item, and the timer for when it can be passed on.
+    pending: Option<(S::Item, Sleep)>,
+}
+
+impl<S: Stream> Stream for Debounce<S> {
+    type Item = S::Item;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        while let Poll::Ready(item) = poll_side(&mut self.stream, cx) {
+            match item {
+                Some(item) => self.pending = Some((item, time::sleep(self.quiet))),
+                // Nothing more is coming, so whatever's pending has settled.
+                None => return Poll::Ready(self.pending.take().map(|(item, _)| item)),
+            }
+        }
+        match &mut self.pending {
+            Some((_, sleep)) => {
+                if Pin::new(sleep).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                Poll::Ready(self.pending.take().map(|(item, _)| item))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub struct Throttle<S: Stream> {
+    stream: Option<S>,
+    period: Duration,
+    // Runs out when the next item may go through; `None` until the first one has.
+    window: Option<Sleep>,
+    pending: Option<S::Item>,
+}
+
+impl<S: Stream> Stream for Throttle<S> {
+    type Item = S::Item;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        // Take everything that's ready, keeping only the latest.
+        let ended = loop {
+            match poll_side(&mut self.stream, cx) {
+                Poll::Ready(Some(item)) => self.pending = Some(item),
+                Poll::Ready(None) => break true,
+                Poll::Pending => break false,
+            }
+        };
+        if self.pending.is_none() {
+            return if ended {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            };
+        }
+        if let Some(window) = &mut self.window {
+            if Pin::new(window).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        self.window = Some(time::sleep(self.period));
+        Poll::Ready(self.pending.take())
+    }
+}
+
+// Items from both streams as they arrive. Ends once both have ended.
+pub fn merge<A, B>(a: A, b: B) -> Merge<A, B>
+where
+    A: Stream,
+    B: Stream<Item = A::Item>,
+{
+    Merge {
+        a: Some(a),
+        b: Some(b),
+        a_first: true,
+    }
+}
+
+pub struct Merge<A, B> {
+    a: Option<A>,
+    b: Option<B>,
+    // Take turns going first, so a busy stream can't starve the other one.
+    a_first: bool,
+}
+
+impl<A, B> Stream for Merge<A, B>
+where
+    A: Stream,
+    B: Stream<Item = A::Item>,
+{
+    type Item = A::Item;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<A::Item>> {
+        let a_first = self.a_first;
+        self.a_first = !a_first;
+        for a_turn in [a_first, !a_first] {
+            let poll = if a_turn {
+                poll_side(&mut self.a, cx)
+            } else {
+                poll_side(&mut self.b, cx)
+            };
+            if let Poll::Ready(Some(item)) = poll {
+                return Poll::Ready(Some(item));
+            }
+        }
+        if self.a.is_none() && self.b.is_none() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// Poll a stream that may have already ended, forgetting it once it does.
+fn poll_side<S: Stream>(side: &mut Option<S>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+    let Some(stream) = side else {
+        return Poll::Ready(None);
+    };
+    let poll = stream.poll_next(cx);
+    if let Poll::Ready(None) = poll {
+        *side = None;
+    }
+    poll
+}
+
+// Items from any of `streams` as they arrive. Ends once all of them have ended.
+pub fn select_all<S: Stream, const N: usize>(streams: [S; N]) -> SelectAll<S, N> {
+    SelectAll {
+        streams: streams.map(Some),
+        next: 0,
+    }
+}
+
+pub struct SelectAll<S, const N: usize> {
+    streams: [Option<S>; N],
+    // Where to start polling next time, moved along round-robin for fairness.
+    next: usize,
+}
+
+impl<S: Stream, const N: usize> Stream for SelectAll<S, N> {
+    type Item = S::Item;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let start = self.next;
+        self.next = (self.next + 1) % N.max(1);
+        for i in 0..N {
+            let side = &mut self.streams[(start + i) % N];
+            if let Poll::Ready(Some(item)) = poll_side(side, cx) {
+                return Poll::Ready(Some(item));
+            }
+        }
+        if self.streams.iter().all(Option::is_none) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// Send everything from `stream` into `sink` until the stream ends, then flush the sink.
+// Stops at the first error from the sink.
+pub async fn forward<S, K>(mut stream: S, sink: &mut K) -> Result<(), K::Error>
+where
+    S: Stream,
+    K: Sink<S::Item>,
+{
+    while let Some(item) = stream.next().await {
+        sink.send(item).await?;
+    }
+    sink.flush().await
+}
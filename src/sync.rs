@@ -1,8 +1,48 @@
 extern crate alloc;
 
-use core::{mem::forget, ops::Deref};
+use core::{
+    alloc::AllocError,
+    cell::UnsafeCell,
+    mem::{self, forget},
+    ops::{Deref, DerefMut},
+    task::Waker,
+};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use critical_section::RestoreState;
+
+use crate::atomic::{AtomicUsize, Ordering};
+
+mod async_mutex;
+mod channel;
+mod claim;
+mod isr_shared;
+mod once_cell;
+mod pipe;
+mod rate_limiter;
+mod signal;
+mod static_cell;
+mod sync_init;
+mod wait_map;
+mod watch;
+
+pub use async_mutex::{AsyncMutex, AsyncMutexGuard};
+pub use channel::Channel;
+pub use claim::{core_peripherals, Claim};
+pub use isr_shared::IsrShared;
+pub use once_cell::OnceCell;
+pub use pipe::{pipe, PipeReader, PipeWriter};
+pub use rate_limiter::RateLimiter;
+pub use signal::Signal;
+pub use static_cell::StaticCell;
+pub use sync_init::SyncInit;
+pub use wait_map::{WaitMap, Waiter};
+pub use watch::{Watch, WatchReceiver};
+
+// Which core this is running on, 0 or 1.
+pub fn core() -> usize {
+    unsafe { (*rp2040_pac::SIO::ptr()).cpuid.read().bits() as usize }
+}
 
 pub struct SpinLock<const N: usize>;
 impl<const N: usize> SpinLock<N> {
@@ -17,49 +57,102 @@ impl<const N: usize> SpinLock<N> {
 
     pub fn lock(&self) {
         let sio = unsafe { &*rp2040_pac::SIO::ptr() };
-        let spinlock = sio.spinlock[N];
+        let spinlock = &sio.spinlock[N];
         while spinlock.read().bits() == 0 {
             cortex_m::asm::nop(); // spinloop wheeeee
         }
+        crate::barrier::acquire();
     }
 
     pub unsafe fn unlock(&self) {
         let sio = unsafe { &*rp2040_pac::SIO::ptr() };
-        let spinlock = sio.spinlock[N];
+        let spinlock = &sio.spinlock[N];
+        crate::barrier::publish();
         spinlock.write(|w| unsafe { w.bits(0xDEADBEEF) }); // Anything will do, but 0xDEADBEEF is cool.
     }
 }
 
-pub struct Mutex<T, const N: usize> {
+// The lock a `Mutex` uses when it isn't given a hardware spinlock of its own: the critical
+// section behind `atomic`, which is interrupts off on this core plus one spinlock shared by
+// everything that uses it.
+pub const SHARED: usize = 32;
+
+// A lock that works across both cores. `Mutex<T>` takes the shared critical section, which
+// also masks interrupts and can be nested, so it's the one to reach for. `Mutex<T, N>` with N
+// up to 31 has hardware spinlock N to itself (and anything else that picks N), and leaves
+// interrupts alone unless it's used through `with`; it's for locks held long enough, or taken
+// often enough, to hold up everything else that shares.
+//
+// The shared critical section can be nested, so taking a `Mutex<T>` the core already holds
+// would get in; that's fatal instead, since it would be two `&mut T` at once.
+pub struct Mutex<T, const N: usize = SHARED> {
     lock: SpinLock<N>,
-    data: T,
+    // Whether a guard exists. Only touched with the lock held.
+    held: core::sync::atomic::AtomicBool,
+    data: UnsafeCell<T>,
 }
 
-struct MutexGuard<'a, T, const N: usize> {
+pub struct MutexGuard<'a, T, const N: usize = SHARED> {
     lock: &'a SpinLock<N>,
-    data: &'a T,
+    held: &'a core::sync::atomic::AtomicBool,
+    data: &'a mut T,
 }
 
 impl<T, const N: usize> Mutex<T, N> {
     pub const fn new(data: T) -> Self {
+        if N > SHARED {
+            panic!("N must be <= 31, or SHARED");
+        }
         Mutex {
-            lock: SpinLock::new(),
-            data,
+            // Not `SpinLock::new`, which won't take SHARED.
+            lock: SpinLock,
+            held: core::sync::atomic::AtomicBool::new(false),
+            data: UnsafeCell::new(data),
         }
     }
     pub fn lock(&self) -> MutexGuard<T, N> {
-        self.lock.lock();
+        if N == SHARED {
+            // Safety: Released when the guard is dropped. Our critical sections don't have to
+            // end in the order they started.
+            unsafe { critical_section::acquire() };
+        } else {
+            self.lock.lock();
+        }
+        // A plain load and store, since the lock is held: the M0+ has no atomic swap.
+        if self.held.load(core::sync::atomic::Ordering::Relaxed) {
+            crate::postmortem::fatal("Mutex locked again by the core holding it");
+        }
+        self.held.store(true, core::sync::atomic::Ordering::Relaxed);
         MutexGuard {
             lock: &self.lock,
-            data: &self.data,
+            held: &self.held,
+            // Safety: We're holding the lock, so nobody else has a reference to the data.
+            data: unsafe { &mut *self.data.get() },
         }
     }
+    // No locking needed: having `&mut self` means nobody else can be using it.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+    // Run `f` with the lock held and interrupts masked on this core, so that the data
+    // can be shared with interrupt handlers without deadlocking.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        cortex_m::interrupt::free(|_| f(&mut self.lock()))
+    }
 }
 
+unsafe impl<T, const N: usize> Sync for Mutex<T, N> where T: Send {}
+
 impl<'a, T, const N: usize> Drop for MutexGuard<'a, T, N> {
     fn drop(&mut self) {
+        self.held
+            .store(false, core::sync::atomic::Ordering::Relaxed);
         // Safety: We're holding the lock, so we're allowed to unlock it.
-        unsafe { self.lock.unlock() };
+        if N == SHARED {
+            unsafe { critical_section::release(RestoreState::invalid()) };
+        } else {
+            unsafe { self.lock.unlock() };
+        }
     }
 }
 
@@ -70,24 +163,41 @@ impl<'a, T, const N: usize> Deref for MutexGuard<'a, T, N> {
     }
 }
 
-struct ArcInner<T, const N: usize> {
+impl<'a, T, const N: usize> DerefMut for MutexGuard<'a, T, N> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data
+    }
+}
+
+struct ArcInner<T> {
     data: T,
-    ref_count: Mutex<usize, N>,
+    // Atomic rather than behind a lock, since wakers clone and drop task references from
+    // interrupt handlers, which could otherwise find the lock held by the code they interrupted.
+    ref_count: AtomicUsize,
 }
 
-pub struct Arc<T, const N: usize> {
-    inner: *const ArcInner<T, N>,
+pub struct Arc<T> {
+    inner: *const ArcInner<T>,
 }
 
-impl<T, const N: usize> Arc<T, N> {
-    pub const fn new(data: T) -> Self {
+impl<T> Arc<T> {
+    pub fn new(data: T) -> Self {
         Arc {
             inner: Box::leak(Box::new(ArcInner {
                 data,
-                ref_count: Mutex::new(1),
+                ref_count: AtomicUsize::new(1),
             })),
         }
     }
+    // Like `new`, but returns an error instead of aborting when the heap is exhausted.
+    pub fn try_new(data: T) -> Result<Self, AllocError> {
+        Ok(Arc {
+            inner: Box::leak(Box::try_new(ArcInner {
+                data,
+                ref_count: AtomicUsize::new(1),
+            })?),
+        })
+    }
     pub fn to_raw(self) -> *const () {
         let ret = self.inner as *const ();
         forget(self); // Do NOT decrement the refcount; from_raw will not increment it.
@@ -98,42 +208,45 @@ impl<T, const N: usize> Arc<T, N> {
     // the ref_count is not modified when this function is called.
     pub unsafe fn from_raw(ptr: *const ()) -> Self {
         Arc {
-            inner: ptr as *const ArcInner<T, N>,
+            inner: ptr as *const ArcInner<T>,
         }
     }
 }
 
-impl<T, const N: usize> Clone for Arc<T, N> {
+impl<T> Clone for Arc<T> {
     fn clone(&self) -> Self {
         // Safety: self.inner can never be invalid, because it's only destroyed when the ref_count is 0.
         // And we're only ever called when the ref_count is > 0 because we have a reference to self.
         // A reference to self means a ref_count > 0 because each clone increments the ref_count
         // and each drop decrements it.
         // So we're safe.
-        let mut ref_count = unsafe { *self.inner }.ref_count.lock();
-        *ref_count += 1;
+        unsafe { &*self.inner }
+            .ref_count
+            .fetch_add(1, Ordering::Relaxed);
         Arc { inner: self.inner }
     }
 }
 
-impl<T, const N: usize> Drop for Arc<T, N> {
+impl<T> Drop for Arc<T> {
     fn drop(&mut self) {
         // Safety: self.inner can never be invalid, because it's only destroyed when the ref_count is 0.
         // And we're only ever called when the ref_count is > 0 because we have a reference to self.
         // A reference to self means a ref_count > 0 because each clone increments the ref_count
         // and each drop decrements it.
         // So we're safe.
-        let mut ref_count = unsafe { &mut *self.inner }.ref_count.lock();
-        *ref_count -= 1;
-        if *ref_count == 0 {
+        let last = unsafe { &*self.inner }
+            .ref_count
+            .fetch_sub(1, Ordering::AcqRel)
+            == 1;
+        if last {
             // Safety: ref_count is now 0, that means we're the last reference to self.inner.
             // So we can safely drop it.
-            unsafe { drop(Box::from_raw(self.inner as *mut ArcInner<T, N>)) }
+            unsafe { drop(Box::from_raw(self.inner as *mut ArcInner<T>)) }
         }
     }
 }
 
-impl<T, const N: usize> Deref for Arc<T, N> {
+impl<T> Deref for Arc<T> {
     type Target = T;
     fn deref(&self) -> &T {
         // Safety: self.inner can never be invalid, because it's only destroyed when the ref_count is 0.
@@ -145,5 +258,136 @@ impl<T, const N: usize> Deref for Arc<T, N> {
     }
 }
 
-unsafe impl<T, const N: usize> Send for Arc<T, N> where T: Send + Sync {}
-unsafe impl<T, const N: usize> Sync for Arc<T, N> where T: Send + Sync {}
+unsafe impl<T> Send for Arc<T> where T: Send + Sync {}
+unsafe impl<T> Sync for Arc<T> where T: Send + Sync {}
+
+// Push onto `vec`, treating running out of memory as fatal rather than panicking. `push` would
+// panic through the allocation error handler, which `panic-free` builds can't have.
+pub fn push_or_fatal<T>(vec: &mut Vec<T>, value: T) {
+    if vec.try_reserve(1).is_err() {
+        crate::postmortem::fatal("out of memory");
+    }
+    // There's room now, but `push` would still link in the code to grow it.
+    let _ = vec.push_within_capacity(value);
+}
+
+// The same for the back of a `VecDeque`, which has no `push_within_capacity`; with the room
+// reserved, `push_back` doesn't grow it.
+pub fn push_back_or_fatal<T>(deque: &mut VecDeque<T>, value: T) {
+    if deque.try_reserve(1).is_err() {
+        crate::postmortem::fatal("out of memory");
+    }
+    deque.push_back(value);
+}
+
+// A list of tasks waiting for something to happen.
+pub struct WaitQueue {
+    wakers: Vec<Waker>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { wakers: Vec::new() }
+    }
+    // Add a waker, unless it would wake a task that's already waiting.
+    pub fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            push_or_fatal(&mut self.wakers, waker.clone());
+        }
+    }
+    pub fn wake_all(&mut self) {
+        for waker in mem::take(&mut self.wakers) {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc as StdArc,
+        },
+        task::{Wake, Waker},
+    };
+
+    use super::*;
+
+    struct Counted<'a>(&'a AtomicUsize);
+
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn arc_drops_once_with_the_last_reference() {
+        let drops = AtomicUsize::new(0);
+        let arc = Arc::new(Counted(&drops));
+        let clone = arc.clone();
+        drop(arc);
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(clone);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn arc_raw_round_trip_keeps_the_count() {
+        let drops = AtomicUsize::new(0);
+        let raw = Arc::new(Counted(&drops)).to_raw();
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(unsafe { Arc::<Counted>::from_raw(raw) });
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn arc_counts_across_threads() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let arc = Arc::new(Counted(&DROPS));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let arc = arc.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        drop(arc.clone());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(arc);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    struct Flag(AtomicUsize);
+
+    impl Wake for Flag {
+        fn wake(self: StdArc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn wait_queue_wakes_each_task_once() {
+        let (a, b) = (
+            StdArc::new(Flag(AtomicUsize::new(0))),
+            StdArc::new(Flag(AtomicUsize::new(0))),
+        );
+        let (waker_a, waker_b) = (Waker::from(a.clone()), Waker::from(b.clone()));
+        let mut queue = WaitQueue::new();
+        queue.register(&waker_a);
+        queue.register(&waker_a.clone());
+        queue.register(&waker_b);
+        queue.wake_all();
+        assert_eq!(a.0.load(Ordering::Relaxed), 1);
+        assert_eq!(b.0.load(Ordering::Relaxed), 1);
+        // Emptied by the wake.
+        queue.wake_all();
+        assert_eq!(a.0.load(Ordering::Relaxed), 1);
+    }
+}
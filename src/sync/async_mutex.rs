@@ -0,0 +1,109 @@
+use core::{
+    cell::UnsafeCell,
+    future::poll_fn,
+    ops::{Deref, DerefMut},
+    task::Poll,
+};
+
+use super::{Mutex, WaitQueue};
+
+struct State {
+    locked: bool,
+    waiters: WaitQueue,
+}
+
+// A mutex that tasks can wait on without blocking the executor.
+// Can be held across await points, and shared between cores as a static.
+pub struct AsyncMutex<T> {
+    state: Mutex<State, 12>,
+    data: UnsafeCell<T>,
+}
+
+pub struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(data: T) -> Self {
+        AsyncMutex {
+            state: Mutex::new(State {
+                locked: false,
+                waiters: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub async fn lock(&self) -> AsyncMutexGuard<'_, T> {
+        #[cfg(feature = "deadlock-detect")]
+        let mut wait = crate::deadlock::Wait::new();
+        poll_fn(|cx| {
+            self.state.with(|state| {
+                if state.locked {
+                    state.waiters.register(cx.waker());
+                    #[cfg(feature = "deadlock-detect")]
+                    wait.start(self.resource(), "mutex");
+                    Poll::Pending
+                } else {
+                    Poll::Ready(self.acquire(state))
+                }
+            })
+        })
+        .await
+    }
+
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        self.state.with(|state| {
+            if state.locked {
+                None
+            } else {
+                Some(self.acquire(state))
+            }
+        })
+    }
+
+    fn acquire(&self, state: &mut State) -> AsyncMutexGuard<'_, T> {
+        state.locked = true;
+        #[cfg(feature = "deadlock-detect")]
+        crate::deadlock::acquired(self.resource());
+        AsyncMutexGuard { mutex: self }
+    }
+
+    #[cfg(feature = "deadlock-detect")]
+    fn resource(&self) -> *const () {
+        self as *const Self as *const ()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+unsafe impl<T> Sync for AsyncMutex<T> where T: Send {}
+
+impl<'a, T> Drop for AsyncMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "deadlock-detect")]
+        crate::deadlock::released(self.mutex.resource());
+        self.mutex.state.with(|state| {
+            state.locked = false;
+            // Wake everyone: a waiter may have given up (been dropped) since it registered.
+            state.waiters.wake_all();
+        })
+    }
+}
+
+impl<'a, T> Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: The guard means we hold the lock.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for AsyncMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The guard means we hold the lock.
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
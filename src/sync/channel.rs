@@ -0,0 +1,129 @@
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+};
+
+use super::{Mutex, WaitQueue};
+
+struct State<T, const CAP: usize> {
+    buf: [Option<T>; CAP],
+    head: usize,
+    len: usize,
+    senders: WaitQueue,
+    receivers: WaitQueue,
+}
+
+// A bounded multi-producer, multi-consumer queue holding up to CAP values.
+// The storage is inline, so a channel in a static needs no heap at all.
+pub struct Channel<T, const CAP: usize> {
+    state: Mutex<State<T, CAP>, 13>,
+}
+
+impl<T, const CAP: usize> Channel<T, CAP> {
+    pub const fn new() -> Self {
+        Channel {
+            state: Mutex::new(State {
+                buf: [const { None }; CAP],
+                head: 0,
+                len: 0,
+                senders: WaitQueue::new(),
+                receivers: WaitQueue::new(),
+            }),
+        }
+    }
+
+    // Send a value, waiting for room if the channel is full.
+    pub async fn send(&self, value: T) {
+        let mut value = Some(value);
+        #[cfg(feature = "deadlock-detect")]
+        let mut wait = crate::deadlock::Wait::new();
+        poll_fn(|cx| {
+            self.state.with(|state| {
+                if state.len == CAP {
+                    state.senders.register(cx.waker());
+                    #[cfg(feature = "deadlock-detect")]
+                    wait.start(self as *const Self as *const (), "channel send");
+                    return Poll::Pending;
+                }
+                // value is only taken when returning Ready, so it's still there.
+                state.push(value.take().unwrap());
+                Poll::Ready(())
+            })
+        })
+        .await
+    }
+
+    // Send a value if there's room, otherwise hand it back.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        self.state.with(|state| {
+            if state.len == CAP {
+                return Err(value);
+            }
+            state.push(value);
+            Ok(())
+        })
+    }
+
+    // Receive a value, waiting for one if the channel is empty.
+    pub async fn recv(&self) -> T {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    // Take a value if there is one, otherwise register to be woken when one is sent.
+    pub fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<T> {
+        self.state.with(|state| match state.pop() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                state.receivers.register(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+
+    // Wait until the channel is empty.
+    pub async fn flush(&self) {
+        poll_fn(|cx| {
+            self.state.with(|state| {
+                if state.len == 0 {
+                    Poll::Ready(())
+                } else {
+                    // Senders are woken whenever a value is taken out.
+                    state.senders.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        self.state.with(|state| state.pop())
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.with(|state| state.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, const CAP: usize> State<T, CAP> {
+    fn push(&mut self, value: T) {
+        self.buf[(self.head + self.len) % CAP] = Some(value);
+        self.len += 1;
+        self.receivers.wake_all();
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.buf[self.head].take();
+        self.head = (self.head + 1) % CAP;
+        self.len -= 1;
+        self.senders.wake_all();
+        value
+    }
+}
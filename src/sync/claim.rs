@@ -0,0 +1,52 @@
+use crate::atomic::{AtomicBool, Ordering};
+
+// Ownership of something that can be conjured up out of nothing, like a PAC peripheral, handed
+// out to whoever asks first. Stealing a peripheral is only sound if nothing else has it; when
+// everything that wants it goes through the same `Claim`, `take` makes sure of that.
+//
+//     static PWM: Claim<rp2040_pac::PWM> = unsafe { Claim::new(rp2040_pac::PWM::steal) };
+//     let pwm = PWM.take().expect("PWM is already in use");
+pub struct Claim<T> {
+    taken: AtomicBool,
+    steal: unsafe fn() -> T,
+}
+
+impl<T> Claim<T> {
+    // Safety: Nothing may call `steal`, or get what it returns any other way, except through
+    // this claim.
+    pub const unsafe fn new(steal: unsafe fn() -> T) -> Self {
+        Claim {
+            taken: AtomicBool::new(false),
+            steal,
+        }
+    }
+
+    // The thing, the first time this is called; `None` after that.
+    pub fn take(&self) -> Option<T> {
+        if self.taken.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        // Safety: The flag was clear, so this is the only one there will ever be.
+        Some(unsafe { (self.steal)() })
+    }
+
+    pub fn is_taken(&self) -> bool {
+        self.taken.load(Ordering::Acquire)
+    }
+}
+
+// The core peripherals (NVIC, SysTick, MPU and so on) are banked: each core sees its own at the
+// same addresses. `cortex_m::Peripherals::take` only knows about one set, so it would refuse
+// core 1 once core 0 had taken its own. These are kept per core instead.
+static CORE_PERIPHERALS: [Claim<cortex_m::Peripherals>; 2] = unsafe {
+    [
+        Claim::new(cortex_m::Peripherals::steal),
+        Claim::new(cortex_m::Peripherals::steal),
+    ]
+};
+
+// This core's core peripherals, the first time it asks.
+pub fn core_peripherals() -> Option<cortex_m::Peripherals> {
+    let core = super::core();
+    CORE_PERIPHERALS[core].take()
+}
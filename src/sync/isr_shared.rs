@@ -0,0 +1,80 @@
+use core::cell::UnsafeCell;
+
+use cortex_m::peripheral::{scb::VectActive, NVIC, SCB};
+use rp2040_pac::Interrupt;
+
+use crate::atomic::{AtomicBool, Ordering};
+
+// Data shared between tasks and the handler for one particular interrupt.
+//
+// Tasks use `lock`, which masks that interrupt on the current core while the closure runs.
+// The handler uses `lock_from_isr`. The M0+ has no BASEPRI, so this masks the one interrupt in
+// the NVIC rather than a priority level. The other core is kept out by a lock flag of the
+// instance's own, not a hardware spinlock: with one spinlock for every `IsrShared`, a task
+// holding one instance could be preempted by a different interrupt going for another, which
+// would spin forever. With a flag each, the only code that can preempt a holder on its core
+// is another instance's, and that doesn't wait on this flag.
+//
+// Both are checked at runtime: `lock` panics outside of thread mode and `lock_from_isr`
+// panics outside of the owning handler, since either would risk spinning forever on a lock
+// held by the code that was preempted.
+pub struct IsrShared<T> {
+    irq: Interrupt,
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+impl<T> IsrShared<T> {
+    pub const fn new(irq: Interrupt, data: T) -> Self {
+        IsrShared {
+            irq,
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Run `f` with the lock flag held, spinning while the other core has it.
+    fn with_flag<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // Safety: Holding the flag makes this the only reference.
+        let ret = f(unsafe { &mut *self.data.get() });
+        self.locked.store(false, Ordering::Release);
+        ret
+    }
+
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        assert!(
+            SCB::vect_active() == VectActive::ThreadMode,
+            "IsrShared::lock called from an exception handler"
+        );
+        let was_enabled = NVIC::is_enabled(self.irq);
+        NVIC::mask(self.irq);
+        // Make sure the mask has taken effect before touching the data.
+        crate::barrier::settle();
+        let ret = self.with_flag(f);
+        if was_enabled {
+            // Safety: It was enabled before, we're just restoring that.
+            unsafe { NVIC::unmask(self.irq) };
+        }
+        ret
+    }
+
+    pub fn lock_from_isr<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        assert!(
+            SCB::vect_active()
+                == VectActive::Interrupt {
+                    irqn: self.irq as u8
+                },
+            "IsrShared::lock_from_isr called outside of its interrupt handler"
+        );
+        self.with_flag(f)
+    }
+}
+
+unsafe impl<T> Sync for IsrShared<T> where T: Send {}
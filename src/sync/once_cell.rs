@@ -0,0 +1,89 @@
+use core::{cell::UnsafeCell, future::poll_fn, mem::MaybeUninit, task::Poll};
+
+use super::{Mutex, WaitQueue};
+
+struct State {
+    initialized: bool,
+    waiters: WaitQueue,
+}
+
+// A value that's set once, and can be waited for until then.
+pub struct OnceCell<T> {
+    state: Mutex<State, 15>,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        OnceCell {
+            state: Mutex::new(State {
+                initialized: false,
+                waiters: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    // Set the value. Hands it back if the cell was already set.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        self.state.with(|state| {
+            if state.initialized {
+                return Err(value);
+            }
+            // Safety: Not initialized yet, so nobody has a reference to the value.
+            unsafe { (*self.value.get()).write(value) };
+            state.initialized = true;
+            state.waiters.wake_all();
+            Ok(())
+        })
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.with(|state| state.initialized) {
+            // Safety: Once initialized, the value is never written again.
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    // Get the value, initializing it with `f` if it isn't set yet.
+    // If another core wins the race, its value is kept and ours is dropped.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        let _ = self.set(f());
+        self.get().unwrap()
+    }
+
+    // Wait until the value has been set.
+    pub async fn wait(&self) -> &T {
+        poll_fn(|cx| {
+            if let Some(value) = self.get() {
+                return Poll::Ready(value);
+            }
+            self.state.with(|state| {
+                if state.initialized {
+                    // Set between the check above and taking the lock; poll again.
+                    cx.waker().wake_by_ref();
+                } else {
+                    state.waiters.register(cx.waker());
+                }
+            });
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+unsafe impl<T> Sync for OnceCell<T> where T: Send + Sync {}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if self.state.with(|state| state.initialized) {
+            // Safety: It's initialized, and we have exclusive access.
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
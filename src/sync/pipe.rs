@@ -0,0 +1,153 @@
+extern crate alloc;
+
+use alloc::{boxed::Box, vec};
+use core::{future::poll_fn, task::Poll};
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+
+use super::{Arc, Mutex, WaitQueue};
+
+struct State {
+    buf: Box<[u8]>,
+    head: usize,
+    len: usize,
+    reader_alive: bool,
+    writer_alive: bool,
+    reader: WaitQueue,
+    writer: WaitQueue,
+}
+
+type Shared = Arc<Mutex<State, 18>>;
+
+// The writing end of a pipe. Reads on the other end return 0 (end of stream) once this is dropped.
+pub struct PipeWriter {
+    shared: Shared,
+}
+
+// The reading end of a pipe. Writes on the other end fail with BrokenPipe once this is dropped.
+pub struct PipeReader {
+    shared: Shared,
+}
+
+// Create a byte pipe that buffers up to `capacity` bytes between a writer and a reader.
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+    let shared = Arc::new(Mutex::new(State {
+        buf: vec![0; capacity].into_boxed_slice(),
+        head: 0,
+        len: 0,
+        reader_alive: true,
+        writer_alive: true,
+        reader: WaitQueue::new(),
+        writer: WaitQueue::new(),
+    }));
+    (
+        PipeWriter {
+            shared: shared.clone(),
+        },
+        PipeReader { shared },
+    )
+}
+
+impl ErrorType for PipeWriter {
+    type Error = ErrorKind;
+}
+
+impl Write for PipeWriter {
+    // Write as much of `buf` as fits, waiting for room if the pipe is full.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        poll_fn(|cx| {
+            self.shared.with(|state| {
+                if !state.reader_alive {
+                    return Poll::Ready(Err(ErrorKind::BrokenPipe));
+                }
+                let cap = state.buf.len();
+                let len = buf.len().min(cap - state.len);
+                if len == 0 {
+                    state.writer.register(cx.waker());
+                    return Poll::Pending;
+                }
+                for &byte in &buf[..len] {
+                    let i = (state.head + state.len) % cap;
+                    state.buf[i] = byte;
+                    state.len += 1;
+                }
+                state.reader.wake_all();
+                Poll::Ready(Ok(len))
+            })
+        })
+        .await
+    }
+
+    // Wait until the reader has taken everything written so far.
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        poll_fn(|cx| {
+            self.shared.with(|state| {
+                if state.len == 0 {
+                    Poll::Ready(Ok(()))
+                } else if !state.reader_alive {
+                    Poll::Ready(Err(ErrorKind::BrokenPipe))
+                } else {
+                    state.writer.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.shared.with(|state| {
+            state.writer_alive = false;
+            state.reader.wake_all();
+        })
+    }
+}
+
+impl ErrorType for PipeReader {
+    type Error = ErrorKind;
+}
+
+impl Read for PipeReader {
+    // Read whatever is buffered, waiting for at least one byte.
+    // Returns 0 once the pipe is empty and the writer is gone.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        poll_fn(|cx| {
+            self.shared.with(|state| {
+                if state.len == 0 {
+                    if !state.writer_alive {
+                        return Poll::Ready(Ok(0));
+                    }
+                    state.reader.register(cx.waker());
+                    return Poll::Pending;
+                }
+                let cap = state.buf.len();
+                let len = buf.len().min(state.len);
+                for byte in &mut buf[..len] {
+                    *byte = state.buf[state.head];
+                    state.head = (state.head + 1) % cap;
+                    state.len -= 1;
+                }
+                state.writer.wake_all();
+                Poll::Ready(Ok(len))
+            })
+        })
+        .await
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.shared.with(|state| {
+            state.reader_alive = false;
+            state.writer.wake_all();
+        })
+    }
+}
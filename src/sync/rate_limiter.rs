@@ -0,0 +1,73 @@
+use core::time::Duration;
+
+use crate::time::{self, Instant};
+
+use super::Mutex;
+
+// Token counts are kept in millionths, so refilling by elapsed microseconds times the rate
+// needs no division.
+const SCALE: u64 = 1_000_000;
+
+struct Bucket {
+    tokens: u64,
+    // When `tokens` was last topped up; `None` until first use, when the bucket starts full.
+    refilled: Option<Instant>,
+}
+
+// A token bucket: allows `rate` acquisitions per second on average, with bursts of up to
+// `burst` at once after a quiet spell. For throttling log output, radio transmissions,
+// flash writes and so on.
+pub struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    bucket: Mutex<Bucket, 25>,
+}
+
+impl RateLimiter {
+    pub const fn new(rate: u32, burst: u32) -> Self {
+        assert!(rate > 0 && burst > 0, "rate and burst must be non-zero");
+        RateLimiter {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: 0,
+                refilled: None,
+            }),
+        }
+    }
+
+    // Take a token if there is one. Otherwise returns when there will be.
+    fn take(&self) -> Result<(), Instant> {
+        let now = Instant::now();
+        self.bucket.with(|bucket| {
+            let capacity = self.burst as u64 * SCALE;
+            let refilled = match bucket.refilled {
+                Some(refilled) => {
+                    ((now - refilled).as_micros() as u64).saturating_mul(self.rate as u64)
+                }
+                None => capacity,
+            };
+            bucket.tokens = bucket.tokens.saturating_add(refilled).min(capacity);
+            bucket.refilled = Some(now);
+            if bucket.tokens >= SCALE {
+                bucket.tokens -= SCALE;
+                Ok(())
+            } else {
+                let wait_us = (SCALE - bucket.tokens).div_ceil(self.rate as u64);
+                Err(now + Duration::from_micros(wait_us))
+            }
+        })
+    }
+
+    // Wait for a token and take it.
+    pub async fn acquire(&self) {
+        while let Err(ready) = self.take() {
+            // Someone else may get there first, in which case we go around again.
+            time::sleep_until(ready).await;
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.take().is_ok()
+    }
+}
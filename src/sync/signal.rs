@@ -0,0 +1,59 @@
+use core::{future::poll_fn, task::Poll};
+
+use super::{Mutex, WaitQueue};
+
+struct State<T> {
+    value: Option<T>,
+    waiters: WaitQueue,
+}
+
+// Holds the latest value sent to it until a task takes it.
+// Signalling again before the value is taken overwrites it.
+// `signal` never waits, so it's fine to call from interrupt handlers.
+pub struct Signal<T> {
+    state: Mutex<State<T>, 14>,
+}
+
+impl<T> Signal<T> {
+    pub const fn new() -> Self {
+        Signal {
+            state: Mutex::new(State {
+                value: None,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    pub fn signal(&self, value: T) {
+        self.state.with(|state| {
+            state.value = Some(value);
+            state.waiters.wake_all();
+        })
+    }
+
+    // Wait for a value and take it.
+    pub async fn wait(&self) -> T {
+        poll_fn(|cx| {
+            self.state.with(|state| match state.value.take() {
+                Some(value) => Poll::Ready(value),
+                None => {
+                    state.waiters.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    pub fn try_take(&self) -> Option<T> {
+        self.state.with(|state| state.value.take())
+    }
+
+    pub fn signaled(&self) -> bool {
+        self.state.with(|state| state.value.is_some())
+    }
+
+    pub fn reset(&self) {
+        self.state.with(|state| state.value = None)
+    }
+}
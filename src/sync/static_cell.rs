@@ -0,0 +1,49 @@
+use core::{cell::UnsafeCell, mem::MaybeUninit};
+
+use crate::atomic::{AtomicBool, Ordering};
+
+// Room for a value that lives forever but is only set up at run time, handed out as
+// `&'static mut` exactly once. This is what `static mut` was for, without taking references
+// to one: the flag makes sure there's only ever one.
+//
+//     static BUF: StaticCell<[u8; 512]> = StaticCell::new();
+//     let buf: &'static mut [u8; 512] = BUF.init([0; 512]);
+pub struct StaticCell<T> {
+    taken: AtomicBool,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Safety: The value is only reachable through the one `&'static mut` handed out, and that
+// can end up on the other core.
+unsafe impl<T: Send> Sync for StaticCell<T> {}
+
+impl<T> StaticCell<T> {
+    pub const fn new() -> Self {
+        StaticCell {
+            taken: AtomicBool::new(false),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    // Store `value` and hand it out. Fatal if the cell has been handed out before.
+    pub fn init(&'static self, value: T) -> &'static mut T {
+        self.uninit().write(value)
+    }
+
+    // Hand out the memory without putting anything in it, for big buffers that `init` would
+    // copy through the stack on the way in. Fatal if the cell has been handed out before.
+    pub fn uninit(&'static self) -> &'static mut MaybeUninit<T> {
+        match self.try_uninit() {
+            Some(value) => value,
+            None => crate::postmortem::fatal("StaticCell handed out twice"),
+        }
+    }
+
+    pub fn try_uninit(&'static self) -> Option<&'static mut MaybeUninit<T>> {
+        if self.taken.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        // Safety: The flag was clear, so this is the only reference there will ever be.
+        Some(unsafe { &mut *self.value.get() })
+    }
+}
@@ -0,0 +1,113 @@
+use core::{cell::UnsafeCell, future::poll_fn, mem::MaybeUninit, task::Poll};
+
+use super::{Mutex, WaitQueue};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Empty,
+    Ready,
+    // References have been handed out, so the value can't be moved any more.
+    Shared,
+    Taken,
+}
+
+struct State {
+    stage: Stage,
+    waiters: WaitQueue,
+}
+
+// Hands a driver from whichever core sets it up to tasks on either core, once it's ready.
+//
+// Core 0 can bring up a peripheral and `init` it here while tasks on core 1 (spawned before
+// it was ready, from the `jumpstart` closure) `get().await` or `take().await` it, without
+// stealing peripherals to get them across. It works the same the other way around.
+//
+// The value is either shared (`get`, for drivers that are `Sync` or wrapped in a lock) or
+// moved out to one task (`take`); not both.
+pub struct SyncInit<T> {
+    state: Mutex<State, 26>,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> SyncInit<T> {
+    pub const fn new() -> Self {
+        SyncInit {
+            state: Mutex::new(State {
+                stage: Stage::Empty,
+                waiters: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    // Set the value and wake everything waiting for it. Hands it back if it was already set.
+    pub fn init(&self, value: T) -> Result<(), T> {
+        self.state.with(|state| {
+            if state.stage != Stage::Empty {
+                return Err(value);
+            }
+            // Safety: Not initialized yet, so nobody has a reference to the value.
+            unsafe { (*self.value.get()).write(value) };
+            state.stage = Stage::Ready;
+            state.waiters.wake_all();
+            Ok(())
+        })
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state
+            .with(|state| matches!(state.stage, Stage::Ready | Stage::Shared))
+    }
+
+    // Wait until the value has been set, and borrow it.
+    //
+    // Panics if it's been taken.
+    pub async fn get(&self) -> &T {
+        poll_fn(|cx| {
+            self.state.with(|state| match state.stage {
+                Stage::Empty => {
+                    state.waiters.register(cx.waker());
+                    Poll::Pending
+                }
+                Stage::Ready | Stage::Shared => {
+                    state.stage = Stage::Shared;
+                    // Safety: Initialized, and from now on never moved or written again.
+                    Poll::Ready(unsafe { (*self.value.get()).assume_init_ref() })
+                }
+                Stage::Taken => panic!("SyncInit::get after the value was taken"),
+            })
+        })
+        .await
+    }
+
+    // Wait until the value has been set, and move it out. Returns `None` if another task took
+    // it first or it's already been borrowed with `get`.
+    pub async fn take(&self) -> Option<T> {
+        poll_fn(|cx| {
+            self.state.with(|state| match state.stage {
+                Stage::Empty => {
+                    state.waiters.register(cx.waker());
+                    Poll::Pending
+                }
+                Stage::Ready => {
+                    state.stage = Stage::Taken;
+                    // Safety: Initialized, and nobody has borrowed it.
+                    Poll::Ready(Some(unsafe { (*self.value.get()).assume_init_read() }))
+                }
+                Stage::Shared | Stage::Taken => Poll::Ready(None),
+            })
+        })
+        .await
+    }
+}
+
+unsafe impl<T> Sync for SyncInit<T> where T: Send + Sync {}
+
+impl<T> Drop for SyncInit<T> {
+    fn drop(&mut self) {
+        if matches!(self.state.lock().stage, Stage::Ready | Stage::Shared) {
+            // Safety: It's initialized and still here, and we have exclusive access.
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
@@ -0,0 +1,141 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use super::Mutex;
+
+struct Entry<K, V> {
+    key: K,
+    // Tells waiters apart, since entries move around as others are removed.
+    id: u32,
+    value: Option<V>,
+    waker: Option<Waker>,
+}
+
+struct Entries<K, V> {
+    entries: Vec<Entry<K, V>>,
+    next_id: u32,
+}
+
+// Routes responses to the task waiting for them, by key: a request id, an MQTT packet id, a
+// transaction number on a shared bus...
+//
+// Register the key *before* sending the request, so a response that comes back quickly has
+// somewhere to go, then await the returned `Waiter`. Whoever receives responses calls
+// `complete`. Dropping the waiter gives the key back.
+pub struct WaitMap<K, V> {
+    state: Mutex<Entries<K, V>, 27>,
+}
+
+impl<K: PartialEq, V> WaitMap<K, V> {
+    pub const fn new() -> Self {
+        WaitMap {
+            state: Mutex::new(Entries {
+                entries: Vec::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    // Start waiting for `key`. Hands the key back if something's already waiting for it.
+    pub fn register(&self, key: K) -> Result<Waiter<'_, K, V>, K> {
+        let id = self.state.with(|state| {
+            if state.entries.iter().any(|entry| entry.key == key) {
+                return Err(key);
+            }
+            let id = state.next_id;
+            state.next_id = state.next_id.wrapping_add(1);
+            state.entries.push(Entry {
+                key,
+                id,
+                value: None,
+                waker: None,
+            });
+            Ok(id)
+        })?;
+        Ok(Waiter { map: self, id })
+    }
+
+    // Hand `value` to whoever is waiting for `key`. Gives it back if nobody is, e.g. because
+    // they gave up, or it's a response to a request that was never made.
+    pub fn complete(&self, key: &K, value: V) -> Result<(), V> {
+        let waker = self.state.with(|state| {
+            let entry = state
+                .entries
+                .iter_mut()
+                .find(|entry| entry.key == *key && entry.value.is_none());
+            match entry {
+                Some(entry) => {
+                    entry.value = Some(value);
+                    Ok(entry.waker.take())
+                }
+                None => Err(value),
+            }
+        })?;
+        // Don't hold the lock while waking.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    // Whether anything is waiting for `key`.
+    pub fn is_waiting(&self, key: &K) -> bool {
+        self.state
+            .with(|state| state.entries.iter().any(|entry| entry.key == *key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.with(|state| state.entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Resolves to the value passed to `complete` for its key.
+pub struct Waiter<'a, K, V> {
+    map: &'a WaitMap<K, V>,
+    id: u32,
+}
+
+impl<K, V> Future for Waiter<'_, K, V> {
+    type Output = V;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<V> {
+        let id = self.id;
+        self.map.state.with(|state| {
+            let index = state
+                .entries
+                .iter()
+                .position(|entry| entry.id == id)
+                .expect("Waiter polled after completing");
+            let entry = &mut state.entries[index];
+            if entry.value.is_some() {
+                let entry = state.entries.swap_remove(index);
+                Poll::Ready(entry.value.unwrap())
+            } else {
+                entry.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<K, V> Drop for Waiter<'_, K, V> {
+    fn drop(&mut self) {
+        // Gone already if it completed; otherwise give the key back. Any value that arrived too
+        // late is dropped after the lock is released.
+        let id = self.id;
+        let removed = self.map.state.with(|state| {
+            let index = state.entries.iter().position(|entry| entry.id == id)?;
+            Some(state.entries.swap_remove(index))
+        });
+        drop(removed);
+    }
+}
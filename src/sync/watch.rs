@@ -0,0 +1,96 @@
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+};
+
+use super::{Mutex, WaitQueue};
+
+struct State<T> {
+    value: Option<T>,
+    // Bumped on every send, so receivers can tell whether they've seen the latest value.
+    version: u32,
+    waiters: WaitQueue,
+}
+
+// Holds the latest value of something (a state, a reading) for any number of tasks to look at.
+// Unlike Signal, reading doesn't take the value; receivers wait for it to change instead.
+// `send` never waits, so it's fine to call from interrupt handlers.
+pub struct Watch<T> {
+    state: Mutex<State<T>, 22>,
+}
+
+impl<T: Clone> Watch<T> {
+    pub const fn new() -> Self {
+        Watch {
+            state: Mutex::new(State {
+                value: None,
+                version: 0,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    pub fn send(&self, value: T) {
+        self.state.with(|state| {
+            state.value = Some(value);
+            state.version = state.version.wrapping_add(1);
+            state.waiters.wake_all();
+        })
+    }
+
+    // Like `send`, but only if the value is different, so receivers aren't woken for nothing.
+    pub fn send_if_changed(&self, value: T)
+    where
+        T: PartialEq,
+    {
+        self.state.with(|state| {
+            if state.value.as_ref() != Some(&value) {
+                state.value = Some(value);
+                state.version = state.version.wrapping_add(1);
+                state.waiters.wake_all();
+            }
+        })
+    }
+
+    // The latest value, if anything has been sent yet.
+    pub fn get(&self) -> Option<T> {
+        self.state.with(|state| state.value.clone())
+    }
+
+    // A receiver that starts out having seen whatever is there now.
+    pub fn receiver(&self) -> WatchReceiver<'_, T> {
+        WatchReceiver {
+            watch: self,
+            seen: self.state.with(|state| state.version),
+        }
+    }
+}
+
+pub struct WatchReceiver<'a, T> {
+    watch: &'a Watch<T>,
+    seen: u32,
+}
+
+impl<T: Clone> WatchReceiver<'_, T> {
+    // Wait for a value this receiver hasn't seen yet. Values sent in between are skipped.
+    pub async fn changed(&mut self) -> T {
+        poll_fn(|cx| self.poll_changed(cx)).await
+    }
+
+    pub fn poll_changed(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+        self.watch.state.with(|state| match &state.value {
+            Some(value) if state.version != self.seen => {
+                self.seen = state.version;
+                Poll::Ready(value.clone())
+            }
+            _ => {
+                state.waiters.register(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+
+    pub fn get(&self) -> Option<T> {
+        self.watch.get()
+    }
+}
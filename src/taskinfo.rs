@@ -0,0 +1,177 @@
+// A table of live tasks for debuggers.
+//
+// `RP2040_ASYNC_TASKS` has a fixed layout so that probe-rs/GDB scripts can find it by symbol
+// and show tasks the way they'd show threads. All fields are little-endian u32s:
+//
+//   magic    = 0x5441_534B ("TASK")
+//   version  = 1
+//   capacity = number of entries
+//   running  = [entry index being polled on core 0, on core 1], u32::MAX if none
+//   entries  = [{ id, name_ptr, name_len, state, poll_fn, polls }; capacity]
+//
+// `id` is unique per spawned task and 0 for a free entry.
+// `name_ptr`/`name_len` are a UTF-8 string in flash.
+// `poll_fn` is the address of the task's `Future::poll`, for symbolizing.
+
+use core::ptr;
+
+use cortex_m::interrupt;
+
+use crate::{
+    atomic::{AtomicU32, Ordering},
+    sync::{self, SpinLock},
+};
+
+pub const MAX_TASKS: usize = 32;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Free = 0,
+    Queued = 1,
+    Running = 2,
+    Waiting = 3,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Entry {
+    id: u32,
+    name_ptr: *const u8,
+    name_len: usize,
+    state: State,
+    poll_fn: usize,
+    polls: u32,
+}
+
+const FREE: Entry = Entry {
+    id: 0,
+    name_ptr: ptr::null(),
+    name_len: 0,
+    state: State::Free,
+    poll_fn: 0,
+    polls: 0,
+};
+
+#[repr(C)]
+struct Table {
+    magic: u32,
+    version: u32,
+    capacity: u32,
+    running: [u32; 2],
+    entries: [Entry; MAX_TASKS],
+}
+
+const NOT_RUNNING: u32 = u32::MAX;
+
+#[no_mangle]
+#[used]
+static mut RP2040_ASYNC_TASKS: Table = Table {
+    magic: 0x5441_534B,
+    version: 1,
+    capacity: MAX_TASKS as u32,
+    running: [NOT_RUNNING; 2],
+    entries: [FREE; MAX_TASKS],
+};
+
+static LOCK: SpinLock<10> = SpinLock::new();
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+fn with_table<R>(f: impl FnOnce(&mut Table) -> R) -> R {
+    // Wakers update the table too, and they can be called from interrupt handlers.
+    interrupt::free(|_| {
+        LOCK.lock();
+        // Safety: We're holding the lock. Debuggers only ever read the table.
+        let ret = f(unsafe { &mut *ptr::addr_of_mut!(RP2040_ASYNC_TASKS) });
+        unsafe { LOCK.unlock() };
+        ret
+    })
+}
+
+// Add a task to the table, returning its slot and id. Returns None if the table is full;
+// the task still runs, it just won't show up.
+pub(crate) fn add(name: &'static str, poll_fn: usize) -> Option<(usize, u32)> {
+    with_table(|table| {
+        let slot = table.entries.iter().position(|entry| entry.id == 0)?;
+        // 0 marks a free entry, so skip it when the ids wrap around.
+        let id = loop {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                break id;
+            }
+        };
+        table.entries[slot] = Entry {
+            id,
+            name_ptr: name.as_ptr(),
+            name_len: name.len(),
+            state: State::Queued,
+            poll_fn,
+            polls: 0,
+        };
+        Some((slot, id))
+    })
+}
+
+// Update a task's state. `id` guards against the slot having been reused.
+pub(crate) fn set_state(slot: usize, id: u32, state: State) {
+    // Indexed with `get_mut` so there's no bounds check to panic: this runs on every poll.
+    with_table(|table| {
+        let (Some(entry), Some(running)) = (
+            table.entries.get_mut(slot),
+            table.running.get_mut(sync::core()),
+        ) else {
+            return;
+        };
+        if entry.id != id {
+            return;
+        }
+        if state == State::Free {
+            *entry = FREE;
+            return;
+        }
+        entry.state = state;
+        if state == State::Running {
+            entry.polls = entry.polls.wrapping_add(1);
+            *running = slot as u32;
+        } else if *running == slot as u32 {
+            *running = NOT_RUNNING;
+        }
+    })
+}
+
+// The name of the task being polled on this core, if any.
+// Doesn't take the lock, so it's usable from the panic handler.
+pub fn current_name() -> Option<&'static str> {
+    let table = unsafe { &*ptr::addr_of!(RP2040_ASYNC_TASKS) };
+    let slot = table.running[sync::core()];
+    let entry = table.entries.get(slot as usize)?;
+    if entry.id == 0 {
+        return None;
+    }
+    // Safety: name_ptr/name_len came from a &'static str in `add`.
+    let name = unsafe { core::slice::from_raw_parts(entry.name_ptr, entry.name_len) };
+    core::str::from_utf8(name).ok()
+}
+
+// The id of the task being polled on this core, if any.
+pub fn current_id() -> Option<u32> {
+    with_table(|table| {
+        let slot = *table.running.get(sync::core())?;
+        let entry = table.entries.get(slot as usize)?;
+        (entry.id != 0).then_some(entry.id)
+    })
+}
+
+// The name of the live task with `id`.
+pub fn name_of(id: u32) -> Option<&'static str> {
+    let (name_ptr, name_len) = with_table(|table| {
+        let entry = table
+            .entries
+            .iter()
+            .find(|entry| entry.id == id && id != 0)?;
+        Some((entry.name_ptr, entry.name_len))
+    })?;
+    // Safety: name_ptr/name_len came from a &'static str in `add`.
+    let name = unsafe { core::slice::from_raw_parts(name_ptr, name_len) };
+    core::str::from_utf8(name).ok()
+}
@@ -0,0 +1,138 @@
+// Keeps an eye on the die temperature using the internal sensor on ADC channel 4.
+//
+// `run` is meant to be spawned as a background task. It publishes the current reading to
+// `STATE`, which any task can watch, and can optionally slow clk_sys down as things heat up.
+
+use embedded_hal_async::delay::DelayNs;
+
+use crate::{adc, sync::Watch};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThermalState {
+    Normal,
+    Warm,
+    Hot,
+    Critical,
+}
+
+// Temperatures are in millidegrees Celsius. A state is entered at its threshold and left once
+// the temperature drops `hysteresis_mdeg` below it, so a reading hovering around a threshold
+// doesn't make the state flap.
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub warm_mdeg: i32,
+    pub hot_mdeg: i32,
+    pub critical_mdeg: i32,
+    pub hysteresis_mdeg: i32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            warm_mdeg: 60_000,
+            hot_mdeg: 75_000,
+            critical_mdeg: 90_000,
+            hysteresis_mdeg: 3_000,
+        }
+    }
+}
+
+impl Thresholds {
+    fn classify(&self, temperature_mdeg: i32, current: ThermalState) -> ThermalState {
+        let levels = [
+            (ThermalState::Critical, self.critical_mdeg),
+            (ThermalState::Hot, self.hot_mdeg),
+            (ThermalState::Warm, self.warm_mdeg),
+        ];
+        for (state, threshold) in levels {
+            // Stay in a state we're already in (or above) until we're clearly below it.
+            let threshold = if current >= state {
+                threshold - self.hysteresis_mdeg
+            } else {
+                threshold
+            };
+            if temperature_mdeg >= threshold {
+                return state;
+            }
+        }
+        ThermalState::Normal
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reading {
+    pub temperature_mdeg: i32,
+    pub state: ThermalState,
+}
+
+// What `run` does besides publishing the state.
+#[derive(Clone, Copy, Debug)]
+pub enum Policy {
+    // Just publish it.
+    Notify,
+    // Also divide clk_sys by the given factor in each state (Normal, Warm, Hot, Critical),
+    // on top of whatever divider was set when `run` started.
+    //
+    // Everything clocked from clk_sys slows down with it, including clk_peri if that's where
+    // it comes from, so UART and SPI rates change too. TIMER runs from clk_ref and isn't
+    // affected, so timekeeping stays right.
+    ScaleSysclk([u32; 4]),
+}
+
+impl Policy {
+    pub const DEFAULT_SCALING: Policy = Policy::ScaleSysclk([1, 2, 4, 8]);
+}
+
+pub static STATE: Watch<Reading> = Watch::new();
+
+// The sensor is noisy, so each reading is an average of this many conversions.
+const SAMPLES: u32 = 8;
+
+// Take a single temperature reading.
+pub fn read_temperature_mdeg() -> i32 {
+    let microvolts = adc::read(adc::TEMPERATURE_SENSOR, SAMPLES) as i32
+        * (adc::REFERENCE_MV as i32 * 1000)
+        / 4096;
+    // From the datasheet: T = 27 - (V - 0.706) / 0.001721
+    27_000 - (microvolts - 706_000) * 1000 / 1721
+}
+
+fn set_sysclk_divider(div: u32) {
+    let clocks = unsafe { &*rp2040_pac::CLOCKS::ptr() };
+    clocks.clk_sys_div.write(|w| unsafe { w.bits(div) });
+    crate::clocks::invalidate();
+}
+
+// Sample the temperature every `interval_ms` and publish it to `STATE`, forever.
+pub async fn run<D: DelayNs>(
+    mut delay: D,
+    thresholds: Thresholds,
+    interval_ms: u32,
+    policy: Policy,
+) -> ! {
+    let clocks = unsafe { &*rp2040_pac::CLOCKS::ptr() };
+    // Integer part in bits 31:8, fraction in 7:0.
+    let base_div = clocks.clk_sys_div.read().bits();
+    let mut state = ThermalState::Normal;
+    loop {
+        let temperature_mdeg = read_temperature_mdeg();
+        let new_state = thresholds.classify(temperature_mdeg, state);
+        if new_state != state {
+            if let Policy::ScaleSysclk(factors) = policy {
+                set_sysclk_divider(base_div.saturating_mul(factors[new_state as usize].max(1)));
+            }
+            log::info!(
+                "thermal: {:?} -> {:?} at {} mdeg",
+                state,
+                new_state,
+                temperature_mdeg
+            );
+            state = new_state;
+        }
+        STATE.send(Reading {
+            temperature_mdeg,
+            state,
+        });
+        delay.delay_ms(interval_ms).await;
+    }
+}
@@ -0,0 +1,381 @@
+use core::{
+    future::{poll_fn, Future},
+    ops::{Add, Sub},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::stream::Stream;
+
+// Where the time comes from: the TIMER peripheral by default, or SysTick with the
+// `systick-time` feature, for firmware that needs the TIMER alarms for itself.
+#[cfg(feature = "systick-time")]
+mod systick;
+#[cfg(feature = "systick-time")]
+use systick as driver;
+#[cfg(feature = "systick-time")]
+pub(crate) use systick::start;
+#[cfg(not(feature = "systick-time"))]
+mod timer;
+#[cfg(not(feature = "systick-time"))]
+use timer as driver;
+
+// A point in time, counted in microseconds since the time driver started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    micros: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant {
+            micros: driver::now(),
+        }
+    }
+
+    pub const fn from_micros(micros: u64) -> Self {
+        Instant { micros }
+    }
+
+    pub const fn as_micros(&self) -> u64 {
+        self.micros
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - *self
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        Instant {
+            micros: self.micros.saturating_add(rhs.as_micros() as u64),
+        }
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        Duration::from_micros(self.micros.saturating_sub(rhs.micros))
+    }
+}
+
+// The smallest step `Instant::now` takes on the calling core: a microsecond, except on core 1
+// with `systick-time`, where it only counts whole SysTick ticks.
+pub fn resolution() -> Duration {
+    Duration::from_micros(driver::resolution_us() as u64)
+}
+
+// Measures elapsed time from when it was started.
+#[derive(Clone, Copy, Debug)]
+pub struct Stopwatch {
+    start: Instant,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Stopwatch {
+            start: Instant::now(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    // Return the elapsed time and start counting again from now.
+    pub fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.start;
+        self.start = now;
+        elapsed
+    }
+}
+
+// Wait until `deadline`. The driver sets up its alarm and interrupt on first use.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+// The future returned by `sleep` and `sleep_until`. It's a plain value, so it can be kept in a
+// struct and moved to a new deadline, which is what the stream adapters do.
+#[derive(Clone, Copy, Debug)]
+pub struct Sleep {
+    deadline: Instant,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let deadline = self.deadline;
+        if Instant::now() >= deadline {
+            return Poll::Ready(());
+        }
+        driver::wake_at(deadline.micros, cx.waker());
+        Poll::Pending
+    }
+}
+
+// A tick every `period`, starting now. Each deadline is a whole number of periods from the
+// first, rather than `period` from when the task got round to waiting again, so time spent
+// between ticks (and the executor's latency in waking the task) doesn't add up over a run.
+//
+//     let mut ticker = time::interval(Duration::from_millis(10));
+//     loop {
+//         ticker.tick().await;
+//         sample();
+//     }
+//
+// If the task falls more than a period behind, the ticks it missed are skipped rather than
+// delivered in a burst; the next one is the next that's still ahead on the same grid.
+pub fn interval(period: Duration) -> Interval {
+    interval_at(Instant::now(), period)
+}
+
+// Like `interval`, but the first tick is at `start`, to line several up on the same grid.
+pub fn interval_at(start: Instant, period: Duration) -> Interval {
+    Interval {
+        sleep: sleep_until(start),
+        period: period.max(Duration::from_micros(1)),
+        missed: 0,
+    }
+}
+
+pub struct Interval {
+    sleep: Sleep,
+    period: Duration,
+    missed: u32,
+}
+
+impl Interval {
+    // Wait for the next tick, and return when it was due.
+    pub fn tick(&mut self) -> impl Future<Output = Instant> + '_ {
+        poll_fn(move |cx| self.poll_tick(cx))
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let due = self.sleep.deadline();
+        let mut next = due + self.period;
+        let now = Instant::now();
+        if next <= now {
+            let period = self.period.as_micros() as u64;
+            let skipped = (now - next).as_micros() as u64 / period + 1;
+            self.missed = self.missed.saturating_add(skipped as u32);
+            next = next + Duration::from_micros(skipped * period);
+        }
+        self.sleep.reset(next);
+        Poll::Ready(due)
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    // Start the grid again with the next tick one period from now, e.g. after a pause.
+    pub fn reset(&mut self) {
+        self.sleep.reset(Instant::now() + self.period);
+    }
+
+    // How many ticks have been skipped because the task was too late for them.
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        self.poll_tick(cx).map(Some)
+    }
+}
+
+// A `timeout` or `with_deadline` ran out first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+// Run `future` for at most `duration`. If it hasn't finished by then, it's dropped.
+//
+//     let byte = time::timeout(Duration::from_millis(10), uart.read_byte()).await?;
+pub async fn timeout<T>(duration: Duration, future: impl Future<Output = T>) -> Result<T, Elapsed> {
+    with_deadline(Instant::now() + duration, future).await
+}
+
+// Run `future` until `deadline`, for a protocol step with one overall limit, however many
+// waits it's made of.
+pub async fn with_deadline<T>(
+    deadline: Instant,
+    future: impl Future<Output = T>,
+) -> Result<T, Elapsed> {
+    crate::future::with_timeout(sleep_until(deadline), future)
+        .await
+        .ok_or(Elapsed)
+}
+
+// A delay provider for drivers generic over `DelayNs`. It has no state, so make one wherever
+// it's needed.
+//
+// The async delay sleeps on the shared alarm; the blocking one spins on the timer, so only
+// use that for short waits.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Delay;
+
+// The timer counts whole microseconds; round up so a delay is never shorter than asked for.
+fn nanos_rounded_up(ns: u32) -> Duration {
+    Duration::from_micros(ns.div_ceil(1000) as u64)
+}
+
+impl embedded_hal::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        let deadline = Instant::now() + nanos_rounded_up(ns);
+        while Instant::now() < deadline {
+            cortex_m::asm::nop();
+        }
+    }
+}
+
+impl embedded_hal_async::delay::DelayNs for Delay {
+    async fn delay_ns(&mut self, ns: u32) {
+        sleep(nanos_rounded_up(ns)).await
+    }
+
+    async fn delay_us(&mut self, us: u32) {
+        sleep(Duration::from_micros(us as u64)).await
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        sleep(Duration::from_millis(ms as u64)).await
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Overrun {
+    // Which iteration overran, counting from 0.
+    pub iteration: u32,
+    pub deadline: Instant,
+    pub late_by: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeadlineStats {
+    pub iterations: u32,
+    pub overruns: u32,
+    // Longest from `arm` to `complete`.
+    pub worst_execution: Duration,
+    // Furthest an iteration went past its deadline.
+    pub worst_lateness: Duration,
+    // Furthest an iteration started from one period after the one before.
+    pub worst_jitter: Duration,
+}
+
+// Checks that each iteration of a periodic task (a control loop, say) finishes in time.
+//
+// Call `arm` at the start of each iteration and `complete` at the end. An iteration that
+// completes after `deadline` from its start, or doesn't complete before the next `arm`, is an
+// overrun: it's counted, and passed to the policy callback if there is one, which can log it,
+// switch to a cheaper algorithm, or reset the chip. Start times are compared against `period`
+// to measure jitter.
+pub struct DeadlineMonitor {
+    period: Duration,
+    deadline: Duration,
+    policy: Option<fn(&Overrun)>,
+    last_start: Option<Instant>,
+    // Set between `arm` and `complete`.
+    armed: Option<Instant>,
+    stats: DeadlineStats,
+}
+
+impl DeadlineMonitor {
+    pub const fn new(period: Duration, deadline: Duration) -> Self {
+        DeadlineMonitor {
+            period,
+            deadline,
+            policy: None,
+            last_start: None,
+            armed: None,
+            stats: DeadlineStats {
+                iterations: 0,
+                overruns: 0,
+                worst_execution: Duration::ZERO,
+                worst_lateness: Duration::ZERO,
+                worst_jitter: Duration::ZERO,
+            },
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: fn(&Overrun)) {
+        self.policy = Some(policy);
+    }
+
+    pub fn arm(&mut self) {
+        let now = Instant::now();
+        if let Some(start) = self.armed.take() {
+            // The last iteration never completed, which is an overrun however long it's been.
+            self.finish(start, now, false);
+        }
+        if let Some(last) = self.last_start {
+            let jitter = (now - last).abs_diff(self.period);
+            self.stats.worst_jitter = self.stats.worst_jitter.max(jitter);
+        }
+        self.last_start = Some(now);
+        self.armed = Some(now);
+    }
+
+    // Returns whether the iteration made its deadline.
+    pub fn complete(&mut self) -> bool {
+        match self.armed.take() {
+            Some(start) => self.finish(start, Instant::now(), true),
+            None => true,
+        }
+    }
+
+    // `completed` is false for an iteration cut off by the next `arm`. Its `late_by` is
+    // however far past the deadline that was, which may be nothing.
+    fn finish(&mut self, start: Instant, end: Instant, completed: bool) -> bool {
+        let iteration = self.stats.iterations;
+        self.stats.iterations = iteration.wrapping_add(1);
+        let execution = end - start;
+        self.stats.worst_execution = self.stats.worst_execution.max(execution);
+        if completed && execution <= self.deadline {
+            return true;
+        }
+        let late_by = execution.saturating_sub(self.deadline);
+        self.stats.overruns = self.stats.overruns.saturating_add(1);
+        self.stats.worst_lateness = self.stats.worst_lateness.max(late_by);
+        if let Some(policy) = self.policy {
+            policy(&Overrun {
+                iteration,
+                deadline: start + self.deadline,
+                late_by,
+            });
+        }
+        false
+    }
+
+    pub fn stats(&self) -> DeadlineStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = DeadlineStats::default();
+    }
+}
@@ -0,0 +1,127 @@
+// The SysTick time driver, for firmware that needs all four TIMER alarms for itself.
+//
+// Core 0's SysTick runs off the 1 µs reference tick, the same one the TIMER counts, so it
+// doesn't care what clk_sys is doing. It interrupts every TICK_US, and the handler counts the
+// ticks and wakes sleepers whose deadline has passed. On core 0, `now` adds on how far the
+// counter is into the current tick. Core 1 can't read core 0's SysTick, so there it only has
+// whole ticks: `Instant`s taken on core 1 are a millisecond apart at best, and
+// `time::resolution` says so.
+//
+// Sleeps end on the first tick after their deadline, so up to TICK_US late, where the TIMER
+// driver gets them to the microsecond. `start` has to be called on core 0 before any of this
+// is used.
+
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    task::Waker,
+};
+
+use cortex_m::peripheral::{SCB, SYST};
+use cortex_m_rt::exception;
+
+use crate::sync::{self, Mutex, WaitQueue};
+
+const TICK_US: u32 = 1000;
+
+// SYST_CSR. CLKSOURCE is left clear, for the external clock.
+const CSR_ENABLE: u32 = 1 << 0;
+const CSR_TICKINT: u32 = 1 << 1;
+
+// Ticks since `start`, as two halves. Only core 0 writes them, and it bumps SEQ before and
+// after, so readers can tell they saw a consistent pair.
+static TICKS_LO: AtomicU32 = AtomicU32::new(0);
+static TICKS_HI: AtomicU32 = AtomicU32::new(0);
+static SEQ: AtomicU32 = AtomicU32::new(0);
+
+// The earliest deadline anyone's waiting on. Everybody is woken when it passes, to check
+// their own.
+struct Alarm {
+    armed: Option<u64>,
+    waiters: WaitQueue,
+}
+
+static ALARM: Mutex<Alarm, 24> = Mutex::new(Alarm {
+    armed: None,
+    waiters: WaitQueue::new(),
+});
+
+// Takes core 0's SysTick for good. Only SYST's registers are touched, so
+// `sync::core_peripherals` still hands out the rest, but its `SYST` mustn't be used.
+pub fn start() {
+    // Safety: Nothing else uses core 0's SysTick with this driver in.
+    unsafe {
+        let syst = &*SYST::PTR;
+        syst.rvr.write(TICK_US - 1);
+        syst.cvr.write(0);
+        // The external clock, which is the 1 µs reference tick, with the interrupt.
+        syst.csr.write(CSR_TICKINT | CSR_ENABLE);
+    }
+}
+
+fn ticks() -> u64 {
+    loop {
+        let seq = SEQ.load(Ordering::Acquire);
+        let hi = TICKS_HI.load(Ordering::Relaxed);
+        let lo = TICKS_LO.load(Ordering::Relaxed);
+        if seq % 2 == 0 && SEQ.load(Ordering::Acquire) == seq {
+            return (hi as u64) << 32 | lo as u64;
+        }
+    }
+}
+
+// Only with interrupts masked on core 0, or from the SysTick handler.
+fn set_ticks(ticks: u64) {
+    let seq = SEQ.load(Ordering::Relaxed);
+    SEQ.store(seq.wrapping_add(1), Ordering::Release);
+    TICKS_HI.store((ticks >> 32) as u32, Ordering::Relaxed);
+    TICKS_LO.store(ticks as u32, Ordering::Relaxed);
+    SEQ.store(seq.wrapping_add(2), Ordering::Release);
+}
+
+pub fn resolution_us() -> u32 {
+    match sync::core() {
+        0 => 1,
+        _ => TICK_US,
+    }
+}
+
+pub fn now() -> u64 {
+    if sync::core() != 0 {
+        return ticks() * TICK_US as u64;
+    }
+    loop {
+        // A tick that's happened but hasn't been counted yet (interrupts are masked, say)
+        // shows up as a pending SysTick. If that or the count changed while we were looking,
+        // look again.
+        let counted = ticks();
+        let pending = SCB::is_pendst_pending();
+        let current = SYST::get_current();
+        if SCB::is_pendst_pending() != pending || ticks() != counted {
+            continue;
+        }
+        // The counter goes from TICK_US - 1 down to 0, and the tick is counted on reaching 0.
+        let into_tick = (TICK_US - current) % TICK_US;
+        return (counted + pending as u64) * TICK_US as u64 + into_tick as u64;
+    }
+}
+
+#[exception]
+fn SysTick() {
+    set_ticks(ticks() + 1);
+    let now = now();
+    ALARM.with(|alarm| {
+        if alarm.armed.is_some_and(|armed| armed <= now) {
+            alarm.armed = None;
+            alarm.waiters.wake_all();
+        }
+    })
+}
+
+// Wake `waker` on the first tick at or after `deadline`; maybe sooner, if someone else's
+// deadline comes first.
+pub fn wake_at(deadline: u64, waker: &Waker) {
+    ALARM.with(|alarm| {
+        alarm.waiters.register(waker);
+        alarm.armed = Some(alarm.armed.map_or(deadline, |armed| armed.min(deadline)));
+    })
+}
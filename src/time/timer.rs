@@ -0,0 +1,85 @@
+// The default time driver: the TIMER peripheral's 64-bit microsecond counter, and ALARM0.
+
+use core::task::Waker;
+
+use cortex_m::peripheral::NVIC;
+use rp2040_pac::Interrupt;
+
+use crate::{
+    irq, reactor,
+    sync::{Mutex, WaitQueue},
+};
+
+pub fn resolution_us() -> u32 {
+    1
+}
+
+pub fn now() -> u64 {
+    let timer = unsafe { &*rp2040_pac::TIMER::ptr() };
+    // The raw registers don't latch like TIMEHR/TIMELR do, but they're safe to read from
+    // both cores at once. The high word can tick over between the two reads, so retry
+    // until we get a consistent pair.
+    loop {
+        let hi = timer.timerawh.read().bits();
+        let lo = timer.timerawl.read().bits();
+        if timer.timerawh.read().bits() == hi {
+            return (hi as u64) << 32 | lo as u64;
+        }
+    }
+}
+
+// Sleeping tasks share ALARM0: it's armed for the earliest deadline anyone's waiting on, and
+// when it fires everybody is woken to check their own deadline and re-arm it if needed.
+struct Alarm {
+    installed: bool,
+    // The deadline ALARM0 is armed for, if it is.
+    armed: Option<u64>,
+    waiters: WaitQueue,
+}
+
+static ALARM: Mutex<Alarm, 24> = Mutex::new(Alarm {
+    installed: false,
+    armed: None,
+    waiters: WaitQueue::new(),
+});
+
+extern "C" fn alarm_handler() {
+    let timer = unsafe { &*rp2040_pac::TIMER::ptr() };
+    timer.intr.write(|w| unsafe { w.bits(1) });
+    ALARM.with(|alarm| {
+        alarm.armed = None;
+        alarm.waiters.wake_all();
+    });
+}
+
+// Wake `waker` at `deadline` or a little after; maybe sooner, if someone else's deadline
+// comes first.
+pub fn wake_at(deadline: u64, waker: &Waker) {
+    let timer = unsafe { &*rp2040_pac::TIMER::ptr() };
+    ALARM.with(|alarm| {
+        if !alarm.installed {
+            alarm.installed = true;
+            irq::reserve(Interrupt::TIMER_IRQ_0);
+            reactor::set_raw_handler(Interrupt::TIMER_IRQ_0, alarm_handler);
+            timer.inte.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+            // Safety: The handler is installed, and only touches ALARM.
+            unsafe { NVIC::unmask(Interrupt::TIMER_IRQ_0) };
+        }
+        alarm.waiters.register(waker);
+        if alarm.armed.is_some_and(|armed| armed <= deadline) {
+            return;
+        }
+        // The alarm only compares the low 32 bits, so one more than ~35 minutes out is armed
+        // for halfway there instead, and re-armed when it fires.
+        let target = deadline.min(now() + (u32::MAX / 2) as u64);
+        alarm.armed = Some(target);
+        timer.alarm0.write(|w| unsafe { w.bits(target as u32) });
+        // If the target went by while we were setting it, the alarm won't fire until the
+        // counter wraps around. Disarm it and go again instead.
+        if now() >= target {
+            timer.armed.write(|w| unsafe { w.bits(1) });
+            alarm.armed = None;
+            waker.wake_by_ref();
+        }
+    })
+}
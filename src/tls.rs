@@ -0,0 +1,915 @@
+// TLS 1.3 client (RFC 8446) over any async byte stream: a TCP connection from `esp_at`, or
+// anything else that implements embedded-io-async's `Read` and `Write`. The connection
+// implements them too, so an MQTT or HTTP client written against those traits runs over TLS
+// unchanged.
+//
+//     let tcp = esp.connect(broker).await?;
+//     let config = Config { server_name: "broker.example.com", psk: None };
+//     let now = Now::Unix(rtc.unix_seconds());
+//     let backend = SecureElement::new(&mut atecc, CA_KEY, Some((DEVICE_CERT, 0)), now);
+//     let mut tls = TlsConnection::connect(tcp, backend, &config, &mut rx, &mut tx).await?;
+//     tls.write_all(&packet).await?;
+//
+// There's one cipher suite, TLS_AES_128_GCM_SHA256, with X25519 key exchange, which every TLS
+// 1.3 server has to support. The server is authenticated one of two ways:
+//
+// - A pre-shared key (`Config::psk`), agreed with the server beforehand, with an X25519
+//   exchange on top so old sessions stay secret if the key leaks. No certificates are
+//   involved, and `PskOnly` is all the backend there needs to be.
+// - Certificates, checked by the `Backend`. `SecureElement` checks an ECDSA P-256 chain
+//   against one trusted CA key and authenticates the client with a key kept in an ATECC608,
+//   which is what AWS IoT and the other cloud brokers want.
+//
+// The receive buffer has to hold a whole record, which can be 16 KB and change, plus any
+// handshake message the server splits across records: 18 KB covers the chains cloud brokers
+// send. The transmit buffer only limits how much goes in one record; the ClientHello needs
+// about 300 bytes, and the client certificate has to fit.
+//
+// Not supported: HelloRetryRequest (from a server that won't do X25519), resumption, early
+// data, and updating our own keys. A KeyUpdate from the server is followed.
+
+use core::ops::Range;
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, ReadExactError, Write};
+
+use crate::crypto::{
+    self,
+    aes::TAG_LEN,
+    sha256::{self, DIGEST_LEN},
+    x25519, Sha256,
+};
+
+mod schedule;
+mod secure_element;
+pub mod x509;
+
+pub use secure_element::SecureElement;
+pub use x509::Now;
+
+use schedule::{Keys, Secret, ZEROS};
+
+pub const ECDSA_SECP256R1_SHA256: u16 = 0x0403;
+
+const HEADER: usize = 5;
+const MAX_PLAINTEXT: usize = 16384;
+const MAX_CIPHERTEXT: usize = MAX_PLAINTEXT + 256;
+
+// Record content types.
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const ALERT: u8 = 21;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+// Handshake message types.
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const NEW_SESSION_TICKET: u8 = 4;
+const ENCRYPTED_EXTENSIONS: u8 = 8;
+const CERTIFICATE: u8 = 11;
+const CERTIFICATE_REQUEST: u8 = 13;
+const CERTIFICATE_VERIFY: u8 = 15;
+const FINISHED: u8 = 20;
+const KEY_UPDATE: u8 = 24;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_PRE_SHARED_KEY: u16 = 41;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 45;
+const EXT_KEY_SHARE: u16 = 51;
+
+const TLS12: u16 = 0x0303;
+const TLS13: u16 = 0x0304;
+const TLS_AES_128_GCM_SHA256: u16 = 0x1301;
+const X25519: u16 = 0x001d;
+const PSK_DHE_KE: u8 = 1;
+const CLOSE_NOTIFY: u8 = 0;
+
+// The ServerHello random that means it's really a HelloRetryRequest.
+const HRR_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+const SERVER_SIGNATURE_CONTEXT: &[u8; 33] = b"TLS 1.3, server CertificateVerify";
+const CLIENT_SIGNATURE_CONTEXT: &[u8; 33] = b"TLS 1.3, client CertificateVerify";
+
+pub struct Config<'a> {
+    // Sent to the server so it knows which certificate to use, and what that certificate has
+    // to be for.
+    pub server_name: &'a str,
+    // An external pre-shared key: its identity and the key itself.
+    pub psk: Option<(&'a [u8], &'a [u8])>,
+}
+
+// The backend couldn't do what was asked, or didn't like what it was given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackendError;
+
+// Where the connection gets its randomness, and how it checks and proves identities. The
+// certificate methods refuse by default, for backends that only do PSK.
+#[allow(async_fn_in_trait)]
+pub trait Backend {
+    async fn random(&mut self, out: &mut [u8; 32]) -> Result<(), BackendError>;
+
+    // Check the server's certificate chain, leaf first, and that it's for `server_name`.
+    async fn verify_certificates(
+        &mut self,
+        _server_name: &str,
+        _chain: Certificates<'_>,
+    ) -> Result<(), BackendError> {
+        Err(BackendError)
+    }
+
+    // Check the server's `signature` over `message` with the key from the leaf certificate.
+    // `scheme` is one of `signature_schemes`.
+    async fn verify_signature(
+        &mut self,
+        _scheme: u16,
+        _message: &[u8],
+        _signature: &[u8],
+    ) -> Result<(), BackendError> {
+        Err(BackendError)
+    }
+
+    // The TLS SignatureSchemes `verify_signature` can check.
+    fn signature_schemes(&self) -> &[u16] {
+        &[ECDSA_SECP256R1_SHA256]
+    }
+
+    // The certificate to send if the server asks for one, in DER.
+    fn client_certificate(&self) -> Option<&[u8]> {
+        None
+    }
+
+    // Sign a SHA-256 `digest` with the client certificate's P-256 key. Returns R then S.
+    async fn sign(&mut self, _digest: &[u8; 32]) -> Result<[u8; 64], BackendError> {
+        Err(BackendError)
+    }
+}
+
+// A backend for connections authenticated with a pre-shared key alone: randomness from
+// `crypto::random`, and no certificates either way.
+pub struct PskOnly;
+
+impl Backend for PskOnly {
+    async fn random(&mut self, out: &mut [u8; 32]) -> Result<(), BackendError> {
+        crypto::random(out);
+        Ok(())
+    }
+}
+
+// The certificates the server sent, leaf first, each in DER.
+#[derive(Clone)]
+pub struct Certificates<'a> {
+    list: &'a [u8],
+}
+
+impl<'a> Iterator for Certificates<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let mut reader = Reader(self.list);
+        let cert = reader.vec24().ok()?;
+        reader.vec16().ok()?;
+        self.list = reader.0;
+        Some(cert)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    // The connection closed in the middle of the handshake or a record.
+    Eof,
+    // The server sent something that doesn't parse, or arrived when it shouldn't have.
+    Decode,
+    // The server wants something this client doesn't do.
+    Unsupported,
+    // The server sent a fatal alert, with this description.
+    Alert(u8),
+    // A record failed authentication.
+    BadRecord,
+    // The server's Finished was wrong, or it never proved who it is.
+    HandshakeFailed,
+    // The backend refused the server's certificates or signature, or couldn't sign.
+    Backend,
+    // A record or handshake message didn't fit its buffer.
+    BufferTooSmall,
+}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(e) => e.kind(),
+            Error::Eof | Error::Alert(_) => ErrorKind::ConnectionAborted,
+            Error::Decode | Error::BadRecord => ErrorKind::InvalidData,
+            Error::Unsupported => ErrorKind::Unsupported,
+            Error::HandshakeFailed | Error::Backend => ErrorKind::PermissionDenied,
+            Error::BufferTooSmall => ErrorKind::OutOfMemory,
+        }
+    }
+}
+
+// What parsing a message can go wrong with.
+enum Malformed {
+    Decode,
+    Unsupported,
+}
+
+impl<E> From<Malformed> for Error<E> {
+    fn from(malformed: Malformed) -> Self {
+        match malformed {
+            Malformed::Decode => Error::Decode,
+            Malformed::Unsupported => Error::Unsupported,
+        }
+    }
+}
+
+pub struct TlsConnection<'b, T, B> {
+    io: T,
+    backend: B,
+    rx: &'b mut [u8],
+    tx: &'b mut [u8],
+    // Bytes of handshake messages waiting at the start of `rx`.
+    handshake: usize,
+    // Decrypted application data in `rx` that hasn't been read yet.
+    plaintext: Range<usize>,
+    read_keys: Option<Keys>,
+    write_keys: Option<Keys>,
+    // What `read_keys` came from, for KeyUpdate.
+    read_secret: Secret,
+    // The server has sent close_notify.
+    closed: bool,
+}
+
+impl<'b, T: Read + Write, B: Backend> TlsConnection<'b, T, B> {
+    // Handshake over `io`, which should be freshly connected.
+    pub async fn connect(
+        io: T,
+        backend: B,
+        config: &Config<'_>,
+        rx: &'b mut [u8],
+        tx: &'b mut [u8],
+    ) -> Result<Self, Error<T::Error>> {
+        let mut tls = TlsConnection {
+            io,
+            backend,
+            rx,
+            tx,
+            handshake: 0,
+            plaintext: 0..0,
+            read_keys: None,
+            write_keys: None,
+            read_secret: ZEROS,
+            closed: false,
+        };
+        tls.handshake(config).await?;
+        Ok(tls)
+    }
+
+    // Tell the server we're done, and hand back the stream.
+    pub async fn close(mut self) -> Result<T, Error<T::Error>> {
+        self.tx[HEADER..HEADER + 2].copy_from_slice(&[2, CLOSE_NOTIFY]);
+        self.write_record(ALERT, 2).await?;
+        self.io.flush().await.map_err(Error::Io)?;
+        Ok(self.io)
+    }
+
+    async fn handshake(&mut self, config: &Config<'_>) -> Result<(), Error<T::Error>> {
+        let mut secret = [0; 32];
+        let mut random = [0; 32];
+        self.backend
+            .random(&mut secret)
+            .await
+            .map_err(|_| Error::Backend)?;
+        self.backend
+            .random(&mut random)
+            .await
+            .map_err(|_| Error::Backend)?;
+        let share = x25519::public_key_chunked(&secret).await;
+        let psk_early = extract_early(config.psk.map(|(_, key)| key));
+
+        let mut transcript = Sha256::new();
+        let len = self.client_hello(config, &random, &share, &psk_early)?;
+        transcript.update(&self.tx[HEADER..len]);
+        self.io
+            .write_all(&self.tx[..len])
+            .await
+            .map_err(Error::Io)?;
+        self.io.flush().await.map_err(Error::Io)?;
+
+        let (kind, len) = self.handshake_message().await?;
+        if kind != SERVER_HELLO {
+            return Err(Error::Decode);
+        }
+        let hello = parse_server_hello(&self.rx[4..len])?;
+        transcript.update(&self.rx[..len]);
+        self.consume_handshake(len);
+        if hello.psk && config.psk.is_none() {
+            return Err(Error::Decode);
+        }
+        let shared = x25519::diffie_hellman_chunked(&secret, &hello.key_share).await;
+        if shared == ZEROS {
+            return Err(Error::Decode);
+        }
+        let early = if hello.psk {
+            psk_early
+        } else {
+            extract_early(None)
+        };
+        let handshake_secret = schedule::extract(&schedule::next_salt(&early), &shared);
+        let hash = transcript.clone().finalize();
+        let client_secret = schedule::derive(&handshake_secret, "c hs traffic", &hash);
+        let server_secret = schedule::derive(&handshake_secret, "s hs traffic", &hash);
+        self.read_keys = Some(Keys::new(&server_secret));
+        self.write_keys = Some(Keys::new(&client_secret));
+
+        // The server's flight: EncryptedExtensions, then either Finished straight away (PSK)
+        // or CertificateRequest, Certificate and CertificateVerify first.
+        let mut certificate_request: Option<([u8; 255], usize)> = None;
+        let mut certificate = false;
+        let mut authenticated = hello.psk;
+        loop {
+            let (kind, len) = self.handshake_message().await?;
+            let body = &self.rx[4..len];
+            match kind {
+                ENCRYPTED_EXTENSIONS => {}
+                CERTIFICATE_REQUEST if !hello.psk && !certificate => {
+                    let context = Reader(body).vec8()?;
+                    let mut copy = [0; 255];
+                    copy[..context.len()].copy_from_slice(context);
+                    certificate_request = Some((copy, context.len()));
+                }
+                CERTIFICATE if !hello.psk && !certificate => {
+                    let chain = parse_certificate(body)?;
+                    self.backend
+                        .verify_certificates(config.server_name, chain)
+                        .await
+                        .map_err(|_| Error::Backend)?;
+                    certificate = true;
+                }
+                CERTIFICATE_VERIFY if certificate && !authenticated => {
+                    let mut reader = Reader(body);
+                    let scheme = reader.u16()?;
+                    let signature = reader.vec16()?;
+                    if !self.backend.signature_schemes().contains(&scheme) {
+                        return Err(Error::Unsupported);
+                    }
+                    let content =
+                        signed_content(SERVER_SIGNATURE_CONTEXT, &transcript.clone().finalize());
+                    self.backend
+                        .verify_signature(scheme, &content, signature)
+                        .await
+                        .map_err(|_| Error::Backend)?;
+                    authenticated = true;
+                }
+                FINISHED => {
+                    let expected =
+                        schedule::finished(&server_secret, &transcript.clone().finalize());
+                    if !authenticated || !crypto::ct_eq(body, &expected) {
+                        return Err(Error::HandshakeFailed);
+                    }
+                    transcript.update(&self.rx[..len]);
+                    self.consume_handshake(len);
+                    break;
+                }
+                _ => return Err(Error::Decode),
+            }
+            transcript.update(&self.rx[..len]);
+            self.consume_handshake(len);
+        }
+        // Nothing from the server can straddle the change of keys.
+        if self.handshake != 0 {
+            return Err(Error::Decode);
+        }
+        let hash = transcript.clone().finalize();
+        let master = schedule::extract(&schedule::next_salt(&handshake_secret), &ZEROS);
+        let client_app = schedule::derive(&master, "c ap traffic", &hash);
+        self.read_secret = schedule::derive(&master, "s ap traffic", &hash);
+        self.read_keys = Some(Keys::new(&self.read_secret));
+
+        // Ours: Certificate and CertificateVerify if the server asked, then Finished.
+        if let Some((context, context_len)) = certificate_request {
+            let len = self.client_certificate(&context[..context_len])?;
+            transcript.update(&self.tx[HEADER..HEADER + len]);
+            self.write_record(HANDSHAKE, len).await?;
+            if self.backend.client_certificate().is_some() {
+                let content =
+                    signed_content(CLIENT_SIGNATURE_CONTEXT, &transcript.clone().finalize());
+                let signature = self
+                    .backend
+                    .sign(&sha256::digest(&content))
+                    .await
+                    .map_err(|_| Error::Backend)?;
+                let mut der = [0; 72];
+                let der_len = x509::encode_ecdsa_signature(&signature, &mut der);
+                let len = 4 + 2 + 2 + der_len;
+                let message = self
+                    .tx
+                    .get_mut(HEADER..HEADER + len)
+                    .ok_or(Error::BufferTooSmall)?;
+                message[..4].copy_from_slice(&message_header(CERTIFICATE_VERIFY, len));
+                message[4..6].copy_from_slice(&ECDSA_SECP256R1_SHA256.to_be_bytes());
+                message[6..8].copy_from_slice(&(der_len as u16).to_be_bytes());
+                message[8..].copy_from_slice(&der[..der_len]);
+                transcript.update(message);
+                self.write_record(HANDSHAKE, len).await?;
+            }
+        }
+        let verify_data = schedule::finished(&client_secret, &transcript.finalize());
+        self.tx[HEADER..HEADER + 4].copy_from_slice(&message_header(FINISHED, 4 + DIGEST_LEN));
+        self.tx[HEADER + 4..HEADER + 4 + DIGEST_LEN].copy_from_slice(&verify_data);
+        self.write_record(HANDSHAKE, 4 + DIGEST_LEN).await?;
+        self.io.flush().await.map_err(Error::Io)?;
+        self.write_keys = Some(Keys::new(&client_app));
+        Ok(())
+    }
+
+    // Build the ClientHello record in `tx`, and return its length.
+    fn client_hello(
+        &mut self,
+        config: &Config<'_>,
+        random: &[u8; 32],
+        share: &[u8; 32],
+        psk_early: &Secret,
+    ) -> Result<usize, Error<T::Error>> {
+        let schemes = self.backend.signature_schemes();
+        let psk_len = config.psk.map_or(0, |(identity, _)| identity.len());
+        if self.tx.len() < 256 + config.server_name.len() + 2 * schemes.len() + psk_len {
+            return Err(Error::BufferTooSmall);
+        }
+        let mut w = Writer {
+            buf: self.tx,
+            len: 0,
+        };
+        w.u8(HANDSHAKE);
+        w.u16(0x0301);
+        let record = w.start(2);
+        w.u8(CLIENT_HELLO);
+        let message = w.start(3);
+        w.u16(TLS12);
+        w.bytes(random);
+        // No session id, and one cipher suite with no compression.
+        w.u8(0);
+        w.u16(2);
+        w.u16(TLS_AES_128_GCM_SHA256);
+        w.u8(1);
+        w.u8(0);
+        let extensions = w.start(2);
+
+        w.u16(EXT_SERVER_NAME);
+        let extension = w.start(2);
+        let list = w.start(2);
+        w.u8(0);
+        let name = w.start(2);
+        w.bytes(config.server_name.as_bytes());
+        w.end(name, 2);
+        w.end(list, 2);
+        w.end(extension, 2);
+
+        w.u16(EXT_SUPPORTED_VERSIONS);
+        w.u16(3);
+        w.u8(2);
+        w.u16(TLS13);
+
+        w.u16(EXT_SUPPORTED_GROUPS);
+        w.u16(4);
+        w.u16(2);
+        w.u16(X25519);
+
+        w.u16(EXT_SIGNATURE_ALGORITHMS);
+        w.u16(2 + 2 * schemes.len() as u16);
+        w.u16(2 * schemes.len() as u16);
+        for &scheme in schemes {
+            w.u16(scheme);
+        }
+
+        w.u16(EXT_KEY_SHARE);
+        w.u16(2 + 4 + 32);
+        w.u16(4 + 32);
+        w.u16(X25519);
+        w.u16(32);
+        w.bytes(share);
+
+        let mut binder_at = None;
+        if let Some((identity, _)) = config.psk {
+            w.u16(EXT_PSK_KEY_EXCHANGE_MODES);
+            w.u16(2);
+            w.u8(1);
+            w.u8(PSK_DHE_KE);
+            // This has to be the last extension.
+            w.u16(EXT_PRE_SHARED_KEY);
+            let extension = w.start(2);
+            let identities = w.start(2);
+            let id = w.start(2);
+            w.bytes(identity);
+            w.end(id, 2);
+            // An external PSK has no ticket age.
+            w.u32(0);
+            w.end(identities, 2);
+            w.u16(1 + DIGEST_LEN as u16);
+            w.u8(DIGEST_LEN as u8);
+            binder_at = Some(w.len);
+            w.bytes(&ZEROS);
+            w.end(extension, 2);
+        }
+        w.end(extensions, 2);
+        w.end(message, 3);
+        w.end(record, 2);
+        let len = w.len;
+
+        // The binder covers the whole ClientHello up to the binders list, whose length is
+        // already filled in above.
+        if let Some(at) = binder_at {
+            let truncated = sha256::digest(&self.tx[HEADER..at - 3]);
+            let binder_key = schedule::derive(psk_early, "ext binder", &sha256::digest(&[]));
+            self.tx[at..at + DIGEST_LEN]
+                .copy_from_slice(&schedule::finished(&binder_key, &truncated));
+        }
+        Ok(len)
+    }
+
+    // Build a Certificate message in `tx` after the record header, and return its length.
+    fn client_certificate(&mut self, context: &[u8]) -> Result<usize, Error<T::Error>> {
+        let cert = self.backend.client_certificate().unwrap_or(&[]);
+        let len = 4
+            + 1
+            + context.len()
+            + 3
+            + if cert.is_empty() {
+                0
+            } else {
+                3 + cert.len() + 2
+            };
+        if self.tx.len() < HEADER + len + 1 + TAG_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+        let mut w = Writer {
+            buf: &mut self.tx[HEADER..],
+            len: 0,
+        };
+        w.u8(CERTIFICATE);
+        let message = w.start(3);
+        w.u8(context.len() as u8);
+        w.bytes(context);
+        let list = w.start(3);
+        if !cert.is_empty() {
+            let entry = w.start(3);
+            w.bytes(cert);
+            w.end(entry, 3);
+            // No extensions.
+            w.u16(0);
+        }
+        w.end(list, 3);
+        w.end(message, 3);
+        Ok(w.len)
+    }
+
+    // Read the next record into `rx`, after any handshake bytes waiting there, and decrypt it
+    // if there are keys yet. Returns its content type and where its content is.
+    async fn read_record(&mut self) -> Result<(u8, Range<usize>), Error<T::Error>> {
+        loop {
+            let start = self.handshake;
+            let body = start + HEADER;
+            if body > self.rx.len() {
+                return Err(Error::BufferTooSmall);
+            }
+            read_exact(&mut self.io, &mut self.rx[start..body]).await?;
+            let header: [u8; HEADER] = self.rx[start..body].try_into().unwrap();
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if len > MAX_CIPHERTEXT {
+                return Err(Error::Decode);
+            }
+            let end = body + len;
+            if end > self.rx.len() {
+                return Err(Error::BufferTooSmall);
+            }
+            read_exact(&mut self.io, &mut self.rx[body..end]).await?;
+            match (header[0], &mut self.read_keys) {
+                // Only there for middleboxes' benefit; it means nothing in TLS 1.3.
+                (CHANGE_CIPHER_SPEC, _) => continue,
+                (APPLICATION_DATA, Some(keys)) => {
+                    if len < TAG_LEN + 1 {
+                        return Err(Error::BadRecord);
+                    }
+                    let (data, tag) = self.rx[body..end].split_at_mut(len - TAG_LEN);
+                    let tag: [u8; TAG_LEN] = (&*tag).try_into().unwrap();
+                    let nonce = keys.next_nonce();
+                    keys.gcm()
+                        .open_chunked(&nonce, &header, data, &tag)
+                        .await
+                        .map_err(|_| Error::BadRecord)?;
+                    // The content, the real content type, then zero padding.
+                    let content_len = data.iter().rposition(|&b| b != 0).ok_or(Error::Decode)?;
+                    return Ok((data[content_len], body..body + content_len));
+                }
+                (content_type @ (HANDSHAKE | ALERT), None) => return Ok((content_type, body..end)),
+                _ => return Err(Error::Decode),
+            }
+        }
+    }
+
+    // Wait until a whole handshake message is at the start of `rx`. Returns its type and its
+    // length, header included; `consume_handshake` it when done with it.
+    async fn handshake_message(&mut self) -> Result<(u8, usize), Error<T::Error>> {
+        loop {
+            if let Some(len) = self.waiting_message() {
+                return Ok((self.rx[0], len));
+            }
+            match self.read_record().await? {
+                (HANDSHAKE, range) => self.append_handshake(range),
+                (ALERT, range) => {
+                    return Err(match alert(&self.rx[range])? {
+                        CLOSE_NOTIFY => Error::Eof,
+                        description => Error::Alert(description),
+                    })
+                }
+                _ => return Err(Error::Decode),
+            }
+        }
+    }
+
+    // The length of the handshake message at the start of `rx`, if all of it is there.
+    fn waiting_message(&self) -> Option<usize> {
+        if self.handshake < 4 {
+            return None;
+        }
+        let len = 4 + u24(&self.rx[1..4]);
+        (self.handshake >= len).then_some(len)
+    }
+
+    fn append_handshake(&mut self, range: Range<usize>) {
+        let len = range.len();
+        self.rx.copy_within(range, self.handshake);
+        self.handshake += len;
+    }
+
+    fn consume_handshake(&mut self, len: usize) {
+        self.rx.copy_within(len..self.handshake, 0);
+        self.handshake -= len;
+    }
+
+    // Deal with handshake messages that come after the handshake.
+    fn post_handshake(&mut self) -> Result<(), Error<T::Error>> {
+        while let Some(len) = self.waiting_message() {
+            match self.rx[0] {
+                // For resumption, which isn't supported.
+                NEW_SESSION_TICKET => {}
+                // If the server asks for ours to be updated too, it isn't: that only matters
+                // to a server that limits how much it accepts under one key.
+                KEY_UPDATE => {
+                    self.read_secret = schedule::update(&self.read_secret);
+                    self.read_keys = Some(Keys::new(&self.read_secret));
+                }
+                _ => return Err(Error::Decode),
+            }
+            self.consume_handshake(len);
+        }
+        Ok(())
+    }
+
+    // Encrypt and send the `len` bytes after the record header in `tx` as one record.
+    async fn write_record(&mut self, content_type: u8, len: usize) -> Result<(), Error<T::Error>> {
+        let end = HEADER + len + 1 + TAG_LEN;
+        if end > self.tx.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        let Some(keys) = &mut self.write_keys else {
+            return Err(Error::Decode);
+        };
+        self.tx[HEADER + len] = content_type;
+        let outer_len = ((len + 1 + TAG_LEN) as u16).to_be_bytes();
+        let header = [APPLICATION_DATA, 3, 3, outer_len[0], outer_len[1]];
+        self.tx[..HEADER].copy_from_slice(&header);
+        let nonce = keys.next_nonce();
+        let (data, tag) = self.tx[HEADER..end].split_at_mut(len + 1);
+        tag.copy_from_slice(&keys.gcm().seal_chunked(&nonce, &header, data).await);
+        self.io.write_all(&self.tx[..end]).await.map_err(Error::Io)
+    }
+}
+
+impl<T: ErrorType, B> ErrorType for TlsConnection<'_, T, B> {
+    type Error = Error<T::Error>;
+}
+
+impl<T: Read + Write, B: Backend> Read for TlsConnection<'_, T, B> {
+    // Returns 0 once the server has closed the connection.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        while self.plaintext.is_empty() {
+            if self.closed || buf.is_empty() {
+                return Ok(0);
+            }
+            match self.read_record().await? {
+                (APPLICATION_DATA, range) => self.plaintext = range,
+                (HANDSHAKE, range) => {
+                    self.append_handshake(range);
+                    self.post_handshake()?;
+                }
+                (ALERT, range) => match alert(&self.rx[range])? {
+                    CLOSE_NOTIFY => self.closed = true,
+                    description => return Err(Error::Alert(description)),
+                },
+                _ => return Err(Error::Decode),
+            }
+        }
+        let n = buf.len().min(self.plaintext.len());
+        buf[..n].copy_from_slice(&self.rx[self.plaintext.start..self.plaintext.start + n]);
+        self.plaintext.start += n;
+        Ok(n)
+    }
+}
+
+impl<T: Read + Write, B: Backend> Write for TlsConnection<'_, T, B> {
+    // Sends up to a record's worth, as much as fits in the transmit buffer.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let room = self
+            .tx
+            .len()
+            .saturating_sub(HEADER + 1 + TAG_LEN)
+            .min(MAX_PLAINTEXT);
+        if room == 0 {
+            return Err(Error::BufferTooSmall);
+        }
+        let n = buf.len().min(room);
+        self.tx[HEADER..HEADER + n].copy_from_slice(&buf[..n]);
+        self.write_record(APPLICATION_DATA, n).await?;
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.io.flush().await.map_err(Error::Io)
+    }
+}
+
+struct ServerHello {
+    key_share: [u8; 32],
+    // The server took our PSK.
+    psk: bool,
+}
+
+fn parse_server_hello(body: &[u8]) -> Result<ServerHello, Malformed> {
+    let mut reader = Reader(body);
+    reader.u16()?;
+    if reader.bytes(32)? == HRR_RANDOM {
+        return Err(Malformed::Unsupported);
+    }
+    reader.vec8()?;
+    if reader.u16()? != TLS_AES_128_GCM_SHA256 {
+        return Err(Malformed::Unsupported);
+    }
+    reader.u8()?;
+    let mut extensions = Reader(reader.vec16()?);
+    let mut version = false;
+    let mut key_share = None;
+    let mut psk = false;
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = Reader(extensions.vec16()?);
+        match kind {
+            EXT_SUPPORTED_VERSIONS => version = data.u16()? == TLS13,
+            EXT_KEY_SHARE => {
+                if data.u16()? != X25519 {
+                    return Err(Malformed::Unsupported);
+                }
+                key_share = Some(data.vec16()?.try_into().map_err(|_| Malformed::Decode)?);
+            }
+            // We only offered the one identity.
+            EXT_PRE_SHARED_KEY => psk = data.u16()? == 0,
+            _ => {}
+        }
+    }
+    match (version, key_share) {
+        (true, Some(key_share)) => Ok(ServerHello { key_share, psk }),
+        _ => Err(Malformed::Unsupported),
+    }
+}
+
+// Check a Certificate message's list is well formed, so `Certificates` can walk it.
+fn parse_certificate(body: &[u8]) -> Result<Certificates<'_>, Malformed> {
+    let mut reader = Reader(body);
+    reader.vec8()?;
+    let list = reader.vec24()?;
+    let mut entries = Reader(list);
+    while !entries.0.is_empty() {
+        if entries.vec24()?.is_empty() {
+            return Err(Malformed::Decode);
+        }
+        entries.vec16()?;
+    }
+    Ok(Certificates { list })
+}
+
+// An alert's description.
+fn alert(body: &[u8]) -> Result<u8, Malformed> {
+    match body {
+        [_level, description] => Ok(*description),
+        _ => Err(Malformed::Decode),
+    }
+}
+
+// The early secret, from a PSK or from nothing.
+fn extract_early(psk: Option<&[u8]>) -> Secret {
+    schedule::extract(&ZEROS, psk.unwrap_or(&ZEROS))
+}
+
+// What a CertificateVerify signs.
+fn signed_content(context: &[u8; 33], transcript: &[u8; DIGEST_LEN]) -> [u8; 64 + 33 + 1 + 32] {
+    let mut content = [0x20; 64 + 33 + 1 + 32];
+    content[64..97].copy_from_slice(context);
+    content[97] = 0;
+    content[98..].copy_from_slice(transcript);
+    content
+}
+
+fn message_header(kind: u8, len: usize) -> [u8; 4] {
+    let body = (len - 4) as u32;
+    [kind, (body >> 16) as u8, (body >> 8) as u8, body as u8]
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+async fn read_exact<T: Read>(io: &mut T, buf: &mut [u8]) -> Result<(), Error<T::Error>> {
+    io.read_exact(buf).await.map_err(|e| match e {
+        ReadExactError::UnexpectedEof => Error::Eof,
+        ReadExactError::Other(e) => Error::Io(e),
+    })
+}
+
+// Reads the big-endian integers and length-prefixed vectors TLS messages are made of.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Malformed> {
+        if self.0.len() < n {
+            return Err(Malformed::Decode);
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Malformed> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Malformed> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn vec8(&mut self) -> Result<&'a [u8], Malformed> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    fn vec16(&mut self) -> Result<&'a [u8], Malformed> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    fn vec24(&mut self) -> Result<&'a [u8], Malformed> {
+        let len = u24(self.bytes(3)?);
+        self.bytes(len)
+    }
+}
+
+// Writes them. The caller checks there's room first.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    // Leave room for a `width` byte length, to be filled in by `end`.
+    fn start(&mut self, width: usize) -> usize {
+        self.len += width;
+        self.len
+    }
+
+    fn end(&mut self, start: usize, width: usize) {
+        let len = (self.len - start).to_be_bytes();
+        self.buf[start - width..start].copy_from_slice(&len[len.len() - width..]);
+    }
+}
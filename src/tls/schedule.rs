@@ -0,0 +1,96 @@
+// The TLS 1.3 key schedule (RFC 8446 section 7) for SHA-256 suites: HKDF, and the traffic
+// keys each secret expands into.
+
+use crate::crypto::{
+    aes::{Aes, Gcm, NONCE_LEN},
+    sha256::{self, DIGEST_LEN},
+    HmacSha256,
+};
+
+pub(super) type Secret = [u8; DIGEST_LEN];
+
+pub(super) const ZEROS: Secret = [0; DIGEST_LEN];
+
+pub(super) fn extract(salt: &[u8], ikm: &[u8]) -> Secret {
+    let mut hmac = HmacSha256::new(salt);
+    hmac.update(ikm);
+    hmac.finalize()
+}
+
+// HKDF-Expand-Label. Everything TLS 1.3 expands with SHA-256 fits in one block, so that's all
+// this does.
+pub(super) fn expand_label(secret: &Secret, label: &str, context: &[u8], out: &mut [u8]) {
+    assert!(out.len() <= DIGEST_LEN);
+    let mut hmac = HmacSha256::new(secret);
+    hmac.update(&(out.len() as u16).to_be_bytes());
+    hmac.update(&[6 + label.len() as u8]);
+    hmac.update(b"tls13 ");
+    hmac.update(label.as_bytes());
+    hmac.update(&[context.len() as u8]);
+    hmac.update(context);
+    hmac.update(&[1]);
+    out.copy_from_slice(&hmac.finalize()[..out.len()]);
+}
+
+// Derive-Secret, given the transcript hash rather than the messages.
+pub(super) fn derive(secret: &Secret, label: &str, transcript: &[u8; DIGEST_LEN]) -> Secret {
+    let mut out = ZEROS;
+    expand_label(secret, label, transcript, &mut out);
+    out
+}
+
+// The salt for the next stage: Derive-Secret(secret, "derived", "").
+pub(super) fn next_salt(secret: &Secret) -> Secret {
+    derive(secret, "derived", &sha256::digest(&[]))
+}
+
+// The Finished (or PSK binder) MAC over `transcript`, keyed from `secret`.
+pub(super) fn finished(secret: &Secret, transcript: &[u8; DIGEST_LEN]) -> [u8; DIGEST_LEN] {
+    let mut key = ZEROS;
+    expand_label(secret, "finished", &[], &mut key);
+    let mut hmac = HmacSha256::new(&key);
+    hmac.update(transcript);
+    hmac.finalize()
+}
+
+// One direction's record protection.
+pub(super) struct Keys {
+    gcm: Gcm,
+    iv: [u8; NONCE_LEN],
+    seq: u64,
+}
+
+impl Keys {
+    pub(super) fn new(secret: &Secret) -> Self {
+        let mut key = [0; 16];
+        let mut iv = [0; NONCE_LEN];
+        expand_label(secret, "key", &[], &mut key);
+        expand_label(secret, "iv", &[], &mut iv);
+        Keys {
+            gcm: Gcm::new(Aes::new(&key).unwrap()),
+            iv,
+            seq: 0,
+        }
+    }
+
+    pub(super) fn gcm(&self) -> &Gcm {
+        &self.gcm
+    }
+
+    // The nonce for the next record, which uses up its sequence number.
+    pub(super) fn next_nonce(&mut self) -> [u8; NONCE_LEN] {
+        let mut nonce = self.iv;
+        for (byte, seq) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *byte ^= seq;
+        }
+        self.seq += 1;
+        nonce
+    }
+}
+
+// The next generation of a traffic secret, for KeyUpdate.
+pub(super) fn update(secret: &Secret) -> Secret {
+    let mut next = ZEROS;
+    expand_label(secret, "traffic upd", &[], &mut next);
+    next
+}
@@ -0,0 +1,139 @@
+// A `Backend` on an ATECC608: its RNG, ECDSA P-256 certificates checked with its Verify
+// command, and client authentication with a private key that never leaves it.
+//
+// The server's chain has to lead to one trusted CA key. Each certificate has to be signed by
+// the next one's key, and the last by the trusted key, or be the trusted key's own: sending
+// the root along or leaving it out both work, and so does pinning the server's own key. Every
+// certificate that signs another has to be a CA allowed to (basicConstraints cA, keyCertSign
+// and any path length limit), so a leaf the CA issued for some other name can't vouch for this
+// one, and every certificate checked has to be valid at the `Now` given.
+
+use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+use super::{
+    x509::{self, Now},
+    Backend, BackendError, Certificates, ECDSA_SECP256R1_SHA256,
+};
+use crate::{atecc608::Atecc608, crypto::sha256};
+
+pub struct SecureElement<'a, I, D> {
+    chip: &'a mut Atecc608<I, D>,
+    trust_anchor: [u8; 64],
+    client: Option<(&'a [u8], u16)>,
+    now: Now,
+    // From the leaf certificate, once the chain has checked out.
+    server_key: Option<[u8; 64]>,
+}
+
+impl<'a, I: I2c, D: DelayNs> SecureElement<'a, I, D> {
+    // `trust_anchor` is the CA's P-256 public key, X then Y. `client` is the device's
+    // certificate in DER and the slot its private key is in, for servers that ask for one.
+    // `now` is what the certificates' validity periods are checked against.
+    pub fn new(
+        chip: &'a mut Atecc608<I, D>,
+        trust_anchor: [u8; 64],
+        client: Option<(&'a [u8], u16)>,
+        now: Now,
+    ) -> Self {
+        SecureElement {
+            chip,
+            trust_anchor,
+            client,
+            now,
+            server_key: None,
+        }
+    }
+
+    // Whether `cert` carries a valid signature by `key`.
+    async fn signed_by(
+        &mut self,
+        cert: &x509::Certificate<'_>,
+        key: &[u8; 64],
+    ) -> Result<(), BackendError> {
+        let signature = cert.signature.ok_or(BackendError)?;
+        match self
+            .chip
+            .verify(&sha256::digest(cert.tbs), &signature, key)
+            .await
+        {
+            Ok(true) => Ok(()),
+            _ => Err(BackendError),
+        }
+    }
+}
+
+impl<I: I2c, D: DelayNs> Backend for SecureElement<'_, I, D> {
+    async fn random(&mut self, out: &mut [u8; 32]) -> Result<(), BackendError> {
+        *out = self.chip.random().await.map_err(|_| BackendError)?;
+        Ok(())
+    }
+
+    async fn verify_certificates(
+        &mut self,
+        server_name: &str,
+        chain: Certificates<'_>,
+    ) -> Result<(), BackendError> {
+        let mut chain = chain.map(x509::Certificate::parse);
+        let leaf = chain.next().flatten().ok_or(BackendError)?;
+        if !leaf.matches_host(server_name) || !leaf.valid_at(self.now) {
+            return Err(BackendError);
+        }
+        let server_key = leaf.public_key.ok_or(BackendError)?;
+        let mut cert = leaf;
+        // CA certificates between `cert` and the leaf.
+        let mut below = 0;
+        while cert.public_key != Some(self.trust_anchor) {
+            match chain.next() {
+                Some(Some(issuer)) => {
+                    let trusted = issuer.public_key == Some(self.trust_anchor);
+                    // The trusted key's own certificate is trusted as it is.
+                    if !trusted && (!issuer.may_issue(below) || !issuer.valid_at(self.now)) {
+                        return Err(BackendError);
+                    }
+                    let key = issuer.public_key.ok_or(BackendError)?;
+                    self.signed_by(&cert, &key).await?;
+                    cert = issuer;
+                    below += 1;
+                }
+                Some(None) => return Err(BackendError),
+                None => {
+                    let anchor = self.trust_anchor;
+                    self.signed_by(&cert, &anchor).await?;
+                    break;
+                }
+            }
+        }
+        self.server_key = Some(server_key);
+        Ok(())
+    }
+
+    async fn verify_signature(
+        &mut self,
+        scheme: u16,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), BackendError> {
+        let key = self.server_key.ok_or(BackendError)?;
+        let signature = x509::decode_ecdsa_signature(signature).ok_or(BackendError)?;
+        if scheme != ECDSA_SECP256R1_SHA256 {
+            return Err(BackendError);
+        }
+        match self
+            .chip
+            .verify(&sha256::digest(message), &signature, &key)
+            .await
+        {
+            Ok(true) => Ok(()),
+            _ => Err(BackendError),
+        }
+    }
+
+    fn client_certificate(&self) -> Option<&[u8]> {
+        self.client.map(|(cert, _)| cert)
+    }
+
+    async fn sign(&mut self, digest: &[u8; 32]) -> Result<[u8; 64], BackendError> {
+        let (_, slot) = self.client.ok_or(BackendError)?;
+        self.chip.sign(slot, digest).await.map_err(|_| BackendError)
+    }
+}
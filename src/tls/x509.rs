@@ -0,0 +1,350 @@
+// Just enough X.509 to check an ECDSA P-256 certificate chain: the signed part, the P-256
+// public key, the ECDSA-with-SHA256 signature, the DNS names a certificate is for, whether it's
+// a CA allowed to sign certificates and how many below it, and when it's valid.
+//
+// Checking validity needs a clock that can be trusted, which a device often doesn't have until
+// it's connected to something, so the caller says which with `Now`.
+
+use crate::gps;
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const BOOLEAN: u8 = 0x01;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+// [0] version, [3] extensions, and dNSName within a GeneralName.
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+const DNS_NAME: u8 = 0x82;
+
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+// keyCertSign, bit 5 of KeyUsage counting from the first byte's top bit.
+const KEY_CERT_SIGN: u8 = 0x04;
+
+// What to check certificates' validity periods against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Now {
+    // Seconds since the Unix epoch, from a clock that can be trusted: an RTC set when the
+    // device was provisioned, GPS, or the like.
+    Unix(u64),
+    // Accept any dates. An expired or not yet valid certificate gets through, so this is for
+    // devices with no trustworthy time at all.
+    Unchecked,
+}
+
+pub struct Certificate<'a> {
+    // The signed part, tag and length included, which is what the signature covers.
+    pub tbs: &'a [u8],
+    // X then Y, if the subject's key is P-256.
+    pub public_key: Option<[u8; 64]>,
+    // R then S, if the issuer signed it with ECDSA and SHA-256.
+    pub signature: Option<[u8; 64]>,
+    // notBefore and notAfter, in seconds since the Unix epoch, if they could be read.
+    pub validity: Option<(u64, u64)>,
+    extensions: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    // `None` if `der` isn't a certificate.
+    pub fn parse(der: &'a [u8]) -> Option<Self> {
+        let (tag, cert, _) = tlv(der)?;
+        if tag != SEQUENCE {
+            return None;
+        }
+        let tbs_len = der_len(cert)?;
+        let (tbs, rest) = cert.split_at(tbs_len);
+        let (_, algorithm, rest) = tlv(rest)?;
+        let (_, signature, _) = tlv(rest)?;
+
+        let (_, mut fields, _) = tlv(tbs)?;
+        let (tag, _, rest) = tlv(fields)?;
+        if tag == VERSION {
+            fields = rest;
+        }
+        // Serial number, signature algorithm and issuer.
+        for _ in 0..3 {
+            fields = tlv(fields)?.2;
+        }
+        let (_, validity, rest) = tlv(fields)?;
+        // Then the subject.
+        let (_, spki, mut fields) = tlv(tlv(rest)?.2)?;
+        let mut extensions: &[u8] = &[];
+        while let Some((tag, value, rest)) = tlv(fields) {
+            if tag == EXTENSIONS {
+                extensions = tlv(value)?.1;
+            }
+            fields = rest;
+        }
+
+        let (_, algorithm_oid, _) = tlv(algorithm)?;
+        Some(Certificate {
+            tbs,
+            public_key: p256_key(spki),
+            signature: if algorithm_oid == ECDSA_WITH_SHA256 {
+                ecdsa_signature(signature)
+            } else {
+                None
+            },
+            validity: parse_validity(validity),
+            extensions,
+        })
+    }
+
+    // Whether `now` is within the certificate's validity period. Always, with `Now::Unchecked`.
+    pub fn valid_at(&self, now: Now) -> bool {
+        match now {
+            Now::Unix(now) => self
+                .validity
+                .is_some_and(|(not_before, not_after)| (not_before..=not_after).contains(&now)),
+            Now::Unchecked => true,
+        }
+    }
+
+    // Whether the certificate's key may sign other certificates with `below` more CA
+    // certificates between this one and the leaf: it has to say it's a CA, its key usage (if
+    // it has one) has to include keyCertSign, and its path length constraint (if it has one)
+    // has to allow that many.
+    pub fn may_issue(&self, below: usize) -> bool {
+        let Some(Some((true, path_len))) = self.extension(BASIC_CONSTRAINTS).map(basic_constraints)
+        else {
+            return false;
+        };
+        let key_cert_sign = match self.extension(KEY_USAGE) {
+            Some(usage) => key_usage(usage).is_some_and(|usage| usage & KEY_CERT_SIGN != 0),
+            None => true,
+        };
+        key_cert_sign && path_len.is_none_or(|path_len| below <= path_len as usize)
+    }
+
+    // Whether one of the certificate's DNS names covers `host`. A `*.` name covers exactly one
+    // label, as browsers have it.
+    pub fn matches_host(&self, host: &str) -> bool {
+        let host = host.as_bytes();
+        self.dns_names().any(|name| match name.strip_prefix(b"*.") {
+            Some(suffix) => host
+                .iter()
+                .position(|&c| c == b'.')
+                .is_some_and(|dot| dot > 0 && host[dot + 1..].eq_ignore_ascii_case(suffix)),
+            None => name.eq_ignore_ascii_case(host),
+        })
+    }
+
+    // The dNSNames in the subject alternative name extension.
+    fn dns_names(&self) -> impl Iterator<Item = &'a [u8]> {
+        let names = self.subject_alt_names().unwrap_or(&[]);
+        Tlvs(names).filter_map(|(tag, value)| (tag == DNS_NAME).then_some(value))
+    }
+
+    fn subject_alt_names(&self) -> Option<&'a [u8]> {
+        Some(tlv(self.extension(SUBJECT_ALT_NAME)?)?.1)
+    }
+
+    // The DER value inside the extension with `id`, if the certificate has it.
+    fn extension(&self, id: &[u8]) -> Option<&'a [u8]> {
+        for (_, extension) in Tlvs(self.extensions) {
+            let (_, oid, rest) = tlv(extension)?;
+            if oid != id {
+                continue;
+            }
+            // The critical flag is optional.
+            let (mut tag, mut value, rest) = tlv(rest)?;
+            if tag == BOOLEAN {
+                (tag, value, _) = tlv(rest)?;
+            }
+            if tag != OCTET_STRING {
+                return None;
+            }
+            return Some(value);
+        }
+        None
+    }
+}
+
+// BasicConstraints: cA, which defaults to false, and pathLenConstraint if there is one.
+fn basic_constraints(der: &[u8]) -> Option<(bool, Option<u8>)> {
+    let (tag, mut fields, _) = tlv(der)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let mut ca = false;
+    if let Some((BOOLEAN, value, rest)) = tlv(fields) {
+        ca = value.first().is_some_and(|&b| b != 0);
+        fields = rest;
+    }
+    let path_len = match tlv(fields) {
+        Some((INTEGER, [len], _)) if *len < 0x80 => Some(*len),
+        Some(_) => return None,
+        None => None,
+    };
+    Some((ca, path_len))
+}
+
+// KeyUsage's first byte of bits, which has keyCertSign in it.
+fn key_usage(der: &[u8]) -> Option<u8> {
+    match tlv(der)? {
+        (BIT_STRING, [_, bits, ..], _) => Some(*bits),
+        _ => None,
+    }
+}
+
+// Validity's notBefore and notAfter.
+fn parse_validity(der: &[u8]) -> Option<(u64, u64)> {
+    let (not_before, rest) = time(der)?;
+    let (not_after, _) = time(rest)?;
+    Some((not_before, not_after))
+}
+
+// A UTCTime or GeneralizedTime, in seconds since the Unix epoch, and what follows it. Only the
+// `Z` forms with seconds, which is all RFC 5280 allows.
+fn time(der: &[u8]) -> Option<(u64, &[u8])> {
+    let (tag, value, rest) = tlv(der)?;
+    let (year, digits) = match (tag, value) {
+        (UTC_TIME, [digits @ .., b'Z']) if digits.len() == 12 => {
+            let yy = number(&digits[..2])?;
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, &digits[2..])
+        }
+        (GENERALIZED_TIME, [digits @ .., b'Z']) if digits.len() == 14 => {
+            (number(&digits[..4])?, &digits[4..])
+        }
+        _ => return None,
+    };
+    let field = |i: usize| number(&digits[i..i + 2]).map(|n| n as u8);
+    let date = gps::Date {
+        year: year as u16,
+        month: field(0)?,
+        day: field(2)?,
+    };
+    let time = gps::Time {
+        hour: field(4)?,
+        minute: field(6)?,
+        second: field(8)?,
+        millis: 0,
+    };
+    Some((gps::unix_micros(date, time) / 1_000_000, rest))
+}
+
+fn number(digits: &[u8]) -> Option<u32> {
+    digits.iter().try_fold(0, |n, &d| {
+        d.is_ascii_digit().then(|| n * 10 + (d - b'0') as u32)
+    })
+}
+
+// SubjectPublicKeyInfo for an uncompressed P-256 point.
+fn p256_key(spki: &[u8]) -> Option<[u8; 64]> {
+    let (_, algorithm, rest) = tlv(spki)?;
+    let (tag, key, _) = tlv(rest)?;
+    let (_, kind, curve) = tlv(algorithm)?;
+    let (_, curve, _) = tlv(curve)?;
+    if tag != BIT_STRING || kind != EC_PUBLIC_KEY || curve != PRIME256V1 {
+        return None;
+    }
+    // No unused bits, then the uncompressed point marker.
+    match key {
+        [0, 4, point @ ..] => point.try_into().ok(),
+        _ => None,
+    }
+}
+
+// A BIT STRING holding an Ecdsa-Sig-Value, as R then S.
+fn ecdsa_signature(bits: &[u8]) -> Option<[u8; 64]> {
+    match bits {
+        [0, sig @ ..] => decode_ecdsa_signature(sig),
+        _ => None,
+    }
+}
+
+// An Ecdsa-Sig-Value, as in a certificate or a TLS CertificateVerify, as R then S.
+pub fn decode_ecdsa_signature(der: &[u8]) -> Option<[u8; 64]> {
+    let (tag, sig, _) = tlv(der)?;
+    let (r_tag, r, rest) = tlv(sig)?;
+    let (s_tag, s, _) = tlv(rest)?;
+    if tag != SEQUENCE || r_tag != INTEGER || s_tag != INTEGER {
+        return None;
+    }
+    let mut out = [0; 64];
+    out[..32].copy_from_slice(&fixed_32(r)?);
+    out[32..].copy_from_slice(&fixed_32(s)?);
+    Some(out)
+}
+
+// An unsigned INTEGER's content as 32 big-endian bytes.
+fn fixed_32(int: &[u8]) -> Option<[u8; 32]> {
+    let start = int.iter().position(|&b| b != 0).unwrap_or(int.len());
+    let int = &int[start..];
+    let mut out = [0; 32];
+    out.get_mut(32usize.checked_sub(int.len())?..)?
+        .copy_from_slice(int);
+    Some(out)
+}
+
+// Encode R then S as an Ecdsa-Sig-Value, which is how TLS carries signatures. Returns the
+// length used of `out`, which needs 72 bytes.
+pub fn encode_ecdsa_signature(signature: &[u8; 64], out: &mut [u8; 72]) -> usize {
+    let mut len = 2;
+    for half in signature.chunks_exact(32) {
+        let start = half.iter().position(|&b| b != 0).unwrap_or(31);
+        let int = &half[start..];
+        let pad = (int[0] & 0x80 != 0) as usize;
+        out[len] = INTEGER;
+        out[len + 1] = (pad + int.len()) as u8;
+        out[len + 2] = 0;
+        out[len + 2 + pad..len + 2 + pad + int.len()].copy_from_slice(int);
+        len += 2 + pad + int.len();
+    }
+    out[0] = SEQUENCE;
+    out[1] = (len - 2) as u8;
+    len
+}
+
+// Tag, content and what follows, for the DER element at the start of `der`.
+fn tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (content, header) = content_len(der)?;
+    let len = header.checked_add(content)?;
+    if len > der.len() {
+        return None;
+    }
+    Some((der[0], &der[header..len], &der[len..]))
+}
+
+// The whole length of the element at the start of `der`, header included.
+fn der_len(der: &[u8]) -> Option<usize> {
+    let (_, _, rest) = tlv(der)?;
+    Some(der.len() - rest.len())
+}
+
+// The content length and the header length.
+fn content_len(der: &[u8]) -> Option<(usize, usize)> {
+    let first = *der.get(1)?;
+    if first < 0x80 {
+        return Some((first as usize, 2));
+    }
+    let bytes = (first & 0x7f) as usize;
+    if bytes == 0 || bytes > 3 {
+        return None;
+    }
+    let len = der
+        .get(2..2 + bytes)?
+        .iter()
+        .fold(0, |len, &b| len << 8 | b as usize);
+    Some((len, 2 + bytes))
+}
+
+// The elements in a SEQUENCE's content, as tag and content.
+struct Tlvs<'a>(&'a [u8]);
+
+impl<'a> Iterator for Tlvs<'a> {
+    type Item = (u8, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (tag, value, rest) = tlv(self.0)?;
+        self.0 = rest;
+        Some((tag, value))
+    }
+}
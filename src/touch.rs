@@ -0,0 +1,260 @@
+// Touch controllers: the resistive XPT2046 (SPI) and the capacitive FT6236/FT6206 (I2C).
+//
+// Both wait on the controller's interrupt line, so nothing is polled while nobody is touching
+// the screen, and both report single-touch events in screen coordinates through `next_event`.
+
+use embedded_hal_async::{
+    delay::DelayNs,
+    digital::Wait,
+    i2c::I2c,
+    spi::{Operation, SpiDevice},
+};
+
+#[derive(Debug)]
+pub enum Error<B, P> {
+    Bus(B),
+    Pin(P),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Down { x: u16, y: u16 },
+    Move { x: u16, y: u16 },
+    Up { x: u16, y: u16 },
+}
+
+// Maps raw controller readings to screen pixels.
+//
+// The raw values at the left/right and top/bottom edges of the screen are found by touching
+// the corners; swapping min and max flips an axis, and `swap_xy` handles rotated panels.
+#[derive(Clone, Copy, Debug)]
+pub struct Calibration {
+    pub x_min: u16,
+    pub x_max: u16,
+    pub y_min: u16,
+    pub y_max: u16,
+    pub swap_xy: bool,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Calibration {
+    // Pass-through for a controller that already reports pixels, like the FT6236.
+    pub const fn identity(width: u16, height: u16) -> Self {
+        Calibration {
+            x_min: 0,
+            x_max: width - 1,
+            y_min: 0,
+            y_max: height - 1,
+            swap_xy: false,
+            width,
+            height,
+        }
+    }
+
+    pub fn apply(&self, x: u16, y: u16) -> (u16, u16) {
+        let (x, y) = if self.swap_xy { (y, x) } else { (x, y) };
+        (
+            scale(x, self.x_min, self.x_max, self.width),
+            scale(y, self.y_min, self.y_max, self.height),
+        )
+    }
+}
+
+fn scale(raw: u16, min: u16, max: u16, size: u16) -> u16 {
+    if min == max {
+        return 0;
+    }
+    let (raw, min, max) = (raw as i32, min as i32, max as i32);
+    let pos = (raw - min) * (size as i32 - 1) / (max - min);
+    pos.clamp(0, size as i32 - 1) as u16
+}
+
+// XPT2046 / ADS7843 commands: 12-bit differential conversions, powering down in between
+// so the pen interrupt stays enabled.
+const XPT_X: u8 = 0xD0;
+const XPT_Y: u8 = 0x90;
+const XPT_Z1: u8 = 0xB0;
+const XPT_Z2: u8 = 0xC0;
+
+// Pressure below this counts as released.
+const XPT_PRESSURE_THRESHOLD: i32 = 300;
+const XPT_SAMPLES: usize = 4;
+
+pub struct Xpt2046<S, P, D> {
+    spi: S,
+    irq: P,
+    delay: D,
+    calibration: Calibration,
+    interval_ms: u32,
+    last: Option<(u16, u16)>,
+}
+
+impl<S, P, D> Xpt2046<S, P, D>
+where
+    S: SpiDevice,
+    P: Wait,
+    D: DelayNs,
+{
+    pub fn new(spi: S, irq: P, delay: D, calibration: Calibration) -> Self {
+        Xpt2046 {
+            spi,
+            irq,
+            delay,
+            calibration,
+            interval_ms: 10,
+            last: None,
+        }
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    // Time between samples while the screen is pressed. 10 ms by default.
+    pub fn set_interval(&mut self, ms: u32) {
+        self.interval_ms = ms;
+    }
+
+    pub async fn next_event(&mut self) -> Result<Event, Error<S::Error, P::Error>> {
+        loop {
+            match self.last {
+                None => self.irq.wait_for_low().await.map_err(Error::Pin)?,
+                Some(_) => self.delay.delay_ms(self.interval_ms).await,
+            }
+            let sample = self.sample().await?;
+            match (self.last, sample) {
+                (None, Some(raw)) => {
+                    let (x, y) = self.calibration.apply(raw.0, raw.1);
+                    self.last = Some((x, y));
+                    return Ok(Event::Down { x, y });
+                }
+                (Some(last), Some(raw)) => {
+                    let (x, y) = self.calibration.apply(raw.0, raw.1);
+                    if (x, y) != last {
+                        self.last = Some((x, y));
+                        return Ok(Event::Move { x, y });
+                    }
+                }
+                (Some((x, y)), None) => {
+                    self.last = None;
+                    return Ok(Event::Up { x, y });
+                }
+                // Noise on the interrupt line.
+                (None, None) => {}
+            }
+        }
+    }
+
+    // Raw averaged position, or None if the pressure is too low to trust it.
+    async fn sample(&mut self) -> Result<Option<(u16, u16)>, Error<S::Error, P::Error>> {
+        let z1 = self.convert(XPT_Z1).await? as i32;
+        let z2 = self.convert(XPT_Z2).await? as i32;
+        if z1 + 4095 - z2 < XPT_PRESSURE_THRESHOLD {
+            return Ok(None);
+        }
+        let (mut x, mut y) = (0u32, 0u32);
+        for _ in 0..XPT_SAMPLES {
+            x += self.convert(XPT_X).await? as u32;
+            y += self.convert(XPT_Y).await? as u32;
+        }
+        Ok(Some((
+            (x / XPT_SAMPLES as u32) as u16,
+            (y / XPT_SAMPLES as u32) as u16,
+        )))
+    }
+
+    async fn convert(&mut self, command: u8) -> Result<u16, Error<S::Error, P::Error>> {
+        let mut result = [0; 2];
+        self.spi
+            .transaction(&mut [Operation::Write(&[command]), Operation::Read(&mut result)])
+            .await
+            .map_err(Error::Bus)?;
+        Ok(u16::from_be_bytes(result) >> 3)
+    }
+}
+
+const FT_ADDRESS: u8 = 0x38;
+const FT_TD_STATUS: u8 = 0x02;
+const FT_G_MODE: u8 = 0xA4;
+const FT_THRESHOLD: u8 = 0x80;
+
+// Event flag in the top bits of P1_XH.
+const FT_EVENT_DOWN: u8 = 0;
+const FT_EVENT_UP: u8 = 1;
+
+pub struct Ft6236<I, P> {
+    i2c: I,
+    int: P,
+    calibration: Calibration,
+    last: Option<(u16, u16)>,
+}
+
+impl<I: I2c, P: Wait> Ft6236<I, P> {
+    // `threshold` is the touch sensitivity, lower is more sensitive; 40 suits most panels.
+    pub async fn new(
+        i2c: I,
+        int: P,
+        calibration: Calibration,
+        threshold: u8,
+    ) -> Result<Self, Error<I::Error, P::Error>> {
+        let mut touch = Ft6236 {
+            i2c,
+            int,
+            calibration,
+            last: None,
+        };
+        // Pulse INT for every new report rather than holding it low while touched.
+        touch
+            .i2c
+            .write(FT_ADDRESS, &[FT_G_MODE, 0x01])
+            .await
+            .map_err(Error::Bus)?;
+        touch
+            .i2c
+            .write(FT_ADDRESS, &[FT_THRESHOLD, threshold])
+            .await
+            .map_err(Error::Bus)?;
+        Ok(touch)
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    pub async fn next_event(&mut self) -> Result<Event, Error<I::Error, P::Error>> {
+        loop {
+            self.int.wait_for_falling_edge().await.map_err(Error::Pin)?;
+            // TD_STATUS, then P1_XH, P1_XL, P1_YH, P1_YL.
+            let mut report = [0; 5];
+            self.i2c
+                .write_read(FT_ADDRESS, &[FT_TD_STATUS], &mut report)
+                .await
+                .map_err(Error::Bus)?;
+            let touches = report[0] & 0x0F;
+            let event = report[1] >> 6;
+            let raw_x = u16::from_be_bytes([report[1] & 0x0F, report[2]]);
+            let raw_y = u16::from_be_bytes([report[3] & 0x0F, report[4]]);
+            let (x, y) = self.calibration.apply(raw_x, raw_y);
+
+            match self.last {
+                Some((last_x, last_y)) if touches == 0 || event == FT_EVENT_UP => {
+                    self.last = None;
+                    return Ok(Event::Up {
+                        x: last_x,
+                        y: last_y,
+                    });
+                }
+                None if touches > 0 && event != FT_EVENT_UP => {
+                    self.last = Some((x, y));
+                    return Ok(Event::Down { x, y });
+                }
+                Some(last) if event != FT_EVENT_DOWN && (x, y) != last => {
+                    self.last = Some((x, y));
+                    return Ok(Event::Move { x, y });
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// A record of what the executor did and when, for working out task interleavings after the
+// fact: spawns, wakes, polls and interrupts, timestamped, in a RAM ring buffer.
+//
+// With the `trace` feature, recording is on from boot and costs a few hundred nanoseconds per
+// event. `pause` freezes the buffer so the events leading up to a failure are kept, and `dump`
+// writes it out over anything embedded-io-async, a USB serial port or an RTT channel say.
+//
+// Dump format, all little-endian: the magic b"RTRC", a version byte (1), a u16 record count,
+// then the records oldest first, 8 bytes each:
+//
+//     bits 0-31   timestamp, TIMER microseconds, wrapping
+//     bits 32-35  kind, see `Kind`
+//     bit  36     core
+//     bit  37     recorded in an interrupt handler
+//     bits 40-63  argument: a task id (from `taskinfo`, 0 if the task isn't in the table), or
+//                 an interrupt number
+//
+// A `Name` record follows each `Spawn`. Instead of a timestamp it has the address of the
+// task's name, and the argument is its length; look it up in the ELF. A `PollEnd` with an
+// argument of 0x800000 set finished the task.
+
+use cortex_m::peripheral::{scb::VectActive, SCB};
+use embedded_io_async::Write;
+
+use crate::{
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    time::Instant,
+};
+
+pub const RECORDS: usize = 512;
+const MAGIC: &[u8; 4] = b"RTRC";
+const VERSION: u8 = 1;
+const FINISHED: u32 = 1 << 23;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    Spawn = 1,
+    Name = 2,
+    Wake = 3,
+    PollStart = 4,
+    PollEnd = 5,
+    Irq = 6,
+}
+
+// Two words per record. Slots are claimed with `NEXT`, so records from both cores and from
+// interrupt handlers never overwrite each other.
+static BUFFER: [AtomicU32; RECORDS * 2] = [const { AtomicU32::new(0) }; RECORDS * 2];
+// Records ever claimed; the ring index is this mod RECORDS.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn record(kind: Kind, arg: u32) {
+    record_with(kind, Instant::now().as_micros() as u32, arg)
+}
+
+pub(crate) fn spawned(id: u32, name: &'static str) {
+    record(Kind::Spawn, id);
+    record_with(Kind::Name, name.as_ptr() as u32, name.len() as u32);
+}
+
+pub(crate) fn polled(id: u32, finished: bool) {
+    record(
+        Kind::PollEnd,
+        id & !FINISHED | if finished { FINISHED } else { 0 },
+    );
+}
+
+fn record_with(kind: Kind, first: u32, arg: u32) {
+    if PAUSED.load(Ordering::Relaxed) {
+        return;
+    }
+    let slot = NEXT.fetch_add(1, Ordering::Relaxed) % RECORDS;
+    let core = crate::sync::core() as u32;
+    let in_irq = SCB::vect_active() != VectActive::ThreadMode;
+    let header = kind as u32 | core << 4 | (in_irq as u32) << 5 | (arg & 0xff_ffff) << 8;
+    BUFFER[slot * 2].store(first, Ordering::Relaxed);
+    BUFFER[slot * 2 + 1].store(header, Ordering::Relaxed);
+}
+
+// Stop recording, so the buffer holds what happened up to now.
+pub fn pause() {
+    PAUSED.store(true, Ordering::Relaxed);
+}
+
+pub fn resume() {
+    PAUSED.store(false, Ordering::Relaxed);
+}
+
+// Throw away everything recorded so far.
+pub fn clear() {
+    NEXT.store(0, Ordering::Relaxed);
+}
+
+// Where the oldest record is and how many there are.
+fn range() -> (usize, usize) {
+    let next = NEXT.load(Ordering::Relaxed);
+    let len = next.min(RECORDS);
+    ((next - len) % RECORDS, len)
+}
+
+// Write the buffer out in the dump format. Pause first, or events recorded while this runs,
+// its own included, may overwrite the oldest ones as they're written.
+pub async fn dump<W: Write>(out: &mut W) -> Result<(), W::Error> {
+    let (oldest, len) = range();
+    out.write_all(MAGIC).await?;
+    out.write_all(&[VERSION]).await?;
+    out.write_all(&(len as u16).to_le_bytes()).await?;
+    // A few records at a time, so a slow port doesn't mean one write per record.
+    let mut chunk = [0; 8 * 16];
+    let mut filled = 0;
+    for i in 0..len {
+        let slot = (oldest + i) % RECORDS;
+        for word in 0..2 {
+            let value = BUFFER[slot * 2 + word].load(Ordering::Relaxed);
+            chunk[filled..filled + 4].copy_from_slice(&value.to_le_bytes());
+            filled += 4;
+        }
+        if filled == chunk.len() {
+            out.write_all(&chunk).await?;
+            filled = 0;
+        }
+    }
+    out.write_all(&chunk[..filled]).await?;
+    out.flush().await
+}
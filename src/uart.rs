@@ -0,0 +1,177 @@
+// `core::fmt::Write` logging to a UART, for `writeln!`-style code. The hardware UART driver
+// is in `port`, and its `UartTx` is what `drain` usually writes to.
+//
+// `Writer` formats a line into its own small buffer and copies it into a `Staging` buffer in
+// one go, so lines from different tasks, cores and interrupt handlers never get mixed up. A
+// `drain` task writes staged lines out to the UART. Nothing on the writing side ever waits:
+// space is reserved with a compare-and-swap, and a line that doesn't fit is dropped and
+// counted.
+//
+// Lines are stored as records of whole words: a header word with the length and a valid bit,
+// which is written last, then the bytes. The drain stops at the first record that isn't valid
+// yet, so records are always written out in the order they were reserved.
+
+use core::fmt;
+
+use embedded_io_async::Write;
+
+use crate::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    sync::Signal,
+};
+
+pub mod port;
+
+pub use port::{Config, Uart, UartRx, UartTx};
+
+// Longest line a `Writer` keeps together. Longer lines are split.
+pub const LINE: usize = 128;
+const VALID: u32 = 1 << 31;
+
+// Staged lines, WORDS words of them. Put it in a static.
+pub struct Staging<const WORDS: usize> {
+    words: [AtomicU32; WORDS],
+    // Words reserved and taken, counting up forever. Indexes are these mod WORDS.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicU32,
+    ready: Signal<()>,
+}
+
+impl<const WORDS: usize> Staging<WORDS> {
+    pub const fn new() -> Self {
+        Staging {
+            words: [const { AtomicU32::new(0) }; WORDS],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU32::new(0),
+            ready: Signal::new(),
+        }
+    }
+
+    pub fn writer(&self) -> Writer<'_, WORDS> {
+        Writer {
+            staging: self,
+            line: [0; LINE],
+            len: 0,
+        }
+    }
+
+    // Lines dropped because the buffer was full, since the last call.
+    pub fn take_dropped(&self) -> u32 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+
+    // Stage `bytes` as one record, or drop it if there's no room.
+    fn push(&self, bytes: &[u8]) -> bool {
+        let words = 1 + bytes.len().div_ceil(4);
+        let start = loop {
+            let head = self.head.load(Ordering::Relaxed);
+            let tail = self.tail.load(Ordering::Acquire);
+            if head.wrapping_sub(tail) + words > WORDS {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            if self
+                .head
+                .compare_exchange(
+                    head,
+                    head.wrapping_add(words),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                break head;
+            }
+        };
+        for (i, chunk) in bytes.chunks(4).enumerate() {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.word(start.wrapping_add(1 + i))
+                .store(u32::from_le_bytes(word), Ordering::Relaxed);
+        }
+        self.word(start)
+            .store(VALID | bytes.len() as u32, Ordering::Release);
+        self.ready.signal(());
+        true
+    }
+
+    // Take the oldest record, if it's been written, into `out`. Returns its length.
+    fn pop(&self, out: &mut [u8; LINE]) -> Option<usize> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let header = self.word(tail).load(Ordering::Acquire);
+        if header & VALID == 0 {
+            return None;
+        }
+        let len = (header & !VALID) as usize;
+        let words = 1 + len.div_ceil(4);
+        for (i, chunk) in out[..len].chunks_mut(4).enumerate() {
+            let word = self.word(tail.wrapping_add(1 + i)).load(Ordering::Relaxed);
+            chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+        }
+        // Any of these words could be the header of a later record, so none of them can be
+        // left looking valid.
+        for i in 0..words {
+            self.word(tail.wrapping_add(i)).store(0, Ordering::Relaxed);
+        }
+        self.tail.store(tail.wrapping_add(words), Ordering::Release);
+        Some(len)
+    }
+
+    fn word(&self, index: usize) -> &AtomicU32 {
+        &self.words[index % WORDS]
+    }
+}
+
+// Formats into a line buffer, and stages the line at each newline and when dropped. Make one
+// per `write!`, or keep one around; it's only a line's worth of bytes.
+pub struct Writer<'a, const WORDS: usize> {
+    staging: &'a Staging<WORDS>,
+    line: [u8; LINE],
+    len: usize,
+}
+
+impl<const WORDS: usize> Writer<'_, WORDS> {
+    fn flush_line(&mut self) {
+        if self.len > 0 {
+            self.staging.push(&self.line[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl<const WORDS: usize> fmt::Write for Writer<'_, WORDS> {
+    // Never fails: a line that can't be staged is dropped, not reported.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.line[self.len] = byte;
+            self.len += 1;
+            if byte == b'\n' || self.len == LINE {
+                self.flush_line();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const WORDS: usize> Drop for Writer<'_, WORDS> {
+    fn drop(&mut self) {
+        self.flush_line();
+    }
+}
+
+// Write staged lines to `uart` as they come in, forever. Returns only if the UART fails.
+pub async fn drain<const WORDS: usize, W: Write>(
+    staging: &Staging<WORDS>,
+    uart: &mut W,
+) -> Result<!, W::Error> {
+    let mut line = [0; LINE];
+    loop {
+        while let Some(len) = staging.pop(&mut line) {
+            uart.write_all(&line[..len]).await?;
+        }
+        uart.flush().await?;
+        staging.ready.wait().await;
+    }
+}
@@ -0,0 +1,408 @@
+// The RP2040's two hardware UARTs, with reads and writes that sleep on the UART's interrupt
+// while the FIFOs are full or empty instead of spinning on them.
+//
+//     let irq = Irq::<UART0_IRQ>::take().unwrap();
+//     let mut uart = Uart::new(pac.UART0, irq, 0, 1, &Config::default(), peri_hz);
+//     let (mut tx, mut rx) = uart.split();
+//     tx.write_all(b"AT\r\n").await?;
+//     let n = rx.read(&mut reply).await?;
+//
+// A write waits for room in the TX FIFO, which wakes it when the FIFO drains to half full. A
+// read wakes when there are four characters in the RX FIFO, or when there are fewer and the
+// line's been idle for 32 bit times; that's the PL011's timeout, so a lone character arrives a
+// few milliseconds late at low baud rates.
+//
+// Each half only enables its own interrupt sources, and only while it's waiting, so a task
+// writing doesn't get woken for every character another task receives. For long writes,
+// `UartTx::write_dma` hands the whole buffer to a DMA channel and only wakes at the end.
+
+use core::{convert::Infallible, marker::PhantomData, ptr, task::Poll, time::Duration};
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use rp2040_pac::uart0::RegisterBlock;
+
+use crate::{
+    dma::{self, Dreq},
+    future,
+    irq::{self, Irq, Line},
+    pio_uart::Parity,
+    resets, time,
+};
+
+// Where the data register is, for the DMA.
+const UARTDR: usize = 0x00;
+
+// UARTDR error bits, which come with the character they're about.
+const DR_FE: u32 = 1 << 8;
+const DR_PE: u32 = 1 << 9;
+const DR_BE: u32 = 1 << 10;
+const DR_OE: u32 = 1 << 11;
+
+// UARTFR
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+// UARTLCR_H
+const LCR_BRK: u32 = 1 << 0;
+const LCR_PEN: u32 = 1 << 1;
+const LCR_EPS: u32 = 1 << 2;
+const LCR_STP2: u32 = 1 << 3;
+const LCR_FEN: u32 = 1 << 4;
+const LCR_WLEN_SHIFT: u32 = 5;
+
+// UARTCR
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+// UARTDMACR: the TX DREQ.
+const DMACR_TXDMAE: u32 = 1 << 1;
+
+// UARTIFLS: TX at half full or less, RX at a quarter (four characters) or more.
+const IFLS_TX_HALF: u32 = 2;
+const IFLS_RX_QUARTER: u32 = 1 << 3;
+
+// UARTIMSC bits, and where the register is for the atomic set and clear aliases.
+const IM_RX: u32 = 1 << 4;
+const IM_TX: u32 = 1 << 5;
+const IM_RT: u32 = 1 << 6;
+const UARTIMSC: usize = 0x38;
+const ALIAS_SET: usize = 0x2000;
+const ALIAS_CLR: usize = 0x3000;
+
+// GPIO function select for the UARTs.
+const FUNCSEL_UART: u32 = 2;
+
+// UART0 or UART1, as the PAC has them.
+pub trait Instance {
+    const INDEX: u8;
+    const TX_DREQ: Dreq;
+    type Line: Line;
+    fn ptr() -> *const RegisterBlock;
+}
+
+impl Instance for rp2040_pac::UART0 {
+    const INDEX: u8 = 0;
+    const TX_DREQ: Dreq = Dreq::UART0_TX;
+    type Line = irq::UART0_IRQ;
+    fn ptr() -> *const RegisterBlock {
+        rp2040_pac::UART0::ptr()
+    }
+}
+
+impl Instance for rp2040_pac::UART1 {
+    const INDEX: u8 = 1;
+    const TX_DREQ: Dreq = Dreq::UART1_TX;
+    type Line = irq::UART1_IRQ;
+    fn ptr() -> *const RegisterBlock {
+        rp2040_pac::UART1::ptr()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub baud: u32,
+    // 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    // 1 or 2.
+    pub stop_bits: u8,
+}
+
+impl Config {
+    // DMX512-A: 250 kbaud 8N2.
+    pub const DMX512: Config = Config {
+        baud: 250_000,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 2,
+    };
+}
+
+// 115200 8N1.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baud: 115_200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The stop bit was low.
+    Framing,
+    Parity,
+    // The line was held low for longer than a character.
+    Break,
+    // The RX FIFO was full when a character came in, so at least one was lost.
+    Overrun,
+}
+
+impl embedded_io_async::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Framing | Error::Parity | Error::Break => ErrorKind::InvalidData,
+            Error::Overrun => ErrorKind::Other,
+        }
+    }
+}
+
+pub struct Uart<U: Instance> {
+    uart: U,
+    irq: Irq<U::Line>,
+}
+
+impl<U: Instance> Uart<U> {
+    // Send on `tx_pin` and receive on `rx_pin`, which have to be ones the UART can have: GPIO
+    // 0/1, 12/13, 16/17 or 28/29 for UART0, and 4/5, 8/9, 20/21 or 24/25 for UART1. `peri_hz`
+    // is clk_peri's frequency, which `clocks::frequencies` measures; usually it's clk_sys.
+    pub fn new(
+        uart: U,
+        irq: Irq<U::Line>,
+        tx_pin: u8,
+        rx_pin: u8,
+        config: &Config,
+        peri_hz: u32,
+    ) -> Self {
+        assert!((5..=8).contains(&config.data_bits) && (1..=2).contains(&config.stop_bits));
+        assert!(
+            tx_pin & 3 == 0 && rx_pin == tx_pin + 1 && uart_for(tx_pin) == U::INDEX && rx_pin < 30,
+            "pins aren't this UART's"
+        );
+        resets::bring_up(1 << (22 + U::INDEX));
+        let regs = unsafe { &*U::ptr() };
+        regs.uartcr.write(|w| unsafe { w.bits(0) });
+
+        // The divisor in 128ths, rounded to the nearest 64th and clamped to what the registers
+        // hold.
+        let div = 8 * peri_hz as u64 / config.baud as u64;
+        let (ibrd, fbrd) = match (div >> 7) as u32 {
+            0 => (1, 0),
+            ibrd if ibrd >= 0xffff => (0xffff, 0),
+            ibrd => (ibrd, ((div & 0x7f) as u32).div_ceil(2)),
+        };
+        regs.uartibrd.write(|w| unsafe { w.bits(ibrd) });
+        regs.uartfbrd.write(|w| unsafe { w.bits(fbrd) });
+        let parity = match config.parity {
+            Parity::None => 0,
+            Parity::Even => LCR_PEN | LCR_EPS,
+            Parity::Odd => LCR_PEN,
+        };
+        let stop = if config.stop_bits == 2 { LCR_STP2 } else { 0 };
+        // Writing LCR_H is also what latches the divisor.
+        regs.uartlcr_h.write(|w| unsafe {
+            w.bits(LCR_FEN | parity | stop | ((config.data_bits - 5) as u32) << LCR_WLEN_SHIFT)
+        });
+        regs.uartifls
+            .write(|w| unsafe { w.bits(IFLS_TX_HALF | IFLS_RX_QUARTER) });
+        regs.uartimsc.write(|w| unsafe { w.bits(0) });
+        regs.uartdmacr.write(|w| unsafe { w.bits(DMACR_TXDMAE) });
+        regs.uartcr
+            .write(|w| unsafe { w.bits(CR_UARTEN | CR_TXE | CR_RXE) });
+
+        let io = unsafe { &*rp2040_pac::IO_BANK0::ptr() };
+        let pads = unsafe { &*rp2040_pac::PADS_BANK0::ptr() };
+        // IE set and OD clear on both; a pull-up on RX, so an unconnected line idles high.
+        pads.gpio[tx_pin as usize].modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 7) | 1 << 6) });
+        pads.gpio[rx_pin as usize]
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 7 | 1 << 2) | 1 << 6 | 1 << 3) });
+        for pin in [tx_pin, rx_pin] {
+            io.gpio[pin as usize]
+                .gpio_ctrl
+                .write(|w| unsafe { w.bits(FUNCSEL_UART) });
+        }
+        Uart { uart, irq }
+    }
+
+    // The two directions, for separate tasks.
+    pub fn split(&mut self) -> (UartTx<'_, U>, UartRx<'_, U>) {
+        (
+            UartTx { irq: &self.irq },
+            UartRx {
+                irq: &self.irq,
+                deferred: None,
+                held: None,
+            },
+        )
+    }
+
+    // Disable the UART and hand back what it was made from.
+    pub fn release(self) -> (U, Irq<U::Line>) {
+        let regs = unsafe { &*U::ptr() };
+        regs.uartimsc.write(|w| unsafe { w.bits(0) });
+        regs.uartcr.write(|w| unsafe { w.bits(0) });
+        (self.uart, self.irq)
+    }
+}
+
+pub struct UartTx<'a, U: Instance> {
+    irq: &'a Irq<U::Line>,
+}
+
+impl<U: Instance> UartTx<'_, U> {
+    // Wait for everything written to have gone out, stop bits included. The UART can't
+    // interrupt on that, so this sleeps until the FIFO's half empty and yields from there.
+    pub async fn flush(&mut self) {
+        let regs = unsafe { &*U::ptr() };
+        while regs.uartfr.read().bits() & FR_BUSY != 0 {
+            if regs.uartfr.read().bits() & FR_TXFF != 0 {
+                wait::<U>(self.irq, IM_TX, || regs.uartfr.read().bits() & FR_TXFF == 0).await;
+            } else {
+                future::pending_once().await;
+            }
+        }
+    }
+
+    // Send `buf` through `channel` instead of from the task, and wait for it all to have gone
+    // out. Panics if the DMA can't read `buf`.
+    pub async fn write_dma(&mut self, channel: &mut dma::Channel, buf: &[u8]) {
+        let dr = (U::ptr() as usize + UARTDR) as *mut u8;
+        // Safety: the transfer is awaited here, or dropped with this future.
+        let transfer = unsafe { channel.write_to(buf, dr, U::TX_DREQ) };
+        transfer.await.expect("UART DMA");
+        self.flush().await;
+    }
+
+    // Finish sending what's been written, then hold the line low for `len` and high for `mark`
+    // after it: the break DMX512 and LIN start a packet with.
+    pub async fn send_break(&mut self, len: Duration, mark: Duration) {
+        self.flush().await;
+        let regs = unsafe { &*U::ptr() };
+        regs.uartlcr_h
+            .modify(|r, w| unsafe { w.bits(r.bits() | LCR_BRK) });
+        time::sleep(len).await;
+        regs.uartlcr_h
+            .modify(|r, w| unsafe { w.bits(r.bits() & !LCR_BRK) });
+        time::sleep(mark).await;
+    }
+}
+
+impl<U: Instance> ErrorType for UartTx<'_, U> {
+    type Error = Infallible;
+}
+
+impl<U: Instance> Write for UartTx<'_, U> {
+    // Waits for room in the FIFO, then fills it from `buf`.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let regs = unsafe { &*U::ptr() };
+        let full = || regs.uartfr.read().bits() & FR_TXFF != 0;
+        wait::<U>(self.irq, IM_TX, || !full()).await;
+        let mut n = 0;
+        while n < buf.len() && !full() {
+            regs.uartdr.write(|w| unsafe { w.bits(buf[n] as u32) });
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        UartTx::flush(self).await;
+        Ok(())
+    }
+}
+
+pub struct UartRx<'a, U: Instance> {
+    irq: &'a Irq<U::Line>,
+    // An error from a character after the first in a read, reported by the next one instead.
+    deferred: Option<Error>,
+    // The character that reported an overrun, which is itself fine.
+    held: Option<u8>,
+}
+
+impl<U: Instance> ErrorType for UartRx<'_, U> {
+    type Error = Error;
+}
+
+impl<U: Instance> Read for UartRx<'_, U> {
+    // Waits for a character, then takes any more that have already arrived. An error is about
+    // one character; the next call carries on with the one after it.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(error) = self.deferred.take() {
+            return Err(error);
+        }
+        let regs = unsafe { &*U::ptr() };
+        let empty = || regs.uartfr.read().bits() & FR_RXFE != 0;
+        let mut n = 0;
+        if let Some(byte) = self.held.take() {
+            buf[0] = byte;
+            n = 1;
+        } else {
+            wait::<U>(self.irq, IM_RX | IM_RT, || !empty()).await;
+        }
+        while n < buf.len() && !empty() {
+            let word = regs.uartdr.read().bits();
+            let error = if word & DR_BE != 0 {
+                Error::Break
+            } else if word & DR_FE != 0 {
+                Error::Framing
+            } else if word & DR_PE != 0 {
+                Error::Parity
+            } else if word & DR_OE != 0 {
+                self.held = Some(word as u8);
+                Error::Overrun
+            } else {
+                buf[n] = word as u8;
+                n += 1;
+                continue;
+            };
+            if n == 0 {
+                return Err(error);
+            }
+            self.deferred = Some(error);
+            break;
+        }
+        Ok(n)
+    }
+}
+
+// Wait for `ready`, with interrupt sources `sources` enabled in between and disabled again
+// however the wait ends.
+async fn wait<U: Instance>(irq: &Irq<U::Line>, sources: u32, ready: impl Fn() -> bool) {
+    let _armed = Armed::<U> {
+        sources,
+        instance: PhantomData,
+    };
+    future::wait_irq(irq, || {
+        if ready() {
+            return Poll::Ready(());
+        }
+        set_sources::<U>(sources, true);
+        Poll::Pending
+    })
+    .await
+}
+
+struct Armed<U: Instance> {
+    sources: u32,
+    instance: PhantomData<U>,
+}
+
+impl<U: Instance> Drop for Armed<U> {
+    fn drop(&mut self) {
+        set_sources::<U>(self.sources, false);
+    }
+}
+
+// Through the atomic set and clear aliases, so the two halves can each change their own bits
+// without a lock, from either core.
+fn set_sources<U: Instance>(sources: u32, enabled: bool) {
+    let alias = if enabled { ALIAS_SET } else { ALIAS_CLR };
+    let imsc = (U::ptr() as usize + alias + UARTIMSC) as *mut u32;
+    unsafe { ptr::write_volatile(imsc, sources) };
+}
+
+// Which UART a TX pin belongs to: they alternate in fours, starting and ending with UART0.
+fn uart_for(tx_pin: u8) -> u8 {
+    ((tx_pin >> 2) ^ (tx_pin >> 3)) & 1
+}
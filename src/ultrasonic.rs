@@ -0,0 +1,168 @@
+// HC-SR04 (and the many compatible modules) ultrasonic distance sensors.
+//
+// The trigger pulse comes from a `gpio::Pulser`, and the echo is timed by a second PIO state
+// machine that counts clk_sys cycles while the echo pin is high, so task latency doesn't show
+// up in the distance. The echo pin is 5V on the original module and needs a divider.
+
+use core::time::Duration;
+
+use crate::{
+    clocks,
+    gpio::Pulser,
+    pio::{self, instr, Program, StateMachine},
+    time::{self, Instant},
+};
+
+// Waits for the echo to go high, then counts X down every other cycle until it goes low again
+// and pushes how far it got.
+const PROGRAM: [u16; 6] = [
+    instr::wait_pin(true, 0),
+    instr::MOV_X_NOT_NULL,
+    // loop:
+    instr::jmp_x_dec(3),
+    instr::jmp_pin(2),
+    instr::MOV_ISR_NOT_X,
+    instr::PUSH_BLOCK,
+];
+const WAITING: u8 = 0;
+const DONE: u8 = 5;
+const CYCLES_PER_COUNT: u64 = 2;
+
+const TRIGGER: Duration = Duration::from_micros(10);
+// The datasheet asks for this long between measurements, so echoes from the last one have
+// died away.
+const CYCLE: Duration = Duration::from_millis(60);
+// Echoes longer than this are past the 4 m the module is rated for. With nothing in range it
+// holds the echo high for about 38 ms.
+const MAX_ECHO: Duration = Duration::from_micros(23_500);
+// From the end of the trigger pulse to giving up on the echo altogether.
+const ECHO_TIMEOUT: Duration = Duration::from_millis(50);
+
+// Speed of sound in mm/s at 0 degrees, and how much it goes up per degree.
+const SOUND_MM_PER_S: i64 = 331_300;
+const SOUND_MM_PER_S_PER_DEG: i64 = 606;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Distance {
+    um: u32,
+}
+
+impl Distance {
+    pub const fn from_um(um: u32) -> Self {
+        Distance { um }
+    }
+
+    pub const fn as_um(&self) -> u32 {
+        self.um
+    }
+
+    pub const fn as_mm(&self) -> u32 {
+        self.um / 1000
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The echo never started: nothing's connected, or the module has no power.
+    Timeout,
+    // The echo came back too late for anything in range, or not at all.
+    OutOfRange,
+}
+
+pub struct UltrasonicSensor {
+    trigger: Pulser,
+    echo: StateMachine,
+    program: Program,
+    echo_pin: u8,
+    temperature_mdeg: i32,
+    last: Option<Instant>,
+}
+
+impl UltrasonicSensor {
+    // Returns `None` if `block` doesn't have two free state machines and room for both
+    // programs.
+    pub fn new(block: u8, trigger_pin: u8, echo_pin: u8) -> Option<Self> {
+        let trigger = Pulser::new(block, trigger_pin)?;
+        let mut echo = pio::claim(block)?;
+        let program = pio::load(block, &PROGRAM)?;
+        pio::use_pin(block, echo_pin);
+        echo.configure(
+            &program,
+            &pio::Config {
+                wrap: DONE,
+                in_base: echo_pin,
+                jmp_pin: echo_pin,
+                ..Default::default()
+            },
+        );
+        echo.set_pindirs(echo_pin, 1, false);
+        echo.set_enabled(true);
+        Some(UltrasonicSensor {
+            trigger,
+            echo,
+            program,
+            echo_pin,
+            temperature_mdeg: 20_000,
+            last: None,
+        })
+    }
+
+    // Air temperature in thousandths of a degree C, for the speed of sound. 20 degrees until
+    // this is called.
+    pub fn set_temperature(&mut self, mdeg: i32) {
+        self.temperature_mdeg = mdeg;
+    }
+
+    // Trigger a measurement and wait for the echo. Measurements are spaced at least 60 ms
+    // apart, so this waits first if the last one was more recent.
+    pub async fn measure(&mut self) -> Result<Distance, Error> {
+        if let Some(last) = self.last {
+            time::sleep_until(last + CYCLE).await;
+        }
+        self.last = Some(Instant::now());
+        // Anything left over from an echo that started after we gave up on it.
+        self.echo.clear_fifos();
+        self.trigger.pulse(TRIGGER).await;
+
+        let echo = &mut self.echo;
+        let Ok(counts) = time::timeout(ECHO_TIMEOUT, echo.pull()).await else {
+            let pc = echo.pc().wrapping_sub(self.program.offset());
+            self.reset();
+            return Err(if pc == WAITING {
+                Error::Timeout
+            } else {
+                Error::OutOfRange
+            });
+        };
+
+        let sys_hz = clocks::sys_hz() as u64;
+        let cycles = counts as u64 * CYCLES_PER_COUNT;
+        if cycles > MAX_ECHO.as_micros() as u64 * sys_hz / 1_000_000 {
+            return Err(Error::OutOfRange);
+        }
+        let sound_mm_per_s =
+            SOUND_MM_PER_S + SOUND_MM_PER_S_PER_DEG * self.temperature_mdeg as i64 / 1000;
+        // Half the round trip.
+        let um = cycles * sound_mm_per_s.max(0) as u64 * 1000 / sys_hz / 2;
+        Ok(Distance::from_um(um as u32))
+    }
+
+    // Go back to waiting for an echo to start.
+    fn reset(&mut self) {
+        let echo = &mut self.echo;
+        echo.set_enabled(false);
+        echo.clear_fifos();
+        echo.restart();
+        echo.exec(instr::jmp(self.program.offset()));
+        echo.set_enabled(true);
+    }
+
+    pub fn release(self) -> (u8, u8) {
+        let echo_pin = self.echo_pin;
+        let program = self.program;
+        let trigger_pin = self.trigger.release();
+        drop(self.echo);
+        pio::unload(program);
+        (trigger_pin, echo_pin)
+    }
+}
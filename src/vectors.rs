@@ -0,0 +1,112 @@
+// Vector tables in RAM, so interrupt handlers can be installed and swapped at runtime.
+//
+// Each core gets its own table (VTOR is per core). `init` copies whatever table the core is
+// currently using and points VTOR at the copy. Core 0 calls it from `main`, and core 1 from
+// `jumpstart` before running its entry point; core 1 boots with core 0's VTOR, so it starts
+// out with the same handlers.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use rp2040_pac::Interrupt;
+
+use crate::{barrier, ramcheck, sync};
+
+// 16 system exceptions, then the 26 IRQs.
+const LEN: usize = 16 + 26;
+
+// VTOR needs the table aligned to its size rounded up to a power of two: 42 words -> 256 bytes.
+#[repr(C, align(256))]
+struct Table([AtomicUsize; LEN]);
+
+static TABLES: [Table; 2] = [
+    Table([const { AtomicUsize::new(0) }; LEN]),
+    Table([const { AtomicUsize::new(0) }; LEN]),
+];
+
+// The table linked into flash, for going back to the original handlers.
+static FLASH_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+fn address(table: &Table) -> u32 {
+    table as *const Table as u32
+}
+
+fn is_ram_table(vtor: u32) -> bool {
+    TABLES.iter().any(|table| address(table) == vtor)
+}
+
+// Move this core's vector table into RAM. Does nothing if it's already there.
+pub fn init() {
+    let ppb = unsafe { &(*rp2040_pac::PPB::ptr()) };
+    let table = &TABLES[sync::core()];
+    let current = ppb.vtor.read().bits();
+    if current == address(table) {
+        return;
+    }
+    if !is_ram_table(current) {
+        FLASH_TABLE.store(current as usize, Ordering::Relaxed);
+    }
+    cortex_m::interrupt::free(|_| {
+        for (i, entry) in table.0.iter().enumerate() {
+            // Safety: VTOR always points at a valid table of at least LEN words.
+            let word = unsafe { (current as *const usize).add(i).read_volatile() };
+            entry.store(word, Ordering::Relaxed);
+        }
+        // The table has to be complete before the core starts fetching from it.
+        barrier::settle();
+        ppb.vtor.write(|w| unsafe { w.bits(address(table)) });
+        barrier::settle();
+    });
+    // Nothing should change it from here on except `set`, which goes through `update`.
+    ramcheck::guard(["vectors.core0", "vectors.core1"][sync::core()], table);
+}
+
+// The address of this core's vector table, for handing to the other core at boot.
+pub fn current() -> u32 {
+    let ppb = unsafe { &(*rp2040_pac::PPB::ptr()) };
+    ppb.vtor.read().bits()
+}
+
+// Install `handler` for `irq` on both cores, returning the address of the previous one.
+//
+// Panics if `init` hasn't been called on this core.
+pub fn set_handler(irq: Interrupt, handler: extern "C" fn()) -> usize {
+    set(irq, handler as usize)
+}
+
+// Put back the handler `irq` had in the flash table, returning the address of the replaced one.
+pub fn reset_handler(irq: Interrupt) -> usize {
+    let flash = FLASH_TABLE.load(Ordering::Relaxed) as *const usize;
+    assert!(!flash.is_null(), "vectors::init hasn't been called");
+    // Safety: This was a valid vector table before init copied it.
+    let original = unsafe { flash.add(16 + irq as usize).read_volatile() };
+    set(irq, original)
+}
+
+// Whether `irq` has the handler it was linked with.
+pub fn is_default(irq: Interrupt) -> bool {
+    let flash = FLASH_TABLE.load(Ordering::Relaxed) as *const usize;
+    if flash.is_null() {
+        return true;
+    }
+    // Safety: As above.
+    let original = unsafe { flash.add(16 + irq as usize).read_volatile() };
+    TABLES[sync::core()].0[16 + irq as usize].load(Ordering::Relaxed) == original
+}
+
+fn set(irq: Interrupt, handler: usize) -> usize {
+    assert!(
+        current() == address(&TABLES[sync::core()]),
+        "vectors::init hasn't been called"
+    );
+    let i = 16 + irq as usize;
+    // Single word stores, so an exception never sees half a handler. No swap on the M0+,
+    // so take the old value with interrupts off.
+    cortex_m::interrupt::free(|_| {
+        let old = TABLES[sync::core()].0[i].load(Ordering::Relaxed);
+        for table in &TABLES {
+            ramcheck::update(table, || table.0[i].store(handler, Ordering::Relaxed));
+        }
+        barrier::settle();
+        old
+    })
+}
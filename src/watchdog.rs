@@ -0,0 +1,124 @@
+// The watchdog: resets the chip if it isn't fed in time, and can reset it on demand.
+//
+// It counts the same 1 µs reference tick as the TIMER, so the timeout doesn't depend on
+// clk_sys. A watchdog reset takes everything but the oscillators with it, and leaves the
+// scratch registers alone, which is how `safemode` and the bootrom pass things across one.
+
+use core::time::Duration;
+
+use crate::atomic::{AtomicU32, Ordering};
+
+// The longest timeout the counter can hold.
+pub const MAX_TIMEOUT: Duration = Duration::from_micros(0x7f_ffff);
+
+// CTRL
+const ENABLE: u32 = 1 << 30;
+const TRIGGER: u32 = 1 << 31;
+const PAUSE_JTAG: u32 = 1 << 24;
+const PAUSE_DBG0: u32 = 1 << 25;
+const PAUSE_DBG1: u32 = 1 << 26;
+
+// What a watchdog reset resets: everything except the ROSC and XOSC.
+const WDSEL: u32 = 0x0001_fffc;
+
+static TIMEOUT_US: AtomicU32 = AtomicU32::new(0);
+
+// The scratch registers that are free for firmware; SCRATCH4-7 belong to the bootrom.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scratch {
+    S0,
+    S1,
+    S2,
+    S3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    // Power-on, brownout or the RUN pin: the scratch registers are zero.
+    PowerOn,
+    // The watchdog wasn't fed in time.
+    Timeout,
+    // `reboot`, or anything else that triggered it on purpose.
+    Forced,
+}
+
+fn watchdog() -> &'static rp2040_pac::watchdog::RegisterBlock {
+    unsafe { &*rp2040_pac::WATCHDOG::ptr() }
+}
+
+// Why the chip last came out of reset.
+pub fn reason() -> Reason {
+    match watchdog().reason.read().bits() {
+        0 => Reason::PowerOn,
+        1 => Reason::Timeout,
+        _ => Reason::Forced,
+    }
+}
+
+// Start the watchdog, or change its timeout. It stops counting while a debugger has either
+// core halted.
+pub fn start(timeout: Duration) {
+    let wd = watchdog();
+    select_reset();
+    wd.ctrl.write(|w| unsafe { w.bits(0) });
+    set_load(timeout);
+    wd.ctrl
+        .write(|w| unsafe { w.bits(ENABLE | PAUSE_JTAG | PAUSE_DBG0 | PAUSE_DBG1) });
+    TIMEOUT_US.store(
+        timeout.min(MAX_TIMEOUT).as_micros() as u32,
+        Ordering::Relaxed,
+    );
+}
+
+pub fn stop() {
+    watchdog().ctrl.write(|w| unsafe { w.bits(0) });
+}
+
+// Put the count back to the full timeout.
+pub fn feed() {
+    let timeout = Duration::from_micros(TIMEOUT_US.load(Ordering::Relaxed) as u64);
+    set_load(timeout);
+}
+
+// Reset the chip now, through the watchdog.
+pub fn reboot() -> ! {
+    let wd = watchdog();
+    select_reset();
+    // Stop the bootrom taking whatever's in SCRATCH4 as an address to jump to.
+    wd.scratch4.write(|w| unsafe { w.bits(0) });
+    wd.ctrl.write(|w| unsafe { w.bits(TRIGGER) });
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+pub fn scratch(register: Scratch) -> u32 {
+    let wd = watchdog();
+    match register {
+        Scratch::S0 => wd.scratch0.read().bits(),
+        Scratch::S1 => wd.scratch1.read().bits(),
+        Scratch::S2 => wd.scratch2.read().bits(),
+        Scratch::S3 => wd.scratch3.read().bits(),
+    }
+}
+
+pub fn set_scratch(register: Scratch, value: u32) {
+    let wd = watchdog();
+    match register {
+        Scratch::S0 => wd.scratch0.write(|w| unsafe { w.bits(value) }),
+        Scratch::S1 => wd.scratch1.write(|w| unsafe { w.bits(value) }),
+        Scratch::S2 => wd.scratch2.write(|w| unsafe { w.bits(value) }),
+        Scratch::S3 => wd.scratch3.write(|w| unsafe { w.bits(value) }),
+    }
+}
+
+fn set_load(timeout: Duration) {
+    // The counter goes down two per tick (erratum RP2040-E1).
+    let ticks = timeout.min(MAX_TIMEOUT).as_micros() as u32 * 2;
+    watchdog().load.write(|w| unsafe { w.bits(ticks) });
+}
+
+fn select_reset() {
+    let psm = unsafe { &*rp2040_pac::PSM::ptr() };
+    psm.wdsel.write(|w| unsafe { w.bits(WDSEL) });
+}